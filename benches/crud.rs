@@ -0,0 +1,132 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use orm::{column, Connection, Object, ObjectId};
+use std::hint::black_box;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone)]
+struct Account {
+    name: String,
+    balance: i64,
+    rate: f64,
+    is_active: bool,
+}
+
+const ROWS: i64 = 1000;
+
+fn account(i: i64) -> Account {
+    Account {
+        name: format!("account {}", i),
+        balance: i * 10,
+        rate: i as f64 / 100.,
+        is_active: i % 2 == 0,
+    }
+}
+
+fn populated() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create_many((0..ROWS).map(account)).unwrap();
+    tx.commit().unwrap();
+    conn
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn create(c: &mut Criterion) {
+    let mut conn = Connection::open_in_memory().unwrap();
+    c.bench_function("create", |b| {
+        b.iter(|| {
+            let tx = conn.new_transaction().unwrap();
+            tx.create(account(1)).unwrap();
+            tx.commit().unwrap();
+        })
+    });
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    c.bench_function("create_many_100", |b| {
+        b.iter(|| {
+            let tx = conn.new_transaction().unwrap();
+            tx.create_many((0..100).map(account)).unwrap();
+            tx.commit().unwrap();
+        })
+    });
+}
+
+fn get(c: &mut Criterion) {
+    let mut conn = populated();
+    let mut id = 0;
+    c.bench_function("get", |b| {
+        b.iter(|| {
+            id = id % ROWS + 1;
+            let tx = conn.new_transaction().unwrap();
+            black_box(
+                tx.get::<Account>(ObjectId::from(id))
+                    .unwrap()
+                    .borrow()
+                    .balance,
+            );
+            tx.rollback().unwrap();
+        })
+    });
+
+    // Every object is in the cache after the first round.
+    let mut conn = populated();
+    let tx = conn.new_transaction().unwrap();
+    let mut id = 0;
+    c.bench_function("get_cached", |b| {
+        b.iter(|| {
+            id = id % ROWS + 1;
+            black_box(
+                tx.get::<Account>(ObjectId::from(id))
+                    .unwrap()
+                    .borrow()
+                    .balance,
+            );
+        })
+    });
+}
+
+fn update(c: &mut Criterion) {
+    let mut conn = populated();
+    let mut id = 0;
+    c.bench_function("update", |b| {
+        b.iter(|| {
+            id = id % ROWS + 1;
+            let tx = conn.new_transaction().unwrap();
+            tx.get::<Account>(ObjectId::from(id))
+                .unwrap()
+                .borrow_mut()
+                .balance += 1;
+            tx.commit().unwrap();
+        })
+    });
+}
+
+fn select(c: &mut Criterion) {
+    let mut conn = populated();
+    c.bench_function("select_all", |b| {
+        b.iter(|| {
+            let tx = conn.new_transaction().unwrap();
+            black_box(tx.select_all::<Account>().unwrap().len());
+            tx.rollback().unwrap();
+        })
+    });
+
+    let active = column("is_active").eq(true);
+    c.bench_function("scan", |b| {
+        b.iter(|| {
+            let tx = conn.new_transaction().unwrap();
+            let mut rows = 0;
+            tx.scan::<Account>(&active, |_, row| {
+                rows += black_box(row).len();
+                Ok(())
+            })
+            .unwrap();
+            tx.rollback().unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, create, get, update, select);
+criterion_main!(benches);
@@ -0,0 +1,183 @@
+#![forbid(unsafe_code)]
+use orm::{dynamic, migrations::Migration, object::Schema, storage::TableStats, Database, Object};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+};
+use thiserror::Error;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const USAGE: &str = "usage: orm-cli <database> <command>
+
+commands:
+    migrate          apply the pending migrations
+    migrations       list the migrations and whether they are applied
+    validate         check the stored tables against the schemas
+    dump [FILE]      write every row as JSON lines, to stdout by default
+    restore FILE     create the rows of a dump
+    stats            print the row and column count of every table";
+
+// The commands know the migrations and object types they are given, so an
+// application builds its own binary around them:
+//
+//     fn main() {
+//         let cli = orm_cli::Cli::new().migration(&AddUsers).object::<User>();
+//         std::process::exit(cli.main());
+//     }
+//
+// The orm-cli binary itself knows none, which leaves it stats, and dumps of
+// nothing.
+#[derive(Default)]
+pub struct Cli<'m> {
+    migrations: Vec<&'m dyn Migration>,
+    schemas: Vec<&'static Schema>,
+}
+
+impl<'m> Cli<'m> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn migration(mut self, migration: &'m dyn Migration) -> Self {
+        self.migrations.push(migration);
+        self
+    }
+
+    // Validated by validate, and registered with dynamic::register, which
+    // dump and restore go by.
+    pub fn object<T: Object>(mut self) -> Self {
+        dynamic::register::<T>();
+        self.schemas.push(T::TABLE);
+        self
+    }
+
+    // Runs the command of the process arguments, printing errors to stderr.
+    // Returns the exit code.
+    pub fn main(&self) -> i32 {
+        let stdout = io::stdout();
+        match self.run(std::env::args().skip(1), &mut stdout.lock()) {
+            Ok(()) => 0,
+            Err(message) => {
+                eprintln!("{}", message);
+                1
+            }
+        }
+    }
+
+    pub fn run<I: IntoIterator<Item = String>>(
+        &self,
+        args: I,
+        out: &mut dyn Write,
+    ) -> Result<(), String> {
+        let args: Vec<_> = args.into_iter().collect();
+        let (path, command, rest) = match args.as_slice() {
+            [path, command, rest @ ..] => (path, command.as_str(), rest),
+            _ => return Err(USAGE.to_string()),
+        };
+        let rest: Vec<_> = rest.iter().map(String::as_str).collect();
+        let mut db = Database::open(path).map_err(|err| err.to_string())?;
+        match (command, rest.as_slice()) {
+            ("migrate", []) => self.migrate(&mut db, out),
+            ("migrations", []) => self.list_migrations(&mut db, out),
+            ("validate", []) => self.validate(&mut db, out),
+            ("dump", []) => dump(&mut db, out, None),
+            ("dump", [file]) => dump(&mut db, out, Some(file)),
+            ("restore", [file]) => restore(&mut db, out, file),
+            ("stats", []) => stats(&mut db, out),
+            _ => return Err(USAGE.to_string()),
+        }
+        .map_err(|err| err.to_string())
+    }
+
+    fn migrate(&self, db: &mut Database, out: &mut dyn Write) -> Result<(), Failure> {
+        let applied = db.migrate(&self.migrations)?;
+        if applied.is_empty() {
+            writeln!(out, "no pending migrations")?;
+        }
+        for version in applied {
+            writeln!(out, "applied {}", version)?;
+        }
+        Ok(())
+    }
+
+    fn list_migrations(&self, db: &mut Database, out: &mut dyn Write) -> Result<(), Failure> {
+        let applied = db.applied_migrations()?;
+        let mut migrations: Vec<_> = self
+            .migrations
+            .iter()
+            .map(|m| (m.version(), m.name().to_string()))
+            .collect();
+        for version in &applied {
+            if !migrations.iter().any(|(known, _)| known == version) {
+                migrations.push((*version, "(unknown)".to_string()));
+            }
+        }
+        migrations.sort();
+        for (version, name) in migrations {
+            let state = match applied.contains(&version) {
+                true => "applied",
+                false => "pending",
+            };
+            writeln!(out, "{} {} {}", version, state, name)?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self, db: &mut Database, out: &mut dyn Write) -> Result<(), Failure> {
+        let mismatches = db.validate_schemas(&self.schemas)?;
+        for mismatch in &mismatches {
+            writeln!(out, "{}", mismatch)?;
+        }
+        match mismatches.len() {
+            0 => Ok(writeln!(out, "{} tables match", self.schemas.len())?),
+            n => Err(Failure::Message(format!("{} tables do not match", n))),
+        }
+    }
+}
+
+fn dump(db: &mut Database, out: &mut dyn Write, file: Option<&str>) -> Result<(), Failure> {
+    match file {
+        Some(file) => {
+            let rows = db.dump(BufWriter::new(File::create(file)?))?;
+            writeln!(out, "dumped {} rows", rows)?;
+        }
+        None => {
+            db.dump(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn restore(db: &mut Database, out: &mut dyn Write, file: &str) -> Result<(), Failure> {
+    let rows = db.restore(BufReader::new(File::open(file)?))?;
+    writeln!(out, "restored {} rows", rows)?;
+    Ok(())
+}
+
+fn stats(db: &mut Database, out: &mut dyn Write) -> Result<(), Failure> {
+    let stats = db.table_stats()?;
+    let width = stats.iter().map(|t| t.name.len()).max().unwrap_or(0).max(5);
+    writeln!(out, "{:width$} {:>10} {:>8}", "table", "rows", "columns")?;
+    for TableStats {
+        name,
+        rows,
+        columns,
+    } in stats
+    {
+        writeln!(out, "{:width$} {:>10} {:>8}", name, rows, columns)?;
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+enum Failure {
+    #[error(transparent)]
+    Orm(#[from] orm::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Message(String),
+}
@@ -0,0 +1,5 @@
+#![forbid(unsafe_code)]
+
+fn main() {
+    std::process::exit(orm_cli::Cli::new().main());
+}
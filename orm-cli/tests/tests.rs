@@ -0,0 +1,106 @@
+use orm::{migrations::Migration, Object, Result, Transaction};
+use orm_cli::Cli;
+use tempfile::NamedTempFile;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+struct Note {
+    text: String,
+}
+
+struct AddNotes;
+
+impl Migration for AddNotes {
+    fn version(&self) -> i64 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "add notes"
+    }
+
+    fn up(&self, tx: &Transaction) -> Result<()> {
+        tx.create(Note {
+            text: "first".to_string(),
+        })?;
+        Ok(())
+    }
+}
+
+fn run(cli: &Cli, args: &[&str]) -> std::result::Result<String, String> {
+    let mut out = Vec::new();
+    cli.run(args.iter().map(|arg| arg.to_string()), &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn commands() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let db = path.to_str().unwrap();
+    let cli = Cli::new().migration(&AddNotes).object::<Note>();
+
+    assert_eq!(
+        run(&cli, &[db, "migrations"]).unwrap(),
+        "1 pending add notes\n"
+    );
+    assert_eq!(run(&cli, &[db, "migrate"]).unwrap(), "applied 1\n");
+    assert_eq!(
+        run(&cli, &[db, "migrate"]).unwrap(),
+        "no pending migrations\n"
+    );
+    assert_eq!(
+        run(&cli, &[db, "migrations"]).unwrap(),
+        "1 applied add notes\n"
+    );
+    assert_eq!(run(&cli, &[db, "validate"]).unwrap(), "1 tables match\n");
+
+    let stats = run(&cli, &[db, "stats"]).unwrap();
+    assert!(stats.starts_with("table "));
+    assert!(stats
+        .lines()
+        .any(|line| line.split_whitespace().eq(["Note", "1", "1"])));
+
+    let dump = run(&cli, &[db, "dump"]).unwrap();
+    assert_eq!(
+        dump,
+        "{\"table\":\"Note\",\"id\":1,\"values\":{\"text\":\"first\"}}\n"
+    );
+    let file = NamedTempFile::new().unwrap().into_temp_path();
+    let file = file.to_str().unwrap();
+    assert_eq!(run(&cli, &[db, "dump", file]).unwrap(), "dumped 1 rows\n");
+
+    let copy = NamedTempFile::new().unwrap().into_temp_path();
+    let copy = copy.to_str().unwrap();
+    assert_eq!(
+        run(&cli, &[copy, "restore", file]).unwrap(),
+        "restored 1 rows\n"
+    );
+    assert_eq!(run(&cli, &[copy, "dump"]).unwrap(), dump);
+}
+
+#[test]
+fn errors() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let db = path.to_str().unwrap();
+    let cli = Cli::new();
+
+    assert!(run(&cli, &[db]).unwrap_err().starts_with("usage: "));
+    assert!(run(&cli, &[db, "dump", "a", "b"])
+        .unwrap_err()
+        .starts_with("usage: "));
+    assert!(run(&cli, &[db, "restore", "/nonexistent/dump"]).is_err());
+
+    #[derive(Object)]
+    struct Other {
+        text: i64,
+    }
+    let cli = Cli::new().object::<Note>().object::<Other>();
+    run(&Cli::new().migration(&AddNotes), &[db, "migrate"]).unwrap();
+    assert_eq!(
+        run(&cli, &[db, "validate"]).unwrap_err(),
+        "1 tables do not match"
+    );
+}
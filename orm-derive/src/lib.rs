@@ -1,12 +1,14 @@
 #![forbid(unsafe_code)]
 use proc_macro::TokenStream;
+use proc_macro_crate::{crate_name, FoundCrate};
 use quote::quote;
 
 use syn::{parse_macro_input, DeriveInput, Fields, Field, FieldsNamed, LitStr, DataStruct, Type};
 
-#[proc_macro_derive(Object, attributes(table_name, column_name))]
+#[proc_macro_derive(Object, attributes(table_name, column_name, lazy, encrypted, compress, computed, hooks, audited, strict, without_rowid, trigger, view))]
 pub fn derive_object(input: TokenStream) -> TokenStream {
     let DeriveInput { ident, data, attrs, .. } = parse_macro_input!(input);
+    let krate = orm_path();
 
     let tables = attrs
         .iter()
@@ -14,12 +16,50 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
         .and_then(|attr| attr.parse_args::<LitStr>().ok().map(|lit_str| lit_str.value()))
         .unwrap_or_else(|| ident.to_string());
 
+    let hooks = if attrs.iter().any(|attr| attr.path().is_ident("hooks")) {
+        format!("fn hooks(&mut self) -> ::std::option::Option<&mut dyn {}::object::Hooks> {{
+            ::std::option::Option::Some(self)
+        }}", krate)
+    } else {
+        String::new()
+    };
+
+    let audited = attrs.iter().any(|attr| attr.path().is_ident("audited"));
+    let strict = attrs.iter().any(|attr| attr.path().is_ident("strict"));
+    let without_rowid = attrs.iter().any(|attr| attr.path().is_ident("without_rowid"));
+    let view = attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("view"))
+        .and_then(|attr| attr.parse_args::<LitStr>().ok().map(|lit_str| format!("::std::option::Option::Some({:?})", lit_str.value())))
+        .unwrap_or_else(|| "::std::option::Option::None".to_string());
+    let triggers = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("trigger"))
+        .filter_map(|attr| attr.parse_args::<LitStr>().ok().map(|lit_str| format!("{:?}", lit_str.value())))
+        .collect::<Vec<String>>()
+        .join(", ");
+
     let fields = if let syn::Data::Struct(DataStruct { fields: Fields::Named(FieldsNamed { named, .. }), .. }) = data {
         Some(named.into_iter().collect::<Vec<Field>>())
     } else {
         None
     };
 
+    let computed = fields.iter().flatten().filter_map(|field| {
+        field.attrs
+            .iter()
+            .find(|attr| attr.path().is_ident("computed"))
+            .and_then(|attr| attr.parse_args::<LitStr>().ok().map(|lit_str| lit_str.value()))
+            .map(|method| format!("self.{} = Self::{}(self);", field.ident.as_ref().unwrap(), method))
+    }).collect::<Vec<String>>();
+    let compute = if computed.is_empty() {
+        String::new()
+    } else {
+        format!("fn compute(&mut self) {{
+            {}
+        }}", computed.join("\n"))
+    };
+
     let (idents, columns, types) = if let Some(fields) = fields {
         fields.into_iter().map(|field| {
             let column = field.attrs
@@ -27,57 +67,99 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                 .find(|attr| attr.path().is_ident("column_name"))
                 .and_then(|attr| attr.parse_args::<LitStr>().ok().map(|lit_str| lit_str.value()))
                 .unwrap_or_else(|| field.ident.as_ref().unwrap().to_string());
+            let lazy = field.attrs.iter().any(|attr| attr.path().is_ident("lazy"));
+            let encrypted = field.attrs.iter().any(|attr| attr.path().is_ident("encrypted"));
+            let compressed = field.attrs.iter().any(|attr| attr.path().is_ident("compress"));
 
-            (field.ident.unwrap(), column, field.ty)
+            (field.ident.unwrap(), (column, lazy, encrypted, compressed), field.ty)
         }).deal_out()
     } else {
         (Vec::new(), Vec::new(), Vec::new())
     };
 
-    let schema_fields = idents.iter().zip(columns.iter()).zip(types.iter()).map(|((ident, column), ty)| {
+    let schema_fields = idents.iter().zip(columns.iter()).zip(types.iter()).map(|((ident, (column, lazy, encrypted, compressed)), ty)| {
+        // Encrypted and compressed columns name a constant that only exists
+        // with the `encryption` or `compression` feature of the orm crate.
+        let enabled = |on: bool, module: &str| if on {
+            format!("{}::{}::ENABLED", krate, module)
+        } else {
+            "false".to_string()
+        };
+        let encrypted = enabled(*encrypted, "encryption");
+        let compressed = enabled(*compressed, "compression");
         format!(
-            "::orm::object::Column {{
+            "{krate}::object::Column {{
                 column_name: \"{}\",
                 attr_name: stringify!({}),
-                typ: <{} as ::orm::data::ObjectType>::TYPE,
+                typ: <{} as {krate}::data::ObjectType>::TYPE,
+                lazy: {},
+                encrypted: {},
+                compressed: {},
             }}",
-            column, ident, type_to_string(ty),
+            column, ident, type_to_string(ty), lazy, encrypted, compressed, krate = krate,
         )
     }).collect::<Vec<String>>().join(", ");
 
-    let deserialize_fields = idents.iter().map(|ident| {
-        format!("{}: iter.next().unwrap().into()", ident)
+    let deserialize_fields = idents.iter().enumerate().map(|(index, ident)| {
+        format!("{}: {}::object::next_field(&mut iter, Self::TABLE, {})?", ident, krate, index)
     }).collect::<Vec<String>>().join(", ");
 
     let serialize_fields = idents.iter().map(|ident| {
         format!("(&self.{}).into()", ident)
     }).collect::<Vec<String>>().join(", ");
 
+    let serialize_into_fields = idents.iter().map(|ident| {
+        format!("row.push({}::data::Value::into_owned((&self.{}).into()));", krate, ident)
+    }).collect::<Vec<String>>().join("\n");
+
     let expanded = format!(
-        "impl ::orm::Object for {} {{
-            const TABLE: &'static ::orm::object::Schema = &::orm::object::Schema {{
+        "impl {krate}::Object for {} {{
+            const TABLE: &'static {krate}::object::Schema = &{krate}::object::Schema {{
                 table_name: \"{}\",
                 type_name: stringify!({}),
                 columns: &[{}],
+                fingerprint: {krate}::object::fingerprint(&[{}]),
+                audited: {},
+                strict: {},
+                without_rowid: {},
+                triggers: &[{}],
+                view: {},
             }};
 
-            fn deserialize(row: ::orm::storage::Row) -> Self {{
+            fn deserialize(
+                row: {krate}::storage::Row,
+            ) -> ::std::result::Result<Self, {krate}::error::DeserializeError> {{
                 let mut iter = row.into_iter();
-                Self {{
+                Ok(Self {{
                     {}
-                }}
+                }})
             }}
-            fn serialize(&self) -> ::orm::storage::Row {{
+            fn serialize(&self) -> {krate}::storage::Row {{
                 let values = vec![{}];
                 values.into()
             }}
+            fn serialize_into(&self, row: &mut {krate}::storage::Row<'static>) {{
+                row.reserve({});
+                {}
+            }}
+            {}
+            {}
         }}",
-        ident, tables, ident, schema_fields, deserialize_fields, serialize_fields
+        ident, tables, ident, schema_fields, schema_fields, audited, strict, without_rowid, triggers, view, deserialize_fields, serialize_fields, idents.len(), serialize_into_fields, hooks, compute, krate = krate
     );
 
     expanded.parse().unwrap()
 }
 
+// Where the generated code finds the orm crate: under the name the user's
+// Cargo.toml gives it, so that a renamed dependency works too. The crate
+// names itself `orm` for its own tests and the code it derives for itself.
+fn orm_path() -> String {
+    match crate_name("orm") {
+        Ok(FoundCrate::Name(name)) => format!("::{}", name),
+        Ok(FoundCrate::Itself) | Err(_) => "::orm".to_string(),
+    }
+}
 
 type DealerResult<A, B, C> = (Vec<A>, Vec<B>, Vec<C>);
 
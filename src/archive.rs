@@ -0,0 +1,261 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    dynamic::{registered, registered_create, registered_tables},
+    error::{Error, Result},
+    memory::default_value,
+    object::schema_dump,
+    storage::{is_unsupported, Row},
+    Database, ObjectId, Transaction,
+};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// An archive is MAGIC followed by a deflated stream of records: for every
+// stored table of a registered type, in name order, one with its name, its
+// DDL and its columns, then one per row with the id and the values. The DDL
+// is that of SQLite, for tools reading archives; restores create the tables
+// from the registered types instead, so an archive restores into any
+// backend. Integers are little-endian and strings are prefixed with their
+// length.
+const MAGIC: &[u8] = b"ORMARCHIVE1\0";
+
+const TABLE: u8 = 1;
+const ROW: u8 = 2;
+const END: u8 = 0;
+
+impl<'a> Transaction<'a> {
+    // Types backed by a view are left out. Returns the number of rows.
+    pub fn write_archive(&self, mut writer: impl Write) -> Result<usize> {
+        self.flush()?;
+        writer.write_all(MAGIC).map_err(io_error)?;
+        let mut out = DeflateEncoder::new(writer, Compression::default());
+        let mut rows = 0;
+        for table in registered_tables() {
+            let schema = match registered(table) {
+                Some(schema) if schema.view.is_none() => schema,
+                _ => continue,
+            };
+            if !self.storage().table_exists(schema.table_name)? {
+                continue;
+            }
+            let mut header = vec![TABLE];
+            put_str(&mut header, schema.table_name);
+            put_str(&mut header, &schema_dump(&[schema]));
+            header.extend((schema.columns.len() as u32).to_le_bytes());
+            for column in schema.columns {
+                put_str(&mut header, column.column_name);
+                header.push(type_tag(column.typ));
+            }
+            out.write_all(&header).map_err(io_error)?;
+
+            let mut record = Vec::new();
+            self.storage()
+                .for_each_row_ref(schema.eager(), "", &[], &mut |id, row| {
+                    record.clear();
+                    record.push(ROW);
+                    record.extend(id.into_i64().to_le_bytes());
+                    for value in row {
+                        put_value(&mut record, value);
+                    }
+                    rows += 1;
+                    out.write_all(&record).map_err(io_error)
+                })?;
+        }
+        out.write_all(&[END]).map_err(io_error)?;
+        out.finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(io_error)?;
+        Ok(rows)
+    }
+
+    // Creates the objects of an archive with their ids, as restore does for
+    // a dump. Archived columns the type no longer declares are an error;
+    // columns it declares since get the zero value of their type.
+    pub fn restore_archive(&self, mut reader: impl Read) -> Result<usize> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(io_error)?;
+        if magic != MAGIC {
+            return Err(invalid("not an archive".to_string()));
+        }
+        match self.defer_foreign_keys(true) {
+            Err(err) if !is_unsupported(&err) => return Err(err),
+            _ => {}
+        }
+        let mut input = DeflateDecoder::new(reader);
+        let mut table = None;
+        let mut rows = 0;
+        loop {
+            match get_u8(&mut input)? {
+                END => return Ok(rows),
+                TABLE => {
+                    let name = get_str(&mut input)?;
+                    let _ddl = get_str(&mut input)?;
+                    let (schema, create) = registered_create(&name)
+                        .ok_or_else(|| invalid(format!("table {} is not registered", name)))?;
+                    let count = get_u32(&mut input)?;
+                    let mut positions = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        let column = get_str(&mut input)?;
+                        let typ = get_type(&mut input)?;
+                        let position = schema
+                            .columns
+                            .iter()
+                            .position(|c| c.column_name == column)
+                            .ok_or_else(|| {
+                                invalid(format!("{} has no column {}", schema.table_name, column))
+                            })?;
+                        positions.push((position, typ));
+                    }
+                    table = Some((schema, create, positions));
+                }
+                ROW => {
+                    let (schema, create, positions) = table
+                        .as_ref()
+                        .ok_or_else(|| invalid("a row before its table".to_string()))?;
+                    let id = ObjectId::from(get_i64(&mut input)?);
+                    let mut row: Row<'static> = schema
+                        .columns
+                        .iter()
+                        .map(|column| default_value(column.typ))
+                        .collect();
+                    for (position, typ) in positions {
+                        let value = get_value(&mut input, *typ)?;
+                        let column = &schema.columns[*position];
+                        if value.data_type() != column.typ {
+                            return Err(invalid(format!(
+                                "{}.{} is archived as {:?}",
+                                schema.table_name, column.column_name, typ
+                            )));
+                        }
+                        row[*position] = value;
+                    }
+                    create(self, Some(id), row)?;
+                    rows += 1;
+                }
+                tag => return Err(invalid(format!("unknown record {}", tag))),
+            }
+        }
+    }
+}
+
+impl Database {
+    // The file is written again if the transaction is retried.
+    pub fn snapshot_to_archive<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        self.transaction(|tx| {
+            let file = File::create(path).map_err(io_error)?;
+            tx.write_archive(BufWriter::new(file))
+        })
+    }
+
+    pub fn restore_from_archive<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        self.transaction(|tx| {
+            let file = File::open(path).map_err(io_error)?;
+            tx.restore_archive(BufReader::new(file))
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn type_tag(typ: DataType) -> u8 {
+    match typ {
+        DataType::String => 0,
+        DataType::Bytes => 1,
+        DataType::Int64 => 2,
+        DataType::Float64 => 3,
+        DataType::Bool => 4,
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_bytes(out, s.as_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// Values are written without their type, which the table record holds.
+fn put_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => put_str(out, s),
+        Value::Bytes(b) => put_bytes(out, b),
+        Value::Int64(i) => out.extend(i.to_le_bytes()),
+        Value::Float64(f) => out.extend(f.to_le_bytes()),
+        Value::Bool(b) => out.push(*b as u8),
+    }
+}
+
+fn get_array<const N: usize>(input: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes).map_err(io_error)?;
+    Ok(bytes)
+}
+
+fn get_u8(input: &mut impl Read) -> Result<u8> {
+    get_array::<1>(input).map(|[b]| b)
+}
+
+fn get_u32(input: &mut impl Read) -> Result<u32> {
+    get_array(input).map(u32::from_le_bytes)
+}
+
+fn get_i64(input: &mut impl Read) -> Result<i64> {
+    get_array(input).map(i64::from_le_bytes)
+}
+
+fn get_bytes(input: &mut impl Read) -> Result<Vec<u8>> {
+    let len = get_u32(input)? as usize;
+    let mut bytes = Vec::new();
+    input
+        .take(len as u64)
+        .read_to_end(&mut bytes)
+        .map_err(io_error)?;
+    match bytes.len() == len {
+        true => Ok(bytes),
+        false => Err(invalid("the archive is truncated".to_string())),
+    }
+}
+
+fn get_str(input: &mut impl Read) -> Result<String> {
+    String::from_utf8(get_bytes(input)?).map_err(|_| invalid("a string is not UTF-8".to_string()))
+}
+
+fn get_type(input: &mut impl Read) -> Result<DataType> {
+    match get_u8(input)? {
+        0 => Ok(DataType::String),
+        1 => Ok(DataType::Bytes),
+        2 => Ok(DataType::Int64),
+        3 => Ok(DataType::Float64),
+        4 => Ok(DataType::Bool),
+        tag => Err(invalid(format!("unknown type {}", tag))),
+    }
+}
+
+fn get_value(input: &mut impl Read, typ: DataType) -> Result<Value<'static>> {
+    Ok(match typ {
+        DataType::String => Value::from(get_str(input)?),
+        DataType::Bytes => Value::from(get_bytes(input)?),
+        DataType::Int64 => Value::from(get_i64(input)?),
+        DataType::Float64 => Value::from(f64::from_le_bytes(get_array(input)?)),
+        DataType::Bool => Value::from(get_u8(input)? != 0),
+    })
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::Storage(Box::new(err))
+}
+
+fn invalid(message: String) -> Error {
+    Error::Storage(format!("invalid archive: {}", message).into())
+}
@@ -0,0 +1,245 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::{Error, Result},
+    object::Object,
+    predicate::Predicate,
+    Connection, ObjectId,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+use tokio::{sync::oneshot, task};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The connection and its transactions are not `Send`, so they live on a
+// dedicated worker thread; the async handles only ship closures to it.
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+enum TxJob {
+    Run(Box<dyn FnOnce(&crate::Transaction) + Send>),
+    Commit(oneshot::Sender<Result<()>>),
+    Rollback(oneshot::Sender<Result<()>>),
+}
+
+// The transaction under way, by number, and the task that opened it, if
+// there is one; None within the task for code run outside of one, as by
+// block_on. A transaction only clears the marker while it is still its own.
+type OpenBy = Arc<Mutex<Option<(u64, Option<task::Id>)>>>;
+
+// Clones share the worker, which stops once the last of them is dropped.
+// The worker runs one job at a time and a transaction holds it until the
+// transaction ends, so calls made meanwhile wait for that. Calls from the
+// task that opened the transaction, which would wait forever, fail instead.
+#[derive(Clone)]
+pub struct Database {
+    jobs: mpsc::Sender<Job>,
+    open_by: OpenBy,
+}
+
+impl Database {
+    #[cfg(feature = "sqlite")]
+    pub async fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Self::spawn(move || Connection::open_sqlite_file(path)).await
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub async fn open_in_memory() -> Result<Self> {
+        Self::spawn(Connection::open_in_memory).await
+    }
+
+    pub async fn spawn<F>(open: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<Connection> + Send + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (ready, opened) = oneshot::channel();
+        thread::spawn(move || {
+            let mut conn = match open() {
+                Ok(conn) => conn,
+                Err(err) => {
+                    let _ = ready.send(Err(err));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            for job in receiver {
+                job(&mut conn);
+            }
+        });
+
+        opened.await.map_err(|_| stopped())??;
+        Ok(Self {
+            jobs,
+            open_by: OpenBy::default(),
+        })
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match *self.open_by.lock().unwrap() {
+            Some((_, task)) if task == task::try_id() => Err(Error::Storage(
+                "the task has a transaction open on the database".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn call<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+    {
+        self.check_open()?;
+        let (reply, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |conn| {
+                let _ = reply.send(f(conn));
+            }))
+            .map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    pub async fn total_changes(&self) -> Result<usize> {
+        self.call(|conn| conn.total_changes()).await
+    }
+
+    // Fails if the task already has one open; see Database.
+    pub async fn transaction(&self) -> Result<Transaction> {
+        self.check_open()?;
+        let (jobs, receiver) = mpsc::channel::<TxJob>();
+        let (started, begun) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |conn| {
+                let tx = match conn.new_transaction() {
+                    Ok(tx) => tx,
+                    Err(err) => {
+                        let _ = started.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = started.send(Ok(()));
+
+                // Runs until commit or rollback; dropping the handle rolls back.
+                for job in receiver {
+                    match job {
+                        TxJob::Run(f) => f(&tx),
+                        TxJob::Commit(reply) => {
+                            let _ = reply.send(tx.commit());
+                            return;
+                        }
+                        TxJob::Rollback(reply) => {
+                            let _ = reply.send(tx.rollback());
+                            return;
+                        }
+                    }
+                }
+            }))
+            .map_err(|_| stopped())?;
+
+        begun.await.map_err(|_| stopped())??;
+        static NUMBERS: AtomicU64 = AtomicU64::new(0);
+        let number = NUMBERS.fetch_add(1, Ordering::Relaxed);
+        *self.open_by.lock().unwrap() = Some((number, task::try_id()));
+        Ok(Transaction {
+            jobs,
+            open_by: self.open_by.clone(),
+            number,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct Transaction {
+    jobs: mpsc::Sender<TxJob>,
+    open_by: OpenBy,
+    number: u64,
+}
+
+// Jobs sent to the worker after this run once the transaction has ended.
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let mut open_by = self.open_by.lock().unwrap();
+        if matches!(*open_by, Some((number, _)) if number == self.number) {
+            *open_by = None;
+        }
+    }
+}
+
+impl Transaction {
+    pub async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&crate::Transaction) -> Result<R> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.jobs
+            .send(TxJob::Run(Box::new(move |tx| {
+                let _ = reply.send(f(tx));
+            })))
+            .map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    pub async fn create<T: Object + Send>(&self, obj: T) -> Result<ObjectId> {
+        self.run(move |tx| Ok(tx.create(obj)?.id().raw())).await
+    }
+
+    pub async fn get<T: Object + Clone + Send>(&self, id: ObjectId) -> Result<T> {
+        self.run(move |tx| Ok(tx.get::<T>(id)?.borrow().clone()))
+            .await
+    }
+
+    pub async fn update<T: Object + Send>(&self, id: ObjectId, obj: T) -> Result<()> {
+        self.run(move |tx| {
+            *tx.get::<T>(id)?.borrow_mut() = obj;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn delete<T: Object>(&self, id: ObjectId) -> Result<()> {
+        self.run(move |tx| {
+            tx.get::<T>(id)?.delete();
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn select<T: Object + Clone + Send>(
+        &self,
+        predicate: Predicate,
+    ) -> Result<Vec<(ObjectId, T)>> {
+        self.run(move |tx| {
+            Ok(tx
+                .select::<T>(&predicate)?
+                .into_iter()
+                .map(|obj| (obj.id().raw(), obj.borrow().clone()))
+                .collect())
+        })
+        .await
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.finish(TxJob::Commit).await
+    }
+
+    pub async fn rollback(self) -> Result<()> {
+        self.finish(TxJob::Rollback).await
+    }
+
+    async fn finish(self, job: fn(oneshot::Sender<Result<()>>) -> TxJob) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.jobs.send(job(reply)).map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> Error {
+    Error::Storage("database worker has stopped".into())
+}
@@ -0,0 +1,133 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::DataType,
+    object::{fingerprint, Column, Schema},
+    storage::{ChangeOp, Row},
+    ObjectId,
+};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Columns of `<table>_history` in front of the audited table's own columns,
+// which hold the row as it was before the change.
+const AUDIT_COLUMNS: [Column; 4] = [
+    Column {
+        column_name: "audit_object_id",
+        attr_name: "audit_object_id",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "audit_operation",
+        attr_name: "audit_operation",
+        typ: DataType::String,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "audit_changed_at",
+        attr_name: "audit_changed_at",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "audit_actor",
+        attr_name: "audit_actor",
+        typ: DataType::String,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+];
+
+// Built once per audited table and kept for the life of the process.
+pub(crate) fn history_schema(schema: &'static Schema) -> &'static Schema {
+    static SCHEMAS: OnceLock<Mutex<HashMap<&'static str, &'static Schema>>> = OnceLock::new();
+    let mut schemas = SCHEMAS
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    schemas.entry(schema.table_name).or_insert_with(|| {
+        let columns: Vec<_> = AUDIT_COLUMNS
+            .iter()
+            .chain(schema.columns)
+            .map(|column| Column {
+                column_name: column.column_name,
+                attr_name: column.attr_name,
+                typ: column.typ,
+                lazy: false,
+                encrypted: column.encrypted,
+                compressed: column.compressed,
+            })
+            .collect();
+        let columns: &'static [Column] = Box::leak(columns.into_boxed_slice());
+        Box::leak(Box::new(Schema {
+            table_name: Box::leak(format!("{}_history", schema.table_name).into_boxed_str()),
+            type_name: schema.type_name,
+            columns,
+            fingerprint: fingerprint(columns),
+            audited: false,
+            strict: schema.strict,
+            without_rowid: false,
+            triggers: &[],
+            view: None,
+        }))
+    })
+}
+
+pub(crate) fn history_row(
+    id: ObjectId,
+    op: ChangeOp,
+    actor: Option<&str>,
+    old: Row<'static>,
+) -> Row<'static> {
+    let changed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64);
+    let mut row: Row = crate::row![
+        id.into_i64().into(),
+        operation_name(op).to_string().into(),
+        changed_at.into(),
+        actor.unwrap_or_default().to_string().into(),
+    ];
+    row.extend(old);
+    row
+}
+
+pub(crate) fn operation_name(op: ChangeOp) -> &'static str {
+    match op {
+        ChangeOp::Insert => "insert",
+        ChangeOp::Update => "update",
+        ChangeOp::Delete => "delete",
+    }
+}
+
+pub(crate) fn parse_operation(name: &str) -> ChangeOp {
+    match name {
+        "insert" => ChangeOp::Insert,
+        "update" => ChangeOp::Update,
+        _ => ChangeOp::Delete,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// One change of an audited object. `old` is the object as it was before the
+// update or delete, or as created for an insert; `actor` is empty unless
+// Transaction::set_actor was used.
+pub struct HistoryEntry<T> {
+    pub operation: ChangeOp,
+    pub changed_at: SystemTime,
+    pub actor: String,
+    pub old: T,
+}
@@ -0,0 +1,480 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::Value,
+    error::Result,
+    object::{Column, Schema},
+    storage::{OnConflict, Row, RowSlice, StorageTransaction, TableInfo},
+    ObjectId,
+};
+use std::{borrow::Cow, time::Instant};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Stores some columns in another form than they are declared with, such as
+// encrypted or compressed, by encoding their values on the way to the
+// storage and decoding them on the way back. The transaction above sees
+// the schema and the values as declared; the storage below sees the schema
+// returned by `stored`.
+pub(crate) trait ColumnCodec {
+    fn handles(&self, column: &Column) -> bool;
+
+    // Only asked for schemas with columns the codec handles.
+    fn stored(&self, schema: &Schema) -> Result<&'static Schema>;
+
+    // The id of the row is only given to codecs binding values to it.
+    fn encode(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        id: Option<ObjectId>,
+        value: &Value,
+    ) -> Result<Value<'static>>;
+
+    fn decode(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        id: ObjectId,
+        value: &Value,
+    ) -> Result<Value<'static>>;
+
+    // Inserted rows only get their id from the storage, so with such codecs
+    // they are written with the encoded columns empty, and those are set
+    // once the id is known. Statements updating many rows write each one
+    // on its own.
+    fn binds_id(&self) -> bool {
+        false
+    }
+}
+
+pub(crate) fn with_codec<'a, C: ColumnCodec + 'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+    codec: C,
+) -> Box<dyn StorageTransaction + 'a> {
+    Box::new(CodecTransaction { inner, codec })
+}
+
+struct CodecTransaction<'a, C> {
+    inner: Box<dyn StorageTransaction + 'a>,
+    codec: C,
+}
+
+impl<'a, C: ColumnCodec> CodecTransaction<'a, C> {
+    fn encoded(&self, schema: &Schema) -> bool {
+        schema
+            .columns
+            .iter()
+            .any(|column| self.codec.handles(column))
+    }
+
+    // The schema to pass on: unchanged for tables the codec has no part in.
+    fn stored<'s>(&self, schema: &'s Schema) -> Result<&'s Schema> {
+        match self.encoded(schema) {
+            true => self.codec.stored(schema),
+            false => Ok(schema),
+        }
+    }
+
+    fn encode<'v>(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        id: Option<ObjectId>,
+        value: &Value<'v>,
+    ) -> Result<Value<'v>> {
+        match self.codec.handles(column) {
+            true => self.codec.encode(schema, column, id, value),
+            false => Ok(value.clone()),
+        }
+    }
+
+    fn encode_row<'r, 'v>(
+        &self,
+        schema: &Schema,
+        id: Option<ObjectId>,
+        row: &'r RowSlice<'v>,
+    ) -> Result<Cow<'r, RowSlice<'v>>> {
+        if !self.encoded(schema) {
+            return Ok(Cow::Borrowed(row));
+        }
+        schema
+            .columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| self.encode(schema, column, id, value))
+            .collect::<Result<Vec<_>>>()
+            .map(Cow::Owned)
+    }
+
+    // The values of the named columns, encoded.
+    fn encode_columns<'v>(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        id: Option<ObjectId>,
+        values: &RowSlice<'v>,
+    ) -> Result<Row<'v>> {
+        columns
+            .iter()
+            .zip(values)
+            .map(|(name, value)| match schema.column(name) {
+                Some(column) => self.encode(schema, column, id, value),
+                None => Ok(value.clone()),
+            })
+            .collect()
+    }
+
+    fn decode_row(&self, schema: &Schema, id: ObjectId, row: &RowSlice) -> Result<Row<'static>> {
+        schema
+            .columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| match self.codec.handles(column) {
+                true => self.codec.decode(schema, column, id, value),
+                false => Ok(value.clone().into_owned()),
+            })
+            .collect()
+    }
+
+    // Whether rows of the schema are inserted before their encoded columns.
+    fn deferred(&self, schema: &Schema) -> bool {
+        self.codec.binds_id() && self.encoded(schema)
+    }
+
+    // The row to insert for a deferred one.
+    fn placeholder<'v>(&self, schema: &Schema, row: &RowSlice<'v>) -> Row<'v> {
+        schema
+            .columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| match self.codec.handles(column) {
+                true => Value::from(Vec::new()),
+                false => value.clone(),
+            })
+            .collect()
+    }
+
+    // Sets the encoded columns of inserted rows.
+    fn write_deferred(
+        &self,
+        schema: &Schema,
+        stored: &Schema,
+        rows: &[(ObjectId, &RowSlice)],
+    ) -> Result<()> {
+        let (columns, positions): (Vec<_>, Vec<_>) = schema
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| self.codec.handles(column))
+            .map(|(position, column)| (column.column_name, position))
+            .unzip();
+        let rows = rows
+            .iter()
+            .map(|(id, row)| {
+                let values = positions
+                    .iter()
+                    .map(|&position| {
+                        self.codec.encode(
+                            schema,
+                            &schema.columns[position],
+                            Some(*id),
+                            &row[position],
+                        )
+                    })
+                    .collect::<Result<Row>>()?;
+                Ok((*id, values))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.inner.update_rows(stored, &columns, &rows)?;
+        Ok(())
+    }
+}
+
+// Values passed to update_where are encoded like those of whole rows, but
+// the parameters of the WHERE clause are not, and encoded columns cannot be
+// compared in SQL.
+impl<'a, C: ColumnCodec> StorageTransaction for CodecTransaction<'a, C> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.inner.table_exists(table)
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.create_table(self.stored(schema)?)
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.drop_table(self.stored(schema)?)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        self.inner.table_names()
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        self.inner.table_info(table)
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        let stored = self.stored(schema)?;
+        let column = stored.column(column.column_name).unwrap_or(column);
+        self.inner.add_column(stored, column)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_table(from, to)
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_column(table, from, to)
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.inner.drop_column(table, column)
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        self.inner.clear_table(self.stored(schema)?)
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        let stored = self.stored(schema)?;
+        if !self.deferred(schema) {
+            let row = self.encode_row(schema, None, row)?;
+            return self.inner.insert_row_with(stored, &row, on_conflict);
+        }
+        let id = self
+            .inner
+            .insert_row_with(stored, &self.placeholder(schema, row), on_conflict)?;
+        if let Some(id) = id {
+            self.write_deferred(schema, stored, &[(id, row)])?;
+        }
+        Ok(id)
+    }
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        let stored = self.stored(schema)?;
+        if !self.deferred(schema) {
+            return self
+                .inner
+                .insert_row(stored, &self.encode_row(schema, None, row)?);
+        }
+        let id = self
+            .inner
+            .insert_row(stored, &self.placeholder(schema, row))?;
+        self.write_deferred(schema, stored, &[(id, row)])?;
+        Ok(id)
+    }
+
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        let stored = self.stored(schema)?;
+        if !self.encoded(schema) {
+            return self.inner.insert_rows(stored, rows);
+        }
+        if !self.deferred(schema) {
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    Ok(self
+                        .encode_row(schema, None, row)?
+                        .iter()
+                        .cloned()
+                        .collect())
+                })
+                .collect::<Result<Vec<Row>>>()?;
+            return self.inner.insert_rows(stored, &rows);
+        }
+        let placeholders: Vec<_> = rows
+            .iter()
+            .map(|row| self.placeholder(schema, row))
+            .collect();
+        let ids = self.inner.insert_rows(stored, &placeholders)?;
+        let written: Vec<_> = ids
+            .iter()
+            .copied()
+            .zip(rows.iter().map(|row| &row[..]))
+            .collect();
+        self.write_deferred(schema, stored, &written)?;
+        Ok(ids)
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        let stored = self.stored(schema)?;
+        self.inner
+            .insert_row_with_id(id, stored, &self.encode_row(schema, Some(id), row)?)
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        let stored = self.stored(schema)?;
+        self.inner
+            .update_row(id, stored, &self.encode_row(schema, Some(id), row)?)
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        let stored = self.stored(schema)?;
+        let bound = self.codec.binds_id()
+            && columns
+                .iter()
+                .any(|name| schema.column(name).is_some_and(|c| self.codec.handles(c)));
+        if !bound {
+            let values = self.encode_columns(schema, columns, None, values)?;
+            return self
+                .inner
+                .update_where(stored, columns, &values, where_sql, params);
+        }
+        let mut rows = Vec::new();
+        self.inner
+            .for_each_row_ref(stored, where_sql, params, &mut |id, _| {
+                rows.push((id, self.encode_columns(schema, columns, Some(id), values)?));
+                Ok(())
+            })?;
+        self.inner.update_rows(stored, columns, &rows)
+    }
+
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        let stored = self.stored(schema)?;
+        let rows = rows
+            .iter()
+            .map(|(id, values)| {
+                Ok((
+                    *id,
+                    self.encode_columns(schema, columns, Some(*id), values)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.inner.update_rows(stored, columns, &rows)
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let row = self.inner.select_row(id, self.stored(schema)?)?;
+        match self.encoded(schema) {
+            true => self.decode_row(schema, id, &row),
+            false => Ok(row),
+        }
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let stored = self.stored(schema)?;
+        if !self.encoded(schema) {
+            return self.inner.for_each_row(stored, where_sql, params, f);
+        }
+        self.inner
+            .for_each_row(stored, where_sql, params, &mut |id, row| {
+                f(id, self.decode_row(schema, id, &row)?)
+            })
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let rows = self
+            .inner
+            .select_rows(self.stored(schema)?, where_sql, params)?;
+        if !self.encoded(schema) {
+            return Ok(rows);
+        }
+        rows.into_iter()
+            .map(|(id, row)| Ok((id, self.decode_row(schema, id, &row)?)))
+            .collect()
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        let stored = self.stored(schema)?;
+        if !self.encoded(schema) {
+            return self.inner.for_each_row_ref(stored, where_sql, params, f);
+        }
+        self.inner
+            .for_each_row_ref(stored, where_sql, params, &mut |id, row| {
+                f(id, &self.decode_row(schema, id, row)?)
+            })
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner
+            .count_rows(self.stored(schema)?, where_sql, params)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.inner.delete_row(id, self.stored(schema)?)
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner
+            .delete_where(self.stored(schema)?, where_sql, params)
+    }
+
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        self.inner.delete_rows(self.stored(schema)?, ids)
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner.execute(sql, params)
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.inner.savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.release_savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.rollback_to_savepoint(name)
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.inner.commit()
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.inner.rollback()
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.inner.commit_and_continue()
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        self.inner.take_statement_log()
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+}
@@ -0,0 +1,143 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::DataType,
+    storage::{ColumnInfo, TableInfo},
+    transaction::FINGERPRINTS,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Emits a `#[derive(Object)]` struct for every table with an integer `id`
+// primary key, e.g. from a build script into OUT_DIR for `include!`. Other
+// tables are listed in a comment, as the ORM cannot address their rows.
+pub fn generate(tables: &[TableInfo]) -> String {
+    let mut out = String::from("// Generated by orm::codegen.\n");
+    for table in tables {
+        if is_bookkeeping(&table.name) {
+            continue;
+        }
+        out.push('\n');
+        match table.column("id") {
+            Some(id) if id.primary_key => out.push_str(&generate_struct(table)),
+            _ => out.push_str(&format!(
+                "// Skipped table {}: it has no integer id primary key.\n",
+                table.name
+            )),
+        }
+    }
+    out
+}
+
+#[cfg(feature = "sqlite")]
+pub fn generate_from_sqlite<P: AsRef<std::path::Path>>(path: P) -> crate::Result<String> {
+    let mut conn = crate::Connection::open_sqlite_read_only(path)?;
+    let tx = conn.new_transaction()?;
+    let tables = tx.introspect()?;
+    tx.rollback()?;
+    Ok(generate(&tables))
+}
+
+fn generate_struct(table: &TableInfo) -> String {
+    let name = pascal_case(&table.name);
+    let mut out = String::from("#[derive(::orm::Object, Clone, Debug)]\n");
+    if name != table.name {
+        out.push_str(&format!("#[table_name({:?})]\n", table.name));
+    }
+    out.push_str(&format!("pub struct {} {{\n", name));
+    for column in table.columns.iter().filter(|c| c.name != "id") {
+        let field = field_name(&column.name);
+        if field != column.name {
+            out.push_str(&format!("    #[column_name({:?})]\n", column.name));
+        }
+        out.push_str(&format!("    pub {}: {},\n", field, rust_type(column)));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn is_bookkeeping(table_name: &str) -> bool {
+    matches!(table_name, "_orm_migrations" | "_orm_outbox") || table_name == FINGERPRINTS.table_name
+}
+
+fn rust_type(column: &ColumnInfo) -> &'static str {
+    let typ = column
+        .typ
+        .unwrap_or_else(|| affinity(column.declared_type.as_deref().unwrap_or_default()));
+    match typ {
+        DataType::String => "String",
+        DataType::Bytes => "Vec<u8>",
+        DataType::Int64 => "i64",
+        DataType::Float64 => "f64",
+        DataType::Bool => "bool",
+    }
+}
+
+// SQLite's rules for the affinity of a declared type, with booleans kept
+// apart from integers.
+fn affinity(declared: &str) -> DataType {
+    let declared = declared.to_ascii_uppercase();
+    if declared.starts_with("BOOL") {
+        DataType::Bool
+    } else if declared.contains("INT") {
+        DataType::Int64
+    } else if ["CHAR", "CLOB", "TEXT"]
+        .iter()
+        .any(|s| declared.contains(s))
+    {
+        DataType::String
+    } else if declared.contains("BLOB") || declared.is_empty() {
+        DataType::Bytes
+    } else {
+        DataType::Float64
+    }
+}
+
+fn pascal_case(name: &str) -> String {
+    let mut out = String::new();
+    for word in name.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            out.push(first.to_ascii_uppercase());
+            out.extend(chars);
+        }
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, 'T');
+    }
+    out
+}
+
+fn field_name(column_name: &str) -> String {
+    let mut out = String::new();
+    let mut prev = None;
+    for c in column_name.chars() {
+        if c.is_ascii_uppercase() {
+            if prev.is_some_and(|p: char| p.is_ascii_lowercase() || p.is_ascii_digit()) {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            out.push(c);
+        } else {
+            out.push('_');
+        }
+        prev = Some(c);
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, 'c');
+    }
+    if is_keyword(&out) {
+        out.push('_');
+    }
+    out
+}
+
+fn is_keyword(ident: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true",
+        "type", "unsafe", "use", "where", "while",
+    ];
+    KEYWORDS.contains(&ident)
+}
@@ -0,0 +1,104 @@
+#![forbid(unsafe_code)]
+use crate::{
+    codec::{with_codec, ColumnCodec},
+    data::{DataType, Value},
+    error::{Error, Result},
+    object::{Column, Schema},
+    storage::StorageTransaction,
+    ObjectId,
+};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{Read, Write};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// What the derive puts in the columns marked `#[compress]`, so that they
+// fail to compile without the `compression` feature.
+pub const ENABLED: bool = true;
+
+// Compressed values start with this. Values without it are stored as they
+// are, either because deflating them would not make them smaller or because
+// they were written before the column was compressed, and are read back as
+// they are.
+const MAGIC: &[u8] = b"\0ORMZ";
+
+pub(crate) fn compressing<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+) -> Box<dyn StorageTransaction + 'a> {
+    with_codec(inner, Deflate)
+}
+
+struct Deflate;
+
+impl ColumnCodec for Deflate {
+    fn handles(&self, column: &Column) -> bool {
+        column.is_compressed()
+    }
+
+    fn stored(&self, schema: &Schema) -> Result<&'static Schema> {
+        Ok(schema.stored_compressed())
+    }
+
+    fn encode(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        _id: Option<ObjectId>,
+        value: &Value,
+    ) -> Result<Value<'static>> {
+        let raw: &[u8] = match value {
+            Value::String(s) => s.as_bytes(),
+            Value::Bytes(b) => b,
+            _ => return Err(compression_error("compress", schema, column)),
+        };
+        let mut encoder = DeflateEncoder::new(MAGIC.to_vec(), Compression::default());
+        let compressed = encoder
+            .write_all(raw)
+            .and_then(|()| encoder.finish())
+            .map_err(|_| compression_error("compress", schema, column))?;
+        // Raw values that look compressed are kept compressed.
+        match compressed.len() < raw.len() || raw.starts_with(MAGIC) {
+            true => Ok(Value::from(compressed)),
+            false => Ok(Value::from(raw.to_vec())),
+        }
+    }
+
+    fn decode(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        _id: ObjectId,
+        value: &Value,
+    ) -> Result<Value<'static>> {
+        let stored = match value {
+            Value::Bytes(bytes) => bytes,
+            _ => return Err(compression_error("decompress", schema, column)),
+        };
+        let raw = match stored.strip_prefix(MAGIC) {
+            Some(compressed) => {
+                let mut raw = Vec::new();
+                DeflateDecoder::new(compressed)
+                    .read_to_end(&mut raw)
+                    .map_err(|_| compression_error("decompress", schema, column))?;
+                raw
+            }
+            None => stored.to_vec(),
+        };
+        match column.typ {
+            DataType::String => String::from_utf8(raw)
+                .map(Value::from)
+                .map_err(|_| compression_error("decompress", schema, column)),
+            _ => Ok(Value::from(raw)),
+        }
+    }
+}
+
+fn compression_error(action: &str, schema: &Schema, column: &Column) -> Error {
+    Error::Storage(
+        format!(
+            "cannot {} {}.{}",
+            action, schema.table_name, column.column_name
+        )
+        .into(),
+    )
+}
@@ -1,39 +1,417 @@
 #![forbid(unsafe_code)]
-use crate::{storage::StorageTransaction, Result, Transaction};
-use std::path::Path;
+#[cfg(feature = "encryption")]
+use crate::encryption::{encrypting, KeyProvider, SharedKeyProvider};
+use crate::{
+    data::Value,
+    instrument::{Instrument, SharedInstrument, SlowQuery, SlowQueryLog, SqlTrace},
+    metrics::{metered, Metrics, SharedMetrics},
+    prefix::with_prefix,
+    scope::{scoped, Scope},
+    storage::{
+        is_unsupported, BackupProgress, ChangeOp, Checkpoint, CheckpointMode, IntegrityReport,
+        StatementCacheStats, StorageConnection, StorageTransaction, TransactionBehavior,
+    },
+    transaction::{AbandonHook, CheckedTables, Observer},
+    ChangeEvent, Error, ObjectId, PendingChanges, ReadTransaction, Result, Transaction,
+};
+use std::{path::Path, sync::Arc, time::Duration};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-trait StorageConnection {
-    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>>;
+pub struct Connection {
+    inner: Box<dyn StorageConnection>,
+    read_only: bool,
+    on_abandon: Option<AbandonHook>,
+    observers: Vec<Observer>,
+    checked_tables: CheckedTables,
+    instrument: Option<SharedInstrument>,
+    metrics: Option<SharedMetrics>,
+    outbox: bool,
+    #[cfg(feature = "encryption")]
+    key_provider: Option<SharedKeyProvider>,
 }
 
-impl StorageConnection for rusqlite::Connection {
-    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
-        Ok(Box::new(self.transaction()?))
+impl Connection {
+    pub fn from_storage<S: StorageConnection + 'static>(storage: S) -> Self {
+        Self {
+            inner: Box::new(storage),
+            read_only: false,
+            on_abandon: None,
+            observers: Vec::new(),
+            checked_tables: CheckedTables::default(),
+            instrument: None,
+            metrics: None,
+            outbox: false,
+            #[cfg(feature = "encryption")]
+            key_provider: None,
+        }
     }
-}
 
-////////////////////////////////////////////////////////////////////////////////
+    // Transactions of a read-only connection reject writes with
+    // Error::ReadOnly before they reach the storage.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
 
-pub struct Connection {
-    inner: Box<dyn StorageConnection>,
-}
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-impl Connection {
-    pub fn open_sqlite_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(Self {
-            inner: Box::new(rusqlite::Connection::open(path)?),
-        })
+    // Runs for every transaction of this connection that is dropped without
+    // finishing a commit or rollback, right before it is rolled back.
+    pub fn on_abandoned_transaction<F>(&mut self, hook: F)
+    where
+        F: Fn(&PendingChanges) + Send + Sync + 'static,
+    {
+        self.on_abandon = Some(Arc::new(hook));
+    }
+
+    // For every transaction of this connection; see Transaction::set_outbox.
+    pub fn set_outbox(&mut self, enabled: bool) {
+        self.outbox = enabled;
     }
 
-    pub fn open_in_memory() -> Result<Self> {
-        Ok(Self {
-            inner: Box::new(rusqlite::Connection::open_in_memory()?),
-        })
+    // Added to every transaction of this connection; see
+    // Transaction::observe.
+    pub fn observe<F>(&mut self, observer: F)
+    where
+        F: Fn(&ChangeEvent) + Send + Sync + 'static,
+    {
+        self.observers.push(Arc::new(observer));
+    }
+
+    // Told how long the statements of the connection take, and the
+    // deserializing of its objects; see instrument::Instrument.
+    pub fn set_instrument<I: Instrument + 'static>(&mut self, instrument: I) -> Result<()> {
+        let instrument: SharedInstrument = Arc::new(instrument);
+        match self.inner.set_instrument(Some(instrument.clone())) {
+            Err(err) if !is_unsupported(&err) => return Err(err),
+            _ => {}
+        }
+        self.instrument = Some(instrument);
+        Ok(())
+    }
+
+    pub fn clear_instrument(&mut self) -> Result<()> {
+        match self.inner.set_instrument(None) {
+            Err(err) if !is_unsupported(&err) => return Err(err),
+            _ => {}
+        }
+        self.instrument = None;
+        Ok(())
+    }
+
+    // Statements that take at least the threshold are passed to the hook with
+    // their SQL, their parameters and how long they took; a quick way to find
+    // the queries that need an index. Backends without timing reject it.
+    pub fn set_slow_query_log<F>(&mut self, threshold: Duration, hook: F) -> Result<()>
+    where
+        F: Fn(&SlowQuery) + Send + Sync + 'static,
+    {
+        self.inner.set_slow_query_log(Some(SlowQueryLog {
+            threshold,
+            hook: Arc::new(hook),
+        }))
+    }
+
+    pub fn clear_slow_query_log(&mut self) -> Result<()> {
+        self.inner.set_slow_query_log(None)
+    }
+
+    // Shows the SQL the ORM generates, with its parameters and how long it
+    // took, as it runs. Schema changes and transaction control are left out.
+    pub fn set_sql_trace<F>(&mut self, hook: F) -> Result<()>
+    where
+        F: Fn(&SqlTrace) + Send + Sync + 'static,
+    {
+        self.inner.set_sql_trace(Some(Arc::new(hook)))
+    }
+
+    pub fn clear_sql_trace(&mut self) -> Result<()> {
+        self.inner.set_sql_trace(None)
+    }
+
+    // Replaces the previous metrics for the transactions begun from now on;
+    // see metrics::Metrics.
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.set_shared_metrics(Some(Arc::new(metrics)));
+    }
+
+    pub(crate) fn set_shared_metrics(&mut self, metrics: Option<SharedMetrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn clear_metrics(&mut self) {
+        self.set_shared_metrics(None);
+    }
+
+    // For the `#[encrypted]` columns of the transactions begun from now on,
+    // which fail without one; see encryption::KeyProvider.
+    #[cfg(feature = "encryption")]
+    pub fn set_key_provider<K: KeyProvider + 'static>(&mut self, provider: K) {
+        self.set_shared_key_provider(Some(Arc::new(provider)));
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_shared_key_provider(&mut self, provider: Option<SharedKeyProvider>) {
+        self.key_provider = provider;
     }
 
     pub fn new_transaction(&mut self) -> Result<Transaction<'_>> {
-        Ok(Transaction::new(self.inner.new_transaction()?))
+        let settings = self.settings();
+        let inner = self.inner.new_transaction()?;
+        Ok(wrap(inner, settings))
+    }
+
+    pub fn new_transaction_with(
+        &mut self,
+        behavior: TransactionBehavior,
+    ) -> Result<Transaction<'_>> {
+        let settings = self.settings();
+        let inner = self.inner.new_transaction_with(behavior)?;
+        Ok(wrap(inner, settings))
+    }
+
+    // Every table of the transaction is stored as "<tenant>_<table>", so
+    // tenants share the model types but not their rows. Tenant names are
+    // limited to ASCII letters, digits and underscores.
+    pub fn tenant_transaction(&mut self, tenant: &str) -> Result<Transaction<'_>> {
+        self.tenant_transaction_with(tenant, TransactionBehavior::Deferred)
+    }
+
+    pub fn tenant_transaction_with(
+        &mut self,
+        tenant: &str,
+        behavior: TransactionBehavior,
+    ) -> Result<Transaction<'_>> {
+        if tenant.is_empty()
+            || !tenant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(Error::Storage(
+                format!("invalid tenant name {:?}", tenant).into(),
+            ));
+        }
+        // The tables of a tenant are checked apart from the shared ones.
+        let settings = TransactionSettings {
+            checked_tables: None,
+            ..self.settings()
+        };
+        let inner = self.inner.new_transaction_with(behavior)?;
+        Ok(wrap(with_prefix(inner, format!("{}_", tenant)), settings))
+    }
+
+    // Every statement of the transaction on the tables the scope covers is
+    // filtered by it, see scope::Scope.
+    pub fn scoped_transaction(&mut self, scope: Scope) -> Result<Transaction<'_>> {
+        self.scoped_transaction_with(scope, TransactionBehavior::Deferred)
+    }
+
+    pub fn scoped_transaction_with(
+        &mut self,
+        scope: Scope,
+        behavior: TransactionBehavior,
+    ) -> Result<Transaction<'_>> {
+        let settings = self.settings();
+        let inner = self.inner.new_transaction_with(behavior)?;
+        Ok(wrap(scoped(inner, scope), settings))
+    }
+
+    pub(crate) fn set_statement_log(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_statement_log(enabled)
+    }
+
+    fn settings(&self) -> TransactionSettings {
+        TransactionSettings {
+            read_only: self.read_only,
+            on_abandon: self.on_abandon.clone(),
+            observers: self.observers.clone(),
+            checked_tables: Some(self.checked_tables.clone()),
+            instrument: self.instrument.clone(),
+            metrics: self.metrics.clone(),
+            outbox: self.outbox,
+            #[cfg(feature = "encryption")]
+            key_provider: self.key_provider.clone(),
+        }
+    }
+
+    fn read_settings(&self) -> TransactionSettings {
+        TransactionSettings {
+            read_only: true,
+            checked_tables: Some(self.checked_tables.clone()),
+            instrument: self.instrument.clone(),
+            metrics: self.metrics.clone(),
+            #[cfg(feature = "encryption")]
+            key_provider: self.key_provider.clone(),
+            ..Default::default()
+        }
     }
+
+    pub fn read_transaction(&mut self) -> Result<ReadTransaction<'_>> {
+        let settings = self.read_settings();
+        let inner = self.inner.new_transaction()?;
+        Ok(ReadTransaction::new(wrap(inner, settings)))
+    }
+
+    // Other connections can go on writing while it is open; in SQLite that
+    // needs the WAL journal mode.
+    pub fn snapshot(&mut self) -> Result<ReadTransaction<'_>> {
+        let settings = self.read_settings();
+        let inner = self.inner.new_snapshot()?;
+        Ok(ReadTransaction::new(wrap(inner, settings)))
+    }
+
+    pub fn attach<P: AsRef<Path>>(&mut self, path: P, alias: &str) -> Result<()> {
+        self.inner.attach(path.as_ref(), alias)?;
+        self.forget_checked_tables();
+        Ok(())
+    }
+
+    pub fn detach(&mut self, alias: &str) -> Result<()> {
+        self.inner.detach(alias)?;
+        self.forget_checked_tables();
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn checked_tables(&self) -> CheckedTables {
+        self.checked_tables.clone()
+    }
+
+    // Transactions take the tables they checked before to exist as they
+    // were; call this after changing tables from another connection.
+    pub fn forget_checked_tables(&self) {
+        self.checked_tables
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+    }
+
+    pub fn backup_to<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mut progress: impl FnMut(BackupProgress),
+    ) -> Result<()> {
+        self.inner.backup_to(path.as_ref(), &mut progress)
+    }
+
+    pub fn vacuum(&mut self) -> Result<()> {
+        self.inner.vacuum()
+    }
+
+    pub fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> Result<()> {
+        self.inner.incremental_vacuum(max_pages)
+    }
+
+    pub fn optimize(&mut self) -> Result<()> {
+        self.inner.optimize()
+    }
+
+    pub fn analyze(&mut self) -> Result<()> {
+        self.inner.analyze()
+    }
+
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<Checkpoint> {
+        self.inner.checkpoint(mode)
+    }
+
+    pub fn statement_cache_stats(&self) -> Option<StatementCacheStats> {
+        self.inner.statement_cache_stats()
+    }
+
+    // Registers a function callable from any SQL run on this connection,
+    // including predicates and Transaction::execute.
+    pub fn create_scalar_function<F>(&mut self, name: &str, function: F) -> Result<()>
+    where
+        F: Fn(&[Value<'_>]) -> Result<Value<'static>> + Send + 'static,
+    {
+        self.inner.create_scalar_function(name, Box::new(function))
+    }
+
+    pub fn on_change<F>(&mut self, hook: F) -> Result<()>
+    where
+        F: FnMut(&str, ChangeOp, ObjectId) + Send + 'static,
+    {
+        self.inner.set_change_hook(Some(Box::new(hook)))
+    }
+
+    pub fn clear_change_hook(&mut self) -> Result<()> {
+        self.inner.set_change_hook(None)
+    }
+
+    pub fn integrity_check(&mut self) -> Result<IntegrityReport> {
+        self.inner.integrity_check(false)
+    }
+
+    pub fn quick_check(&mut self) -> Result<IntegrityReport> {
+        self.inner.integrity_check(true)
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    pub fn rekey(&mut self, key: &str) -> Result<()> {
+        self.inner.rekey(key)
+    }
+
+    pub fn set_foreign_keys(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_foreign_keys(enabled)
+    }
+
+    pub fn total_changes(&self) -> Result<usize> {
+        self.inner.total_changes()
+    }
+
+    // Runs `f` with the storage tuned for writing many rows, see
+    // Database::bulk_load_mode. Backends without such settings just run it.
+    pub fn bulk_load_mode<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R>) -> Result<R> {
+        self.set_bulk_load(true)?;
+        let result = f(self);
+        let restored = self.set_bulk_load(false);
+        result.and_then(|r| restored.map(|()| r))
+    }
+
+    // An enable that fails is undone; its error is the one returned.
+    pub(crate) fn set_bulk_load(&mut self, enabled: bool) -> Result<()> {
+        match self.inner.set_bulk_load(enabled) {
+            Err(err) if is_unsupported(&err) => Ok(()),
+            Err(err) => {
+                if enabled {
+                    let _ = self.inner.set_bulk_load(false);
+                }
+                Err(err)
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+// What a connection passes on to each of its transactions.
+#[derive(Default)]
+struct TransactionSettings {
+    read_only: bool,
+    on_abandon: Option<AbandonHook>,
+    observers: Vec<Observer>,
+    checked_tables: Option<CheckedTables>,
+    instrument: Option<SharedInstrument>,
+    metrics: Option<SharedMetrics>,
+    outbox: bool,
+    #[cfg(feature = "encryption")]
+    key_provider: Option<SharedKeyProvider>,
+}
+
+fn wrap<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+    settings: TransactionSettings,
+) -> Transaction<'a> {
+    let inner = metered(inner, settings.metrics);
+    #[cfg(feature = "encryption")]
+    let inner = encrypting(inner, settings.key_provider);
+    Transaction::new(inner)
+        .with_read_only(settings.read_only)
+        .with_abandon_hook(settings.on_abandon)
+        .with_instrument(settings.instrument)
+        .with_observers(&settings.observers)
+        .with_outbox(settings.outbox)
+        .with_checked_tables(settings.checked_tables)
 }
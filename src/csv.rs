@@ -0,0 +1,307 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{Error, Result},
+    memory::default_value,
+    object::{Column, Object, Schema},
+    storage::{Row, RowSlice},
+    ObjectId, Transaction,
+};
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+};
+use thiserror::Error;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// With headers, the first record names the columns, by column name and "id",
+// and an import takes them in any order; columns it leaves out get the zero
+// value of their type. Without, the fields are the id, unless left out with
+// with_id(false), followed by the columns in the order of the schema.
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    pub(crate) headers: bool,
+    pub(crate) delimiter: u8,
+    pub(crate) with_id: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            headers: true,
+            delimiter: b',',
+            with_id: true,
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn headers(mut self, enabled: bool) -> Self {
+        self.headers = enabled;
+        self
+    }
+
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn with_id(mut self, enabled: bool) -> Self {
+        self.with_id = enabled;
+        self
+    }
+}
+
+// Every line of an import that could not be turned into an object, with the
+// reason, so that a file can be fixed in one go. Nothing is imported then.
+#[derive(Error, Debug)]
+#[error("invalid CSV: {}", describe(.lines))]
+pub struct CsvError {
+    pub lines: Vec<(u64, String)>,
+}
+
+fn describe(lines: &[(u64, String)]) -> String {
+    let mut text = String::new();
+    for (i, (line, message)) in lines.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "; " };
+        let _ = write!(text, "{}line {}: {}", separator, line, message);
+    }
+    text
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Bytes are written as hex; booleans as true and false, and read from 1 and
+// 0 as well. Empty fields of columns other than strings read as zero.
+impl<'a> Transaction<'a> {
+    pub fn export_csv<T: Object>(&self, writer: impl Write) -> Result<usize> {
+        self.export_csv_with::<T>(writer, &CsvOptions::default())
+    }
+
+    pub fn export_csv_with<T: Object>(
+        &self,
+        writer: impl Write,
+        options: &CsvOptions,
+    ) -> Result<usize> {
+        self.flush()?;
+        let schema = T::TABLE;
+        let mut writer = ::csv::WriterBuilder::new()
+            .delimiter(options.delimiter)
+            .from_writer(writer);
+        if options.headers {
+            let id = options.with_id.then_some("id");
+            let names = schema.column_names();
+            writer
+                .write_record(id.into_iter().chain(names))
+                .map_err(csv_error)?;
+        }
+        let mut rows = 0;
+        self.scan_stored::<T>(|id, row| {
+            writer
+                .write_record(to_record(id, row, options.with_id))
+                .map_err(csv_error)?;
+            rows += 1;
+            Ok(())
+        })?;
+        writer
+            .flush()
+            .map_err(|err| Error::Storage(Box::new(err)))?;
+        Ok(rows)
+    }
+
+    pub fn import_csv<T: Object>(&self, reader: impl Read) -> Result<usize> {
+        self.import_csv_with::<T>(reader, &CsvOptions::default())
+    }
+
+    // Rows with an id keep it, others get a new one. Returns the number of
+    // objects created.
+    pub fn import_csv_with<T: Object>(
+        &self,
+        reader: impl Read,
+        options: &CsvOptions,
+    ) -> Result<usize> {
+        let schema = T::TABLE;
+        let mut reader = ::csv::ReaderBuilder::new()
+            .has_headers(options.headers)
+            .delimiter(options.delimiter)
+            .flexible(true)
+            .from_reader(reader);
+        let layout = match options.headers {
+            true => {
+                let headers = reader.headers().map_err(csv_error)?;
+                Layout::from_headers(schema, headers).map_err(|message| {
+                    Error::Storage(Box::new(CsvError {
+                        lines: vec![(1, message)],
+                    }))
+                })?
+            }
+            false => Layout::positional(schema, options.with_id),
+        };
+
+        let mut objects = Vec::new();
+        let mut lines = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(csv_error)?;
+            let line = record.position().map_or(0, |position| position.line());
+            match layout.read(schema, &record) {
+                Ok((id, row)) => match T::deserialize(row) {
+                    Ok(obj) => objects.push((id, obj)),
+                    Err(err) => lines.push((line, err.to_string())),
+                },
+                Err(message) => lines.push((line, message)),
+            }
+        }
+        if !lines.is_empty() {
+            return Err(Error::Storage(Box::new(CsvError { lines })));
+        }
+
+        let imported = objects.len();
+        for (id, obj) in objects {
+            match id {
+                Some(id) => self.create_with_id(id, obj)?,
+                None => self.create(obj)?,
+            };
+        }
+        Ok(imported)
+    }
+}
+
+fn to_record(id: ObjectId, row: &RowSlice, with_id: bool) -> Vec<String> {
+    let id = with_id.then(|| id.into_i64().to_string());
+    id.into_iter().chain(row.iter().map(to_field)).collect()
+}
+
+fn to_field(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        Value::Bytes(bytes) => bytes.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        }),
+        Value::Int64(i) => i.to_string(),
+        Value::Float64(f) => f.to_string(),
+        Value::Bool(b) => b.to_string(),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Where the id and each column of the schema are found in a record.
+struct Layout {
+    id: Option<usize>,
+    columns: Vec<Option<usize>>,
+}
+
+impl Layout {
+    fn positional(schema: &Schema, with_id: bool) -> Self {
+        let offset = with_id as usize;
+        Self {
+            id: with_id.then_some(0),
+            columns: (0..schema.columns.len())
+                .map(|i| Some(i + offset))
+                .collect(),
+        }
+    }
+
+    fn from_headers(
+        schema: &Schema,
+        headers: &::csv::StringRecord,
+    ) -> std::result::Result<Self, String> {
+        if let Some(unknown) = headers
+            .iter()
+            .find(|header| *header != "id" && schema.column(header).is_none())
+        {
+            return Err(format!("{} has no column {}", schema.table_name, unknown));
+        }
+        let position = |name: &str| headers.iter().position(|header| header == name);
+        Ok(Self {
+            id: position("id"),
+            columns: schema
+                .columns
+                .iter()
+                .map(|column| position(column.column_name))
+                .collect(),
+        })
+    }
+
+    fn read(
+        &self,
+        schema: &Schema,
+        record: &::csv::StringRecord,
+    ) -> std::result::Result<(Option<ObjectId>, Row<'static>), String> {
+        let field = |position: usize| {
+            record
+                .get(position)
+                .ok_or_else(|| format!("expected at least {} fields", position + 1))
+        };
+        let id = match self.id {
+            Some(position) => {
+                let id = field(position)?;
+                match id.is_empty() {
+                    true => None,
+                    false => Some(ObjectId::from(
+                        id.parse::<i64>()
+                            .map_err(|_| format!("{:?} is not an id", id))?,
+                    )),
+                }
+            }
+            None => None,
+        };
+        let row = schema
+            .columns
+            .iter()
+            .zip(&self.columns)
+            .map(|(column, position)| match position {
+                Some(position) => to_value(schema, column, field(*position)?),
+                None => Ok(default_value(column.typ)),
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok((id, row))
+    }
+}
+
+fn to_value(
+    schema: &Schema,
+    column: &Column,
+    field: &str,
+) -> std::result::Result<Value<'static>, String> {
+    if field.is_empty() && column.typ != DataType::String {
+        return Ok(default_value(column.typ));
+    }
+    let converted = match column.typ {
+        DataType::String => Some(Value::from(field.to_string())),
+        DataType::Bytes => from_hex(field).map(Value::from),
+        DataType::Int64 => field.trim().parse::<i64>().ok().map(Value::from),
+        DataType::Float64 => field.trim().parse::<f64>().ok().map(Value::from),
+        DataType::Bool => match field.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => Some(Value::from(true)),
+            "false" | "0" => Some(Value::from(false)),
+            _ => None,
+        },
+    };
+    converted.ok_or_else(|| {
+        format!(
+            "{}.{} expects {:?}, got {:?}",
+            schema.table_name, column.column_name, column.typ, field
+        )
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn csv_error(err: ::csv::Error) -> Error {
+    Error::Storage(Box::new(err))
+}
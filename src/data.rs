@@ -1,12 +1,13 @@
 #![forbid(unsafe_code)]
 
-use rusqlite::types::ToSqlOutput;
-use rusqlite::{Result, ToSql};
-use std::{borrow::Cow, fmt};
+use std::{
+    borrow::Cow, cmp::Ordering, fmt, hash::Hash, marker::PhantomData, num::ParseIntError,
+    str::FromStr,
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct ObjectId(i64);
 
 impl From<i64> for ObjectId {
@@ -15,27 +16,162 @@ impl From<i64> for ObjectId {
     }
 }
 
+impl From<ObjectId> for i64 {
+    fn from(id: ObjectId) -> Self {
+        id.0
+    }
+}
+
 impl ObjectId {
     pub fn into_i64(&self) -> i64 {
         self.0
     }
 }
 
+// Parses what Display writes, as in an id taken from a URL.
+impl FromStr for ObjectId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+// An id is serialized as the plain number, in JSON as in any other format.
+#[cfg(feature = "serde")]
+impl serde::Serialize for ObjectId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ObjectId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <i64 as serde::Deserialize>::deserialize(deserializer).map(Self)
+    }
+}
+
 impl fmt::Display for ObjectId {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         write!(formatter, "{:?}", self.0)
     }
 }
 
-impl ToSql for ObjectId {
-    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
-        Ok(ToSqlOutput::from(self.0))
+// The id of an object of type T, as returned by Tx::id, so that passing the
+// id of a User to get::<Order> does not compile. Raw ObjectIds still convert
+// into an Id of any type, and an Id converts back with raw.
+pub struct Id<T>(ObjectId, PhantomData<fn() -> T>);
+
+impl<T> Id<T> {
+    pub fn new(id: ObjectId) -> Self {
+        Self(id, PhantomData)
+    }
+
+    pub fn raw(self) -> ObjectId {
+        self.0
+    }
+
+    pub fn into_i64(&self) -> i64 {
+        self.0.into_i64()
+    }
+}
+
+impl<T> Clone for Id<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Id<T> {}
+
+impl<T> PartialEq for Id<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for Id<T> {}
+
+impl<T> PartialOrd for Id<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Id<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<T> Hash for Id<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<T> fmt::Debug for Id<T> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Id({})", self.0)
+    }
+}
+
+impl<T> fmt::Display for Id<T> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(formatter)
+    }
+}
+
+impl<T> From<ObjectId> for Id<T> {
+    fn from(id: ObjectId) -> Self {
+        Self::new(id)
+    }
+}
+
+impl<T> From<i64> for Id<T> {
+    fn from(id: i64) -> Self {
+        Self::new(ObjectId(id))
+    }
+}
+
+impl<T> From<Id<T>> for ObjectId {
+    fn from(id: Id<T>) -> Self {
+        id.0
+    }
+}
+
+impl<T> From<Id<T>> for i64 {
+    fn from(id: Id<T>) -> Self {
+        id.0.into_i64()
+    }
+}
+
+impl<T> FromStr for Id<T> {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self::new)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> serde::Serialize for Id<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for Id<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <ObjectId as serde::Deserialize>::deserialize(deserializer).map(Self::new)
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum DataType {
     String,
     Bytes,
@@ -64,6 +200,7 @@ impl_object_type!(bool, DataType::Bool);
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value<'a> {
     String(Cow<'a, str>),
     Bytes(Cow<'a, [u8]>),
@@ -72,6 +209,33 @@ pub enum Value<'a> {
     Bool(bool),
 }
 
+impl<'a> Value<'a> {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Value::String(_) => DataType::String,
+            Value::Bytes(_) => DataType::Bytes,
+            Value::Int64(_) => DataType::Int64,
+            Value::Float64(_) => DataType::Float64,
+            Value::Bool(_) => DataType::Bool,
+        }
+    }
+
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::String(s) => Value::String(Cow::Owned(s.into_owned())),
+            Value::Bytes(b) => Value::Bytes(Cow::Owned(b.into_owned())),
+            Value::Int64(i) => Value::Int64(i),
+            Value::Float64(f) => Value::Float64(f),
+            Value::Bool(b) => Value::Bool(b),
+        }
+    }
+}
+
+// The fallible counterpart of `From<Value>`, used by Object::deserialize.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Option<Self>;
+}
+
 macro_rules! impl_value_from {
     ($from_type:ty, $variant:ident) => {
         impl<'a> From<&'a $from_type> for Value<'static> {
@@ -80,6 +244,12 @@ macro_rules! impl_value_from {
             }
         }
 
+        impl From<$from_type> for Value<'static> {
+            fn from(typ: $from_type) -> Self {
+                Value::$variant(typ)
+            }
+        }
+
         impl<'a> From<Value<'a>> for $from_type {
             fn from(val: Value<'a>) -> Self {
                 if let Value::$variant(x) = val {
@@ -88,6 +258,15 @@ macro_rules! impl_value_from {
                 panic!("Unexpected value variant");
             }
         }
+
+        impl FromValue for $from_type {
+            fn from_value(value: Value) -> Option<Self> {
+                match value {
+                    Value::$variant(x) => Some(x),
+                    _ => None,
+                }
+            }
+        }
     };
 }
 
@@ -99,6 +278,12 @@ macro_rules! impl_cow_value_from {
             }
         }
 
+        impl From<$from_type> for Value<'static> {
+            fn from(typ: $from_type) -> Self {
+                Value::$variant(Cow::Owned(typ))
+            }
+        }
+
         impl<'a> From<Value<'a>> for $from_type {
             fn from(val: Value<'a>) -> Self {
                 if let Value::$variant(x) = val {
@@ -107,6 +292,15 @@ macro_rules! impl_cow_value_from {
                 panic!("Unexpected value variant");
             }
         }
+
+        impl FromValue for $from_type {
+            fn from_value(value: Value) -> Option<Self> {
+                match value {
+                    Value::$variant(x) => Some(x.into_owned()),
+                    _ => None,
+                }
+            }
+        }
     };
 }
 
@@ -116,14 +310,14 @@ impl_value_from!(i64, Int64);
 impl_value_from!(f64, Float64);
 impl_value_from!(bool, Bool);
 
-impl<'a> ToSql for Value<'a> {
-    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
-        match self {
-            Value::String(s) => Ok(ToSqlOutput::from(s.as_ref())),
-            Value::Bytes(b) => Ok(ToSqlOutput::from(b.as_ref())),
-            Value::Int64(i) => Ok(ToSqlOutput::from(*i)),
-            Value::Float64(f) => Ok(ToSqlOutput::from(*f)),
-            Value::Bool(b) => Ok(ToSqlOutput::from(*b)),
-        }
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(s: &'a str) -> Self {
+        Value::String(Cow::Borrowed(s))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value<'a> {
+    fn from(b: &'a [u8]) -> Self {
+        Value::Bytes(Cow::Borrowed(b))
     }
 }
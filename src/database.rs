@@ -0,0 +1,796 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::Value,
+    error::{Error, Result, SchemaMismatchError},
+    instrument::{Instrument, SlowQuery, SqlTrace},
+    metrics::{Metrics, SharedMetrics},
+    migrations::{Migration, PlannedMigration},
+    object::{Object, Schema},
+    repair::{RepairPolicy, SchemaRepair},
+    scope::Scope,
+    storage::{
+        BackupProgress, ChangeOp, Checkpoint, CheckpointMode, IntegrityReport, StatementCacheStats,
+        StorageConnection, TableInfo, TableStats, TransactionBehavior,
+    },
+    ChangeEvent, Connection, ObjectId, PendingChanges, ReadTransaction, Transaction,
+};
+#[cfg(feature = "sqlite")]
+use crate::{
+    metrics::metered,
+    pool::{Pool, PoolOptions},
+    sqlite::{open_reader, ReadStorage},
+    transaction::CheckedTables,
+};
+use std::sync::Arc;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+use std::{thread, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+type ErrorHook = Box<dyn Fn(&Error) + Send + Sync>;
+
+// How each attempt of Database::run begins its transaction.
+#[derive(Clone, Copy)]
+enum Begin<'s> {
+    Plain,
+    Tenant(&'s str),
+    Scoped(&'s Scope),
+}
+
+pub struct Database {
+    conn: Connection,
+    #[cfg(feature = "sqlite")]
+    readers: Option<Readers>,
+    retry: RetryPolicy,
+    behavior: TransactionBehavior,
+    error_hook: Option<ErrorHook>,
+}
+
+impl Database {
+    #[cfg(feature = "sqlite")]
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_connection(Connection::open_sqlite_file(path)?))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn open_in_memory() -> Result<Self> {
+        Ok(Self::from_connection(Connection::open_in_memory()?))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn open_shared_memory(name: &str) -> Result<Self> {
+        Ok(Self::from_connection(Connection::open_shared_memory(name)?))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn open_read_only<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_connection(Connection::open_sqlite_read_only(
+            path,
+        )?))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn open_with<P: AsRef<std::path::Path>>(
+        path: P,
+        options: &DatabaseOptions,
+    ) -> Result<Self> {
+        // Readers would block the writer outside WAL mode.
+        if options.read_connections > 0 && options.journal_mode != Some(JournalMode::Wal) {
+            return Err(Error::Storage(
+                "read connections need the WAL journal mode".into(),
+            ));
+        }
+        let conn = Connection::open_sqlite_file_with(&path, options)?;
+        let mut db = Self::from_connection(conn)
+            .with_retry_policy(options.retry.clone())
+            .with_transaction_behavior(options.transaction_behavior);
+        if options.read_connections > 0 {
+            db.readers = Some(Readers::open(
+                path.as_ref(),
+                options,
+                db.conn.checked_tables(),
+            ));
+        }
+        Ok(db)
+    }
+
+    pub fn from_storage<S: StorageConnection + 'static>(storage: S) -> Self {
+        Self::from_connection(Connection::from_storage(storage))
+    }
+
+    pub fn from_connection(conn: Connection) -> Self {
+        Self {
+            conn,
+            #[cfg(feature = "sqlite")]
+            readers: None,
+            retry: RetryPolicy::default(),
+            behavior: TransactionBehavior::default(),
+            error_hook: None,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    // Applies to the transactions started by Database::transaction.
+    pub fn with_transaction_behavior(mut self, behavior: TransactionBehavior) -> Self {
+        self.behavior = behavior;
+        self
+    }
+
+    pub fn transaction_behavior(&self) -> TransactionBehavior {
+        self.behavior
+    }
+
+    pub fn on_abandoned_transaction<F>(&mut self, hook: F)
+    where
+        F: Fn(&PendingChanges) + Send + Sync + 'static,
+    {
+        self.conn.on_abandoned_transaction(hook)
+    }
+
+    // Called with every error a method of the database returns, e.g. to log
+    // or count failures in one place. A transaction reports its error once,
+    // after the last attempt.
+    pub fn set_error_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.error_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_error_hook(&mut self) {
+        self.error_hook = None;
+    }
+
+    pub fn observe<F>(&mut self, observer: F)
+    where
+        F: Fn(&ChangeEvent) + Send + Sync + 'static,
+    {
+        self.conn.observe(observer)
+    }
+
+    pub fn set_outbox(&mut self, enabled: bool) {
+        self.conn.set_outbox(enabled)
+    }
+
+    // For the database's own connection and its readers.
+    #[cfg(feature = "encryption")]
+    pub fn set_key_provider<K: crate::encryption::KeyProvider + 'static>(&mut self, provider: K) {
+        let provider: crate::encryption::SharedKeyProvider = Arc::new(provider);
+        #[cfg(feature = "sqlite")]
+        if let Some(readers) = &self.readers {
+            *readers
+                .key_provider
+                .lock()
+                .unwrap_or_else(|err| err.into_inner()) = Some(provider.clone());
+        }
+        self.conn.set_shared_key_provider(Some(provider));
+    }
+
+    pub fn set_instrument<I: Instrument + 'static>(&mut self, instrument: I) -> Result<()> {
+        report(&self.error_hook, self.conn.set_instrument(instrument))
+    }
+
+    pub fn clear_instrument(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.clear_instrument())
+    }
+
+    pub fn set_slow_query_log<F>(&mut self, threshold: Duration, hook: F) -> Result<()>
+    where
+        F: Fn(&SlowQuery) + Send + Sync + 'static,
+    {
+        report(
+            &self.error_hook,
+            self.conn.set_slow_query_log(threshold, hook),
+        )
+    }
+
+    pub fn clear_slow_query_log(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.clear_slow_query_log())
+    }
+
+    pub fn set_sql_trace<F>(&mut self, hook: F) -> Result<()>
+    where
+        F: Fn(&SqlTrace) + Send + Sync + 'static,
+    {
+        report(&self.error_hook, self.conn.set_sql_trace(hook))
+    }
+
+    pub fn clear_sql_trace(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.clear_sql_trace())
+    }
+
+    // For the transactions of the database's own connection and of its
+    // readers; see metrics::Metrics.
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        let metrics: SharedMetrics = Arc::new(metrics);
+        #[cfg(feature = "sqlite")]
+        if let Some(readers) = &self.readers {
+            *readers
+                .metrics
+                .lock()
+                .unwrap_or_else(|err| err.into_inner()) = Some(metrics.clone());
+        }
+        self.conn.set_shared_metrics(Some(metrics));
+    }
+
+    pub fn clear_metrics(&mut self) {
+        #[cfg(feature = "sqlite")]
+        if let Some(readers) = &self.readers {
+            *readers
+                .metrics
+                .lock()
+                .unwrap_or_else(|err| err.into_inner()) = None;
+        }
+        self.conn.clear_metrics();
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+
+    // Commits if the closure returns Ok and rolls back otherwise. On
+    // Error::LockConflict, or a table lock of a shared-cache database, the
+    // whole closure is rerun per the retry policy.
+    pub fn transaction<R>(&mut self, f: impl FnMut(&Transaction) -> Result<R>) -> Result<R> {
+        let retry = self.retry.clone();
+        self.transaction_with_retry(&retry, f)
+    }
+
+    pub fn transaction_with_retry<R>(
+        &mut self,
+        retry: &RetryPolicy,
+        f: impl FnMut(&Transaction) -> Result<R>,
+    ) -> Result<R> {
+        self.run(retry, Begin::Plain, f)
+    }
+
+    // As transaction, with the tables of the tenant, see
+    // Connection::tenant_transaction.
+    pub fn tenant_transaction<R>(
+        &mut self,
+        tenant: &str,
+        f: impl FnMut(&Transaction) -> Result<R>,
+    ) -> Result<R> {
+        let retry = self.retry.clone();
+        self.run(&retry, Begin::Tenant(tenant), f)
+    }
+
+    // As transaction, filtered by the scope, see
+    // Connection::scoped_transaction.
+    pub fn scoped_transaction<R>(
+        &mut self,
+        scope: &Scope,
+        f: impl FnMut(&Transaction) -> Result<R>,
+    ) -> Result<R> {
+        let retry = self.retry.clone();
+        self.run(&retry, Begin::Scoped(scope), f)
+    }
+
+    fn run<R>(
+        &mut self,
+        retry: &RetryPolicy,
+        begin: Begin,
+        mut f: impl FnMut(&Transaction) -> Result<R>,
+    ) -> Result<R> {
+        let mut attempt = 1;
+        loop {
+            let tx = match begin {
+                Begin::Plain => self.conn.new_transaction_with(self.behavior),
+                Begin::Tenant(tenant) => self.conn.tenant_transaction_with(tenant, self.behavior),
+                Begin::Scoped(scope) => self
+                    .conn
+                    .scoped_transaction_with(scope.clone(), self.behavior),
+            };
+            let result = tx.and_then(|tx| {
+                let result = f(&tx);
+                tx.finish(result)
+            });
+            match result {
+                Err(err) if err.is_lock_wait() && attempt < retry.max_attempts => {
+                    thread::sleep(retry.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return report(&self.error_hook, result),
+            }
+        }
+    }
+
+    pub fn migrate(&mut self, migrations: &[&dyn Migration]) -> Result<Vec<i64>> {
+        report(&self.error_hook, self.conn.migrate(migrations))
+    }
+
+    pub fn migration_plan(
+        &mut self,
+        migrations: &[&dyn Migration],
+    ) -> Result<Vec<PlannedMigration>> {
+        report(&self.error_hook, self.conn.migration_plan(migrations))
+    }
+
+    pub fn migrate_down(&mut self, migrations: &[&dyn Migration], target: i64) -> Result<Vec<i64>> {
+        report(&self.error_hook, self.conn.migrate_down(migrations, target))
+    }
+
+    pub fn migrate_down_to(
+        &mut self,
+        migrations: &[&dyn Migration],
+        target: i64,
+    ) -> Result<Vec<i64>> {
+        report(
+            &self.error_hook,
+            self.conn.migrate_down_to(migrations, target),
+        )
+    }
+
+    pub fn applied_migrations(&mut self) -> Result<Vec<i64>> {
+        report(&self.error_hook, self.conn.applied_migrations())
+    }
+
+    pub fn drop_table<T: Object>(&mut self) -> Result<()> {
+        self.transaction(|tx| tx.drop_table::<T>())
+    }
+
+    pub fn truncate<T: Object>(&mut self) -> Result<usize> {
+        self.transaction(|tx| tx.truncate::<T>())
+    }
+
+    pub fn sync_schema<T: Object>(&mut self) -> Result<Vec<&'static str>> {
+        self.transaction(|tx| tx.sync_schema::<T>())
+    }
+
+    pub fn repair_schema<T: Object>(&mut self, policy: RepairPolicy) -> Result<SchemaRepair> {
+        self.transaction(|tx| tx.repair_schema::<T>(policy))
+    }
+
+    pub fn introspect(&mut self) -> Result<Vec<TableInfo>> {
+        self.transaction(|tx| tx.introspect())
+    }
+
+    pub fn table_stats(&mut self) -> Result<Vec<TableStats>> {
+        self.transaction(|tx| tx.table_stats())
+    }
+
+    pub fn validate_schema<T: Object>(&mut self) -> Result<()> {
+        self.transaction(|tx| tx.validate_schema::<T>())
+    }
+
+    pub fn validate_schemas(
+        &mut self,
+        schemas: &[&'static Schema],
+    ) -> Result<Vec<SchemaMismatchError>> {
+        self.transaction(|tx| tx.validate_schemas(schemas))
+    }
+
+    pub fn read_transaction(&mut self) -> Result<ReadTransaction<'_>> {
+        report(&self.error_hook, self.conn.read_transaction())
+    }
+
+    pub fn snapshot(&mut self) -> Result<ReadTransaction<'_>> {
+        report(&self.error_hook, self.conn.snapshot())
+    }
+
+    // Runs f in a read transaction, on one of the readers if the database
+    // was opened with DatabaseOptions::read_connections.
+    pub fn read<R>(&mut self, f: impl FnOnce(&ReadTransaction) -> Result<R>) -> Result<R> {
+        #[cfg(feature = "sqlite")]
+        if let Some(readers) = &self.readers {
+            return report(&self.error_hook, readers.read(f));
+        }
+        let result = self.conn.read_transaction().and_then(|tx| {
+            let result = f(&tx);
+            tx.close().and(result)
+        });
+        report(&self.error_hook, result)
+    }
+
+    // A handle to the readers that other threads can read through while
+    // this one writes.
+    #[cfg(feature = "sqlite")]
+    pub fn readers(&self) -> Option<Readers> {
+        self.readers.clone()
+    }
+
+    // Objects whose table_name is "<alias>.<table>" are stored in the
+    // attached file.
+    pub fn attach<P: AsRef<std::path::Path>>(&mut self, path: P, alias: &str) -> Result<()> {
+        report(&self.error_hook, self.conn.attach(path, alias))
+    }
+
+    pub fn detach(&mut self, alias: &str) -> Result<()> {
+        report(&self.error_hook, self.conn.detach(alias))
+    }
+
+    // Snapshots the database without blocking other connections for the
+    // whole copy.
+    pub fn backup_to<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        progress: impl FnMut(BackupProgress),
+    ) -> Result<()> {
+        report(&self.error_hook, self.conn.backup_to(path, progress))
+    }
+
+    pub fn vacuum(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.vacuum())
+    }
+
+    pub fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> Result<()> {
+        report(&self.error_hook, self.conn.incremental_vacuum(max_pages))
+    }
+
+    pub fn optimize(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.optimize())
+    }
+
+    pub fn analyze(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.analyze())
+    }
+
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<Checkpoint> {
+        report(&self.error_hook, self.conn.checkpoint(mode))
+    }
+
+    pub fn statement_cache_stats(&self) -> Option<StatementCacheStats> {
+        self.conn.statement_cache_stats()
+    }
+
+    pub fn create_scalar_function<F>(&mut self, name: &str, function: F) -> Result<()>
+    where
+        F: Fn(&[Value<'_>]) -> Result<Value<'static>> + Send + 'static,
+    {
+        report(
+            &self.error_hook,
+            self.conn.create_scalar_function(name, function),
+        )
+    }
+
+    pub fn set_foreign_keys(&mut self, enabled: bool) -> Result<()> {
+        report(&self.error_hook, self.conn.set_foreign_keys(enabled))
+    }
+
+    pub fn on_change<F>(&mut self, hook: F) -> Result<()>
+    where
+        F: FnMut(&str, ChangeOp, ObjectId) + Send + 'static,
+    {
+        report(&self.error_hook, self.conn.on_change(hook))
+    }
+
+    pub fn clear_change_hook(&mut self) -> Result<()> {
+        report(&self.error_hook, self.conn.clear_change_hook())
+    }
+
+    // Both checks read the whole database; run them at startup rather than
+    // on every open of a large file.
+    pub fn integrity_check(&mut self) -> Result<IntegrityReport> {
+        report(&self.error_hook, self.conn.integrity_check())
+    }
+
+    pub fn quick_check(&mut self) -> Result<IntegrityReport> {
+        report(&self.error_hook, self.conn.quick_check())
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    pub fn rekey(&mut self, key: &str) -> Result<()> {
+        report(&self.error_hook, self.conn.rekey(key))
+    }
+
+    pub fn total_changes(&self) -> Result<usize> {
+        report(&self.error_hook, self.conn.total_changes())
+    }
+
+    // For large imports: on SQLite, commits are not synced, the rollback
+    // journal is kept in memory and indexes that are not UNIQUE are built
+    // once at the end, until `f` returns and the previous settings are
+    // restored. A crash in between can leave the file corrupt, so load into
+    // a file that can be rebuilt.
+    // Errors of `f` have been reported by the transactions that failed.
+    pub fn bulk_load_mode<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R>) -> Result<R> {
+        report(&self.error_hook, self.conn.set_bulk_load(true))?;
+        let result = f(self);
+        let restored = report(&self.error_hook, self.conn.set_bulk_load(false));
+        result.and_then(|r| restored.map(|()| r))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Read-only connections to the file of a database, opened as needed up to
+// DatabaseOptions::read_connections. In WAL mode they read the last commit
+// without waiting on the writer or on each other.
+#[cfg(feature = "sqlite")]
+#[derive(Clone)]
+pub struct Readers {
+    pool: Arc<Pool<ReadStorage>>,
+    checked_tables: CheckedTables,
+    metrics: Arc<Mutex<Option<SharedMetrics>>>,
+    #[cfg(feature = "encryption")]
+    key_provider: Arc<Mutex<Option<crate::encryption::SharedKeyProvider>>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Readers {
+    fn open(
+        path: &std::path::Path,
+        options: &DatabaseOptions,
+        checked_tables: CheckedTables,
+    ) -> Self {
+        let path = path.to_path_buf();
+        let options = options.clone();
+        let pool_options = PoolOptions {
+            max_size: options.read_connections,
+            ..Default::default()
+        };
+        Self {
+            pool: Arc::new(Pool::new(pool_options, move || {
+                open_reader(&path, &options)
+            })),
+            checked_tables,
+            metrics: Arc::default(),
+            #[cfg(feature = "encryption")]
+            key_provider: Arc::default(),
+        }
+    }
+
+    // Waits for a free reader if all of them are busy. Tables checked by the
+    // database's own connection are not checked again.
+    pub fn read<R>(&self, f: impl FnOnce(&ReadTransaction) -> Result<R>) -> Result<R> {
+        let mut conn = self.pool.get()?;
+        let metrics = self
+            .metrics
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        let inner = metered(StorageConnection::new_transaction(&mut *conn)?, metrics);
+        #[cfg(feature = "encryption")]
+        let inner = crate::encryption::encrypting(
+            inner,
+            self.key_provider
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .clone(),
+        );
+        let tx = Transaction::new(inner)
+            .with_read_only(true)
+            .with_checked_tables(Some(self.checked_tables.clone()));
+        let tx = ReadTransaction::new(tx);
+        let result = f(&tx);
+        tx.close().and(result)
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.pool.open_connections()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+            Self::Persist => "PERSIST",
+            Self::Memory => "MEMORY",
+            Self::Wal => "WAL",
+            Self::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    // Doubles after every failed attempt, up to max_backoff.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32 << attempt.saturating_sub(1).min(16);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoVacuum {
+    None,
+    Full,
+    Incremental,
+}
+
+impl AutoVacuum {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "NONE",
+            Self::Full => "FULL",
+            Self::Incremental => "INCREMENTAL",
+        }
+    }
+}
+
+fn report<R>(hook: &Option<ErrorHook>, result: Result<R>) -> Result<R> {
+    if let (Some(hook), Err(err)) = (hook, &result) {
+        hook(err);
+    }
+    result
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Settings left unset keep the SQLite defaults.
+#[derive(Clone, Debug, Default)]
+pub struct DatabaseOptions {
+    pub(crate) journal_mode: Option<JournalMode>,
+    pub(crate) synchronous: Option<Synchronous>,
+    pub(crate) foreign_keys: Option<bool>,
+    pub(crate) cache_size: Option<i64>,
+    pub(crate) mmap_size: Option<i64>,
+    pub(crate) auto_vacuum: Option<AutoVacuum>,
+    pub(crate) statement_cache_capacity: Option<usize>,
+    pub(crate) busy_timeout: Option<Duration>,
+    pub(crate) optimize_on_close: bool,
+    pub(crate) strict_tables: bool,
+    pub(crate) table_prefix: Option<String>,
+    pub(crate) log_parameter_values: bool,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) transaction_behavior: TransactionBehavior,
+    pub(crate) read_connections: usize,
+    #[cfg(feature = "sqlcipher")]
+    pub(crate) key: Option<String>,
+}
+
+impl DatabaseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn journal_mode(mut self, mode: JournalMode) -> Self {
+        self.journal_mode = Some(mode);
+        self
+    }
+
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = Some(synchronous);
+        self
+    }
+
+    pub fn foreign_keys(mut self, enabled: bool) -> Self {
+        self.foreign_keys = Some(enabled);
+        self
+    }
+
+    // Positive values are pages, negative values are KiB, as in SQLite.
+    pub fn cache_size(mut self, size: i64) -> Self {
+        self.cache_size = Some(size);
+        self
+    }
+
+    pub fn mmap_size(mut self, bytes: i64) -> Self {
+        self.mmap_size = Some(bytes);
+        self
+    }
+
+    pub fn auto_vacuum(mut self, mode: AutoVacuum) -> Self {
+        self.auto_vacuum = Some(mode);
+        self
+    }
+
+    pub fn statement_cache_capacity(mut self, capacity: usize) -> Self {
+        self.statement_cache_capacity = Some(capacity);
+        self
+    }
+
+    // How long SQLite itself waits on a locked database before reporting
+    // Error::LockConflict.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    // Runs PRAGMA optimize when the connection is dropped, as SQLite
+    // recommends for long-lived connections.
+    pub fn optimize_on_close(mut self, enabled: bool) -> Self {
+        self.optimize_on_close = enabled;
+        self
+    }
+
+    // Creates tables as STRICT, so SQLite rejects a value of the wrong type
+    // instead of storing it with type affinity.
+    pub fn strict_tables(mut self, enabled: bool) -> Self {
+        self.strict_tables = enabled;
+        self
+    }
+
+    // Stores every table as "<prefix><table>", see prefix::PrefixedStorage.
+    pub fn table_prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        self.table_prefix = Some(prefix.into());
+        self
+    }
+
+    // Shows the parameter values of a failed statement in its error instead
+    // of only their types. They may hold data that should not reach the logs.
+    pub fn log_parameter_values(mut self, enabled: bool) -> Self {
+        self.log_parameter_values = enabled;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn transaction_behavior(mut self, behavior: TransactionBehavior) -> Self {
+        self.transaction_behavior = behavior;
+        self
+    }
+
+    // Keeps up to this many read-only connections for Database::read and
+    // Database::readers. Needs the WAL journal mode.
+    pub fn read_connections(mut self, count: usize) -> Self {
+        self.read_connections = count;
+        self
+    }
+
+    // Passphrase of a SQLCipher database; a new file is encrypted with it.
+    #[cfg(feature = "sqlcipher")]
+    pub fn key<K: Into<String>>(mut self, key: K) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+}
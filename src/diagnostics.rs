@@ -0,0 +1,359 @@
+#![forbid(unsafe_code)]
+use crate::error::{
+    BorrowedError, ConflictError, ConstraintError, ConstraintKind, DeserializeError, Error,
+    ErrorKind, Mismatch, MissingColumnError, NotFoundError, SchemaFingerprintMismatchError,
+    SchemaMismatchError, StatementError, UnexpectedTypeError,
+};
+use miette::{Diagnostic, LabeledSpan, SourceCode};
+use std::fmt::Display;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// miette::Diagnostic for every error, behind the `diagnostics` feature. The
+// source a label points at is the table or column at fault, or the failed
+// statement; the help names the usual fix.
+
+type Text<'a> = Option<Box<dyn Display + 'a>>;
+type Labels<'a> = Option<Box<dyn Iterator<Item = LabeledSpan> + 'a>>;
+
+fn code<'a>(kind: ErrorKind) -> Text<'a> {
+    Some(Box::new(format!("orm::{}", kind.code())))
+}
+
+fn text<'a>(text: impl Display + 'a) -> Text<'a> {
+    Some(Box::new(text))
+}
+
+fn label<'a>(offset: usize, len: usize, text: String) -> Labels<'a> {
+    Some(Box::new(std::iter::once(
+        LabeledSpan::new_primary_with_span(Some(text), (offset, len)),
+    )))
+}
+
+// The whole source, which is a single name.
+fn label_all<'a>(source: &str, text: String) -> Labels<'a> {
+    label(0, source.len(), text)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Diagnostic for Error {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(self.kind())
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        match (self.inner(), self) {
+            (Some(inner), _) => inner.help(),
+            (None, Error::LockConflict) => text(
+                "another connection holds a lock on the database; run the transaction again, \
+                or raise DatabaseOptions::busy_timeout",
+            ),
+            (None, Error::Locked(err)) if err.is_shared_cache() => {
+                text("another connection of the shared cache holds a lock on the table")
+            }
+            (None, Error::Locked(_)) => text(
+                "a statement of this connection still uses the table; finish it before \
+                changing the table",
+            ),
+            (None, Error::ReadOnly) => text("open the database read-write to change it"),
+            (None, Error::Timeout) => text(
+                "a statement ran past Transaction::set_deadline; narrow the query or give it \
+                more time",
+            ),
+            (None, _) => None,
+        }
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.inner()?.source_code()
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        self.inner()?.labels()
+    }
+
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        self.inner()?.related()
+    }
+}
+
+impl Error {
+    fn inner(&self) -> Option<&dyn Diagnostic> {
+        match self {
+            Error::NotFound(err) => Some(&**err),
+            Error::UnexpectedType(err) => Some(&**err),
+            Error::MissingColumn(err) => Some(&**err),
+            Error::Deserialize(err) => Some(&**err),
+            Error::SchemaFingerprintMismatch(err) => Some(&**err),
+            Error::SchemaMismatch(err) => Some(&**err),
+            Error::Conflict(err) => Some(&**err),
+            Error::Borrowed(err) => Some(&**err),
+            Error::Constraint(err) => Some(&**err),
+            Error::Storage(source) => source
+                .downcast_ref::<StatementError>()
+                .map(|err| err as &dyn Diagnostic),
+            Error::LockConflict | Error::Locked(_) | Error::ReadOnly | Error::Timeout => None,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Diagnostic for NotFoundError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::NotFound)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text("the object was deleted, or was never created in this database")
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.type_name)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        label_all(
+            &self.type_name,
+            format!("no object with id {}", self.object_id),
+        )
+    }
+}
+
+impl Diagnostic for BorrowedError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Borrowed)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text("drop the other borrow of the object first")
+    }
+}
+
+impl Diagnostic for ConflictError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Conflict)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text("run the transaction again to work on the current rows")
+    }
+}
+
+impl Diagnostic for UnexpectedTypeError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Schema)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text(format!(
+            "the row was written outside the ORM or by another version of {}; \
+            fix the stored value or change the type of {}::{}",
+            self.type_name, self.type_name, self.attr_name
+        ))
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.column_name)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        label_all(
+            self.column_name,
+            format!(
+                "stored as {}, expected {:?}",
+                self.got_type, self.expected_type
+            ),
+        )
+    }
+}
+
+impl Diagnostic for MissingColumnError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Schema)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text("add the column with a migration, or with Transaction::sync_schema")
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.column_name)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        label_all(
+            &self.column_name,
+            format!("not a column of table {}", self.table_name),
+        )
+    }
+}
+
+impl Diagnostic for DeserializeError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Schema)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text(format!(
+            "the row does not fit {}; compare the table with the struct",
+            self.type_name
+        ))
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.attr_name)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        label_all(self.attr_name, format!("expected {:?}", self.expected))
+    }
+}
+
+impl Diagnostic for SchemaFingerprintMismatchError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Schema)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text("migrate the table, or run Transaction::sync_schema during development")
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.table_name)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        label_all(
+            self.table_name,
+            format!("created from another version of {}", self.type_name),
+        )
+    }
+}
+
+// Every mismatch is a related diagnostic that points at its column.
+impl Diagnostic for SchemaMismatchError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Schema)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        text("migrate the table, or run Transaction::sync_schema during development")
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.table_name)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        label_all(
+            self.table_name,
+            format!("does not match {}", self.type_name),
+        )
+    }
+
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        Some(Box::new(
+            self.mismatches.iter().map(|m| m as &dyn Diagnostic),
+        ))
+    }
+}
+
+impl Diagnostic for Mismatch {
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        match self {
+            Mismatch::MissingTable => None,
+            Mismatch::MissingColumn { column }
+            | Mismatch::ExtraColumn { column }
+            | Mismatch::Type { column, .. } => Some(column),
+        }
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        match self {
+            Mismatch::MissingTable => None,
+            Mismatch::MissingColumn { column } => label_all(column, "missing".to_string()),
+            Mismatch::ExtraColumn { column } => label_all(column, "not declared".to_string()),
+            Mismatch::Type {
+                column,
+                expected,
+                found,
+            } => label_all(column, format!("{:?}, expected {:?}", found, expected)),
+        }
+    }
+}
+
+// Labels the columns of the constraint, when the backend names them.
+impl Diagnostic for ConstraintError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Constraint)
+    }
+
+    fn help<'a>(&'a self) -> Text<'a> {
+        let columns = self.columns.join(", ");
+        match self.kind {
+            ConstraintKind::Unique | ConstraintKind::PrimaryKey if !columns.is_empty() => {
+                text(format!("a row with the same {} already exists", columns))
+            }
+            ConstraintKind::NotNull if !columns.is_empty() => {
+                text(format!("{} must be set", columns))
+            }
+            ConstraintKind::ForeignKey => {
+                text("the referenced row does not exist, or a row still refers to the deleted one")
+            }
+            ConstraintKind::Check => text("a CHECK constraint of the table rejects the row"),
+            _ => None,
+        }
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.message)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        let labels: Vec<_> = self
+            .columns
+            .iter()
+            .filter_map(|column| {
+                let offset = self.message.rfind(column.as_str())?;
+                Some(LabeledSpan::new_with_span(
+                    Some(format!("{:?}", self.kind)),
+                    (offset, column.len()),
+                ))
+            })
+            .collect();
+        match labels.is_empty() {
+            true => None,
+            false => Some(Box::new(labels.into_iter())),
+        }
+    }
+}
+
+// Points at the table or column SQLite complains about, if it is named in the
+// statement, and at the whole statement otherwise.
+impl Diagnostic for StatementError {
+    fn code<'a>(&'a self) -> Text<'a> {
+        code(ErrorKind::Storage)
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.sql)
+    }
+
+    fn labels(&self) -> Labels<'_> {
+        let message = self.source.to_string();
+        let name = [
+            "no such table: ",
+            "no such column: ",
+            "has no column named ",
+        ]
+        .iter()
+        .find_map(|prefix| message.split_once(prefix))
+        .map(|(_, name)| name.trim());
+        match name.and_then(|name| Some((self.sql.find(name)?, name.len()))) {
+            Some((offset, len)) => label(offset, len, message),
+            None => label_all(&self.sql, message),
+        }
+    }
+}
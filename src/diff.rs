@@ -0,0 +1,162 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::Result,
+    object::Schema,
+    storage::{select_stored, Row, TableInfo},
+    Database, ObjectId,
+};
+use std::collections::BTreeMap;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// What changed from database `a` to database `b` in the tables of the given
+// schemas: how the stored tables differ, and which rows were added, removed
+// or changed, by id. Rows are only compared where both tables have every
+// column of the schema.
+pub fn diff(a: &mut Database, b: &mut Database, schemas: &[&'static Schema]) -> Result<DiffReport> {
+    let mut tables = Vec::with_capacity(schemas.len());
+    for &schema in schemas {
+        let (info_a, rows_a) = read(a, schema)?;
+        let (info_b, rows_b) = read(b, schema)?;
+        let mut table = TableDiff {
+            table: schema.table_name,
+            schema: schema_differences(info_a.as_ref(), info_b.as_ref()),
+            ..TableDiff::default()
+        };
+        if let (Some(rows_a), Some(rows_b)) = (rows_a, rows_b) {
+            diff_rows(schema, rows_a, rows_b, &mut table);
+        }
+        tables.push(table);
+    }
+    Ok(DiffReport { tables })
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DiffReport {
+    pub tables: Vec<TableDiff>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.tables.iter().all(TableDiff::is_empty)
+    }
+}
+
+// Ids are in ascending order.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TableDiff {
+    pub table: &'static str,
+    pub schema: Vec<SchemaDifference>,
+    pub added: Vec<ObjectId>,
+    pub removed: Vec<ObjectId>,
+    pub changed: Vec<RowChange>,
+}
+
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        self.schema.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+// Types are None for declared types the ORM does not map to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SchemaDifference {
+    TableAdded,
+    TableRemoved,
+    ColumnAdded(String),
+    ColumnRemoved(String),
+    ColumnTypeChanged {
+        column: String,
+        from: Option<DataType>,
+        to: Option<DataType>,
+    },
+}
+
+// The columns of the row that differ, with their value in `a` and in `b`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RowChange {
+    pub id: ObjectId,
+    pub columns: Vec<(&'static str, Value<'static>, Value<'static>)>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type Rows = BTreeMap<i64, Row<'static>>;
+
+// The stored table, and its rows when it has every column of the schema.
+fn read(db: &mut Database, schema: &Schema) -> Result<(Option<TableInfo>, Option<Rows>)> {
+    db.read(|tx| {
+        let storage = tx.transaction().storage();
+        if !storage.table_exists(schema.table_name)? {
+            return Ok((None, None));
+        }
+        let info = storage.table_info(schema.table_name)?;
+        if schema
+            .column_names()
+            .any(|name| info.column(name).is_none())
+        {
+            return Ok((Some(info), None));
+        }
+        let rows = select_stored(storage, schema)?
+            .into_iter()
+            .map(|(id, row)| (id.into_i64(), row))
+            .collect();
+        Ok((Some(info), Some(rows)))
+    })
+}
+
+fn schema_differences(a: Option<&TableInfo>, b: Option<&TableInfo>) -> Vec<SchemaDifference> {
+    let (a, b) = match (a, b) {
+        (None, None) => return Vec::new(),
+        (None, Some(_)) => return vec![SchemaDifference::TableAdded],
+        (Some(_), None) => return vec![SchemaDifference::TableRemoved],
+        (Some(a), Some(b)) => (a, b),
+    };
+    let mut differences = Vec::new();
+    for column in &a.columns {
+        match b.column(&column.name) {
+            None => differences.push(SchemaDifference::ColumnRemoved(column.name.clone())),
+            Some(other) if other.typ != column.typ => {
+                differences.push(SchemaDifference::ColumnTypeChanged {
+                    column: column.name.clone(),
+                    from: column.typ,
+                    to: other.typ,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for column in &b.columns {
+        if a.column(&column.name).is_none() {
+            differences.push(SchemaDifference::ColumnAdded(column.name.clone()));
+        }
+    }
+    differences
+}
+
+fn diff_rows(schema: &'static Schema, a: Rows, mut b: Rows, table: &mut TableDiff) {
+    for (id, row_a) in a {
+        let Some(row_b) = b.remove(&id) else {
+            table.removed.push(ObjectId::from(id));
+            continue;
+        };
+        let columns: Vec<_> = schema
+            .columns
+            .iter()
+            .zip(row_a.into_iter().zip(row_b))
+            .filter(|(_, (value_a, value_b))| value_a != value_b)
+            .map(|(column, (value_a, value_b))| (column.column_name, value_a, value_b))
+            .collect();
+        if !columns.is_empty() {
+            table.changed.push(RowChange {
+                id: ObjectId::from(id),
+                columns,
+            });
+        }
+    }
+    table.added = b.into_keys().map(ObjectId::from).collect();
+}
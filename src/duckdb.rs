@@ -0,0 +1,435 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{Error, MissingColumnError, NotFoundError, Result, UnexpectedTypeError},
+    object::Schema,
+    predicate::quote,
+    storage::{OnConflict, Row, RowSlice, StorageConnection, StorageTransaction},
+    Connection, ObjectId,
+};
+use ::duckdb::{params_from_iter, types::Value as DuckValue};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    path::Path,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct DuckdbStorage {
+    conn: ::duckdb::Connection,
+    changes: Cell<usize>,
+}
+
+impl DuckdbStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_connection(::duckdb::Connection::open(path)?))
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Ok(Self::from_connection(
+            ::duckdb::Connection::open_in_memory()?
+        ))
+    }
+
+    pub fn from_connection(conn: ::duckdb::Connection) -> Self {
+        Self {
+            conn,
+            changes: Cell::new(0),
+        }
+    }
+}
+
+impl StorageConnection for DuckdbStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(DuckdbTransaction {
+            inner: RefCell::new(Some(self.conn.transaction()?)),
+            changes: &self.changes,
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        Ok(self.changes.get())
+    }
+}
+
+impl Connection {
+    pub fn open_duckdb_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_storage(DuckdbStorage::open(path)?))
+    }
+
+    pub fn open_duckdb_in_memory() -> Result<Self> {
+        Ok(Self::from_storage(DuckdbStorage::open_in_memory()?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct DuckdbTransaction<'a> {
+    inner: RefCell<Option<::duckdb::Transaction<'a>>>,
+    changes: &'a Cell<usize>,
+}
+
+impl<'a> DuckdbTransaction<'a> {
+    fn with<R>(
+        &self,
+        f: impl FnOnce(&::duckdb::Transaction<'a>) -> ::duckdb::Result<R>,
+    ) -> Result<R> {
+        let inner = self.inner.borrow();
+        Ok(f(inner.as_ref().ok_or_else(finished)?)?)
+    }
+
+    fn execute_counted(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        let changes = self.with(|tx| tx.execute(sql, params_from_iter(to_params(params))))?;
+        self.changes.set(self.changes.get() + changes);
+        Ok(changes)
+    }
+
+    fn query<R>(
+        &self,
+        sql: &str,
+        params: &RowSlice,
+        mut f: impl FnMut(&::duckdb::Row) -> Result<R>,
+    ) -> Result<Vec<R>> {
+        let inner = self.inner.borrow();
+        let tx = inner.as_ref().ok_or_else(finished)?;
+        let mut stmt = tx.prepare(sql)?;
+        let mut rows = stmt.query(params_from_iter(to_params(params)))?;
+
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            result.push(f(row)?);
+        }
+        Ok(result)
+    }
+}
+
+impl<'a> StorageTransaction for DuckdbTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        let rows = self.query(
+            "SELECT 1 FROM information_schema.tables \
+            WHERE table_schema = current_schema() AND table_name = ?",
+            &[table.into()],
+            |_| Ok(()),
+        )?;
+        Ok(!rows.is_empty())
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let mut query = format!(
+            "CREATE SEQUENCE {}; CREATE TABLE {} (id BIGINT PRIMARY KEY",
+            sequence_name(schema),
+            quote(schema.table_name)
+        );
+        for column in schema.columns {
+            query.push_str(&format!(
+                ", {} {}",
+                quote(column.column_name),
+                sql_type(column.typ)
+            ));
+        }
+        query.push(')');
+
+        self.with(|tx| tx.execute_batch(&query))
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.with(|tx| {
+            tx.execute_batch(&format!(
+                "DROP TABLE {}; DROP SEQUENCE IF EXISTS {}",
+                quote(schema.table_name),
+                sequence_name(schema)
+            ))
+        })
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes =
+            self.execute_counted(&format!("DELETE FROM {}", quote(schema.table_name)), &[])?;
+        self.with(|tx| {
+            tx.execute_batch(&format!(
+                "DROP SEQUENCE IF EXISTS {0}; CREATE SEQUENCE {0}",
+                sequence_name(schema)
+            ))
+        })?;
+        Ok(changes)
+    }
+
+    // Ids come from a per-table sequence rather than a column default, so
+    // `clear_table` can restart it without touching the table definition.
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        let verb = match on_conflict {
+            OnConflict::Abort => "INSERT",
+            OnConflict::Ignore => "INSERT OR IGNORE",
+            OnConflict::Replace => "INSERT OR REPLACE",
+        };
+        let mut columns = vec!["id".to_string()];
+        columns.extend(schema.columns.iter().map(|c| quote(c.column_name)));
+        let sequence = format!("{}_id_seq", schema.table_name).replace('\'', "''");
+        let mut values = vec![format!("nextval('{}')", sequence)];
+        values.extend(schema.columns.iter().map(|_| "?".to_string()));
+        let query = format!(
+            "{} INTO {} ({}) VALUES ({}) RETURNING id",
+            verb,
+            quote(schema.table_name),
+            columns.join(", "),
+            values.join(", ")
+        );
+
+        let ids = self
+            .query(&query, row, |row| Ok(row.get::<_, i64>(0)?))
+            .map_err(|err| map_schema_error(err, schema))?;
+        let id = ids.first().map(|id| ObjectId::from(*id));
+        self.changes.set(self.changes.get() + id.is_some() as usize);
+        Ok(id)
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        if schema.columns.is_empty() {
+            return Ok(0);
+        }
+        let assignments: Vec<_> = schema
+            .columns
+            .iter()
+            .map(|c| format!("{} = ?", quote(c.column_name)))
+            .collect();
+        let query = format!(
+            "UPDATE {} SET {} WHERE id = ?",
+            quote(schema.table_name),
+            assignments.join(", ")
+        );
+
+        let mut params = row.to_vec();
+        params.push(Value::Int64(id.into_i64()));
+        self.execute_counted(&query, &params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        if columns.is_empty() {
+            return Ok(0);
+        }
+        let assignments: Vec<_> = columns
+            .iter()
+            .map(|c| format!("{} = ?", quote(c)))
+            .collect();
+        let mut query = format!(
+            "UPDATE {} SET {}",
+            quote(schema.table_name),
+            assignments.join(", ")
+        );
+        push_where(&mut query, where_sql);
+
+        let mut args = values.to_vec();
+        args.extend_from_slice(params);
+        self.execute_counted(&query, &args)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let query = format!("{} WHERE id = ?", select_text(schema, false));
+        let mut rows = self
+            .query(&query, &[Value::Int64(id.into_i64())], |row| {
+                read_row(row, schema, 0)
+            })
+            .map_err(|err| map_schema_error(err, schema))?;
+        match rows.pop() {
+            Some(row) => Ok(row),
+            None => Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            )))),
+        }
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let mut query = select_text(schema, true);
+        push_where(&mut query, where_sql);
+        query.push_str(" ORDER BY id");
+
+        let rows = self
+            .query(&query, params, |row| {
+                Ok((
+                    ObjectId::from(row.get::<_, i64>(0)?),
+                    read_row(row, schema, 1)?,
+                ))
+            })
+            .map_err(|err| map_schema_error(err, schema))?;
+        for (id, row) in rows {
+            f(id, row)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let mut query = format!("SELECT COUNT(*) FROM {}", quote(schema.table_name));
+        push_where(&mut query, where_sql);
+
+        let counts = self
+            .query(&query, params, |row| Ok(row.get::<_, i64>(0)?))
+            .map_err(|err| map_schema_error(err, schema))?;
+        Ok(counts.first().copied().unwrap_or(0) as usize)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        let query = format!("DELETE FROM {} WHERE id = ?", quote(schema.table_name));
+        if self.execute_counted(&query, &[Value::Int64(id.into_i64())])? == 0 {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            ))));
+        }
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let mut query = format!("DELETE FROM {}", quote(schema.table_name));
+        push_where(&mut query, where_sql);
+        self.execute_counted(&query, params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.execute_counted(sql, params)
+    }
+
+    fn commit(&self) -> Result<()> {
+        let tx = self.inner.borrow_mut().take().ok_or_else(finished)?;
+        Ok(tx.commit()?)
+    }
+
+    fn rollback(&self) -> Result<()> {
+        let tx = self.inner.borrow_mut().take().ok_or_else(finished)?;
+        Ok(tx.rollback()?)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl From<::duckdb::Error> for Error {
+    fn from(err: ::duckdb::Error) -> Self {
+        match &err {
+            ::duckdb::Error::DuckDBFailure(_, Some(text))
+                if text.contains("Conflict on") || text.contains("write-write conflict") =>
+            {
+                Error::LockConflict
+            }
+            _ => Error::Storage(Box::new(err)),
+        }
+    }
+}
+
+fn finished() -> Error {
+    Error::Storage("transaction is already finished".into())
+}
+
+fn map_schema_error(err: Error, schema: &Schema) -> Error {
+    let text = match &err {
+        Error::Storage(source) => source.to_string(),
+        _ => return err,
+    };
+    let missing = ["Referenced column \"", "column with name \""]
+        .iter()
+        .find_map(|marker| {
+            let start = text.find(marker)? + marker.len();
+            let len = text[start..].find('"')?;
+            Some(&text[start..start + len])
+        });
+    missing
+        .and_then(|name| MissingColumnError::from_column_name(name, schema))
+        .unwrap_or(err)
+}
+
+fn sequence_name(schema: &Schema) -> String {
+    quote(&format!("{}_id_seq", schema.table_name))
+}
+
+fn sql_type(typ: DataType) -> &'static str {
+    match typ {
+        DataType::String => "VARCHAR",
+        DataType::Bytes => "BLOB",
+        DataType::Int64 => "BIGINT",
+        DataType::Float64 => "DOUBLE",
+        DataType::Bool => "BOOLEAN",
+    }
+}
+
+fn select_text(schema: &Schema, with_id: bool) -> String {
+    let mut columns: Vec<_> = with_id.then(|| "id".to_string()).into_iter().collect();
+    columns.extend(schema.columns.iter().map(|c| quote(c.column_name)));
+    if columns.is_empty() {
+        columns.push("1".to_string());
+    }
+    format!(
+        "SELECT {} FROM {}",
+        columns.join(", "),
+        quote(schema.table_name)
+    )
+}
+
+fn push_where(query: &mut String, where_sql: &str) {
+    if !where_sql.is_empty() {
+        query.push_str(" WHERE ");
+        query.push_str(where_sql);
+    }
+}
+
+fn to_params(values: &RowSlice) -> Vec<DuckValue> {
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(s) => DuckValue::Text(s.to_string()),
+            Value::Bytes(b) => DuckValue::Blob(b.to_vec()),
+            Value::Int64(i) => DuckValue::BigInt(*i),
+            Value::Float64(f) => DuckValue::Double(*f),
+            Value::Bool(b) => DuckValue::Boolean(*b),
+        })
+        .collect()
+}
+
+fn read_row(row: &::duckdb::Row, schema: &Schema, offset: usize) -> Result<Row<'static>> {
+    let mut line = Row::with_capacity(schema.columns.len());
+    for (i, column) in schema.columns.iter().enumerate() {
+        let index = i + offset;
+        let value = match column.typ {
+            DataType::String => row.get(index).map(|s: String| Value::String(Cow::Owned(s))),
+            DataType::Bytes => row.get(index).map(|b: Vec<u8>| Value::Bytes(Cow::Owned(b))),
+            DataType::Int64 => row.get(index).map(Value::Int64),
+            DataType::Float64 => row.get(index).map(Value::Float64),
+            DataType::Bool => row.get(index).map(Value::Bool),
+        };
+        let value = value.map_err(|err| match err {
+            ::duckdb::Error::InvalidColumnType(_, _, got_type) => {
+                Error::UnexpectedType(Box::new(UnexpectedTypeError::new(
+                    schema.type_name,
+                    column.attr_name,
+                    schema.table_name,
+                    column.column_name,
+                    column.typ,
+                    got_type.to_string(),
+                )))
+            }
+            err => err.into(),
+        })?;
+        line.push(value);
+    }
+    Ok(line)
+}
@@ -0,0 +1,267 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, ObjectId, Value},
+    error::{Error, NotFoundError, Result, UnexpectedTypeError},
+    object::{fingerprint, Column, Object, Schema},
+    predicate::Predicate,
+    storage::{Row, RowSlice},
+    Transaction,
+};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Types are looked up by Schema::table_name. Registering another type under
+// the same table name replaces the earlier one.
+pub fn register<T: Object>() {
+    registry().insert(
+        T::TABLE.table_name,
+        Registered {
+            schema: T::TABLE,
+            create: create::<T>,
+        },
+    );
+}
+
+pub fn registered(table_name: &str) -> Option<&'static Schema> {
+    registry()
+        .get(table_name)
+        .map(|registered| registered.schema)
+}
+
+pub fn registered_tables() -> Vec<&'static str> {
+    let mut tables: Vec<_> = registry().keys().copied().collect();
+    tables.sort_unstable();
+    tables
+}
+
+// Creates the object with the given id, if any, or a new one.
+pub(crate) type CreateFn = fn(&Transaction, Option<ObjectId>, Row<'static>) -> Result<ObjectId>;
+
+pub(crate) fn registered_create(table_name: &str) -> Option<(&'static Schema, CreateFn)> {
+    registry()
+        .get(table_name)
+        .map(|registered| (registered.schema, registered.create))
+}
+
+#[derive(Clone, Copy)]
+struct Registered {
+    schema: &'static Schema,
+    create: CreateFn,
+}
+
+fn create<T: Object>(
+    tx: &Transaction,
+    id: Option<ObjectId>,
+    row: Row<'static>,
+) -> Result<ObjectId> {
+    let obj = T::deserialize(row)?;
+    Ok(match id {
+        Some(id) => tx.create_with_id(id, obj)?.id().raw(),
+        None => tx.create(obj)?.id().raw(),
+    })
+}
+
+fn registry() -> MutexGuard<'static, HashMap<&'static str, Registered>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<&'static str, Registered>>> = OnceLock::new();
+    REGISTRY
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A stored row of a registered type, with values in schema column order.
+pub struct DynamicRow {
+    pub schema: &'static Schema,
+    pub id: ObjectId,
+    pub values: Row<'static>,
+}
+
+impl DynamicRow {
+    pub fn get(&self, column_name: &str) -> Option<&Value<'static>> {
+        let position = self
+            .schema
+            .columns
+            .iter()
+            .position(|column| column.column_name == column_name)?;
+        self.values.get(position)
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = (&'static str, &Value<'static>)> {
+        self.schema
+            .columns
+            .iter()
+            .map(|column| column.column_name)
+            .zip(self.values.iter())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A table defined at runtime, such as one holding user-defined fields. Its
+// rows are DynamicRows, see Transaction::dynamic_table.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct DynamicSchema {
+    table_name: String,
+    columns: Vec<(String, DataType)>,
+}
+
+impl DynamicSchema {
+    pub fn new<S: Into<String>>(table_name: S) -> Self {
+        Self {
+            table_name: table_name.into(),
+            columns: Vec::new(),
+        }
+    }
+
+    pub fn column<S: Into<String>>(mut self, name: S, typ: DataType) -> Self {
+        self.columns.push((name.into(), typ));
+        self
+    }
+
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    // The storage API takes static schemas, so one is built per distinct
+    // definition and kept for the life of the process.
+    pub fn schema(&self) -> &'static Schema {
+        static SCHEMAS: OnceLock<Mutex<HashMap<DynamicSchema, &'static Schema>>> = OnceLock::new();
+        let mut schemas = SCHEMAS
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        schemas.entry(self.clone()).or_insert_with(|| {
+            let columns: Vec<_> = self
+                .columns
+                .iter()
+                .map(|(name, typ)| {
+                    let name: &'static str = Box::leak(name.clone().into_boxed_str());
+                    Column {
+                        column_name: name,
+                        attr_name: name,
+                        typ: *typ,
+                        lazy: false,
+                        encrypted: false,
+                        compressed: false,
+                    }
+                })
+                .collect();
+            let columns: &'static [Column] = Box::leak(columns.into_boxed_slice());
+            let table_name: &'static str = Box::leak(self.table_name.clone().into_boxed_str());
+            Box::leak(Box::new(Schema {
+                table_name,
+                type_name: table_name,
+                columns,
+                fingerprint: fingerprint(columns),
+                audited: false,
+                strict: false,
+                without_rowid: false,
+                triggers: &[],
+                view: None,
+            }))
+        })
+    }
+}
+
+// Rows are read and written right away, bypassing the object cache, hooks
+// and observers of the transaction.
+pub struct DynamicTable<'t, 'a> {
+    tx: &'t Transaction<'a>,
+    schema: &'static Schema,
+}
+
+impl<'t, 'a> DynamicTable<'t, 'a> {
+    pub(crate) fn new(tx: &'t Transaction<'a>, schema: &'static Schema) -> Self {
+        Self { tx, schema }
+    }
+
+    pub fn schema(&self) -> &'static Schema {
+        self.schema
+    }
+
+    // Values are in column order.
+    pub fn create(&self, values: Row<'static>) -> Result<ObjectId> {
+        self.tx.ensure_writable_table(self.schema)?;
+        check_values(self.schema, &values)?;
+        let id = self.tx.storage().insert_row(self.schema, &values)?;
+        self.tx.record_changes(1);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: ObjectId) -> Result<DynamicRow> {
+        Ok(DynamicRow {
+            schema: self.schema,
+            id,
+            values: self.tx.storage().select_row(id, self.schema)?,
+        })
+    }
+
+    pub fn select(&self, predicate: &Predicate) -> Result<Vec<DynamicRow>> {
+        predicate.check_columns(self.schema)?;
+        let (where_sql, params) = predicate.to_sql();
+        Ok(self
+            .tx
+            .storage()
+            .select_rows(self.schema, &where_sql, &params)?
+            .into_iter()
+            .map(|(id, values)| DynamicRow {
+                schema: self.schema,
+                id,
+                values,
+            })
+            .collect())
+    }
+
+    pub fn update(&self, id: ObjectId, values: Row<'static>) -> Result<()> {
+        self.tx.ensure_writable_table(self.schema)?;
+        check_values(self.schema, &values)?;
+        if self.tx.storage().update_row(id, self.schema, &values)? == 0 {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                self.schema.type_name,
+            ))));
+        }
+        self.tx.record_changes(1);
+        Ok(())
+    }
+
+    pub fn delete(&self, id: ObjectId) -> Result<()> {
+        self.tx.ensure_writable_table(self.schema)?;
+        self.tx.storage().delete_row(id, self.schema)?;
+        self.tx.record_changes(1);
+        Ok(())
+    }
+}
+
+pub(crate) fn check_values(schema: &Schema, values: &RowSlice) -> Result<()> {
+    if values.len() != schema.columns.len() {
+        return Err(Error::Storage(
+            format!(
+                "{} expects {} values, got {}",
+                schema.type_name,
+                schema.columns.len(),
+                values.len()
+            )
+            .into(),
+        ));
+    }
+    for (column, value) in schema.columns.iter().zip(values) {
+        if value.data_type() != column.typ {
+            return Err(Error::UnexpectedType(Box::new(UnexpectedTypeError::new(
+                schema.type_name,
+                column.attr_name,
+                schema.table_name,
+                column.column_name,
+                column.typ,
+                format!("{:?}", value.data_type()),
+            ))));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+#![forbid(unsafe_code)]
+use crate::{
+    codec::{with_codec, ColumnCodec},
+    data::{DataType, Value},
+    error::{Error, Result},
+    memory::default_value,
+    object::{Column, Schema},
+    storage::StorageTransaction,
+    ObjectId,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub type Key = [u8; 32];
+
+// Where the AES-256-GCM keys of `#[encrypted]` columns come from, see
+// Database::set_key_provider. Every value is stored with the id of the key
+// it was sealed with, so that keys can be rotated: new values take the
+// current key, and older ones are opened with the key of their id.
+pub trait KeyProvider: Send + Sync {
+    fn current_key(&self) -> Result<(u32, Key)>;
+
+    fn key(&self, id: u32) -> Result<Key>;
+}
+
+pub type SharedKeyProvider = Arc<dyn KeyProvider>;
+
+// A single key, with id 0.
+impl KeyProvider for Key {
+    fn current_key(&self) -> Result<(u32, Key)> {
+        Ok((0, *self))
+    }
+
+    fn key(&self, id: u32) -> Result<Key> {
+        match id {
+            0 => Ok(*self),
+            _ => Err(Error::Storage(format!("no encryption key {}", id).into())),
+        }
+    }
+}
+
+// What the derive puts in the columns marked `#[encrypted]`, so that they
+// fail to compile without the `encryption` feature instead of being stored
+// in the clear.
+pub const ENABLED: bool = true;
+
+// Every transaction of a connection goes through this, with or without a
+// key provider, so that an encrypted column is never written without one.
+pub(crate) fn encrypting<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+    provider: Option<SharedKeyProvider>,
+) -> Box<dyn StorageTransaction + 'a> {
+    with_codec(
+        inner,
+        Encryption {
+            provider,
+            current: OnceCell::new(),
+            keys: RefCell::default(),
+        },
+    )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A value is sealed as the id of its key, big-endian, the nonce and the
+// ciphertext with its tag. The table and column name and the id of the row
+// are the associated data, so a value copied into another column or row
+// does not open; renaming an encrypted column or its table, or giving a row
+// another id, leaves its values unreadable as well.
+struct Encryption {
+    provider: Option<SharedKeyProvider>,
+    current: OnceCell<u32>,
+    keys: RefCell<HashMap<u32, LessSafeKey>>,
+}
+
+impl Encryption {
+    fn provider(&self) -> &dyn KeyProvider {
+        self.provider.as_deref().unwrap()
+    }
+
+    fn with_key<R>(&self, id: u32, f: impl FnOnce(&LessSafeKey) -> R) -> Result<R> {
+        let mut keys = self.keys.borrow_mut();
+        let key = match keys.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(less_safe_key(&self.provider().key(id)?)),
+        };
+        Ok(f(key))
+    }
+
+    // The current key is asked for once per transaction.
+    fn current_key(&self) -> Result<u32> {
+        if let Some(id) = self.current.get() {
+            return Ok(*id);
+        }
+        let (id, key) = self.provider().current_key()?;
+        self.keys.borrow_mut().insert(id, less_safe_key(&key));
+        Ok(*self.current.get_or_init(|| id))
+    }
+}
+
+impl ColumnCodec for Encryption {
+    fn handles(&self, column: &Column) -> bool {
+        column.encrypted
+    }
+
+    fn binds_id(&self) -> bool {
+        true
+    }
+
+    fn stored(&self, schema: &Schema) -> Result<&'static Schema> {
+        if self.provider.is_none() {
+            return Err(Error::Storage(
+                format!(
+                    "{} has encrypted columns, but no key provider is set",
+                    schema.table_name
+                )
+                .into(),
+            ));
+        }
+        Ok(schema.stored_encrypted())
+    }
+
+    fn encode(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        row: Option<ObjectId>,
+        value: &Value,
+    ) -> Result<Value<'static>> {
+        let row = row.ok_or_else(|| encryption_error("encrypt", schema, column))?;
+        let id = self.current_key()?;
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| Error::Storage("cannot generate a nonce".into()))?;
+        let mut sealed = to_plaintext(value);
+        self.with_key(id, |key| {
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(associated_data(schema, column, row)),
+                &mut sealed,
+            )
+        })?
+        .map_err(|_| encryption_error("encrypt", schema, column))?;
+        let mut stored = Vec::with_capacity(4 + NONCE_LEN + sealed.len());
+        stored.extend_from_slice(&id.to_be_bytes());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&sealed);
+        Ok(Value::from(stored))
+    }
+
+    // The placeholders read for lazy columns not loaded yet are empty, and
+    // read as zero. Any other value must open.
+    fn decode(
+        &self,
+        schema: &Schema,
+        column: &Column,
+        row: ObjectId,
+        value: &Value,
+    ) -> Result<Value<'static>> {
+        let stored = match value {
+            Value::Bytes(bytes) if bytes.is_empty() && column.lazy => {
+                return Ok(default_value(column.typ))
+            }
+            Value::Bytes(bytes) if bytes.len() >= 4 + NONCE_LEN => bytes,
+            _ => return Err(encryption_error("decrypt", schema, column)),
+        };
+        let id = u32::from_be_bytes(stored[..4].try_into().unwrap());
+        let nonce = Nonce::try_assume_unique_for_key(&stored[4..4 + NONCE_LEN]).unwrap();
+        let mut sealed = stored[4 + NONCE_LEN..].to_vec();
+        let plaintext = self.with_key(id, |key| {
+            key.open_in_place(
+                nonce,
+                Aad::from(associated_data(schema, column, row)),
+                &mut sealed,
+            )
+            .map(|plaintext| from_plaintext(column.typ, plaintext))
+        })?;
+        plaintext
+            .ok()
+            .flatten()
+            .ok_or_else(|| encryption_error("decrypt", schema, column))
+    }
+}
+
+fn less_safe_key(key: &Key) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap())
+}
+
+fn associated_data(schema: &Schema, column: &Column, row: ObjectId) -> Vec<u8> {
+    format!(
+        "{}.{}#{}",
+        schema.table_name,
+        column.column_name,
+        row.into_i64()
+    )
+    .into_bytes()
+}
+
+fn to_plaintext(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(s) => s.as_bytes().to_vec(),
+        Value::Bytes(b) => b.to_vec(),
+        Value::Int64(i) => i.to_le_bytes().to_vec(),
+        Value::Float64(f) => f.to_le_bytes().to_vec(),
+        Value::Bool(b) => vec![*b as u8],
+    }
+}
+
+fn from_plaintext(typ: DataType, plaintext: &[u8]) -> Option<Value<'static>> {
+    match typ {
+        DataType::String => String::from_utf8(plaintext.to_vec()).ok().map(Value::from),
+        DataType::Bytes => Some(Value::from(plaintext.to_vec())),
+        DataType::Int64 => plaintext
+            .try_into()
+            .ok()
+            .map(i64::from_le_bytes)
+            .map(Value::from),
+        DataType::Float64 => plaintext
+            .try_into()
+            .ok()
+            .map(f64::from_le_bytes)
+            .map(Value::from),
+        DataType::Bool => match plaintext {
+            [b] => Some(Value::from(*b != 0)),
+            _ => None,
+        },
+    }
+}
+
+fn encryption_error(action: &str, schema: &Schema, column: &Column) -> Error {
+    Error::Storage(
+        format!(
+            "cannot {} {}.{}",
+            action, schema.table_name, column.column_name
+        )
+        .into(),
+    )
+}
@@ -1,9 +1,17 @@
 #![forbid(unsafe_code)]
 
-use crate::Error::{LockConflict, MissingColumn, Storage};
+use crate::Error::MissingColumn;
+#[cfg(feature = "sqlite")]
+use crate::Error::{Constraint, LockConflict, Locked, ReadOnly, Storage};
 use crate::{data::DataType, object::Schema, ObjectId};
-use rusqlite::Error::SqliteFailure;
-use rusqlite::ErrorCode::DatabaseBusy;
+#[cfg(feature = "sqlite")]
+use rusqlite::{
+    Error::SqliteFailure,
+    ErrorCode::{ConstraintViolation, DatabaseBusy, DatabaseLocked, ReadOnly as SqliteReadOnly},
+};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
 use thiserror::Error;
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -15,14 +23,175 @@ pub enum Error {
     UnexpectedType(Box<UnexpectedTypeError>),
     #[error(transparent)]
     MissingColumn(Box<MissingColumnError>),
+    #[error(transparent)]
+    Deserialize(Box<DeserializeError>),
+    #[error(transparent)]
+    SchemaFingerprintMismatch(Box<SchemaFingerprintMismatchError>),
+    #[error(transparent)]
+    SchemaMismatch(Box<SchemaMismatchError>),
+    #[error(transparent)]
+    Conflict(Box<ConflictError>),
+    #[error(transparent)]
+    Borrowed(Box<BorrowedError>),
+    #[error(transparent)]
+    Constraint(Box<ConstraintError>),
+    // SQLITE_BUSY: another connection holds a lock on the database.
     #[error("database is locked")]
     LockConflict,
+    #[error(transparent)]
+    Locked(Box<LockedError>),
+    #[error("database is opened read-only")]
+    ReadOnly,
+    // A statement was interrupted at the deadline of the transaction.
+    #[error("transaction deadline has passed")]
+    Timeout,
     #[error("storage error: {0}")]
-    Storage(#[source] Box<dyn std::error::Error>),
+    Storage(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+// Categories of errors that stay put when variants are added. The codes are
+// meant for logs and APIs and never change.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    NotFound,
+    Conflict,
+    Borrowed,
+    Constraint,
+    Locked,
+    ReadOnly,
+    Schema,
+    Io,
+    Timeout,
+    Storage,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Conflict => "conflict",
+            Self::Borrowed => "borrowed",
+            Self::Constraint => "constraint",
+            Self::Locked => "locked",
+            Self::ReadOnly => "read_only",
+            Self::Schema => "schema",
+            Self::Io => "io",
+            Self::Timeout => "timeout",
+            Self::Storage => "storage",
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::Conflict(_) => ErrorKind::Conflict,
+            Error::Borrowed(_) => ErrorKind::Borrowed,
+            Error::Constraint(_) => ErrorKind::Constraint,
+            Error::LockConflict | Error::Locked(_) => ErrorKind::Locked,
+            Error::ReadOnly => ErrorKind::ReadOnly,
+            Error::Timeout => ErrorKind::Timeout,
+            Error::UnexpectedType(_)
+            | Error::MissingColumn(_)
+            | Error::Deserialize(_)
+            | Error::SchemaFingerprintMismatch(_)
+            | Error::SchemaMismatch(_) => ErrorKind::Schema,
+            Error::Storage(source) if is_io(source.as_ref()) => ErrorKind::Io,
+            Error::Storage(_) => ErrorKind::Storage,
+        }
+    }
+
+    // What Database::transaction waits out: a lock held by another connection.
+    pub(crate) fn is_lock_wait(&self) -> bool {
+        match self {
+            Error::LockConflict => true,
+            Error::Locked(err) => err.is_shared_cache(),
+            _ => false,
+        }
+    }
+
+    // Whether running the transaction again may succeed: locks held by other
+    // connections, rows changed concurrently and interrupted statements.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::LockConflict | Error::Conflict(_) => true,
+            Error::Locked(err) => err.is_shared_cache(),
+            Error::Storage(source) => is_transient(source.as_ref()),
+            _ => false,
+        }
+    }
+
+    // Where the error was built, with the `backtrace` feature. Captured as
+    // std::backtrace::Backtrace::capture does, so it is only filled in when
+    // RUST_BACKTRACE or RUST_LIB_BACKTRACE is set. Storage errors have one if
+    // they come from a failed statement; LockConflict and ReadOnly have none.
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match self {
+            Error::NotFound(err) => Some(&err.trace),
+            Error::UnexpectedType(err) => Some(&err.trace),
+            Error::MissingColumn(err) => Some(&err.trace),
+            Error::Deserialize(err) => Some(&err.trace),
+            Error::SchemaFingerprintMismatch(err) => Some(&err.trace),
+            Error::SchemaMismatch(err) => Some(&err.trace),
+            Error::Conflict(err) => Some(&err.trace),
+            Error::Borrowed(err) => Some(&err.trace),
+            Error::Constraint(err) => Some(&err.trace),
+            Error::Locked(err) => Some(&err.trace),
+            Error::Storage(source) => source
+                .downcast_ref::<StatementError>()
+                .map(|err| &err.trace),
+            Error::LockConflict | Error::ReadOnly | Error::Timeout => None,
+        }
+    }
 }
 
+fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    use std::io::ErrorKind::{Interrupted, TimedOut, WouldBlock};
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return matches!(err.kind(), Interrupted | TimedOut | WouldBlock);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(SqliteFailure(err, _)) = err.downcast_ref::<rusqlite::Error>() {
+        return err.code == rusqlite::ErrorCode::OperationInterrupted;
+    }
+    match err.downcast_ref::<StatementError>() {
+        Some(err) => is_transient(err.source.as_ref()),
+        None => false,
+    }
+}
+
+fn is_io(err: &(dyn std::error::Error + 'static)) -> bool {
+    if err.is::<std::io::Error>() {
+        return true;
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(SqliteFailure(err, _)) = err.downcast_ref::<rusqlite::Error>() {
+        use rusqlite::ErrorCode::{CannotOpen, DiskFull, SystemIoFailure};
+        return matches!(err.code, CannotOpen | DiskFull | SystemIoFailure);
+    }
+    match err.downcast_ref::<StatementError>() {
+        Some(err) => is_io(err.source.as_ref()),
+        None => false,
+    }
+}
+
+// thiserror takes fields of type Backtrace for a provider that needs nightly.
+#[cfg(feature = "backtrace")]
+type Trace = Backtrace;
+
+// Errors cross threads in the async and threaded backends and are boxed as
+// `dyn Error + Send + Sync` by applications, so every variant has to stay so.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<Error>();
+};
+
 ////////////////////////////////////////////////////////////////////////////////
 
+#[cfg(feature = "sqlite")]
 #[derive(Debug)]
 pub struct ErrorWithCtx<T>
 where
@@ -31,6 +200,7 @@ where
     inner: T,
 }
 
+#[cfg(feature = "sqlite")]
 impl From<rusqlite::Error> for ErrorWithCtx<rusqlite::Error> {
     fn from(err: rusqlite::Error) -> Self {
         ErrorWithCtx { inner: err }
@@ -38,16 +208,27 @@ impl From<rusqlite::Error> for ErrorWithCtx<rusqlite::Error> {
 }
 
 ////////////////////////////////////////////////////////////////////////////////
+#[cfg(feature = "sqlite")]
 impl From<ErrorWithCtx<rusqlite::Error>> for Error {
     fn from(err: ErrorWithCtx<rusqlite::Error>) -> Self {
         match err.inner {
-            SqliteFailure(err, _) if err.code != DatabaseBusy => Storage(Box::new(err)),
-            SqliteFailure(_, _) => LockConflict,
+            SqliteFailure(err, _) if err.code == SqliteReadOnly => ReadOnly,
+            SqliteFailure(err, _) if err.code == DatabaseBusy => LockConflict,
+            SqliteFailure(err, message) if err.code == DatabaseLocked => {
+                let message = message.unwrap_or_else(|| err.to_string());
+                Locked(Box::new(LockedError::new(err.extended_code, message)))
+            }
+            SqliteFailure(err, message) if err.code == ConstraintViolation => {
+                let kind = ConstraintKind::from_sqlite(err.extended_code);
+                let message = message.unwrap_or_else(|| err.to_string());
+                Constraint(Box::new(ConstraintError::from_sqlite(kind, message)))
+            }
             err => Storage(Box::new(err)),
         }
     }
 }
 
+#[cfg(feature = "sqlite")]
 impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Self {
         Self::from(ErrorWithCtx::from(err))
@@ -56,21 +237,193 @@ impl From<rusqlite::Error> for Error {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+// A storage error together with the statement that caused it. Parameters are
+// only listed by type unless DatabaseOptions::log_parameter_values is set.
+#[derive(Error, Debug)]
+#[error("{source} (statement: {sql}; parameters: [{}])", .params.join(", "))]
+pub struct StatementError {
+    pub sql: String,
+    pub params: Vec<String>,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl StatementError {
+    pub fn new(
+        sql: String,
+        params: Vec<String>,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    ) -> Self {
+        Self {
+            sql,
+            params,
+            source,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 #[derive(Error, Debug)]
 #[error("object is not found: type '{type_name}', id {object_id}")]
 pub struct NotFoundError {
     pub object_id: ObjectId,
-    pub type_name: &'static str,
+    pub type_name: Cow<'static, str>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
 }
 
 impl NotFoundError {
+    pub fn new(object_id: ObjectId, type_name: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            object_id,
+            type_name: type_name.into(),
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("object is already borrowed: type '{type_name}', id {object_id}")]
+pub struct BorrowedError {
+    pub object_id: ObjectId,
+    pub type_name: &'static str,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl BorrowedError {
     pub fn new(object_id: ObjectId, type_name: &'static str) -> Self {
         Self {
             object_id,
             type_name,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConstraintKind {
+    ForeignKey,
+    Unique,
+    PrimaryKey,
+    NotNull,
+    Check,
+    Other,
+}
+
+impl ConstraintKind {
+    // The extended result codes, which libsqlite3-sys does not export.
+    #[cfg(feature = "sqlite")]
+    fn from_sqlite(extended_code: i32) -> Self {
+        match extended_code >> 8 {
+            3 => Self::ForeignKey,
+            8 => Self::Unique,
+            6 | 10 => Self::PrimaryKey,
+            5 => Self::NotNull,
+            1 => Self::Check,
+            _ => Self::Other,
+        }
+    }
+}
+
+// SQLITE_LOCKED, which unlike SQLITE_BUSY is mostly a conflict within the
+// same connection, e.g. dropping a table that a statement still reads, and
+// is not solved by waiting. A table lock of a shared-cache database, held by
+// another connection, is the exception.
+#[derive(Error, Debug)]
+#[error("database table is locked: {message}")]
+pub struct LockedError {
+    pub extended_code: i32,
+    pub message: String,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl LockedError {
+    // SQLITE_LOCKED_SHAREDCACHE
+    const SHARED_CACHE: i32 = 6 | (1 << 8);
+
+    pub fn new(extended_code: i32, message: String) -> Self {
+        Self {
+            extended_code,
+            message,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+
+    pub fn is_shared_cache(&self) -> bool {
+        self.extended_code == Self::SHARED_CACHE
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The message is the one reported by the backend. The table and columns are
+// known for UNIQUE, PRIMARY KEY and NOT NULL failures, but not for foreign
+// keys, whose violations SQLite does not attribute. `existing` is the row
+// that already holds the values, see Transaction::set_conflict_lookup.
+#[derive(Error, Debug)]
+#[error("constraint violated ({kind:?}): {message}")]
+pub struct ConstraintError {
+    pub kind: ConstraintKind,
+    pub message: String,
+    pub table: Option<String>,
+    pub columns: Vec<String>,
+    pub existing: Option<ObjectId>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl ConstraintError {
+    pub fn new(kind: ConstraintKind, message: String) -> Self {
+        Self {
+            kind,
+            message,
+            table: None,
+            columns: Vec::new(),
+            existing: None,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+
+    pub fn with_location(mut self, table: Option<String>, columns: Vec<String>) -> Self {
+        self.table = table;
+        self.columns = columns;
+        self
+    }
+
+    pub fn column(&self) -> Option<&str> {
+        self.columns.first().map(String::as_str)
+    }
+
+    // "UNIQUE constraint failed: User.name, User.email" and the like.
+    #[cfg(feature = "sqlite")]
+    fn from_sqlite(kind: ConstraintKind, message: String) -> Self {
+        let mut table = None;
+        let mut columns = Vec::new();
+        if let Some((_, names)) = message.split_once("constraint failed: ") {
+            for name in names.split(", ") {
+                if let Some((t, c)) = name.rsplit_once('.') {
+                    table = Some(t.to_string());
+                    columns.push(c.to_string());
+                }
+            }
         }
+        Self::new(kind, message).with_location(table, columns)
     }
 }
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Error, Debug)]
@@ -85,6 +438,8 @@ pub struct UnexpectedTypeError {
     pub column_name: &'static str,
     pub expected_type: DataType,
     pub got_type: String,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
 }
 
 impl UnexpectedTypeError {
@@ -103,36 +458,84 @@ impl UnexpectedTypeError {
             column_name,
             expected_type,
             got_type,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
         }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+// A row that does not fit the object, e.g. one that is too short or holds a
+// value of another type. `got` is None for a missing value.
 #[derive(Error, Debug)]
 #[error(
-    "missing a column for {type_name}::{attr_name} \
-    (table: {table_name}, column: {column_name})"
+    "cannot deserialize {type_name}::{attr_name}: expected {expected:?}, got {}",
+    got.map_or("no value".to_string(), |got| format!("{:?}", got))
 )]
-pub struct MissingColumnError {
+pub struct DeserializeError {
     pub type_name: &'static str,
     pub attr_name: &'static str,
-    pub table_name: &'static str,
-    pub column_name: &'static str,
+    pub expected: DataType,
+    pub got: Option<DataType>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
 }
 
-impl MissingColumnError {
+impl DeserializeError {
     pub fn new(
         type_name: &'static str,
         attr_name: &'static str,
-        table_name: &'static str,
-        column_name: &'static str,
+        expected: DataType,
+        got: Option<DataType>,
     ) -> Self {
         Self {
             type_name,
             attr_name,
-            table_name,
-            column_name,
+            expected,
+            got,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+
+impl From<DeserializeError> for Error {
+    fn from(err: DeserializeError) -> Self {
+        Error::Deserialize(Box::new(err))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "missing a column for {type_name}::{attr_name} \
+    (table: {table_name}, column: {column_name})"
+)]
+pub struct MissingColumnError {
+    pub type_name: Cow<'static, str>,
+    pub attr_name: Cow<'static, str>,
+    pub table_name: Cow<'static, str>,
+    pub column_name: Cow<'static, str>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl MissingColumnError {
+    pub fn new(
+        type_name: impl Into<Cow<'static, str>>,
+        attr_name: impl Into<Cow<'static, str>>,
+        table_name: impl Into<Cow<'static, str>>,
+        column_name: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        Self {
+            type_name: type_name.into(),
+            attr_name: attr_name.into(),
+            table_name: table_name.into(),
+            column_name: column_name.into(),
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
         }
     }
 
@@ -147,25 +550,28 @@ impl MissingColumnError {
             _ => return None,
         };
 
-        let name = err_text[omit..].trim();
+        Self::from_column_name(err_text[omit..].trim(), schema)
+    }
+
+    pub fn from_column_name(name: &str, schema: &Schema) -> Option<crate::Error> {
         schema
             .columns
             .iter()
             .find(|info| info.column_name == name)
             .map(|info| {
                 MissingColumn(Box::new(MissingColumnError::new(
-                    <&str>::clone(&schema.type_name),
-                    <&str>::clone(&info.attr_name),
-                    <&str>::clone(&schema.table_name),
-                    <&str>::clone(&info.column_name),
+                    schema.type_name,
+                    info.attr_name,
+                    schema.table_name,
+                    info.column_name,
                 )))
             })
             .or_else(|| {
                 if name == "id" {
                     Some(MissingColumn(Box::new(MissingColumnError::new(
-                        <&str>::clone(&schema.type_name),
+                        schema.type_name,
                         "id",
-                        <&str>::clone(&schema.table_name),
+                        schema.table_name,
                         "id",
                     ))))
                 } else {
@@ -175,5 +581,146 @@ impl MissingColumnError {
     }
 }
 
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "schema of {type_name} does not match table {table_name}: \
+    expected fingerprint {expected:016x}, found {found:016x}"
+)]
+pub struct SchemaFingerprintMismatchError {
+    pub type_name: &'static str,
+    pub table_name: &'static str,
+    pub expected: u64,
+    pub found: u64,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl SchemaFingerprintMismatchError {
+    pub fn new(
+        type_name: &'static str,
+        table_name: &'static str,
+        expected: u64,
+        found: u64,
+    ) -> Self {
+        Self {
+            type_name,
+            table_name,
+            expected,
+            found,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "schema of {type_name} does not match table {table_name}: {}",
+    list_mismatches(.mismatches)
+)]
+pub struct SchemaMismatchError {
+    pub type_name: &'static str,
+    pub table_name: &'static str,
+    pub mismatches: Vec<Mismatch>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl SchemaMismatchError {
+    pub fn new(
+        type_name: &'static str,
+        table_name: &'static str,
+        mismatches: Vec<Mismatch>,
+    ) -> Self {
+        Self {
+            type_name,
+            table_name,
+            mismatches,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Mismatch {
+    MissingTable,
+    MissingColumn {
+        column: String,
+    },
+    // The table has a column the type does not declare.
+    ExtraColumn {
+        column: String,
+    },
+    Type {
+        column: String,
+        expected: DataType,
+        found: DataType,
+    },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingTable => write!(f, "table does not exist"),
+            Self::MissingColumn { column } => write!(f, "column {} is missing", column),
+            Self::ExtraColumn { column } => write!(f, "column {} is not declared", column),
+            Self::Type {
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "column {} has type {:?}, expected {:?}",
+                column, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+fn list_mismatches(mismatches: &[Mismatch]) -> String {
+    mismatches
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("objects were changed concurrently: {}", list_objects(.objects))]
+pub struct ConflictError {
+    // Type name and id of every object whose row no longer matches the one
+    // that was loaded.
+    pub objects: Vec<(&'static str, ObjectId)>,
+    #[cfg(feature = "backtrace")]
+    trace: Trace,
+}
+
+impl ConflictError {
+    pub fn new(objects: Vec<(&'static str, ObjectId)>) -> Self {
+        Self {
+            objects,
+            #[cfg(feature = "backtrace")]
+            trace: Backtrace::capture(),
+        }
+    }
+}
+
+fn list_objects(objects: &[(&'static str, ObjectId)]) -> String {
+    objects
+        .iter()
+        .map(|(type_name, id)| format!("{} {}", type_name, id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,69 @@
+#![forbid(unsafe_code)]
+use crate::{
+    dynamic::registered,
+    error::{Error, Result},
+    json::to_row,
+    Database, ObjectId, Transaction,
+};
+use serde_json::Value as Json;
+use std::{collections::HashMap, path::Path};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A fixture file is a JSON object keyed by table name, loaded in file order.
+// A table holds a list of rows or an object of named rows; a row maps column
+// names to values, and omitted columns get the zero value of their type.
+// Tables must be registered with dynamic::register. Returns the ids of the
+// named rows.
+impl<'a> Transaction<'a> {
+    pub fn load_fixtures<P: AsRef<Path>>(&self, path: P) -> Result<HashMap<String, ObjectId>> {
+        let json = std::fs::read_to_string(path).map_err(|err| Error::Storage(Box::new(err)))?;
+        self.load_fixtures_str(&json)
+    }
+
+    pub fn load_fixtures_str(&self, json: &str) -> Result<HashMap<String, ObjectId>> {
+        let tables: serde_json::Map<String, Json> =
+            serde_json::from_str(json).map_err(|err| Error::Storage(Box::new(err)))?;
+
+        let mut ids = HashMap::new();
+        for (table_name, rows) in &tables {
+            let schema = registered(table_name)
+                .ok_or_else(|| invalid(format!("table {} is not registered", table_name)))?;
+            let rows: Vec<_> = match rows {
+                Json::Array(rows) => rows.iter().map(|row| (None, row)).collect(),
+                Json::Object(rows) => rows
+                    .iter()
+                    .map(|(name, row)| (Some(name.as_str()), row))
+                    .collect(),
+                _ => {
+                    return Err(invalid(format!(
+                        "rows of {} must be a list or an object",
+                        table_name
+                    )))
+                }
+            };
+
+            for (name, row) in rows {
+                let row = to_row(schema, row, &[]).map_err(invalid)?;
+                let id = self.create_dynamic(table_name, row)?;
+                if let Some(name) = name {
+                    if ids.insert(name.to_string(), id).is_some() {
+                        return Err(invalid(format!("fixture {} is defined twice", name)));
+                    }
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
+
+impl Database {
+    pub fn load_fixtures<P: AsRef<Path>>(&mut self, path: P) -> Result<HashMap<String, ObjectId>> {
+        let path = path.as_ref();
+        self.transaction(|tx| tx.load_fixtures(path))
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::Storage(format!("invalid fixtures: {}", message).into())
+}
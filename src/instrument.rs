@@ -0,0 +1,100 @@
+#![forbid(unsafe_code)]
+use crate::data::Value;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Operation {
+    Prepare,
+    Execute,
+    Deserialize,
+}
+
+// Told how long every storage operation of a connection took, see
+// Connection::set_instrument. The target is the SQL of a prepare or execute,
+// and the type name of a deserialize. Backends that do not time their
+// statements only report Deserialize.
+pub trait Instrument: Send + Sync {
+    fn record(&self, operation: Operation, target: &str, elapsed: Duration);
+}
+
+pub type SharedInstrument = Arc<dyn Instrument>;
+
+// A statement that took at least the threshold of the slow query log of its
+// connection, see Connection::set_slow_query_log. Parameters are listed by
+// their SQLite type, or by value with DatabaseOptions::log_parameter_values.
+#[derive(Debug)]
+pub struct SlowQuery<'a> {
+    pub sql: &'a str,
+    pub params: Vec<String>,
+    pub elapsed: Duration,
+}
+
+pub type SlowQueryHook = Arc<dyn Fn(&SlowQuery) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct SlowQueryLog {
+    pub threshold: Duration,
+    pub hook: SlowQueryHook,
+}
+
+// Only looks at the clock when there is an instrument to tell.
+pub(crate) fn timed<R>(
+    instrument: Option<&dyn Instrument>,
+    operation: Operation,
+    target: &str,
+    f: impl FnOnce() -> R,
+) -> R {
+    match instrument {
+        Some(instrument) => {
+            let start = Instant::now();
+            let result = f();
+            instrument.record(operation, target, start.elapsed());
+            result
+        }
+        None => f(),
+    }
+}
+
+// A statement as it was run by its connection, with the values bound to it,
+// see Connection::set_sql_trace. Booleans are bound as Int64.
+#[derive(Debug)]
+pub struct SqlTrace<'a> {
+    pub sql: &'a str,
+    pub params: Vec<Value<'a>>,
+    pub elapsed: Duration,
+}
+
+pub type SqlTraceHook = Arc<dyn Fn(&SqlTrace) + Send + Sync>;
+
+// Adds up the time spent in a statement between the calls of a callback
+// that reads its rows, which is reported once the statement is done.
+#[cfg(feature = "sqlite")]
+pub(crate) struct Stopwatch(Option<Duration>);
+
+#[cfg(feature = "sqlite")]
+impl Stopwatch {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self(enabled.then_some(Duration::ZERO))
+    }
+
+    pub(crate) fn time<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        match &mut self.0 {
+            Some(total) => {
+                let start = Instant::now();
+                let result = f();
+                *total += start.elapsed();
+                result
+            }
+            None => f(),
+        }
+    }
+
+    pub(crate) fn elapsed(self) -> Option<Duration> {
+        self.0
+    }
+}
@@ -0,0 +1,225 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    dynamic::{registered, registered_create, registered_tables},
+    error::{Error, Result},
+    memory::default_value,
+    object::{Column, Object, Schema},
+    storage::{is_unsupported, Row, RowSlice},
+    Database, ObjectId, Transaction,
+};
+use serde_json::{json, Map, Value as Json};
+use std::io::{BufRead, Read, Write};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A table is exported as a JSON list with one object per row, keyed by column
+// name plus "id". Rows are written as they are read, so the table is never
+// held in memory. Bytes become a list of numbers; non-finite floats, which
+// JSON has no numbers for, become null and fail the import.
+impl<'a> Transaction<'a> {
+    pub fn export_json<T: Object>(&self, mut writer: impl Write) -> Result<usize> {
+        self.flush()?;
+        let mut rows = 0;
+        writer.write_all(b"[").map_err(io_error)?;
+        self.scan_stored::<T>(|id, row| {
+            let separator: &[u8] = if rows == 0 { b"\n" } else { b",\n" };
+            writer.write_all(separator).map_err(io_error)?;
+            serde_json::to_writer(&mut writer, &from_row(T::TABLE, id, row)).map_err(json_error)?;
+            rows += 1;
+            Ok(())
+        })?;
+        writer.write_all(b"\n]\n").map_err(io_error)?;
+        writer.flush().map_err(io_error)?;
+        Ok(rows)
+    }
+
+    // Rows with an "id" keep it, others get a new one; missing columns get
+    // the zero value of their type. Returns the number of objects created.
+    pub fn import_json<T: Object>(&self, reader: impl Read) -> Result<usize> {
+        let rows: Vec<Json> = serde_json::from_reader(reader).map_err(json_error)?;
+        for row in &rows {
+            let id = match row.get("id") {
+                Some(id) => Some(
+                    id.as_i64()
+                        .map(ObjectId::from)
+                        .ok_or_else(|| invalid(format!("{} is not an id", id)))?,
+                ),
+                None => None,
+            };
+            let row = to_row(T::TABLE, row, &["id"]).map_err(invalid)?;
+            let obj = T::deserialize(row)?;
+            match id {
+                Some(id) => self.create_with_id(id, obj)?,
+                None => self.create(obj)?,
+            };
+        }
+        Ok(rows.len())
+    }
+
+    // Every stored table of a type registered with dynamic::register, one
+    // line per row: {"table": ..., "id": ..., "values": {...}}, with the
+    // values keyed by column name. Tables are written in name order, and
+    // types backed by a view are left out. Returns the number of rows.
+    pub fn dump(&self, mut writer: impl Write) -> Result<usize> {
+        self.flush()?;
+        let mut rows = 0;
+        for table in registered_tables() {
+            let schema = match registered(table) {
+                Some(schema) if schema.view.is_none() => schema,
+                _ => continue,
+            };
+            if !self.storage().table_exists(schema.table_name)? {
+                continue;
+            }
+            self.storage()
+                .for_each_row_ref(schema.eager(), "", &[], &mut |id, row| {
+                    let values = to_values(schema, row);
+                    let record = json!({"table": table, "id": id.into_i64(), "values": values});
+                    serde_json::to_writer(&mut writer, &record).map_err(json_error)?;
+                    writer.write_all(b"\n").map_err(io_error)?;
+                    rows += 1;
+                    Ok(())
+                })?;
+        }
+        writer.flush().map_err(io_error)?;
+        Ok(rows)
+    }
+
+    // Creates the objects of a dump with their ids, as create_with_id would,
+    // checking foreign keys at commit where the backend can defer them.
+    // Columns missing from a record get the zero value of their type.
+    pub fn restore(&self, reader: impl BufRead) -> Result<usize> {
+        match self.defer_foreign_keys(true) {
+            Err(err) if !is_unsupported(&err) => return Err(err),
+            _ => {}
+        }
+        let mut rows = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(io_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Json = serde_json::from_str(&line).map_err(json_error)?;
+            let invalid_line = |message: String| invalid(format!("line {}: {}", i + 1, message));
+            let table = record["table"]
+                .as_str()
+                .ok_or_else(|| invalid_line("the record has no table".to_string()))?;
+            let id = record["id"]
+                .as_i64()
+                .map(ObjectId::from)
+                .ok_or_else(|| invalid_line(format!("{} is not an id", record["id"])))?;
+            let (schema, create) = registered_create(table)
+                .ok_or_else(|| invalid_line(format!("table {} is not registered", table)))?;
+            let row = to_row(schema, &record["values"], &[]).map_err(invalid_line)?;
+            create(self, Some(id), row)?;
+            rows += 1;
+        }
+        Ok(rows)
+    }
+}
+
+impl Database {
+    pub fn dump(&mut self, mut writer: impl Write) -> Result<usize> {
+        self.transaction(|tx| tx.dump(&mut writer))
+    }
+
+    pub fn restore(&mut self, reader: impl BufRead) -> Result<usize> {
+        let mut reader = Some(reader);
+        self.transaction(|tx| match reader.take() {
+            Some(reader) => tx.restore(reader),
+            None => Err(Error::Storage("a restore cannot be retried".into())),
+        })
+    }
+}
+
+fn from_row(schema: &Schema, id: ObjectId, row: &RowSlice) -> Json {
+    let mut fields = Map::with_capacity(row.len() + 1);
+    fields.insert("id".to_string(), Json::from(id.into_i64()));
+    fields.extend(to_values(schema, row));
+    Json::Object(fields)
+}
+
+fn to_values(schema: &Schema, row: &RowSlice) -> Map<String, Json> {
+    schema
+        .columns
+        .iter()
+        .zip(row)
+        .map(|(column, value)| (column.column_name.to_string(), to_json(value)))
+        .collect()
+}
+
+fn to_json(value: &Value) -> Json {
+    match value {
+        Value::String(s) => Json::from(s.as_ref()),
+        Value::Bytes(b) => Json::from(b.as_ref()),
+        Value::Int64(i) => Json::from(*i),
+        Value::Float64(f) => Json::from(*f),
+        Value::Bool(b) => Json::from(*b),
+    }
+}
+
+// Keys of the row that are neither columns nor listed in `extra` are an
+// error, as they are most likely a typo.
+pub(crate) fn to_row(
+    schema: &Schema,
+    row: &Json,
+    extra: &[&str],
+) -> std::result::Result<Row<'static>, String> {
+    let fields = row
+        .as_object()
+        .ok_or_else(|| format!("a row of {} must be an object", schema.table_name))?;
+    if let Some(unknown) = fields
+        .keys()
+        .find(|key| !extra.contains(&key.as_str()) && schema.column(key).is_none())
+    {
+        return Err(format!("{} has no column {}", schema.table_name, unknown));
+    }
+
+    schema
+        .columns
+        .iter()
+        .map(|column| match fields.get(column.column_name) {
+            Some(value) => to_value(schema, column, value),
+            None => Ok(default_value(column.typ)),
+        })
+        .collect()
+}
+
+fn to_value(
+    schema: &Schema,
+    column: &Column,
+    value: &Json,
+) -> std::result::Result<Value<'static>, String> {
+    let converted = match (column.typ, value) {
+        (DataType::String, Json::String(s)) => Some(Value::from(s.clone())),
+        (DataType::Bytes, Json::String(s)) => Some(Value::from(s.clone().into_bytes())),
+        (DataType::Bytes, Json::Array(bytes)) => bytes
+            .iter()
+            .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+            .collect::<Option<Vec<_>>>()
+            .map(Value::from),
+        (DataType::Int64, Json::Number(n)) => n.as_i64().map(Value::from),
+        (DataType::Float64, Json::Number(n)) => n.as_f64().map(Value::from),
+        (DataType::Bool, Json::Bool(b)) => Some(Value::from(*b)),
+        _ => None,
+    };
+    converted.ok_or_else(|| {
+        format!(
+            "{}.{} expects {:?}, got {}",
+            schema.table_name, column.column_name, column.typ, value
+        )
+    })
+}
+
+fn invalid(message: String) -> Error {
+    Error::Storage(format!("invalid JSON import: {}", message).into())
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::Storage(Box::new(err))
+}
+
+fn json_error(err: serde_json::Error) -> Error {
+    Error::Storage(Box::new(err))
+}
@@ -1,16 +1,90 @@
 #![forbid(unsafe_code)]
+// So that `::orm` paths, which the derive emits, resolve inside the crate.
+extern crate self as orm;
+
+#[cfg(feature = "archive")]
+mod archive;
+mod audit;
+#[cfg(any(feature = "encryption", feature = "compression"))]
+mod codec;
 mod connection;
-mod error;
+mod database;
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+#[cfg(feature = "fixtures")]
+mod fixtures;
+#[cfg(feature = "serde")]
+mod json;
+mod read;
+#[cfg(feature = "tracing")]
+mod trace;
 mod transaction;
 
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod codegen;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(feature = "csv")]
+pub mod csv;
 pub mod data;
+pub mod diff;
+pub mod dynamic;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod error;
+pub mod instrument;
+pub mod memory;
+pub mod metrics;
+pub mod migrations;
+pub mod mock;
 pub mod object;
+pub mod outbox;
+pub mod pool;
+pub mod predicate;
+pub mod prefix;
+pub mod prelude;
+pub mod repair;
+pub mod scope;
+#[cfg(feature = "spatial")]
+pub mod spatial;
 pub mod storage;
+pub mod sync;
+pub mod testing;
+pub mod threaded;
+
+#[cfg(feature = "duckdb")]
+pub mod duckdb;
+#[cfg(feature = "libsql")]
+pub mod libsql;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "redb")]
+pub mod redb;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "web")]
+pub mod web;
 
+pub use audit::HistoryEntry;
 pub use connection::Connection;
-pub use data::ObjectId;
-pub use error::{Error, Result};
-pub use object::Object;
-pub use transaction::{ObjectState, Transaction, Tx};
+pub use data::{Id, ObjectId};
+#[cfg(feature = "sqlite")]
+pub use database::Readers;
+pub use database::{AutoVacuum, Database, DatabaseOptions, JournalMode, RetryPolicy, Synchronous};
+pub use diff::diff;
+pub use error::{ConstraintKind, Error, ErrorKind, Mismatch, Result};
+pub use object::{schema_dump, Object};
+pub use pool::{Pool, PoolOptions};
+pub use predicate::{column, Predicate};
+pub use read::{ReadTransaction, ReadTx};
+pub use storage::{ChangeOp, CheckpointMode, OnConflict, TransactionBehavior};
+pub use transaction::{
+    ChangeEvent, ObjectState, PendingChange, PendingChanges, Savepoint, Transaction, Tx,
+};
 
+#[cfg(feature = "derive")]
 pub use orm_derive::Object;
@@ -0,0 +1,318 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{Error, LockedError, MissingColumnError, NotFoundError, Result, UnexpectedTypeError},
+    object::Schema,
+    storage::{OnConflict, Row, RowSlice, StorageConnection, StorageTransaction},
+    Connection, ObjectId,
+};
+use std::{borrow::Cow, cell::RefCell};
+use tokio::runtime::Runtime;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The libsql client is async; every call is driven to completion on a
+// private current-thread runtime so it fits the synchronous storage traits.
+pub struct LibsqlStorage {
+    runtime: Runtime,
+    _database: ::libsql::Database,
+    conn: ::libsql::Connection,
+}
+
+impl LibsqlStorage {
+    pub fn connect_remote(url: &str, auth_token: &str) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| Error::Storage(Box::new(err)))?;
+        let database = runtime.block_on(
+            ::libsql::Builder::new_remote(url.to_string(), auth_token.to_string()).build(),
+        )?;
+        let conn = database.connect()?;
+        Ok(Self {
+            runtime,
+            _database: database,
+            conn,
+        })
+    }
+}
+
+impl StorageConnection for LibsqlStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let inner = self.runtime.block_on(self.conn.transaction())?;
+        Ok(Box::new(LibsqlTransaction {
+            runtime: &self.runtime,
+            inner: RefCell::new(Some(inner)),
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        Ok(self.conn.total_changes() as usize)
+    }
+}
+
+impl Connection {
+    pub fn open_libsql(url: &str, auth_token: &str) -> Result<Self> {
+        Ok(Self::from_storage(LibsqlStorage::connect_remote(
+            url, auth_token,
+        )?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct LibsqlTransaction<'a> {
+    runtime: &'a Runtime,
+    inner: RefCell<Option<::libsql::Transaction>>,
+}
+
+impl<'a> LibsqlTransaction<'a> {
+    fn execute_sql(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        let inner = self.inner.borrow();
+        let tx = inner.as_ref().ok_or_else(finished)?;
+        Ok(self.runtime.block_on(tx.execute(sql, to_params(params)))? as usize)
+    }
+
+    fn query_sql(&self, sql: &str, params: &RowSlice) -> Result<Vec<::libsql::Row>> {
+        let inner = self.inner.borrow();
+        let tx = inner.as_ref().ok_or_else(finished)?;
+        Ok(self.runtime.block_on(async {
+            let mut rows = tx.query(sql, to_params(params)).await?;
+            let mut result = Vec::new();
+            while let Some(row) = rows.next().await? {
+                result.push(row);
+            }
+            Ok::<_, ::libsql::Error>(result)
+        })?)
+    }
+
+    fn last_insert_rowid(&self) -> Result<i64> {
+        let inner = self.inner.borrow();
+        Ok(inner.as_ref().ok_or_else(finished)?.last_insert_rowid())
+    }
+}
+
+impl<'a> StorageTransaction for LibsqlTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        let rows = self.query_sql(
+            "SELECT name FROM sqlite_master WHERE type IN ('table', 'view') AND name = ?",
+            &[table.into()],
+        )?;
+        Ok(!rows.is_empty())
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.execute_sql(&schema.create_text(), &[])?;
+        Ok(())
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.execute_sql(&schema.drop_text(), &[])?;
+        Ok(())
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes = self.execute_sql(&schema.delete_where_text(""), &[])?;
+        if self.table_exists("sqlite_sequence")? {
+            self.execute_sql(
+                "DELETE FROM sqlite_sequence WHERE name = ?",
+                &[schema.table_name.into()],
+            )?;
+        }
+        Ok(changes)
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        let changes = self
+            .execute_sql(schema.insert_text_with(on_conflict), row)
+            .map_err(|err| map_schema_error(err, schema))?;
+        if changes == 0 {
+            return Ok(None);
+        }
+        if schema.without_rowid {
+            let rows = self.query_sql(&schema.last_id_text(), &[])?;
+            let id = match rows.first() {
+                Some(row) => row.get::<i64>(0)?,
+                None => 0,
+            };
+            return Ok(Some(ObjectId::from(id)));
+        }
+        Ok(Some(ObjectId::from(self.last_insert_rowid()?)))
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        if schema.columns.is_empty() {
+            return Ok(0);
+        }
+        let mut params = row.to_vec();
+        params.push(Value::Int64(id.into_i64()));
+        self.execute_sql(schema.update_text(), &params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        if columns.is_empty() {
+            return Ok(0);
+        }
+        let mut args = values.to_vec();
+        args.extend_from_slice(params);
+        self.execute_sql(&schema.update_where_text(columns, where_sql), &args)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let rows = self
+            .query_sql(schema.select_text(), &[Value::Int64(id.into_i64())])
+            .map_err(|err| map_schema_error(err, schema))?;
+        match rows.first() {
+            Some(row) => read_row(row, schema, 0),
+            None => Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            )))),
+        }
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let rows = self
+            .query_sql(&schema.select_where_text(where_sql), params)
+            .map_err(|err| map_schema_error(err, schema))?;
+        for row in rows {
+            let id = row.get::<i64>(0)?;
+            f(ObjectId::from(id), read_row(&row, schema, 1)?)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let rows = self
+            .query_sql(&schema.count_text(where_sql), params)
+            .map_err(|err| map_schema_error(err, schema))?;
+        let count = match rows.first() {
+            Some(row) => row.get::<i64>(0)?,
+            None => 0,
+        };
+        Ok(count as usize)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        if self.execute_sql(schema.delete_text(), &[Value::Int64(id.into_i64())])? == 0 {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            ))));
+        }
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.execute_sql(&schema.delete_where_text(where_sql), params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.execute_sql(sql, params)
+    }
+
+    fn commit(&self) -> Result<()> {
+        let tx = self.inner.borrow_mut().take().ok_or_else(finished)?;
+        Ok(self.runtime.block_on(tx.commit())?)
+    }
+
+    fn rollback(&self) -> Result<()> {
+        let tx = self.inner.borrow_mut().take().ok_or_else(finished)?;
+        Ok(self.runtime.block_on(tx.rollback())?)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl From<::libsql::Error> for Error {
+    fn from(err: ::libsql::Error) -> Self {
+        match err {
+            // SQLITE_BUSY and SQLITE_LOCKED
+            ::libsql::Error::SqliteFailure(5, _) => Error::LockConflict,
+            ::libsql::Error::SqliteFailure(6, message) => {
+                Error::Locked(Box::new(LockedError::new(6, message)))
+            }
+            err => Error::Storage(Box::new(err)),
+        }
+    }
+}
+
+fn finished() -> Error {
+    Error::Storage("transaction is already finished".into())
+}
+
+fn map_schema_error(err: Error, schema: &Schema) -> Error {
+    let text = match &err {
+        Error::Storage(source) => source.to_string(),
+        _ => return err,
+    };
+    MissingColumnError::get_error_from_text(text.trim_end_matches('`'), schema).unwrap_or(err)
+}
+
+fn to_params(values: &RowSlice) -> Vec<::libsql::Value> {
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(s) => ::libsql::Value::Text(s.to_string()),
+            Value::Bytes(b) => ::libsql::Value::Blob(b.to_vec()),
+            Value::Int64(i) => ::libsql::Value::Integer(*i),
+            Value::Float64(f) => ::libsql::Value::Real(*f),
+            Value::Bool(b) => ::libsql::Value::Integer(*b as i64),
+        })
+        .collect()
+}
+
+fn read_row(row: &::libsql::Row, schema: &Schema, offset: usize) -> Result<Row<'static>> {
+    let mut line = Row::with_capacity(schema.columns.len());
+    for (i, column) in schema.columns.iter().enumerate() {
+        let value = row.get_value((i + offset) as i32)?;
+        let value = match (column.typ, value) {
+            (DataType::String, ::libsql::Value::Text(s)) => Value::String(Cow::Owned(s)),
+            (DataType::Bytes, ::libsql::Value::Blob(b)) => Value::Bytes(Cow::Owned(b)),
+            (DataType::Int64, ::libsql::Value::Integer(i)) => Value::Int64(i),
+            (DataType::Float64, ::libsql::Value::Real(f)) => Value::Float64(f),
+            (DataType::Float64, ::libsql::Value::Integer(i)) => Value::Float64(i as f64),
+            (DataType::Bool, ::libsql::Value::Integer(i)) => Value::Bool(i != 0),
+            (_, value) => {
+                let got_type = match value {
+                    ::libsql::Value::Null => "Null",
+                    ::libsql::Value::Integer(_) => "Integer",
+                    ::libsql::Value::Real(_) => "Real",
+                    ::libsql::Value::Text(_) => "Text",
+                    ::libsql::Value::Blob(_) => "Blob",
+                };
+                return Err(Error::UnexpectedType(Box::new(UnexpectedTypeError::new(
+                    schema.type_name,
+                    column.attr_name,
+                    schema.table_name,
+                    column.column_name,
+                    column.typ,
+                    got_type.to_string(),
+                ))));
+            }
+        };
+        line.push(value);
+    }
+    Ok(line)
+}
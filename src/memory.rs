@@ -0,0 +1,802 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{
+        ConstraintError, ConstraintKind, Error, MissingColumnError, NotFoundError, Result,
+        UnexpectedTypeError,
+    },
+    object::{Column, Schema},
+    predicate::CompareOp,
+    storage::{
+        unsupported, ColumnInfo, OnConflict, Row, RowSlice, StorageConnection, StorageTransaction,
+        TableInfo,
+    },
+    ObjectId,
+};
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: HashMap<String, MemoryTable>,
+    total_changes: usize,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageConnection for MemoryStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(MemoryTransaction {
+            tables: RefCell::new(self.tables.clone()),
+            changes: Cell::new(0),
+            savepoints: RefCell::default(),
+            storage: RefCell::new(Some(self)),
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        Ok(self.total_changes)
+    }
+}
+
+#[derive(Clone)]
+struct MemoryTable {
+    columns: Vec<(String, DataType)>,
+    rows: BTreeMap<i64, Row<'static>>,
+    next_id: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Works on a snapshot of the tables; commit swaps it in, rollback drops it.
+struct MemoryTransaction<'a> {
+    tables: RefCell<HashMap<String, MemoryTable>>,
+    changes: Cell<usize>,
+    savepoints: RefCell<Vec<Savepoint>>,
+    storage: RefCell<Option<&'a mut MemoryStorage>>,
+}
+
+struct Savepoint {
+    name: String,
+    tables: HashMap<String, MemoryTable>,
+    changes: usize,
+}
+
+impl<'a> MemoryTransaction<'a> {
+    fn with_table<R>(
+        &self,
+        schema: &Schema,
+        f: impl FnOnce(&mut MemoryTable, &[usize]) -> Result<R>,
+    ) -> Result<R> {
+        let mut tables = self.tables.borrow_mut();
+        let table = tables
+            .get_mut(schema.table_name)
+            .ok_or_else(|| no_such_table(schema.table_name))?;
+        let positions = positions(&table.columns, schema)?;
+        f(table, &positions)
+    }
+
+    fn record_changes(&self, changes: usize) -> usize {
+        self.changes.set(self.changes.get() + changes);
+        changes
+    }
+
+    fn finish(&self) -> Result<Option<&'a mut MemoryStorage>> {
+        match self.storage.borrow_mut().take() {
+            Some(storage) => Ok(Some(storage)),
+            None => Err(Error::Storage("transaction is already finished".into())),
+        }
+    }
+}
+
+impl<'a> StorageTransaction for MemoryTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        Ok(self.tables.borrow().contains_key(table))
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let mut tables = self.tables.borrow_mut();
+        if tables.contains_key(schema.table_name) {
+            return Err(Error::Storage(
+                format!("table {} already exists", schema.table_name).into(),
+            ));
+        }
+        let table = MemoryTable {
+            columns: schema
+                .columns
+                .iter()
+                .map(|c| (c.column_name.to_string(), c.typ))
+                .collect(),
+            rows: BTreeMap::new(),
+            next_id: 1,
+        };
+        tables.insert(schema.table_name.to_string(), table);
+        Ok(())
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        match self.tables.borrow_mut().remove(schema.table_name) {
+            Some(_) => Ok(()),
+            None => Err(no_such_table(schema.table_name)),
+        }
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<_> = self.tables.borrow().keys().cloned().collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        let tables = self.tables.borrow();
+        let stored = tables.get(table).ok_or_else(|| no_such_table(table))?;
+        let id = ColumnInfo {
+            name: "id".into(),
+            declared_type: None,
+            typ: Some(DataType::Int64),
+            not_null: true,
+            default: None,
+            primary_key: true,
+        };
+        let columns = stored.columns.iter().map(|(name, typ)| ColumnInfo {
+            name: name.clone(),
+            declared_type: None,
+            typ: Some(*typ),
+            not_null: true,
+            default: None,
+            primary_key: false,
+        });
+        Ok(TableInfo {
+            name: table.to_string(),
+            columns: std::iter::once(id).chain(columns).collect(),
+        })
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        let mut tables = self.tables.borrow_mut();
+        let table = tables
+            .get_mut(schema.table_name)
+            .ok_or_else(|| no_such_table(schema.table_name))?;
+        if table
+            .columns
+            .iter()
+            .any(|(name, _)| name == column.column_name)
+        {
+            return Err(Error::Storage(
+                format!("duplicate column name: {}", column.column_name).into(),
+            ));
+        }
+        table
+            .columns
+            .push((column.column_name.to_string(), column.typ));
+        for row in table.rows.values_mut() {
+            row.push(default_value(column.typ));
+        }
+        Ok(())
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        let mut tables = self.tables.borrow_mut();
+        if tables.contains_key(to) {
+            return Err(Error::Storage(
+                format!("table {} already exists", to).into(),
+            ));
+        }
+        let table = tables.remove(from).ok_or_else(|| no_such_table(from))?;
+        tables.insert(to.to_string(), table);
+        Ok(())
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        let mut tables = self.tables.borrow_mut();
+        let table = tables.get_mut(table).ok_or_else(|| no_such_table(table))?;
+        if table.columns.iter().any(|(name, _)| name == to) {
+            return Err(Error::Storage(
+                format!("duplicate column name: {}", to).into(),
+            ));
+        }
+        let column = table
+            .columns
+            .iter_mut()
+            .find(|(name, _)| name == from)
+            .ok_or_else(|| no_such_column(from))?;
+        column.0 = to.to_string();
+        Ok(())
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        let mut tables = self.tables.borrow_mut();
+        let table = tables.get_mut(table).ok_or_else(|| no_such_table(table))?;
+        let position = table
+            .columns
+            .iter()
+            .position(|(name, _)| name == column)
+            .ok_or_else(|| no_such_column(column))?;
+        table.columns.remove(position);
+        for row in table.rows.values_mut() {
+            row.remove(position);
+        }
+        Ok(())
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes = self.with_table(schema, |table, _| {
+            let changes = table.rows.len();
+            table.rows.clear();
+            table.next_id = 1;
+            Ok(changes)
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    // Memory tables carry no constraints, so every insert succeeds. Rows
+    // another backend would ignore or replace are not told apart, so those
+    // strategies fail rather than insert the duplicates they are to avoid.
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        if on_conflict != OnConflict::Abort {
+            return Err(unsupported("ignoring or replacing conflicting rows"));
+        }
+        let id = self.with_table(schema, |table, positions| {
+            let id = table.next_id;
+            table.next_id += 1;
+            let stored = new_row(&table.columns, positions, row);
+            table.rows.insert(id, stored);
+            Ok(id)
+        })?;
+        self.record_changes(1);
+        Ok(Some(ObjectId::from(id)))
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        self.with_table(schema, |table, positions| {
+            let id = id.into_i64();
+            if table.rows.contains_key(&id) {
+                return Err(Error::Constraint(Box::new(
+                    ConstraintError::new(
+                        ConstraintKind::PrimaryKey,
+                        format!("id {} is already taken in {}", id, schema.table_name),
+                    )
+                    .with_location(Some(schema.table_name.to_string()), vec!["id".to_string()]),
+                )));
+            }
+            table.next_id = table.next_id.max(id + 1);
+            let stored = new_row(&table.columns, positions, row);
+            table.rows.insert(id, stored);
+            Ok(())
+        })?;
+        self.record_changes(1);
+        Ok(())
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        let changes = self.with_table(schema, |table, positions| {
+            Ok(match table.rows.get_mut(&id.into_i64()) {
+                Some(stored) => {
+                    for (&position, value) in positions.iter().zip(row) {
+                        stored[position] = value.clone().into_owned();
+                    }
+                    1
+                }
+                None => 0,
+            })
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        let changes = self.with_table(schema, |table, _| {
+            let targets = columns
+                .iter()
+                .map(|name| position(&table.columns, name, schema))
+                .collect::<Result<Vec<_>>>()?;
+            let filter = Filter::parse(where_sql, params, &table.columns, schema)?;
+
+            let mut changes = 0;
+            for (id, stored) in table.rows.iter_mut() {
+                if filter.matches(*id, stored) {
+                    for (&position, value) in targets.iter().zip(values) {
+                        stored[position] = value.clone().into_owned();
+                    }
+                    changes += 1;
+                }
+            }
+            Ok(changes)
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        self.with_table(schema, |table, positions| {
+            match table.rows.get(&id.into_i64()) {
+                Some(stored) => read_row(stored, positions, schema),
+                None => Err(Error::NotFound(Box::new(NotFoundError::new(
+                    id,
+                    schema.type_name,
+                )))),
+            }
+        })
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let rows = self.with_table(schema, |table, positions| {
+            let filter = Filter::parse(where_sql, params, &table.columns, schema)?;
+            table
+                .rows
+                .iter()
+                .filter(|(id, stored)| filter.matches(**id, stored))
+                .map(|(id, stored)| Ok((ObjectId::from(*id), read_row(stored, positions, schema)?)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        for (id, row) in rows {
+            f(id, row)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.with_table(schema, |table, _| {
+            let filter = Filter::parse(where_sql, params, &table.columns, schema)?;
+            Ok(table
+                .rows
+                .iter()
+                .filter(|(id, stored)| filter.matches(**id, stored))
+                .count())
+        })
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.with_table(schema, |table, _| match table.rows.remove(&id.into_i64()) {
+            Some(_) => Ok(()),
+            None => Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            )))),
+        })?;
+        self.record_changes(1);
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let changes = self.with_table(schema, |table, _| {
+            let filter = Filter::parse(where_sql, params, &table.columns, schema)?;
+            let before = table.rows.len();
+            table.rows.retain(|id, stored| !filter.matches(*id, stored));
+            Ok(before - table.rows.len())
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    fn execute(&self, _sql: &str, _params: &RowSlice) -> Result<usize> {
+        Err(Error::Storage(
+            "raw SQL is not supported by the in-memory backend".into(),
+        ))
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.savepoints.borrow_mut().push(Savepoint {
+            name: name.to_string(),
+            tables: self.tables.borrow().clone(),
+            changes: self.changes.get(),
+        });
+        Ok(())
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        let mut savepoints = self.savepoints.borrow_mut();
+        let position = savepoint_position(&savepoints, name)?;
+        savepoints.truncate(position);
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        let mut savepoints = self.savepoints.borrow_mut();
+        let position = savepoint_position(&savepoints, name)?;
+        savepoints.truncate(position + 1);
+        *self.tables.borrow_mut() = savepoints[position].tables.clone();
+        self.changes.set(savepoints[position].changes);
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        if let Some(storage) = self.finish()? {
+            storage.tables = self.tables.take();
+            storage.total_changes += self.changes.get();
+        }
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.finish()?;
+        Ok(())
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        let mut storage = self.storage.borrow_mut();
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| Error::Storage("transaction is already finished".into()))?;
+        storage.tables = self.tables.borrow().clone();
+        storage.total_changes += self.changes.replace(0);
+        self.savepoints.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Stored rows follow the column order the table was created with, which
+// need not match the schema used to access it.
+pub(crate) type Layout = [(String, DataType)];
+
+pub(crate) fn position(layout: &Layout, name: &str, schema: &Schema) -> Result<usize> {
+    layout
+        .iter()
+        .position(|(column_name, _)| column_name == name)
+        .ok_or_else(|| missing_column(name, schema))
+}
+
+pub(crate) fn positions(layout: &Layout, schema: &Schema) -> Result<Vec<usize>> {
+    schema
+        .columns
+        .iter()
+        .map(|c| position(layout, c.column_name, schema))
+        .collect()
+}
+
+pub(crate) fn new_row(layout: &Layout, positions: &[usize], row: &RowSlice) -> Row<'static> {
+    let mut stored: Row = layout.iter().map(|(_, typ)| default_value(*typ)).collect();
+    for (&position, value) in positions.iter().zip(row) {
+        stored[position] = value.clone().into_owned();
+    }
+    stored
+}
+
+pub(crate) fn read_row(
+    stored: &RowSlice<'static>,
+    positions: &[usize],
+    schema: &Schema,
+) -> Result<Row<'static>> {
+    let mut line = Row::with_capacity(positions.len());
+    for (column, &position) in schema.columns.iter().zip(positions) {
+        let value = &stored[position];
+        if value.data_type() != column.typ {
+            return Err(Error::UnexpectedType(Box::new(UnexpectedTypeError::new(
+                schema.type_name,
+                column.attr_name,
+                schema.table_name,
+                column.column_name,
+                column.typ,
+                format!("{:?}", value.data_type()),
+            ))));
+        }
+        line.push(value.clone());
+    }
+    Ok(line)
+}
+
+pub(crate) fn default_value(typ: DataType) -> Value<'static> {
+    match typ {
+        DataType::String => String::new().into(),
+        DataType::Bytes => Vec::new().into(),
+        DataType::Int64 => 0i64.into(),
+        DataType::Float64 => 0f64.into(),
+        DataType::Bool => false.into(),
+    }
+}
+
+fn savepoint_position(savepoints: &[Savepoint], name: &str) -> Result<usize> {
+    savepoints
+        .iter()
+        .rposition(|savepoint| savepoint.name == name)
+        .ok_or_else(|| Error::Storage(format!("no such savepoint: {}", name).into()))
+}
+
+fn no_such_table(table_name: &str) -> Error {
+    Error::Storage(format!("no such table: {}", table_name).into())
+}
+
+fn no_such_column(column_name: &str) -> Error {
+    Error::Storage(format!("no such column: {}", column_name).into())
+}
+
+fn missing_column(name: &str, schema: &Schema) -> Error {
+    MissingColumnError::from_column_name(name, schema)
+        .unwrap_or_else(|| Error::Storage(format!("no such column: {}", name).into()))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The in-memory and key-value backends understand the where clauses produced by
+// `Predicate::to_sql` and the ORM itself, not arbitrary SQL.
+pub(crate) enum Filter {
+    All,
+    Compare(Field, CompareOp, Value<'static>),
+    IsNull(bool),
+    In(Field, Vec<Value<'static>>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Field {
+    Id,
+    Column(usize),
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Keyword(&'static str),
+    Op(CompareOp),
+    Param,
+    Open,
+    Close,
+    Comma,
+}
+
+impl Filter {
+    pub(crate) fn parse(
+        sql: &str,
+        params: &RowSlice,
+        layout: &Layout,
+        schema: &Schema,
+    ) -> Result<Self> {
+        if sql.trim().is_empty() {
+            return Ok(Filter::All);
+        }
+        let unsupported = || Error::Storage(format!("unsupported where clause: {}", sql).into());
+        let mut parser = Parser {
+            tokens: tokenize(sql).ok_or_else(unsupported)?,
+            pos: 0,
+            params: params.iter(),
+            layout,
+            schema,
+        };
+        let filter = parser.parse_or()?.ok_or_else(unsupported)?;
+        if parser.pos != parser.tokens.len() {
+            return Err(unsupported());
+        }
+        Ok(filter)
+    }
+
+    pub(crate) fn matches(&self, id: i64, row: &RowSlice) -> bool {
+        let get = |field: &Field| match field {
+            Field::Id => Value::Int64(id),
+            Field::Column(position) => row[*position].clone(),
+        };
+        match self {
+            Filter::All => true,
+            Filter::Compare(field, op, value) => match compare(&get(field), value) {
+                Some(ordering) => match op {
+                    CompareOp::Eq => ordering == Ordering::Equal,
+                    CompareOp::Ne => ordering != Ordering::Equal,
+                    CompareOp::Lt => ordering == Ordering::Less,
+                    CompareOp::Le => ordering != Ordering::Greater,
+                    CompareOp::Gt => ordering == Ordering::Greater,
+                    CompareOp::Ge => ordering != Ordering::Less,
+                },
+                None => false,
+            },
+            // Stored values are never NULL.
+            Filter::IsNull(negated) => *negated,
+            Filter::In(field, values) => {
+                let value = get(field);
+                values
+                    .iter()
+                    .any(|other| compare(&value, other) == Some(Ordering::Equal))
+            }
+            Filter::And(lhs, rhs) => lhs.matches(id, row) && rhs.matches(id, row),
+            Filter::Or(lhs, rhs) => lhs.matches(id, row) || rhs.matches(id, row),
+            Filter::Not(inner) => !inner.matches(id, row),
+        }
+    }
+}
+
+fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    let number = |value: &Value| match value {
+        Value::Int64(i) => Some(*i as f64),
+        Value::Float64(f) => Some(*f),
+        Value::Bool(b) => Some(*b as i64 as f64),
+        _ => None,
+    };
+    match (lhs, rhs) {
+        (Value::Int64(a), Value::Int64(b)) => Some(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bytes(a), Value::Bytes(b)) => Some(a.cmp(b)),
+        _ => number(lhs)?.partial_cmp(&number(rhs)?),
+    }
+}
+
+fn tokenize(sql: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let token = match ch {
+            ' ' | '\t' | '\n' => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '?' => Token::Param,
+            '=' => Token::Op(CompareOp::Eq),
+            '<' | '>' | '!' => {
+                let next = chars.peek().copied();
+                let op = match (ch, next) {
+                    ('<', Some('=')) => Some(CompareOp::Le),
+                    ('<', Some('>')) | ('!', Some('=')) => Some(CompareOp::Ne),
+                    ('>', Some('=')) => Some(CompareOp::Ge),
+                    _ => None,
+                };
+                match (op, ch) {
+                    (Some(op), _) => {
+                        chars.next();
+                        Token::Op(op)
+                    }
+                    (None, '<') => Token::Op(CompareOp::Lt),
+                    (None, '>') => Token::Op(CompareOp::Gt),
+                    _ => return None,
+                }
+            }
+            '"' => {
+                let mut ident = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            ident.push('"');
+                        }
+                        '"' => break,
+                        ch => ident.push(ch),
+                    }
+                }
+                Token::Ident(ident)
+            }
+            ch if ch.is_alphanumeric() || ch == '_' => {
+                let mut word = ch.to_string();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_alphanumeric() || ch == '_') {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+                let keyword = ["AND", "OR", "NOT", "IS", "NULL", "IN"]
+                    .into_iter()
+                    .find(|keyword| keyword.eq_ignore_ascii_case(&word));
+                match keyword {
+                    Some(keyword) => Token::Keyword(keyword),
+                    None => Token::Ident(word),
+                }
+            }
+            _ => return None,
+        };
+        tokens.push(token);
+    }
+    Some(tokens)
+}
+
+struct Parser<'p, 'v> {
+    tokens: Vec<Token>,
+    pos: usize,
+    params: std::slice::Iter<'p, Value<'v>>,
+    layout: &'p Layout,
+    schema: &'p Schema,
+}
+
+impl<'p, 'v> Parser<'p, 'v> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: Token) -> bool {
+        if self.tokens.get(self.pos) == Some(&token) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn param(&mut self) -> Option<Value<'static>> {
+        match self.next()? {
+            Token::Param => Some(self.params.next()?.clone().into_owned()),
+            _ => None,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Option<Filter>> {
+        let mut filter = match self.parse_and()? {
+            Some(filter) => filter,
+            None => return Ok(None),
+        };
+        while self.eat(Token::Keyword("OR")) {
+            match self.parse_and()? {
+                Some(rhs) => filter = Filter::Or(Box::new(filter), Box::new(rhs)),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(filter))
+    }
+
+    fn parse_and(&mut self) -> Result<Option<Filter>> {
+        let mut filter = match self.parse_unary()? {
+            Some(filter) => filter,
+            None => return Ok(None),
+        };
+        while self.eat(Token::Keyword("AND")) {
+            match self.parse_unary()? {
+                Some(rhs) => filter = Filter::And(Box::new(filter), Box::new(rhs)),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(filter))
+    }
+
+    fn parse_unary(&mut self) -> Result<Option<Filter>> {
+        if self.eat(Token::Keyword("NOT")) {
+            return Ok(self
+                .parse_unary()?
+                .map(|inner| Filter::Not(Box::new(inner))));
+        }
+        if self.eat(Token::Open) {
+            let filter = self.parse_or()?;
+            return Ok(filter.filter(|_| self.eat(Token::Close)));
+        }
+
+        let field = match self.next() {
+            Some(Token::Ident(name)) if name == "id" => Field::Id,
+            Some(Token::Ident(name)) => Field::Column(position(self.layout, &name, self.schema)?),
+            _ => return Ok(None),
+        };
+        Ok(match self.next() {
+            Some(Token::Op(op)) => self.param().map(|value| Filter::Compare(field, op, value)),
+            Some(Token::Keyword("IS")) => {
+                let negated = self.eat(Token::Keyword("NOT"));
+                self.eat(Token::Keyword("NULL"))
+                    .then_some(Filter::IsNull(negated))
+            }
+            Some(Token::Keyword("IN")) if self.eat(Token::Open) => {
+                let mut values = Vec::new();
+                loop {
+                    match self.param() {
+                        Some(value) => values.push(value),
+                        None => return Ok(None),
+                    }
+                    if !self.eat(Token::Comma) {
+                        break;
+                    }
+                }
+                self.eat(Token::Close).then_some(Filter::In(field, values))
+            }
+            _ => None,
+        })
+    }
+}
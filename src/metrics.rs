@@ -0,0 +1,393 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::{ErrorKind, Result},
+    object::{Column, Schema},
+    storage::{OnConflict, Row, RowSlice, StorageTransaction, TableInfo},
+    ObjectId,
+};
+use std::{
+    cell::Cell,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Operation {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Count,
+    Execute,
+    Schema,
+    Commit,
+    Rollback,
+}
+
+impl Operation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Select => "select",
+            Operation::Insert => "insert",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+            Operation::Count => "count",
+            Operation::Execute => "execute",
+            Operation::Schema => "schema",
+            Operation::Commit => "commit",
+            Operation::Rollback => "rollback",
+        }
+    }
+}
+
+// Told about every storage operation of the transactions of a connection or
+// database, see Database::set_metrics, to be fed into a metrics system. The
+// table is empty for statements passed to execute and for commits and
+// rollbacks. Failed operations are recorded as well; those that ran into a
+// lock held by another connection are also counted as lock conflicts, once
+// for every attempt of a retried transaction.
+pub trait Metrics: Send + Sync {
+    fn operation(&self, operation: Operation, table: &str, elapsed: Duration);
+
+    fn rows_read(&self, _table: &str, _rows: usize) {}
+
+    fn rows_written(&self, _table: &str, _rows: usize) {}
+
+    fn lock_conflict(&self, _operation: Operation, _table: &str) {}
+}
+
+pub type SharedMetrics = Arc<dyn Metrics>;
+
+pub(crate) fn metered<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+    metrics: Option<SharedMetrics>,
+) -> Box<dyn StorageTransaction + 'a> {
+    match metrics {
+        Some(metrics) => Box::new(MeteredTransaction { inner, metrics }),
+        None => inner,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct MeteredTransaction<'a> {
+    inner: Box<dyn StorageTransaction + 'a>,
+    metrics: SharedMetrics,
+}
+
+// How the result of an operation counts towards rows read and written.
+enum Rows {
+    None,
+    Read(usize),
+    Written(usize),
+}
+
+impl<'a> MeteredTransaction<'a> {
+    fn measure<R>(
+        &self,
+        operation: Operation,
+        table: &str,
+        rows: impl FnOnce(&R) -> Rows,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        let start = Instant::now();
+        let result = f();
+        self.metrics.operation(operation, table, start.elapsed());
+        match &result {
+            Ok(value) => match rows(value) {
+                Rows::Read(rows) => self.metrics.rows_read(table, rows),
+                Rows::Written(rows) => self.metrics.rows_written(table, rows),
+                Rows::None => {}
+            },
+            Err(err) if err.kind() == ErrorKind::Locked => {
+                self.metrics.lock_conflict(operation, table)
+            }
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+fn none<R>(_: &R) -> Rows {
+    Rows::None
+}
+
+fn written(rows: &usize) -> Rows {
+    Rows::Written(*rows)
+}
+
+impl<'a> StorageTransaction for MeteredTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.measure(Operation::Schema, table, none, || {
+            self.inner.table_exists(table)
+        })
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.measure(Operation::Schema, schema.table_name, none, || {
+            self.inner.create_table(schema)
+        })
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.measure(Operation::Schema, schema.table_name, none, || {
+            self.inner.drop_table(schema)
+        })
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        self.measure(Operation::Schema, "", none, || self.inner.table_names())
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        self.measure(Operation::Schema, table, none, || {
+            self.inner.table_info(table)
+        })
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        self.measure(Operation::Schema, schema.table_name, none, || {
+            self.inner.add_column(schema, column)
+        })
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.measure(Operation::Schema, from, none, || {
+            self.inner.rename_table(from, to)
+        })
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.measure(Operation::Schema, table, none, || {
+            self.inner.rename_column(table, from, to)
+        })
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.measure(Operation::Schema, table, none, || {
+            self.inner.drop_column(table, column)
+        })
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        self.measure(Operation::Delete, schema.table_name, written, || {
+            self.inner.clear_table(schema)
+        })
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        self.measure(
+            Operation::Insert,
+            schema.table_name,
+            |id: &Option<ObjectId>| Rows::Written(id.is_some() as usize),
+            || self.inner.insert_row_with(schema, row, on_conflict),
+        )
+    }
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        self.measure(
+            Operation::Insert,
+            schema.table_name,
+            |_| Rows::Written(1),
+            || self.inner.insert_row(schema, row),
+        )
+    }
+
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        self.measure(
+            Operation::Insert,
+            schema.table_name,
+            |ids: &Vec<ObjectId>| Rows::Written(ids.len()),
+            || self.inner.insert_rows(schema, rows),
+        )
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        self.measure(
+            Operation::Insert,
+            schema.table_name,
+            |_| Rows::Written(1),
+            || self.inner.insert_row_with_id(id, schema, row),
+        )
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        self.measure(Operation::Update, schema.table_name, written, || {
+            self.inner.update_row(id, schema, row)
+        })
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        self.measure(Operation::Update, schema.table_name, written, || {
+            self.inner
+                .update_where(schema, columns, values, where_sql, params)
+        })
+    }
+
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        self.measure(Operation::Update, schema.table_name, written, || {
+            self.inner.update_rows(schema, columns, rows)
+        })
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        self.measure(
+            Operation::Select,
+            schema.table_name,
+            |_| Rows::Read(1),
+            || self.inner.select_row(id, schema),
+        )
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let rows = Cell::new(0);
+        self.measure(
+            Operation::Select,
+            schema.table_name,
+            |_| Rows::Read(rows.get()),
+            || {
+                self.inner
+                    .for_each_row(schema, where_sql, params, &mut |id, row| {
+                        rows.set(rows.get() + 1);
+                        f(id, row)
+                    })
+            },
+        )
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        self.measure(
+            Operation::Select,
+            schema.table_name,
+            |rows: &Vec<_>| Rows::Read(rows.len()),
+            || self.inner.select_rows(schema, where_sql, params),
+        )
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        let rows = Cell::new(0);
+        self.measure(
+            Operation::Select,
+            schema.table_name,
+            |_| Rows::Read(rows.get()),
+            || {
+                self.inner
+                    .for_each_row_ref(schema, where_sql, params, &mut |id, row| {
+                        rows.set(rows.get() + 1);
+                        f(id, row)
+                    })
+            },
+        )
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.measure(Operation::Count, schema.table_name, none, || {
+            self.inner.count_rows(schema, where_sql, params)
+        })
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.measure(
+            Operation::Delete,
+            schema.table_name,
+            |_| Rows::Written(1),
+            || self.inner.delete_row(id, schema),
+        )
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.measure(Operation::Delete, schema.table_name, written, || {
+            self.inner.delete_where(schema, where_sql, params)
+        })
+    }
+
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        self.measure(
+            Operation::Delete,
+            schema.table_name,
+            |_| Rows::Written(ids.len()),
+            || self.inner.delete_rows(schema, ids),
+        )
+    }
+
+    // Rows changed by the statement are not told apart from rows it read.
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.measure(Operation::Execute, "", written, || {
+            self.inner.execute(sql, params)
+        })
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.inner.savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.release_savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.rollback_to_savepoint(name)
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.measure(Operation::Commit, "", none, || self.inner.commit())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.measure(Operation::Rollback, "", none, || self.inner.rollback())
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.measure(Operation::Commit, "", none, || {
+            self.inner.commit_and_continue()
+        })
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        self.inner.take_statement_log()
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+}
@@ -0,0 +1,281 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::DataType,
+    error::{DeserializeError, Error, Result},
+    object::{fingerprint, next_field, Column, Object, Schema},
+    storage::Row,
+    transaction::FINGERPRINTS,
+    Connection, Transaction,
+};
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A versioned schema change. Versions order the migrations and must be
+// unique; a migration that is applied once is never run again.
+pub trait Migration {
+    fn version(&self) -> i64;
+
+    fn name(&self) -> &str {
+        ""
+    }
+
+    fn up(&self, tx: &Transaction) -> Result<()>;
+
+    fn down(&self, _tx: &Transaction) -> Result<()> {
+        Err(Error::Storage(
+            format!("migration {} cannot be reverted", self.version()).into(),
+        ))
+    }
+}
+
+// A pending migration and the statements it would run, see
+// Connection::migration_plan.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PlannedMigration {
+    pub version: i64,
+    pub name: String,
+    pub statements: Vec<String>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct AppliedMigration {
+    version: i64,
+    name: String,
+    applied_at: i64,
+}
+
+const COLUMNS: &[Column] = &[
+    Column {
+        column_name: "version",
+        attr_name: "version",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "name",
+        attr_name: "name",
+        typ: DataType::String,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "applied_at",
+        attr_name: "applied_at",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+];
+
+impl Object for AppliedMigration {
+    const TABLE: &'static Schema = &Schema {
+        table_name: "_orm_migrations",
+        type_name: "AppliedMigration",
+        columns: COLUMNS,
+        fingerprint: fingerprint(COLUMNS),
+        audited: false,
+        strict: false,
+        without_rowid: false,
+        triggers: &[],
+        view: None,
+    };
+
+    fn serialize(&self) -> Row<'_> {
+        crate::row![
+            self.version.into(),
+            self.name.as_str().into(),
+            self.applied_at.into(),
+        ]
+    }
+
+    fn deserialize(row: Row) -> std::result::Result<Self, DeserializeError> {
+        let mut values = row.into_iter();
+        Ok(Self {
+            version: next_field(&mut values, Self::TABLE, 0)?,
+            name: next_field(&mut values, Self::TABLE, 1)?,
+            applied_at: next_field(&mut values, Self::TABLE, 2)?,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Connection {
+    // Versions of the applied migrations, in ascending order.
+    pub fn applied_migrations(&mut self) -> Result<Vec<i64>> {
+        let tx = self.new_transaction()?;
+        let applied = applied_versions(&tx)?;
+        tx.commit()?;
+        Ok(applied)
+    }
+
+    // Each pending migration runs in a transaction of its own together with
+    // its bookkeeping row, so a failing one leaves the earlier ones applied
+    // and nothing of itself. Returns the versions that were applied.
+    pub fn migrate(&mut self, migrations: &[&dyn Migration]) -> Result<Vec<i64>> {
+        let mut migrations = sorted(migrations)?;
+        let applied: HashSet<_> = self.applied_migrations()?.into_iter().collect();
+        migrations.retain(|migration| !applied.contains(&migration.version()));
+
+        let mut versions = Vec::new();
+        for migration in migrations {
+            let tx = self.new_transaction()?;
+            migration.up(&tx)?;
+            tx.create(AppliedMigration {
+                version: migration.version(),
+                name: migration.name().to_string(),
+                applied_at: now_millis(),
+            })?;
+            tx.commit()?;
+            versions.push(migration.version());
+        }
+        Ok(versions)
+    }
+
+    // The pending migrations in the order migrate would apply them, with the
+    // statements each runs. They run in one transaction that is rolled back,
+    // so every migration sees the changes of the earlier ones. Reads,
+    // transaction control and the ORM's bookkeeping are left out.
+    pub fn migration_plan(
+        &mut self,
+        migrations: &[&dyn Migration],
+    ) -> Result<Vec<PlannedMigration>> {
+        let mut migrations = sorted(migrations)?;
+        let applied: HashSet<_> = self.applied_migrations()?.into_iter().collect();
+        migrations.retain(|migration| !applied.contains(&migration.version()));
+
+        self.set_statement_log(true)?;
+        let plan = self.plan(&migrations);
+        self.set_statement_log(false)?;
+        plan
+    }
+
+    fn plan(&mut self, migrations: &[&dyn Migration]) -> Result<Vec<PlannedMigration>> {
+        let tx = self.new_transaction()?;
+        tx.storage().take_statement_log()?;
+        let mut plan = Vec::new();
+        for migration in migrations {
+            migration.up(&tx)?;
+            tx.flush()?;
+            let statements = tx.storage().take_statement_log()?;
+            plan.push(PlannedMigration {
+                version: migration.version(),
+                name: migration.name().to_string(),
+                statements: statements
+                    .into_iter()
+                    .filter(|sql| is_planned(sql))
+                    .collect(),
+            });
+        }
+        tx.rollback()?;
+        Ok(plan)
+    }
+
+    // Reverts the applied migrations above the target version, newest first.
+    // Returns the versions that were reverted.
+    pub fn migrate_down(&mut self, migrations: &[&dyn Migration], target: i64) -> Result<Vec<i64>> {
+        let mut migrations = sorted(migrations)?;
+        let applied: HashSet<_> = self.applied_migrations()?.into_iter().collect();
+        migrations.retain(|migration| {
+            migration.version() > target && applied.contains(&migration.version())
+        });
+
+        let mut versions = Vec::new();
+        for migration in migrations.into_iter().rev() {
+            let tx = self.new_transaction()?;
+            migration.down(&tx)?;
+            tx.delete_where::<AppliedMigration>(&crate::column("version").eq(migration.version()))?;
+            tx.commit()?;
+            versions.push(migration.version());
+        }
+        Ok(versions)
+    }
+
+    // As migrate_down, but all in one transaction: either every applied
+    // migration above the target is reverted or none is. Fails if one of them
+    // is missing from migrations.
+    pub fn migrate_down_to(
+        &mut self,
+        migrations: &[&dyn Migration],
+        target: i64,
+    ) -> Result<Vec<i64>> {
+        let migrations = sorted(migrations)?;
+        let tx = self.new_transaction()?;
+        let mut versions = Vec::new();
+        for version in applied_versions(&tx)?.into_iter().rev() {
+            if version <= target {
+                break;
+            }
+            let migration = migrations
+                .iter()
+                .find(|migration| migration.version() == version)
+                .ok_or_else(|| {
+                    Error::Storage(format!("applied migration {} is not known", version).into())
+                })?;
+            migration.down(&tx)?;
+            tx.delete_where::<AppliedMigration>(&crate::column("version").eq(version))?;
+            versions.push(version);
+        }
+        tx.commit()?;
+        Ok(versions)
+    }
+}
+
+fn applied_versions(tx: &Transaction) -> Result<Vec<i64>> {
+    let mut versions: Vec<_> = tx
+        .select_all::<AppliedMigration>()?
+        .iter()
+        .map(|applied| applied.borrow().version)
+        .collect();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+fn is_planned(sql: &str) -> bool {
+    const SKIPPED: &[&str] = &[
+        "SELECT",
+        "PRAGMA",
+        "BEGIN",
+        "COMMIT",
+        "END",
+        "ROLLBACK",
+        "SAVEPOINT",
+        "RELEASE",
+        "--",
+    ];
+    let sql = sql.trim_start();
+    let bookkeeping = [FINGERPRINTS.table_name, AppliedMigration::TABLE.table_name];
+    !SKIPPED.iter().any(|prefix| {
+        sql.get(..prefix.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+    }) && !bookkeeping.iter().any(|table| sql.contains(table))
+}
+
+fn sorted<'m>(migrations: &[&'m dyn Migration]) -> Result<Vec<&'m dyn Migration>> {
+    let mut migrations = migrations.to_vec();
+    migrations.sort_by_key(|migration| migration.version());
+    if let Some(pair) = migrations
+        .windows(2)
+        .find(|pair| pair[0].version() == pair[1].version())
+    {
+        return Err(Error::Storage(
+            format!("migration version {} is used twice", pair[0].version()).into(),
+        ));
+    }
+    Ok(migrations)
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
@@ -0,0 +1,384 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::{Error, Result},
+    instrument::{SharedInstrument, SlowQueryLog, SqlTraceHook},
+    memory::{read_row, MemoryStorage},
+    object::{Column, Schema},
+    storage::{
+        OnConflict, Row, RowSlice, StorageConnection, StorageTransaction, TableInfo,
+        TransactionBehavior,
+    },
+    transaction::FINGERPRINTS,
+    ObjectId,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MockOp {
+    Begin,
+    Insert,
+    Update,
+    Select,
+    Delete,
+    Execute,
+    Commit,
+}
+
+type ErrorFactory = Box<dyn Fn() -> Error + Send>;
+
+#[derive(Default)]
+struct Script {
+    calls: HashMap<MockOp, usize>,
+    failures: HashMap<(MockOp, usize), ErrorFactory>,
+    rows: HashMap<usize, Row<'static>>,
+}
+
+// A handle to the script of a MockStorage that stays usable after the
+// storage is moved into a Connection or Database. Calls are counted from 1
+// over the lifetime of the storage, across transactions.
+#[derive(Clone, Default)]
+pub struct MockScript(Arc<Mutex<Script>>);
+
+impl MockScript {
+    pub fn fail_nth<F>(&self, op: MockOp, n: usize, error: F) -> &Self
+    where
+        F: Fn() -> Error + Send + 'static,
+    {
+        self.lock().failures.insert((op, n), Box::new(error));
+        self
+    }
+
+    // The nth select_row returns this row, checked against the schema as a
+    // real backend would, instead of the stored one.
+    pub fn return_row(&self, n: usize, row: Row<'static>) -> &Self {
+        self.lock().rows.insert(n, row);
+        self
+    }
+
+    pub fn calls(&self, op: MockOp) -> usize {
+        self.lock().calls.get(&op).copied().unwrap_or(0)
+    }
+
+    pub fn reset(&self) {
+        *self.lock() = Script::default();
+    }
+
+    fn record(&self, op: MockOp) -> Result<usize> {
+        let mut script = self.lock();
+        let n = script.calls.entry(op).or_insert(0);
+        *n += 1;
+        let n = *n;
+        match script.failures.get(&(op, n)) {
+            Some(error) => Err(error()),
+            None => Ok(n),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Script> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct MockStorage<S = MemoryStorage> {
+    inner: S,
+    script: MockScript,
+}
+
+impl MockStorage {
+    pub fn new() -> Self {
+        Self::wrap(MemoryStorage::new())
+    }
+}
+
+impl Default for MockStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: StorageConnection> MockStorage<S> {
+    pub fn wrap(inner: S) -> Self {
+        Self {
+            inner,
+            script: MockScript::default(),
+        }
+    }
+
+    pub fn script(&self) -> MockScript {
+        self.script.clone()
+    }
+}
+
+impl<S: StorageConnection> StorageConnection for MockStorage<S> {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        self.script.record(MockOp::Begin)?;
+        Ok(Box::new(MockTransaction {
+            inner: self.inner.new_transaction()?,
+            script: self.script.clone(),
+        }))
+    }
+
+    fn new_transaction_with(
+        &mut self,
+        behavior: TransactionBehavior,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        self.script.record(MockOp::Begin)?;
+        Ok(Box::new(MockTransaction {
+            inner: self.inner.new_transaction_with(behavior)?,
+            script: self.script.clone(),
+        }))
+    }
+
+    fn new_snapshot(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        self.script.record(MockOp::Begin)?;
+        Ok(Box::new(MockTransaction {
+            inner: self.inner.new_snapshot()?,
+            script: self.script.clone(),
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        self.inner.total_changes()
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.inner.ping()
+    }
+
+    fn set_foreign_keys(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_foreign_keys(enabled)
+    }
+
+    fn set_statement_log(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_statement_log(enabled)
+    }
+
+    fn set_instrument(&mut self, instrument: Option<SharedInstrument>) -> Result<()> {
+        self.inner.set_instrument(instrument)
+    }
+
+    fn set_slow_query_log(&mut self, log: Option<SlowQueryLog>) -> Result<()> {
+        self.inner.set_slow_query_log(log)
+    }
+
+    fn set_sql_trace(&mut self, hook: Option<SqlTraceHook>) -> Result<()> {
+        self.inner.set_sql_trace(hook)
+    }
+
+    fn set_bulk_load(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_bulk_load(enabled)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct MockTransaction<'a> {
+    inner: Box<dyn StorageTransaction + 'a>,
+    script: MockScript,
+}
+
+impl<'a> MockTransaction<'a> {
+    // The ORM's own bookkeeping is not part of the script.
+    fn record(&self, op: MockOp, schema: &Schema) -> Result<usize> {
+        if schema.table_name == FINGERPRINTS.table_name {
+            return Ok(0);
+        }
+        self.script.record(op)
+    }
+}
+
+impl<'a> StorageTransaction for MockTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.inner.table_exists(table)
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.create_table(schema)
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.drop_table(schema)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        self.inner.table_names()
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        self.inner.table_info(table)
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        self.inner.add_column(schema, column)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_table(from, to)
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_column(table, from, to)
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.inner.drop_column(table, column)
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        self.record(MockOp::Delete, schema)?;
+        self.inner.clear_table(schema)
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        self.record(MockOp::Insert, schema)?;
+        self.inner.insert_row_with(schema, row, on_conflict)
+    }
+
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        self.record(MockOp::Insert, schema)?;
+        self.inner.insert_rows(schema, rows)
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        self.record(MockOp::Insert, schema)?;
+        self.inner.insert_row_with_id(id, schema, row)
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        self.record(MockOp::Update, schema)?;
+        self.inner.update_row(id, schema, row)
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        self.record(MockOp::Update, schema)?;
+        self.inner
+            .update_where(schema, columns, values, where_sql, params)
+    }
+
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        self.record(MockOp::Update, schema)?;
+        self.inner.update_rows(schema, columns, rows)
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let n = self.record(MockOp::Select, schema)?;
+        match self.script.lock().rows.remove(&n) {
+            Some(row) => {
+                let positions: Vec<_> = (0..row.len()).collect();
+                read_row(&row, &positions, schema)
+            }
+            None => self.inner.select_row(id, schema),
+        }
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        self.record(MockOp::Select, schema)?;
+        self.inner.for_each_row(schema, where_sql, params, f)
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        self.record(MockOp::Select, schema)?;
+        self.inner.for_each_row_ref(schema, where_sql, params, f)
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.record(MockOp::Select, schema)?;
+        self.inner.count_rows(schema, where_sql, params)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.record(MockOp::Delete, schema)?;
+        self.inner.delete_row(id, schema)
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.record(MockOp::Delete, schema)?;
+        self.inner.delete_where(schema, where_sql, params)
+    }
+
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        self.record(MockOp::Delete, schema)?;
+        self.inner.delete_rows(schema, ids)
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.script.record(MockOp::Execute)?;
+        self.inner.execute(sql, params)
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.inner.savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.release_savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.rollback_to_savepoint(name)
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.script.record(MockOp::Commit)?;
+        self.inner.commit()
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.inner.rollback()
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.script.record(MockOp::Commit)?;
+        self.script.record(MockOp::Begin)?;
+        self.inner.commit_and_continue()
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        self.inner.take_statement_log()
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+}
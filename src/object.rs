@@ -1,87 +1,529 @@
 #![forbid(unsafe_code)]
-use crate::{data::DataType, storage::Row};
-use std::any::Any;
+use crate::{
+    data::{DataType, FromValue, Value},
+    error::{DeserializeError, Result},
+    storage::{OnConflict, Row},
+    ObjectId, Transaction,
+};
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, OnceLock},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub trait Object: Any + Sized {
     const TABLE: &'static Schema;
-    fn serialize(&self) -> Row;
-    fn deserialize(row: Row) -> Self;
+    fn serialize(&self) -> Row<'_>;
+    fn deserialize(row: Row) -> std::result::Result<Self, DeserializeError>;
+
+    // Appends the values of serialize, owned, so that one buffer can be
+    // reused for many objects. Derived types do it without a row of their
+    // own in between.
+    fn serialize_into(&self, row: &mut Row<'static>) {
+        row.extend(self.serialize().into_iter().map(Value::into_owned));
+    }
+
+    // `#[hooks]` on a derived type returns the object itself.
+    fn hooks(&mut self) -> Option<&mut dyn Hooks> {
+        None
+    }
+
+    // Sets the fields marked `#[computed("method")]` of a derived type to
+    // what `method(&self)` returns, in the order they are declared. Runs
+    // right before before_save; writes that bypass the objects, such as
+    // update_where, leave computed columns as they are.
+    fn compute(&mut self) {}
+}
+
+// Called by the transaction for objects whose type opts in with `#[hooks]`.
+// An error from before_save or after_create fails the operation that ran it.
+pub trait Hooks {
+    // Before the row is inserted, and before a modified object is written.
+    fn before_save(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn after_create(&mut self, _tx: &Transaction, _id: ObjectId) -> Result<()> {
+        Ok(())
+    }
+
+    // For every object created or written in the transaction, once it is
+    // committed.
+    fn after_commit(&mut self, _id: ObjectId) {}
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(PartialEq, Eq, Debug)]
 pub struct Schema {
     pub table_name: &'static str,
     pub type_name: &'static str,
     pub columns: &'static [Column],
+    pub fingerprint: u64,
+    // `#[audited]`: inserts, updates and deletes are recorded in
+    // `<table>_history`.
+    pub audited: bool,
+    // `#[strict]` and `#[without_rowid]`: created as a STRICT or a WITHOUT
+    // ROWID table on SQLite. Ignored by the other backends.
+    pub strict: bool,
+    pub without_rowid: bool,
+    // `#[trigger("CREATE TRIGGER ...")]`: raw SQL run right after the table
+    // is created.
+    pub triggers: &'static [&'static str],
+    // `#[view("SELECT id, ...")]`: the table is a view over this query, and
+    // its objects can be read but not written.
+    pub view: Option<&'static str>,
 }
 
 impl Schema {
-    pub fn select_text(&self) -> String {
-        let columns = if self.columns.is_empty() {
-            "1".to_string()
-        } else {
-            self.columns
-                .iter()
-                .map(|c| c.column_name)
-                .collect::<Vec<_>>()
-                .join(", ")
-        };
+    pub fn column(&self, name: &str) -> Option<&'static Column> {
+        self.columns.iter().find(|c| c.column_name == name)
+    }
 
-        format!("SELECT {} FROM {} WHERE id = ?", columns, self.table_name)
+    pub fn column_names(&self) -> impl Iterator<Item = &'static str> {
+        self.columns.iter().map(|c| c.column_name)
     }
 
-    pub fn insert_text(&self) -> String {
-        let fields: Vec<_> = self.columns.iter().map(|c| c.column_name).collect();
-        let placeholders: Vec<_> = (0..self.columns.len()).map(|_| "?").collect();
+    pub fn select_text(&self) -> &'static str {
+        self.cached_sql(Statement::Select, || {
+            let columns = if self.columns.is_empty() {
+                "1".to_string()
+            } else {
+                self.columns
+                    .iter()
+                    .map(Column::select_expr)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+
+            format!("SELECT {} FROM {} WHERE id = ?", columns, self.table_name)
+        })
+    }
+
+    pub fn select_where_text(&self, where_sql: &str) -> String {
+        let mut columns = vec!["id"];
+        columns.extend(self.columns.iter().map(Column::select_expr));
+
+        let mut query = format!("SELECT {} FROM {}", columns.join(", "), self.table_name);
+        if !where_sql.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(where_sql);
+        }
+        query
+    }
+
+    pub fn count_text(&self, where_sql: &str) -> String {
+        let mut query = format!("SELECT COUNT(*) FROM {}", self.table_name);
+        if !where_sql.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(where_sql);
+        }
+        query
+    }
+
+    pub fn insert_text(&self) -> &'static str {
+        self.insert_text_with(OnConflict::Abort)
+    }
+
+    pub fn insert_text_with(&self, on_conflict: OnConflict) -> &'static str {
+        self.cached_sql(Statement::Insert(on_conflict), || {
+            self.build_insert_text(on_conflict)
+        })
+    }
+
+    fn build_insert_text(&self, on_conflict: OnConflict) -> String {
+        let verb = match on_conflict {
+            OnConflict::Abort => "INSERT",
+            OnConflict::Ignore => "INSERT OR IGNORE",
+            OnConflict::Replace => "INSERT OR REPLACE",
+        };
+        // A WITHOUT ROWID table has no rowid to take the id from, so the next
+        // one is picked here; unlike AUTOINCREMENT, the ids of deleted rows at
+        // the end of the table are reused.
+        let mut fields = Vec::new();
+        let mut placeholders = Vec::new();
+        let next_id = format!("(SELECT IFNULL(MAX(id), 0) + 1 FROM {})", self.table_name);
+        if self.without_rowid {
+            fields.push("id");
+            placeholders.push(next_id.as_str());
+        } else if self.columns.is_empty() {
+            return format!("{} INTO {} DEFAULT VALUES", verb, self.table_name);
+        }
+
+        fields.extend(self.columns.iter().map(|c| c.column_name));
+        placeholders.extend(self.columns.iter().map(|_| "?"));
 
         format!(
-            "INSERT INTO {} ({}) VALUES ({})",
+            "{} INTO {} ({}) VALUES ({})",
+            verb,
             self.table_name,
             fields.join(", "),
             placeholders.join(", ")
         )
     }
 
-    pub fn delete_text(&self) -> String {
-        format!("DELETE FROM {} WHERE id = ?", self.table_name)
-    }
-
-    pub fn update_text(&self) -> String {
-        let new_values: Vec<_> = self
-            .columns
-            .iter()
-            .map(|c| format!("{} = ?", c.column_name))
-            .collect();
+    // A plain INSERT of `rows` rows at once.
+    pub fn insert_rows_text(&self, rows: usize) -> String {
+        let fields: Vec<_> = self.columns.iter().map(|c| c.column_name).collect();
+        let placeholders = format!("({})", vec!["?"; fields.len()].join(", "));
 
         format!(
-            "UPDATE {} SET {} WHERE id = ?",
+            "INSERT INTO {} ({}) VALUES {}",
             self.table_name,
-            new_values.join(", ")
+            fields.join(", "),
+            vec![placeholders.as_str(); rows].join(", ")
         )
     }
+
+    pub fn insert_with_id_text(&self) -> &'static str {
+        self.cached_sql(Statement::InsertWithId, || {
+            let mut fields = vec!["id"];
+            fields.extend(self.columns.iter().map(|c| c.column_name));
+            let placeholders = vec!["?"; fields.len()];
+
+            format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                self.table_name,
+                fields.join(", "),
+                placeholders.join(", ")
+            )
+        })
+    }
+
+    // The id of the row just inserted by insert_text, for tables where
+    // last_insert_rowid does not apply.
+    pub fn last_id_text(&self) -> String {
+        format!("SELECT MAX(id) FROM {}", self.table_name)
+    }
+
+    pub fn delete_text(&self) -> &'static str {
+        self.cached_sql(Statement::Delete, || {
+            format!("DELETE FROM {} WHERE id = ?", self.table_name)
+        })
+    }
+
+    pub fn update_text(&self) -> &'static str {
+        self.cached_sql(Statement::Update, || {
+            let new_values: Vec<_> = self
+                .columns
+                .iter()
+                .map(|c| format!("{} = ?", c.column_name))
+                .collect();
+
+            format!(
+                "UPDATE {} SET {} WHERE id = ?",
+                self.table_name,
+                new_values.join(", ")
+            )
+        })
+    }
+
+    pub fn update_where_text(&self, columns: &[&str], where_sql: &str) -> String {
+        let new_values: Vec<_> = columns.iter().map(|c| format!("{} = ?", c)).collect();
+
+        let mut query = format!("UPDATE {} SET {}", self.table_name, new_values.join(", "));
+        if !where_sql.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(where_sql);
+        }
+        query
+    }
+
+    pub fn delete_where_text(&self, where_sql: &str) -> String {
+        let mut query = format!("DELETE FROM {}", self.table_name);
+        if !where_sql.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(where_sql);
+        }
+        query
+    }
+
+    pub fn drop_text(&self) -> String {
+        let kind = if self.view.is_some() { "VIEW" } else { "TABLE" };
+        format!("DROP {} {}", kind, self.table_name)
+    }
+
     pub fn create_text(&self) -> String {
-        let mut query = format!(
-            "CREATE TABLE {} (id INTEGER PRIMARY KEY AUTOINCREMENT",
-            self.table_name
-        );
+        self.create_text_with(false)
+    }
+
+    // STRICT tables make SQLite reject values of the wrong type on write;
+    // `strict` applies it to tables not declared `#[strict]`.
+    pub fn create_text_with(&self, strict: bool) -> String {
+        if let Some(view) = self.view {
+            return format!("CREATE VIEW {} AS {}", self.table_name, view);
+        }
+        let mut query = format!("CREATE TABLE {} (id INTEGER PRIMARY KEY", self.table_name);
+        if !self.without_rowid {
+            query.push_str(" AUTOINCREMENT");
+        }
 
         for column in self.columns {
-            query.push_str(&format!(", {} {}", column.column_name, column.attr_name));
+            query.push_str(&format!(", {}", column_definition(column)));
         }
 
         query.push(')');
+        let mut options = Vec::new();
+        if strict || self.strict {
+            options.push("STRICT");
+        }
+        if self.without_rowid {
+            options.push("WITHOUT ROWID");
+        }
+        if !options.is_empty() {
+            query.push(' ');
+            query.push_str(&options.join(", "));
+        }
 
         query
     }
+
+    // Existing rows get the zero value of the type, as in a default object.
+    pub fn add_column_text(&self, column: &Column) -> String {
+        let default = match column.stored_type() {
+            DataType::String => "''",
+            DataType::Bytes => "X''",
+            DataType::Int64 | DataType::Bool => "0",
+            DataType::Float64 => "0.0",
+        };
+        format!(
+            "ALTER TABLE {} ADD COLUMN {} DEFAULT {}",
+            self.table_name,
+            column_definition(column),
+            default
+        )
+    }
+}
+
+// The statements run for every object. Their SQL is built once per schema and
+// kept for the life of the process, as schemas are static; it is looked up by
+// the addresses of the schema's names, which are never freed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Statement {
+    Select,
+    Insert(OnConflict),
+    InsertWithId,
+    Update,
+    Delete,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct SchemaKey {
+    table_name: (usize, usize),
+    columns: (usize, usize),
+    without_rowid: bool,
+}
+
+impl Schema {
+    fn key(&self) -> SchemaKey {
+        SchemaKey {
+            table_name: (self.table_name.as_ptr() as usize, self.table_name.len()),
+            columns: (self.columns.as_ptr() as usize, self.columns.len()),
+            without_rowid: self.without_rowid,
+        }
+    }
+
+    fn cached_sql(&self, statement: Statement, build: impl FnOnce() -> String) -> &'static str {
+        static SQL: OnceLock<Mutex<HashMap<(SchemaKey, Statement), &'static str>>> =
+            OnceLock::new();
+        let key = (self.key(), statement);
+        let mut sql = SQL
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        sql.entry(key)
+            .or_insert_with(|| Box::leak(build().into_boxed_str()))
+    }
+
+    // The schema with no lazy columns, which reads them too.
+    pub(crate) fn eager(&self) -> &'static Schema {
+        static SCHEMAS: OnceLock<Mutex<HashMap<SchemaKey, &'static Schema>>> = OnceLock::new();
+        self.derived(&SCHEMAS, |column| Column {
+            lazy: false,
+            ..*column
+        })
+    }
+
+    // The schema as the storage below encryption sees it, with the encrypted
+    // columns as plain BLOBs.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn stored_encrypted(&self) -> &'static Schema {
+        static SCHEMAS: OnceLock<Mutex<HashMap<SchemaKey, &'static Schema>>> = OnceLock::new();
+        self.derived(&SCHEMAS, |column| Column {
+            typ: match column.encrypted {
+                true => DataType::Bytes,
+                false => column.typ,
+            },
+            encrypted: false,
+            ..*column
+        })
+    }
+
+    // Likewise below compression, which comes first.
+    #[cfg(feature = "compression")]
+    pub(crate) fn stored_compressed(&self) -> &'static Schema {
+        static SCHEMAS: OnceLock<Mutex<HashMap<SchemaKey, &'static Schema>>> = OnceLock::new();
+        self.derived(&SCHEMAS, |column| Column {
+            typ: match column.is_compressed() {
+                true => DataType::Bytes,
+                false => column.typ,
+            },
+            compressed: false,
+            ..*column
+        })
+    }
+
+    fn derived(
+        &self,
+        cache: &'static OnceLock<Mutex<HashMap<SchemaKey, &'static Schema>>>,
+        map: fn(&Column) -> Column,
+    ) -> &'static Schema {
+        let mut schemas = cache
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        schemas.entry(self.key()).or_insert_with(|| {
+            let columns: Vec<_> = self.columns.iter().map(map).collect();
+            Box::leak(Box::new(Schema {
+                columns: Box::leak(columns.into_boxed_slice()),
+                ..*self
+            }))
+        })
+    }
+}
+
+fn column_definition(column: &Column) -> String {
+    let typ = match column.stored_type() {
+        DataType::String => "TEXT",
+        DataType::Bytes => "BLOB",
+        DataType::Int64 | DataType::Bool => "INTEGER",
+        DataType::Float64 => "REAL",
+    };
+    format!("{} {} NOT NULL", column.column_name, typ)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Column {
     pub column_name: &'static str,
     pub attr_name: &'static str,
     pub typ: DataType,
+    // `#[lazy]`: left at its zero value when the object is read, until
+    // Transaction::load_field. Honored by the SQLite backends.
+    pub lazy: bool,
+    // `#[encrypted]`: stored as a BLOB sealed with the key provider of the
+    // connection, see encryption::KeyProvider.
+    pub encrypted: bool,
+    // `#[compress]`: deflated, and stored as a BLOB. Only for strings and
+    // bytes; see compression.
+    pub compressed: bool,
+}
+
+impl Column {
+    pub const fn stored_type(&self) -> DataType {
+        match self.encrypted || self.is_compressed() {
+            true => DataType::Bytes,
+            false => self.typ,
+        }
+    }
+
+    pub(crate) const fn is_compressed(&self) -> bool {
+        self.compressed && matches!(self.typ, DataType::String | DataType::Bytes)
+    }
+
+    // What SELECTs read for the column: a placeholder for lazy ones, which
+    // the transaction never writes back unless the field is changed.
+    fn select_expr(&self) -> &'static str {
+        match (self.lazy, self.stored_type()) {
+            (false, _) => self.column_name,
+            (true, DataType::String) => "''",
+            (true, DataType::Bytes) => "X''",
+            (true, DataType::Int64 | DataType::Bool) => "0",
+            (true, DataType::Float64) => "0.0",
+        }
+    }
+}
+
+// As in an error message: `order_table (IsTall Bool)`.
+impl fmt::Display for Schema {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} (", self.table_name)?;
+        for (i, column) in self.columns.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(formatter, "{}{}", separator, column)?;
+        }
+        write!(formatter, ")")
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} {:?}", self.column_name, self.typ)?;
+        if self.lazy {
+            write!(formatter, " lazy")?;
+        }
+        if self.encrypted {
+            write!(formatter, " encrypted")?;
+        }
+        if self.is_compressed() {
+            write!(formatter, " compressed")?;
+        }
+        Ok(())
+    }
+}
+
+// The CREATE TABLE statements the SQLite backend issues for these types,
+// with their triggers and the history tables of audited ones, as one script.
+pub fn schema_dump(schemas: &[&'static Schema]) -> String {
+    let mut script = String::new();
+    for schema in schemas {
+        script.push_str(&schema.create_text());
+        script.push_str(";\n");
+        for trigger in schema.triggers {
+            script.push_str(trigger.trim_end().trim_end_matches(';'));
+            script.push_str(";\n");
+        }
+        if schema.audited {
+            script.push_str(&crate::audit::history_schema(schema).create_text());
+            script.push_str(";\n");
+        }
+    }
+    script
+}
+
+// Reads the value of column `index` in Object::deserialize.
+pub fn next_field<'a, T: FromValue>(
+    values: &mut impl Iterator<Item = Value<'a>>,
+    schema: &Schema,
+    index: usize,
+) -> std::result::Result<T, DeserializeError> {
+    let column = &schema.columns[index];
+    let value = values.next();
+    let got = value.as_ref().map(Value::data_type);
+    value
+        .and_then(T::from_value)
+        .ok_or_else(|| DeserializeError::new(schema.type_name, column.attr_name, column.typ, got))
+}
+
+// FNV-1a over the column names and stored types, usable in the derived `TABLE` const.
+pub const fn fingerprint(columns: &[Column]) -> u64 {
+    const PRIME: u64 = 0x100000001b3;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < columns.len() {
+        let name = columns[i].column_name.as_bytes();
+        let mut j = 0;
+        while j < name.len() {
+            hash = (hash ^ name[j] as u64).wrapping_mul(PRIME);
+            j += 1;
+        }
+        hash = (hash ^ 0xff).wrapping_mul(PRIME);
+        hash = (hash ^ columns[i].stored_type() as u64).wrapping_mul(PRIME);
+        i += 1;
+    }
+    hash
 }
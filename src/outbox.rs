@@ -0,0 +1,227 @@
+#![forbid(unsafe_code)]
+use crate::{
+    audit::{operation_name, parse_operation},
+    data::{DataType, Value},
+    error::{DeserializeError, Result},
+    object::{fingerprint, next_field, Column, Object, Schema},
+    predicate::column,
+    storage::{ChangeOp, Row},
+    Database, ObjectId, Transaction,
+};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// With the outbox on, see Connection::set_outbox, every row a transaction
+// writes is recorded in `_orm_outbox` as the transaction commits, in the
+// same transaction, so that the record exists exactly when the change does.
+// A consumer then hands the records to a message queue and marks them
+// dispatched. The record names the row; its contents are read from the
+// table.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OutboxEvent {
+    pub seq: ObjectId,
+    pub table: String,
+    pub id: ObjectId,
+    pub op: ChangeOp,
+    pub recorded_at: SystemTime,
+}
+
+pub(crate) struct OutboxEntry {
+    table_name: String,
+    object_id: i64,
+    operation: String,
+    recorded_at: i64,
+    // Milliseconds since the epoch, or 0 while pending.
+    dispatched_at: i64,
+}
+
+const COLUMNS: &[Column] = &[
+    Column {
+        column_name: "table_name",
+        attr_name: "table_name",
+        typ: DataType::String,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "object_id",
+        attr_name: "object_id",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "operation",
+        attr_name: "operation",
+        typ: DataType::String,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "recorded_at",
+        attr_name: "recorded_at",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+    Column {
+        column_name: "dispatched_at",
+        attr_name: "dispatched_at",
+        typ: DataType::Int64,
+        lazy: false,
+        encrypted: false,
+        compressed: false,
+    },
+];
+
+impl Object for OutboxEntry {
+    const TABLE: &'static Schema = &Schema {
+        table_name: "_orm_outbox",
+        type_name: "OutboxEntry",
+        columns: COLUMNS,
+        fingerprint: fingerprint(COLUMNS),
+        audited: false,
+        strict: false,
+        without_rowid: false,
+        triggers: &[],
+        view: None,
+    };
+
+    fn serialize(&self) -> Row<'_> {
+        crate::row![
+            self.table_name.as_str().into(),
+            self.object_id.into(),
+            self.operation.as_str().into(),
+            self.recorded_at.into(),
+            self.dispatched_at.into(),
+        ]
+    }
+
+    fn deserialize(row: Row) -> std::result::Result<Self, DeserializeError> {
+        let mut values = row.into_iter();
+        Ok(Self {
+            table_name: next_field(&mut values, Self::TABLE, 0)?,
+            object_id: next_field(&mut values, Self::TABLE, 1)?,
+            operation: next_field(&mut values, Self::TABLE, 2)?,
+            recorded_at: next_field(&mut values, Self::TABLE, 3)?,
+            dispatched_at: next_field(&mut values, Self::TABLE, 4)?,
+        })
+    }
+}
+
+impl OutboxEntry {
+    pub(crate) fn new(table_name: &str, id: ObjectId, op: ChangeOp) -> Self {
+        Self {
+            table_name: table_name.to_string(),
+            object_id: id.into_i64(),
+            operation: operation_name(op).to_string(),
+            recorded_at: now_millis(),
+            dispatched_at: 0,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl<'a> Transaction<'a> {
+    // The oldest records not yet dispatched, in the order they were made.
+    // Records are read without loading them as objects, and only the
+    // `limit` oldest seen so far are kept, whatever order the rows come in.
+    pub fn pending_outbox(&self, limit: usize) -> Result<Vec<OutboxEvent>> {
+        if !self.storage().table_exists(OutboxEntry::TABLE.table_name)? {
+            return Ok(Vec::new());
+        }
+        self.flush()?;
+        let mut pending = BTreeMap::new();
+        self.scan::<OutboxEntry>(&column("dispatched_at").eq(0), |seq, row| {
+            if pending.len() == limit
+                && pending.last_key_value().is_none_or(|(last, _)| *last < seq)
+            {
+                return Ok(());
+            }
+            let entry =
+                OutboxEntry::deserialize(row.iter().cloned().map(Value::into_owned).collect())?;
+            pending.insert(
+                seq,
+                OutboxEvent {
+                    seq,
+                    table: entry.table_name,
+                    id: ObjectId::from(entry.object_id),
+                    op: parse_operation(&entry.operation),
+                    recorded_at: UNIX_EPOCH
+                        + Duration::from_millis(entry.recorded_at.max(0) as u64),
+                },
+            );
+            if pending.len() > limit {
+                pending.pop_last();
+            }
+            Ok(())
+        })?;
+        Ok(pending.into_values().collect())
+    }
+
+    pub fn mark_dispatched(&self, seqs: &[ObjectId]) -> Result<()> {
+        let now = now_millis();
+        for seq in seqs {
+            self.get::<OutboxEntry>(*seq)?.borrow_mut().dispatched_at = now;
+        }
+        Ok(())
+    }
+
+    // Deletes the records dispatched before the given time. Returns how many.
+    pub fn purge_outbox(&self, dispatched_before: SystemTime) -> Result<usize> {
+        if !self.storage().table_exists(OutboxEntry::TABLE.table_name)? {
+            return Ok(0);
+        }
+        let before = dispatched_before
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        let dispatched = column("dispatched_at").gt(0);
+        self.delete_where::<OutboxEntry>(&dispatched.and(column("dispatched_at").lt(before)))
+    }
+}
+
+impl Database {
+    // Hands up to `limit` pending records to `publish`, in order, and marks
+    // those it accepts dispatched. Stops at the first error, which it returns
+    // once the records before it are marked. A crash between publishing and
+    // marking publishes a record again, so consumers should expect
+    // duplicates. Returns the number dispatched.
+    pub fn dispatch_outbox(
+        &mut self,
+        limit: usize,
+        mut publish: impl FnMut(&OutboxEvent) -> Result<()>,
+    ) -> Result<usize> {
+        let (dispatched, failure) = self.transaction(|tx| {
+            let mut dispatched = Vec::new();
+            let mut failure = None;
+            for event in tx.pending_outbox(limit)? {
+                if let Err(err) = publish(&event) {
+                    failure = Some(err);
+                    break;
+                }
+                dispatched.push(event.seq);
+            }
+            tx.mark_dispatched(&dispatched)?;
+            Ok((dispatched.len(), failure))
+        })?;
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(dispatched),
+        }
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
@@ -0,0 +1,172 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::{Error, Result},
+    storage::StorageConnection,
+    Transaction,
+};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    pub max_size: usize,
+    pub timeout: Duration,
+    pub health_check: bool,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            timeout: Duration::from_secs(30),
+            health_check: true,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type Factory<S> = Box<dyn Fn() -> Result<S> + Send + Sync>;
+
+pub struct Pool<S> {
+    factory: Factory<S>,
+    options: PoolOptions,
+    state: Mutex<PoolState<S>>,
+    released: Condvar,
+}
+
+struct PoolState<S> {
+    idle: Vec<S>,
+    open: usize,
+}
+
+impl<S: StorageConnection> Pool<S> {
+    pub fn new<F>(options: PoolOptions, factory: F) -> Self
+    where
+        F: Fn() -> Result<S> + Send + Sync + 'static,
+    {
+        Self {
+            factory: Box::new(factory),
+            options,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn options(&self) -> &PoolOptions {
+        &self.options
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.lock().open
+    }
+
+    pub fn idle_connections(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    pub fn get(&self) -> Result<PooledConnection<'_, S>> {
+        let deadline = Instant::now() + self.options.timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(mut conn) = state.idle.pop() {
+                if self.options.health_check && conn.ping().is_err() {
+                    state.open -= 1;
+                    continue;
+                }
+                return Ok(self.wrap(conn));
+            }
+
+            if state.open < self.options.max_size {
+                state.open += 1;
+                drop(state);
+                return match (self.factory)() {
+                    Ok(conn) => Ok(self.wrap(conn)),
+                    Err(err) => {
+                        self.lock().open -= 1;
+                        self.released.notify_one();
+                        Err(err)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Storage(
+                    "timed out waiting for a pooled connection".into(),
+                ));
+            }
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+
+    pub fn transaction<R>(&self, f: impl FnOnce(&Transaction) -> Result<R>) -> Result<R> {
+        let mut conn = self.get()?;
+        let tx = conn.new_transaction()?;
+        let result = f(&tx);
+        tx.finish(result)
+    }
+
+    fn wrap(&self, conn: S) -> PooledConnection<'_, S> {
+        PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState<S>> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct PooledConnection<'p, S: StorageConnection> {
+    pool: &'p Pool<S>,
+    conn: Option<S>,
+}
+
+impl<'p, S: StorageConnection> PooledConnection<'p, S> {
+    pub fn new_transaction(&mut self) -> Result<Transaction<'_>> {
+        let inner = self.deref_mut().new_transaction()?;
+        // Without a key provider, so that encrypted columns are refused.
+        #[cfg(feature = "encryption")]
+        let inner = crate::encryption::encrypting(inner, None);
+        Ok(Transaction::new(inner))
+    }
+}
+
+impl<'p, S: StorageConnection> Deref for PooledConnection<'p, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<'p, S: StorageConnection> DerefMut for PooledConnection<'p, S> {
+    fn deref_mut(&mut self) -> &mut S {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<'p, S: StorageConnection> Drop for PooledConnection<'p, S> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.lock().idle.push(conn);
+            self.pool.released.notify_one();
+        }
+    }
+}
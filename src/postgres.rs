@@ -0,0 +1,607 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{
+        ConstraintError, ConstraintKind, Error, MissingColumnError, NotFoundError, Result,
+        UnexpectedTypeError,
+    },
+    object::{Column, Schema},
+    predicate::quote,
+    storage::{
+        ColumnInfo, OnConflict, Row, RowSlice, StorageConnection, StorageTransaction, TableInfo,
+    },
+    Connection, ObjectId,
+};
+use ::postgres::{
+    error::SqlState,
+    types::{FromSql, ToSql, Type},
+    Client, IsolationLevel, NoTls,
+};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct PostgresStorage {
+    client: Client,
+    changes: Cell<usize>,
+}
+
+impl PostgresStorage {
+    pub fn connect(params: &str) -> Result<Self> {
+        Ok(Self::from_client(Client::connect(params, NoTls)?))
+    }
+
+    pub fn from_client(client: Client) -> Self {
+        Self {
+            client,
+            changes: Cell::new(0),
+        }
+    }
+}
+
+impl StorageConnection for PostgresStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(PostgresTransaction {
+            inner: RefCell::new(Some(self.client.transaction()?)),
+            changes: &self.changes,
+        }))
+    }
+
+    fn new_snapshot(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let tx = self
+            .client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()?;
+        Ok(Box::new(PostgresTransaction {
+            inner: RefCell::new(Some(tx)),
+            changes: &self.changes,
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        Ok(self.changes.get())
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.client.simple_query("SELECT 1")?;
+        Ok(())
+    }
+}
+
+impl Connection {
+    pub fn open_postgres(params: &str) -> Result<Self> {
+        Ok(Self::from_storage(PostgresStorage::connect(params)?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct PostgresTransaction<'a> {
+    inner: RefCell<Option<::postgres::Transaction<'a>>>,
+    changes: &'a Cell<usize>,
+}
+
+impl<'a> PostgresTransaction<'a> {
+    fn with<R>(
+        &self,
+        f: impl FnOnce(&mut ::postgres::Transaction<'a>) -> std::result::Result<R, ::postgres::Error>,
+    ) -> Result<R> {
+        let mut inner = self.inner.borrow_mut();
+        let tx = inner
+            .as_mut()
+            .ok_or_else(|| Error::Storage("transaction is already finished".into()))?;
+        Ok(f(tx)?)
+    }
+
+    fn execute_counted(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        let params = to_params(params);
+        let changes = self.with(|tx| tx.execute(sql, &param_refs(&params)))? as usize;
+        self.changes.set(self.changes.get() + changes);
+        Ok(changes)
+    }
+}
+
+impl<'a> StorageTransaction for PostgresTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        let row = self.with(|tx| {
+            tx.query_opt(
+                "SELECT 1 FROM information_schema.tables \
+                WHERE table_schema = current_schema() AND table_name = $1",
+                &[&table],
+            )
+        })?;
+        Ok(row.is_some())
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let mut query = format!(
+            "CREATE TABLE {} (id BIGSERIAL PRIMARY KEY",
+            quote(schema.table_name)
+        );
+        for column in schema.columns {
+            query.push_str(&format!(
+                ", {} {}",
+                quote(column.column_name),
+                sql_type(column.typ)
+            ));
+        }
+        query.push(')');
+
+        self.with(|tx| tx.batch_execute(&query))
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.with(|tx| tx.batch_execute(&format!("DROP TABLE {}", quote(schema.table_name))))
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        let rows = self.with(|tx| {
+            tx.query(
+                "SELECT table_name FROM information_schema.tables \
+                WHERE table_schema = current_schema() AND table_type = 'BASE TABLE' \
+                ORDER BY table_name",
+                &[],
+            )
+        })?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        let rows = self.with(|tx| {
+            tx.query(
+                "SELECT c.column_name, c.data_type, c.is_nullable = 'NO', c.column_default, \
+                EXISTS (SELECT 1 FROM information_schema.table_constraints t \
+                    JOIN information_schema.key_column_usage k \
+                    USING (constraint_schema, constraint_name) \
+                    WHERE t.constraint_type = 'PRIMARY KEY' \
+                    AND t.table_schema = c.table_schema AND t.table_name = c.table_name \
+                    AND k.column_name = c.column_name) \
+                FROM information_schema.columns c \
+                WHERE c.table_schema = current_schema() AND c.table_name = $1 \
+                ORDER BY c.ordinal_position",
+                &[&table],
+            )
+        })?;
+        if rows.is_empty() {
+            return Err(Error::Storage(format!("no such table: {}", table).into()));
+        }
+        let columns = rows
+            .iter()
+            .map(|row| {
+                let declared: String = row.get(1);
+                let typ = [
+                    DataType::String,
+                    DataType::Bytes,
+                    DataType::Int64,
+                    DataType::Float64,
+                    DataType::Bool,
+                ]
+                .into_iter()
+                .find(|candidate| sql_type(*candidate).eq_ignore_ascii_case(&declared));
+                ColumnInfo {
+                    name: row.get(0),
+                    declared_type: Some(declared),
+                    typ,
+                    not_null: row.get(2),
+                    default: row.get(3),
+                    primary_key: row.get(4),
+                }
+            })
+            .collect();
+        Ok(TableInfo {
+            name: table.to_string(),
+            columns,
+        })
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.with(|tx| {
+            tx.batch_execute(&format!(
+                "ALTER TABLE {} RENAME TO {}",
+                quote(from),
+                quote(to)
+            ))
+        })
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.with(|tx| {
+            tx.batch_execute(&format!(
+                "ALTER TABLE {} RENAME COLUMN {} TO {}",
+                quote(table),
+                quote(from),
+                quote(to)
+            ))
+        })
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.with(|tx| {
+            tx.batch_execute(&format!(
+                "ALTER TABLE {} DROP COLUMN {}",
+                quote(table),
+                quote(column)
+            ))
+        })
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes =
+            self.execute_counted(&format!("DELETE FROM {}", quote(schema.table_name)), &[])?;
+        let table = quote(schema.table_name);
+        self.with(|tx| {
+            tx.execute(
+                "SELECT setval(pg_get_serial_sequence($1, 'id'), 1, false)",
+                &[&table],
+            )
+        })?;
+        Ok(changes)
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        let mut query = if schema.columns.is_empty() {
+            format!("INSERT INTO {} DEFAULT VALUES", quote(schema.table_name))
+        } else {
+            format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                quote(schema.table_name),
+                column_list(schema.columns),
+                placeholders(1, schema.columns.len()),
+            )
+        };
+        match on_conflict {
+            OnConflict::Abort => {}
+            OnConflict::Ignore => query.push_str(" ON CONFLICT DO NOTHING"),
+            // Rows are inserted without an id, so this replaces the row at
+            // the next id of the sequence, such as one written with an
+            // explicit id; other unique constraints still fail the insert.
+            OnConflict::Replace => {
+                let assignments: Vec<_> = match schema.columns.is_empty() {
+                    true => vec!["id = EXCLUDED.id".to_string()],
+                    false => schema
+                        .columns
+                        .iter()
+                        .map(|c| format!("{0} = EXCLUDED.{0}", quote(c.column_name)))
+                        .collect(),
+                };
+                query.push_str(" ON CONFLICT (id) DO UPDATE SET ");
+                query.push_str(&assignments.join(", "));
+            }
+        }
+        query.push_str(" RETURNING id");
+
+        let params = to_params(row);
+        let inserted = self
+            .with(|tx| tx.query_opt(query.as_str(), &param_refs(&params)))
+            .map_err(|err| map_schema_error(err, schema))?;
+        let id = inserted.map(|row| ObjectId::from(row.get::<_, i64>(0)));
+        self.changes.set(self.changes.get() + id.is_some() as usize);
+        Ok(id)
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        if schema.columns.is_empty() {
+            return Ok(0);
+        }
+        let assignments: Vec<_> = schema.columns.iter().map(|c| c.column_name).collect();
+        let query = format!(
+            "UPDATE {} SET {} WHERE id = ${}",
+            quote(schema.table_name),
+            assignment_list(&assignments),
+            schema.columns.len() + 1
+        );
+
+        let mut params = row.to_vec();
+        params.push(Value::Int64(id.into_i64()));
+        self.execute_counted(&query, &params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        if columns.is_empty() {
+            return Ok(0);
+        }
+        let mut query = format!(
+            "UPDATE {} SET {}",
+            quote(schema.table_name),
+            assignment_list(columns)
+        );
+        push_where(&mut query, where_sql, columns.len() + 1);
+
+        let mut args = values.to_vec();
+        args.extend_from_slice(params);
+        self.execute_counted(&query, &args)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let columns = if schema.columns.is_empty() {
+            "1".to_string()
+        } else {
+            column_list(schema.columns)
+        };
+        let query = format!(
+            "SELECT {} FROM {} WHERE id = $1",
+            columns,
+            quote(schema.table_name)
+        );
+
+        let row = self
+            .with(|tx| tx.query_opt(query.as_str(), &[&id.into_i64()]))
+            .map_err(|err| map_schema_error(err, schema))?
+            .ok_or_else(|| Error::NotFound(Box::new(NotFoundError::new(id, schema.type_name))))?;
+        read_row(&row, schema, 0)
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let mut columns = vec!["id".to_string()];
+        columns.extend(schema.columns.iter().map(|c| quote(c.column_name)));
+        let mut query = format!(
+            "SELECT {} FROM {}",
+            columns.join(", "),
+            quote(schema.table_name)
+        );
+        push_where(&mut query, where_sql, 1);
+
+        let params = to_params(params);
+        let rows = self
+            .with(|tx| tx.query(query.as_str(), &param_refs(&params)))
+            .map_err(|err| map_schema_error(err, schema))?;
+        for row in rows {
+            let id = ObjectId::from(row.get::<_, i64>(0));
+            f(id, read_row(&row, schema, 1)?)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let mut query = format!("SELECT COUNT(*) FROM {}", quote(schema.table_name));
+        push_where(&mut query, where_sql, 1);
+
+        let params = to_params(params);
+        let row = self
+            .with(|tx| tx.query_one(query.as_str(), &param_refs(&params)))
+            .map_err(|err| map_schema_error(err, schema))?;
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        let query = format!("DELETE FROM {} WHERE id = $1", quote(schema.table_name));
+        if self.execute_counted(&query, &[Value::Int64(id.into_i64())])? == 0 {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            ))));
+        }
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let mut query = format!("DELETE FROM {}", quote(schema.table_name));
+        push_where(&mut query, where_sql, 1);
+        self.execute_counted(&query, params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.execute_counted(sql, params)
+    }
+
+    // Applies to constraints declared DEFERRABLE only.
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        let mode = if deferred { "DEFERRED" } else { "IMMEDIATE" };
+        self.with(|tx| tx.batch_execute(&format!("SET CONSTRAINTS ALL {}", mode)))
+    }
+
+    fn commit(&self) -> Result<()> {
+        match self.inner.borrow_mut().take() {
+            Some(tx) => Ok(tx.commit()?),
+            None => Err(Error::Storage("transaction is already finished".into())),
+        }
+    }
+
+    fn rollback(&self) -> Result<()> {
+        match self.inner.borrow_mut().take() {
+            Some(tx) => Ok(tx.rollback()?),
+            None => Err(Error::Storage("transaction is already finished".into())),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl From<::postgres::Error> for Error {
+    fn from(err: ::postgres::Error) -> Self {
+        match err.code() {
+            Some(code)
+                if *code == SqlState::T_R_SERIALIZATION_FAILURE
+                    || *code == SqlState::T_R_DEADLOCK_DETECTED
+                    || *code == SqlState::LOCK_NOT_AVAILABLE =>
+            {
+                Error::LockConflict
+            }
+            Some(code) if code.code().starts_with("23") => {
+                let kind = match *code {
+                    SqlState::FOREIGN_KEY_VIOLATION => ConstraintKind::ForeignKey,
+                    SqlState::UNIQUE_VIOLATION => ConstraintKind::Unique,
+                    SqlState::NOT_NULL_VIOLATION => ConstraintKind::NotNull,
+                    SqlState::CHECK_VIOLATION => ConstraintKind::Check,
+                    _ => ConstraintKind::Other,
+                };
+                let error = match err.as_db_error() {
+                    Some(db_error) => ConstraintError::new(kind, db_error.message().to_string())
+                        .with_location(
+                            db_error.table().map(str::to_string),
+                            db_error.column().map(str::to_string).into_iter().collect(),
+                        ),
+                    None => ConstraintError::new(kind, err.to_string()),
+                };
+                Error::Constraint(Box::new(error))
+            }
+            _ => Error::Storage(Box::new(err)),
+        }
+    }
+}
+
+fn map_schema_error(err: Error, schema: &Schema) -> Error {
+    let db_error = match &err {
+        Error::Storage(source) => source
+            .downcast_ref::<::postgres::Error>()
+            .and_then(|err| err.as_db_error()),
+        _ => None,
+    };
+    let missing = match db_error {
+        Some(db_error) if *db_error.code() == SqlState::UNDEFINED_COLUMN => {
+            db_error.message().split('"').nth(1)
+        }
+        _ => None,
+    };
+    missing
+        .and_then(|name| MissingColumnError::from_column_name(name, schema))
+        .unwrap_or(err)
+}
+
+fn sql_type(typ: DataType) -> &'static str {
+    match typ {
+        DataType::String => "TEXT",
+        DataType::Bytes => "BYTEA",
+        DataType::Int64 => "BIGINT",
+        DataType::Float64 => "DOUBLE PRECISION",
+        DataType::Bool => "BOOLEAN",
+    }
+}
+
+fn column_list(columns: &[Column]) -> String {
+    columns
+        .iter()
+        .map(|c| quote(c.column_name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn assignment_list(columns: &[&str]) -> String {
+    columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{} = ${}", quote(c), i + 1))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn placeholders(first: usize, count: usize) -> String {
+    (first..first + count)
+        .map(|i| format!("${}", i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn push_where(query: &mut String, where_sql: &str, first: usize) {
+    if !where_sql.is_empty() {
+        query.push_str(" WHERE ");
+        query.push_str(&number_placeholders(where_sql, first));
+    }
+}
+
+// Predicates render SQLite-style `?` placeholders; PostgreSQL wants `$n`.
+fn number_placeholders(sql: &str, first: usize) -> String {
+    let mut result = String::with_capacity(sql.len());
+    let mut next = first;
+    let mut quote_char = None;
+    for ch in sql.chars() {
+        match (ch, quote_char) {
+            ('?', None) => {
+                result.push_str(&format!("${}", next));
+                next += 1;
+                continue;
+            }
+            ('\'' | '"', None) => quote_char = Some(ch),
+            (ch, Some(open)) if ch == open => quote_char = None,
+            _ => {}
+        }
+        result.push(ch);
+    }
+    result
+}
+
+fn to_params(values: &RowSlice) -> Vec<Box<dyn ToSql + Sync>> {
+    values
+        .iter()
+        .map(|value| -> Box<dyn ToSql + Sync> {
+            match value {
+                Value::String(s) => Box::new(s.to_string()),
+                Value::Bytes(b) => Box::new(b.to_vec()),
+                Value::Int64(i) => Box::new(*i),
+                Value::Float64(f) => Box::new(*f),
+                Value::Bool(b) => Box::new(*b),
+            }
+        })
+        .collect()
+}
+
+fn param_refs(params: &[Box<dyn ToSql + Sync>]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|param| param.as_ref()).collect()
+}
+
+fn read_row(row: &::postgres::Row, schema: &Schema, offset: usize) -> Result<Row<'static>> {
+    let mut line = Row::with_capacity(schema.columns.len());
+    for (i, column) in schema.columns.iter().enumerate() {
+        let index = i + offset;
+        let value = match column.typ {
+            DataType::String => read_value::<String>(row, index, schema, column)
+                .map(|s| Value::String(Cow::Owned(s))),
+            DataType::Bytes => read_value::<Vec<u8>>(row, index, schema, column)
+                .map(|b| Value::Bytes(Cow::Owned(b))),
+            DataType::Int64 => read_value::<i64>(row, index, schema, column).map(Value::Int64),
+            DataType::Float64 => read_value::<f64>(row, index, schema, column).map(Value::Float64),
+            DataType::Bool => read_value::<bool>(row, index, schema, column).map(Value::Bool),
+        }?;
+        line.push(value);
+    }
+    Ok(line)
+}
+
+fn read_value<T>(row: &::postgres::Row, index: usize, schema: &Schema, column: &Column) -> Result<T>
+where
+    T: for<'r> FromSql<'r>,
+{
+    let unexpected = |got_type: String| {
+        Error::UnexpectedType(Box::new(UnexpectedTypeError::new(
+            schema.type_name,
+            column.attr_name,
+            schema.table_name,
+            column.column_name,
+            column.typ,
+            got_type,
+        )))
+    };
+
+    let typ: &Type = row.columns()[index].type_();
+    if !T::accepts(typ) {
+        return Err(unexpected(typ.name().to_string()));
+    }
+    row.try_get::<_, Option<T>>(index)?
+        .ok_or_else(|| unexpected("Null".to_string()))
+}
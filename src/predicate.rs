@@ -0,0 +1,238 @@
+#![forbid(unsafe_code)]
+#[cfg(feature = "spatial")]
+use crate::spatial::{within_sql, BBox};
+use crate::{
+    data::Value,
+    error::{Error, Result},
+    object::Schema,
+    storage::Row,
+};
+use std::{fmt, ops};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "<>",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        };
+        formatter.write_str(op)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    Compare(&'static str, CompareOp, Value<'static>),
+    IsNull(&'static str),
+    IsNotNull(&'static str),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+    // Geometries of the column that lie within the box, edges included.
+    #[cfg(feature = "spatial")]
+    WithinBBox(&'static str, BBox),
+}
+
+impl Predicate {
+    pub fn and(self, other: Predicate) -> Self {
+        Predicate::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Predicate) -> Self {
+        Predicate::Or(Box::new(self), Box::new(other))
+    }
+
+    pub fn to_sql(&self) -> (String, Row<'static>) {
+        let mut sql = String::new();
+        let mut params = Row::new();
+        self.write_sql(&mut sql, &mut params, &|_| None);
+        (sql, params)
+    }
+
+    // With the spatial index of each column that has one.
+    #[cfg(feature = "spatial")]
+    pub(crate) fn to_sql_with(
+        &self,
+        index: &dyn Fn(&str) -> Option<String>,
+    ) -> (String, Row<'static>) {
+        let mut sql = String::new();
+        let mut params = Row::new();
+        self.write_sql(&mut sql, &mut params, index);
+        (sql, params)
+    }
+
+    pub(crate) fn columns(&self) -> Vec<&'static str> {
+        match self {
+            Predicate::Compare(column, ..)
+            | Predicate::IsNull(column)
+            | Predicate::IsNotNull(column) => {
+                vec![*column]
+            }
+            #[cfg(feature = "spatial")]
+            Predicate::WithinBBox(column, _) => vec![*column],
+            Predicate::And(lhs, rhs) | Predicate::Or(lhs, rhs) => {
+                let mut columns = lhs.columns();
+                columns.extend(rhs.columns());
+                columns
+            }
+            Predicate::Not(inner) => inner.columns(),
+        }
+    }
+
+    // Names are quoted, and SQLite reads a quoted name that matches no column
+    // as a string, so a misspelt column would match every row instead of
+    // failing. Predicates are checked against the schema before they run.
+    pub(crate) fn check_columns(&self, schema: &Schema) -> Result<()> {
+        match self
+            .columns()
+            .into_iter()
+            .find(|column| *column != "id" && schema.column(column).is_none())
+        {
+            Some(unknown) => Err(Error::Storage(
+                format!("{} has no column {}", schema.table_name, unknown).into(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // The columns of the WithinBBox predicates.
+    #[cfg(feature = "spatial")]
+    pub(crate) fn geometry_columns(&self) -> Vec<&'static str> {
+        match self {
+            #[cfg(feature = "spatial")]
+            Predicate::WithinBBox(column, _) => vec![*column],
+            Predicate::And(lhs, rhs) | Predicate::Or(lhs, rhs) => {
+                let mut columns = lhs.geometry_columns();
+                columns.extend(rhs.geometry_columns());
+                columns
+            }
+            Predicate::Not(inner) => inner.geometry_columns(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[cfg_attr(not(feature = "spatial"), allow(clippy::only_used_in_recursion))]
+    fn write_sql(
+        &self,
+        sql: &mut String,
+        params: &mut Row<'static>,
+        index: &dyn Fn(&str) -> Option<String>,
+    ) {
+        match self {
+            Predicate::Compare(column, op, value) => {
+                sql.push_str(&format!("{} {} ?", quote(column), op));
+                params.push(value.clone());
+            }
+            Predicate::IsNull(column) => sql.push_str(&format!("{} IS NULL", quote(column))),
+            Predicate::IsNotNull(column) => sql.push_str(&format!("{} IS NOT NULL", quote(column))),
+            Predicate::And(lhs, rhs) => {
+                sql.push('(');
+                lhs.write_sql(sql, params, index);
+                sql.push_str(" AND ");
+                rhs.write_sql(sql, params, index);
+                sql.push(')');
+            }
+            Predicate::Or(lhs, rhs) => {
+                sql.push('(');
+                lhs.write_sql(sql, params, index);
+                sql.push_str(" OR ");
+                rhs.write_sql(sql, params, index);
+                sql.push(')');
+            }
+            Predicate::Not(inner) => {
+                sql.push_str("NOT (");
+                inner.write_sql(sql, params, index);
+                sql.push(')');
+            }
+            #[cfg(feature = "spatial")]
+            Predicate::WithinBBox(column, bbox) => {
+                within_sql(column, bbox, index(column).as_deref(), sql, params)
+            }
+        }
+    }
+}
+
+impl ops::Not for Predicate {
+    type Output = Predicate;
+
+    fn not(self) -> Self::Output {
+        Predicate::Not(Box::new(self))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug)]
+pub struct ColumnRef(&'static str);
+
+pub fn column(name: &'static str) -> ColumnRef {
+    ColumnRef(name)
+}
+
+impl ColumnRef {
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+
+    fn compare<'a>(self, op: CompareOp, value: impl Into<Value<'a>>) -> Predicate {
+        Predicate::Compare(self.0, op, value.into().into_owned())
+    }
+
+    pub fn eq<'a>(self, value: impl Into<Value<'a>>) -> Predicate {
+        self.compare(CompareOp::Eq, value)
+    }
+
+    pub fn ne<'a>(self, value: impl Into<Value<'a>>) -> Predicate {
+        self.compare(CompareOp::Ne, value)
+    }
+
+    pub fn lt<'a>(self, value: impl Into<Value<'a>>) -> Predicate {
+        self.compare(CompareOp::Lt, value)
+    }
+
+    pub fn le<'a>(self, value: impl Into<Value<'a>>) -> Predicate {
+        self.compare(CompareOp::Le, value)
+    }
+
+    pub fn gt<'a>(self, value: impl Into<Value<'a>>) -> Predicate {
+        self.compare(CompareOp::Gt, value)
+    }
+
+    pub fn ge<'a>(self, value: impl Into<Value<'a>>) -> Predicate {
+        self.compare(CompareOp::Ge, value)
+    }
+
+    pub fn is_null(self) -> Predicate {
+        Predicate::IsNull(self.0)
+    }
+
+    pub fn is_not_null(self) -> Predicate {
+        Predicate::IsNotNull(self.0)
+    }
+
+    #[cfg(feature = "spatial")]
+    pub fn within_bbox(self, bbox: BBox) -> Predicate {
+        Predicate::WithinBBox(self.0, bbox)
+    }
+}
+
+pub(crate) fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
@@ -0,0 +1,393 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::Result,
+    instrument::{SharedInstrument, SlowQueryLog, SqlTraceHook},
+    object::{Column, Schema},
+    storage::{
+        BackupProgress, ChangeHook, ChangeOp, Checkpoint, CheckpointMode, IntegrityReport,
+        OnConflict, Row, RowSlice, ScalarFunction, StatementCacheStats, StorageConnection,
+        StorageTransaction, TableInfo, TransactionBehavior,
+    },
+    ObjectId,
+};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Stores every table as "<prefix><table>", so that several applications can
+// share one database. The ORM only ever sees the unprefixed names; SQL passed
+// to Transaction::execute is not rewritten.
+pub struct PrefixedStorage<S> {
+    inner: S,
+    prefix: String,
+}
+
+impl<S: StorageConnection> PrefixedStorage<S> {
+    pub fn new<P: Into<String>>(inner: S, prefix: P) -> Self {
+        Self {
+            inner,
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+impl<S: StorageConnection> StorageConnection for PrefixedStorage<S> {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let inner = self.inner.new_transaction()?;
+        Ok(with_prefix(inner, self.prefix.clone()))
+    }
+
+    fn new_snapshot(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let inner = self.inner.new_snapshot()?;
+        Ok(with_prefix(inner, self.prefix.clone()))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        self.inner.total_changes()
+    }
+
+    fn new_transaction_with(
+        &mut self,
+        behavior: TransactionBehavior,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        let inner = self.inner.new_transaction_with(behavior)?;
+        Ok(with_prefix(inner, self.prefix.clone()))
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.inner.ping()
+    }
+
+    fn attach(&mut self, path: &Path, alias: &str) -> Result<()> {
+        self.inner.attach(path, alias)
+    }
+
+    fn detach(&mut self, alias: &str) -> Result<()> {
+        self.inner.detach(alias)
+    }
+
+    fn backup_to(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()> {
+        self.inner.backup_to(path, progress)
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        self.inner.vacuum()
+    }
+
+    fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> Result<()> {
+        self.inner.incremental_vacuum(max_pages)
+    }
+
+    fn optimize(&mut self) -> Result<()> {
+        self.inner.optimize()
+    }
+
+    fn analyze(&mut self) -> Result<()> {
+        self.inner.analyze()
+    }
+
+    fn checkpoint(&mut self, mode: CheckpointMode) -> Result<Checkpoint> {
+        self.inner.checkpoint(mode)
+    }
+
+    fn statement_cache_stats(&self) -> Option<StatementCacheStats> {
+        self.inner.statement_cache_stats()
+    }
+
+    fn integrity_check(&mut self, quick: bool) -> Result<IntegrityReport> {
+        self.inner.integrity_check(quick)
+    }
+
+    fn create_scalar_function(&mut self, name: &str, function: ScalarFunction) -> Result<()> {
+        self.inner.create_scalar_function(name, function)
+    }
+
+    fn set_foreign_keys(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_foreign_keys(enabled)
+    }
+
+    // Writes to tables without the prefix are reported under their full name.
+    fn set_change_hook(&mut self, hook: Option<ChangeHook>) -> Result<()> {
+        let hook = hook.map(|mut hook| {
+            let prefix = self.prefix.clone();
+            Box::new(move |table: &str, op: ChangeOp, id: ObjectId| {
+                hook(table.strip_prefix(&*prefix).unwrap_or(table), op, id)
+            }) as ChangeHook
+        });
+        self.inner.set_change_hook(hook)
+    }
+
+    fn set_statement_log(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_statement_log(enabled)
+    }
+
+    fn set_instrument(&mut self, instrument: Option<SharedInstrument>) -> Result<()> {
+        self.inner.set_instrument(instrument)
+    }
+
+    fn set_slow_query_log(&mut self, log: Option<SlowQueryLog>) -> Result<()> {
+        self.inner.set_slow_query_log(log)
+    }
+
+    fn set_sql_trace(&mut self, hook: Option<SqlTraceHook>) -> Result<()> {
+        self.inner.set_sql_trace(hook)
+    }
+
+    fn set_bulk_load(&mut self, enabled: bool) -> Result<()> {
+        self.inner.set_bulk_load(enabled)
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    fn rekey(&mut self, key: &str) -> Result<()> {
+        self.inner.rekey(key)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn with_prefix<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+    prefix: String,
+) -> Box<dyn StorageTransaction + 'a> {
+    Box::new(PrefixedTransaction { inner, prefix })
+}
+
+struct PrefixedTransaction<'a> {
+    inner: Box<dyn StorageTransaction + 'a>,
+    prefix: String,
+}
+
+impl<'a> PrefixedTransaction<'a> {
+    fn table(&self, table: &str) -> &'static str {
+        prefixed(&self.prefix, table)
+    }
+
+    fn schema(&self, schema: &Schema) -> Schema {
+        Schema {
+            table_name: self.table(schema.table_name),
+            ..*schema
+        }
+    }
+}
+
+impl<'a> StorageTransaction for PrefixedTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.inner.table_exists(self.table(table))
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.create_table(&self.schema(schema))
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.drop_table(&self.schema(schema))
+    }
+
+    // Tables of other applications are left out.
+    fn table_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .inner
+            .table_names()?
+            .iter()
+            .filter_map(|table| table.strip_prefix(&*self.prefix).map(str::to_string))
+            .collect())
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        let mut info = self.inner.table_info(self.table(table))?;
+        info.name = table.to_string();
+        Ok(info)
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        self.inner.add_column(&self.schema(schema), column)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_table(self.table(from), self.table(to))
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_column(self.table(table), from, to)
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.inner.drop_column(self.table(table), column)
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        self.inner.clear_table(&self.schema(schema))
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        self.inner
+            .insert_row_with(&self.schema(schema), row, on_conflict)
+    }
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        self.inner.insert_row(&self.schema(schema), row)
+    }
+
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        self.inner.insert_rows(&self.schema(schema), rows)
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        self.inner.insert_row_with_id(id, &self.schema(schema), row)
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        self.inner.update_row(id, &self.schema(schema), row)
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        self.inner
+            .update_where(&self.schema(schema), columns, values, where_sql, params)
+    }
+
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        self.inner.update_rows(&self.schema(schema), columns, rows)
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        self.inner.select_row(id, &self.schema(schema))
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        self.inner
+            .for_each_row(&self.schema(schema), where_sql, params, f)
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        self.inner
+            .select_rows(&self.schema(schema), where_sql, params)
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        self.inner
+            .for_each_row_ref(&self.schema(schema), where_sql, params, f)
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner
+            .count_rows(&self.schema(schema), where_sql, params)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.inner.delete_row(id, &self.schema(schema))
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner
+            .delete_where(&self.schema(schema), where_sql, params)
+    }
+
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        self.inner.delete_rows(&self.schema(schema), ids)
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner.execute(sql, params)
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.inner.savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.release_savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.rollback_to_savepoint(name)
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.inner.commit()
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.inner.rollback()
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.inner.commit_and_continue()
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        self.inner.take_statement_log()
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Tables of an attached database keep their "<alias>." in front of the
+// prefix. Names are built once and kept for the life of the process, as
+// Schema::table_name is static.
+fn prefixed(prefix: &str, table: &str) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashMap<(String, String), &'static str>>> = OnceLock::new();
+    let mut names = NAMES
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    names
+        .entry((prefix.to_string(), table.to_string()))
+        .or_insert_with(|| {
+            let name = match table.split_once('.') {
+                Some((alias, table)) => format!("{}.{}{}", alias, prefix, table),
+                None => format!("{}{}", prefix, table),
+            };
+            Box::leak(name.into_boxed_str())
+        })
+}
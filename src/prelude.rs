@@ -0,0 +1,10 @@
+#![forbid(unsafe_code)]
+
+// What most code needs, in one import: `use orm::prelude::*;`. The name
+// Object brings both the trait and, with the derive feature, the derive and
+// its attributes. Generated code finds the crate on its own, under whatever
+// name it is a dependency.
+pub use crate::{
+    column, object::Hooks, Connection, Database, Error, Id, Object, ObjectId, Predicate, Result,
+    Transaction, Tx,
+};
@@ -0,0 +1,101 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::Result, object::Object, predicate::Predicate, transaction::Transaction, Id, ObjectId, Tx,
+};
+use std::{cell::Ref, collections::HashMap, hash::Hash};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A transaction that can only read. Objects are handed out as ReadTx, which
+// has no borrow_mut or delete, and the transaction is rolled back when
+// closed or dropped, so nothing done through it can reach storage.
+pub struct ReadTransaction<'a> {
+    tx: Transaction<'a>,
+}
+
+impl<'a> ReadTransaction<'a> {
+    pub(crate) fn new(tx: Transaction<'a>) -> Self {
+        Self { tx }
+    }
+
+    pub(crate) fn transaction(&self) -> &Transaction<'a> {
+        &self.tx
+    }
+
+    pub fn get<T: Object>(&self, id: impl Into<Id<T>>) -> Result<ReadTx<'_, T>> {
+        self.tx.get(id).map(ReadTx)
+    }
+
+    pub fn try_get<T: Object>(&self, id: impl Into<Id<T>>) -> Result<Option<ReadTx<'_, T>>> {
+        Ok(self.tx.try_get(id)?.map(ReadTx))
+    }
+
+    pub fn select<T: Object>(&self, predicate: &Predicate) -> Result<Vec<ReadTx<'_, T>>> {
+        Ok(self.tx.select(predicate)?.into_iter().map(ReadTx).collect())
+    }
+
+    pub fn select_all<T: Object>(&self) -> Result<Vec<ReadTx<'_, T>>> {
+        Ok(self.tx.select_all()?.into_iter().map(ReadTx).collect())
+    }
+
+    pub fn select_map<T: Object>(
+        &self,
+        predicate: &Predicate,
+    ) -> Result<HashMap<ObjectId, ReadTx<'_, T>>> {
+        Ok(self
+            .tx
+            .select_map(predicate)?
+            .into_iter()
+            .map(|(id, obj)| (id, ReadTx(obj)))
+            .collect())
+    }
+
+    pub fn select_map_by<T: Object, K: Eq + Hash>(
+        &self,
+        predicate: &Predicate,
+        key: impl Fn(&T) -> K,
+    ) -> Result<HashMap<K, ReadTx<'_, T>>> {
+        Ok(self
+            .tx
+            .select_map_by(predicate, key)?
+            .into_iter()
+            .map(|(key, obj)| (key, ReadTx(obj)))
+            .collect())
+    }
+
+    pub fn count_where<T: Object>(&self, predicate: &Predicate) -> Result<usize> {
+        self.tx.count_where::<T>(predicate)
+    }
+
+    pub fn close(self) -> Result<()> {
+        self.tx.rollback()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct ReadTx<'a, T>(Tx<'a, T>);
+
+impl<'a, T> Clone for ReadTx<'a, T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<'a, T: Object> ReadTx<'a, T> {
+    pub fn id(&self) -> Id<T> {
+        self.0.id()
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>> {
+        self.0.try_borrow()
+    }
+
+    pub fn into_owned(self) -> T {
+        self.0.into_owned()
+    }
+}
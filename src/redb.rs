@@ -0,0 +1,499 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{Error, NotFoundError, Result},
+    memory::{new_row, position, positions, read_row, Filter},
+    object::Schema,
+    storage::{unsupported, OnConflict, Row, RowSlice, StorageConnection, StorageTransaction},
+    Connection, ObjectId,
+};
+use ::redb::{ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    path::Path,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Column layout and the next id of every table, keyed by table name.
+const TABLES: TableDefinition<&str, &[u8]> = TableDefinition::new("orm_tables");
+
+pub struct RedbStorage {
+    db: ::redb::Database,
+    changes: Cell<usize>,
+}
+
+impl RedbStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            db: ::redb::Database::create(path)?,
+            changes: Cell::new(0),
+        })
+    }
+}
+
+impl StorageConnection for RedbStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(RedbTransaction {
+            inner: RefCell::new(Some(self.db.begin_write()?)),
+            pending: Cell::new(0),
+            changes: &self.changes,
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        Ok(self.changes.get())
+    }
+}
+
+impl Connection {
+    pub fn open_redb_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_storage(RedbStorage::open(path)?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct RedbTransaction<'a> {
+    inner: RefCell<Option<WriteTransaction>>,
+    pending: Cell<usize>,
+    changes: &'a Cell<usize>,
+}
+
+struct TableInfo {
+    columns: Vec<(String, DataType)>,
+    next_id: i64,
+}
+
+impl<'a> RedbTransaction<'a> {
+    fn with<R>(&self, f: impl FnOnce(&WriteTransaction) -> Result<R>) -> Result<R> {
+        let inner = self.inner.borrow();
+        f(inner.as_ref().ok_or_else(finished)?)
+    }
+
+    fn record_changes(&self, changes: usize) -> usize {
+        self.pending.set(self.pending.get() + changes);
+        changes
+    }
+
+    fn table_info(&self, tx: &WriteTransaction, table_name: &str) -> Result<TableInfo> {
+        let tables = tx.open_table(TABLES)?;
+        let encoded = tables
+            .get(table_name)?
+            .ok_or_else(|| no_such_table(table_name))?;
+        let mut values = decode_row(encoded.value())?.into_iter();
+
+        let next_id = match values.next() {
+            Some(Value::Int64(next_id)) => next_id,
+            _ => return Err(corrupted()),
+        };
+        let mut columns = Vec::new();
+        while let Some(name) = values.next() {
+            match (name, values.next()) {
+                (Value::String(name), Some(Value::Int64(tag))) => {
+                    columns.push((name.into_owned(), data_type(tag as u8)?))
+                }
+                _ => return Err(corrupted()),
+            }
+        }
+        Ok(TableInfo { columns, next_id })
+    }
+
+    fn store_table_info(
+        &self,
+        tx: &WriteTransaction,
+        table_name: &str,
+        info: &TableInfo,
+    ) -> Result<()> {
+        let mut values = vec![Value::Int64(info.next_id)];
+        for (name, typ) in &info.columns {
+            values.push(Value::String(Cow::Borrowed(name)));
+            values.push(Value::Int64(*typ as i64));
+        }
+        tx.open_table(TABLES)?
+            .insert(table_name, encode_row(&values).as_slice())?;
+        Ok(())
+    }
+
+    // Decoded rows matching the where clause, in id order.
+    fn scan(
+        &self,
+        tx: &WriteTransaction,
+        schema: &Schema,
+        info: &TableInfo,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(i64, Row<'static>)>> {
+        let filter = Filter::parse(where_sql, params, &info.columns, schema)?;
+        let name = rows_table(schema.table_name);
+        let table = tx.open_table(rows_definition(&name))?;
+
+        let mut rows = Vec::new();
+        for entry in table.iter()? {
+            let (id, encoded) = entry?;
+            let stored = decode_row(encoded.value())?;
+            if filter.matches(id.value(), &stored) {
+                rows.push((id.value(), stored));
+            }
+        }
+        Ok(rows)
+    }
+
+    fn store_rows(
+        &self,
+        tx: &WriteTransaction,
+        table_name: &str,
+        rows: &[(i64, Row<'static>)],
+    ) -> Result<()> {
+        let name = rows_table(table_name);
+        let mut table = tx.open_table(rows_definition(&name))?;
+        for (id, stored) in rows {
+            table.insert(*id, encode_row(stored).as_slice())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> StorageTransaction for RedbTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.with(|tx| Ok(tx.open_table(TABLES)?.get(table)?.is_some()))
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        if self.table_exists(schema.table_name)? {
+            return Err(Error::Storage(
+                format!("table {} already exists", schema.table_name).into(),
+            ));
+        }
+        self.with(|tx| {
+            let info = TableInfo {
+                columns: schema
+                    .columns
+                    .iter()
+                    .map(|c| (c.column_name.to_string(), c.typ))
+                    .collect(),
+                next_id: 1,
+            };
+            self.store_table_info(tx, schema.table_name, &info)?;
+            let name = rows_table(schema.table_name);
+            tx.open_table(rows_definition(&name))?;
+            Ok(())
+        })
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.with(|tx| {
+            if tx.open_table(TABLES)?.remove(schema.table_name)?.is_none() {
+                return Err(no_such_table(schema.table_name));
+            }
+            let name = rows_table(schema.table_name);
+            tx.delete_table(rows_definition(&name))?;
+            Ok(())
+        })
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes = self.with(|tx| {
+            let mut info = self.table_info(tx, schema.table_name)?;
+            let name = rows_table(schema.table_name);
+            let changes = tx.open_table(rows_definition(&name))?.len()? as usize;
+            tx.delete_table(rows_definition(&name))?;
+            tx.open_table(rows_definition(&name))?;
+            info.next_id = 1;
+            self.store_table_info(tx, schema.table_name, &info)?;
+            Ok(changes)
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    // Key-value tables carry no constraints, so every insert succeeds; the
+    // strategies for conflicts fail as with memory storage.
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        if on_conflict != OnConflict::Abort {
+            return Err(unsupported("ignoring or replacing conflicting rows"));
+        }
+        let id = self.with(|tx| {
+            let mut info = self.table_info(tx, schema.table_name)?;
+            let positions = positions(&info.columns, schema)?;
+            let id = info.next_id;
+            let stored = new_row(&info.columns, &positions, row);
+            self.store_rows(tx, schema.table_name, &[(id, stored)])?;
+            info.next_id += 1;
+            self.store_table_info(tx, schema.table_name, &info)?;
+            Ok(id)
+        })?;
+        self.record_changes(1);
+        Ok(Some(ObjectId::from(id)))
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        let changes = self.with(|tx| {
+            let info = self.table_info(tx, schema.table_name)?;
+            let positions = positions(&info.columns, schema)?;
+            let name = rows_table(schema.table_name);
+            let stored = match tx.open_table(rows_definition(&name))?.get(id.into_i64())? {
+                Some(encoded) => decode_row(encoded.value())?,
+                None => return Ok(0),
+            };
+
+            let mut stored = stored;
+            for (&position, value) in positions.iter().zip(row) {
+                stored[position] = value.clone().into_owned();
+            }
+            self.store_rows(tx, schema.table_name, &[(id.into_i64(), stored)])?;
+            Ok(1)
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        let changes = self.with(|tx| {
+            let info = self.table_info(tx, schema.table_name)?;
+            let targets = columns
+                .iter()
+                .map(|name| position(&info.columns, name, schema))
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut rows = self.scan(tx, schema, &info, where_sql, params)?;
+            for (_, stored) in rows.iter_mut() {
+                for (&position, value) in targets.iter().zip(values) {
+                    stored[position] = value.clone().into_owned();
+                }
+            }
+            self.store_rows(tx, schema.table_name, &rows)?;
+            Ok(rows.len())
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        self.with(|tx| {
+            let info = self.table_info(tx, schema.table_name)?;
+            let positions = positions(&info.columns, schema)?;
+            let name = rows_table(schema.table_name);
+            match tx.open_table(rows_definition(&name))?.get(id.into_i64())? {
+                Some(encoded) => read_row(&decode_row(encoded.value())?, &positions, schema),
+                None => Err(Error::NotFound(Box::new(NotFoundError::new(
+                    id,
+                    schema.type_name,
+                )))),
+            }
+        })
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let rows = self.with(|tx| {
+            let info = self.table_info(tx, schema.table_name)?;
+            let positions = positions(&info.columns, schema)?;
+            self.scan(tx, schema, &info, where_sql, params)?
+                .into_iter()
+                .map(|(id, stored)| {
+                    Ok((ObjectId::from(id), read_row(&stored, &positions, schema)?))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        for (id, row) in rows {
+            f(id, row)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.with(|tx| {
+            let info = self.table_info(tx, schema.table_name)?;
+            Ok(self.scan(tx, schema, &info, where_sql, params)?.len())
+        })
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.with(|tx| {
+            self.table_info(tx, schema.table_name)?;
+            let name = rows_table(schema.table_name);
+            let mut table = tx.open_table(rows_definition(&name))?;
+            let removed = table.remove(id.into_i64())?.is_some();
+            if !removed {
+                return Err(Error::NotFound(Box::new(NotFoundError::new(
+                    id,
+                    schema.type_name,
+                ))));
+            }
+            Ok(())
+        })?;
+        self.record_changes(1);
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let changes = self.with(|tx| {
+            let info = self.table_info(tx, schema.table_name)?;
+            let rows = self.scan(tx, schema, &info, where_sql, params)?;
+            let name = rows_table(schema.table_name);
+            let mut table = tx.open_table(rows_definition(&name))?;
+            for (id, _) in &rows {
+                table.remove(*id)?;
+            }
+            Ok(rows.len())
+        })?;
+        Ok(self.record_changes(changes))
+    }
+
+    fn execute(&self, _sql: &str, _params: &RowSlice) -> Result<usize> {
+        Err(Error::Storage(
+            "raw SQL is not supported by the redb backend".into(),
+        ))
+    }
+
+    fn commit(&self) -> Result<()> {
+        let tx = self.inner.borrow_mut().take().ok_or_else(finished)?;
+        tx.commit()?;
+        self.changes.set(self.changes.get() + self.pending.get());
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        let tx = self.inner.borrow_mut().take().ok_or_else(finished)?;
+        tx.abort()?;
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+macro_rules! impl_from_redb_error {
+    ($($err:ty),*) => {
+        $(
+            impl From<$err> for Error {
+                fn from(err: $err) -> Self {
+                    Error::Storage(Box::new(::redb::Error::from(err)))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_redb_error!(
+    ::redb::DatabaseError,
+    ::redb::TransactionError,
+    ::redb::TableError,
+    ::redb::StorageError,
+    ::redb::CommitError
+);
+
+fn rows_table(table_name: &str) -> String {
+    format!("orm_rows:{}", table_name)
+}
+
+fn rows_definition(name: &str) -> TableDefinition<'_, i64, &'static [u8]> {
+    TableDefinition::new(name)
+}
+
+fn finished() -> Error {
+    Error::Storage("transaction is already finished".into())
+}
+
+fn no_such_table(table_name: &str) -> Error {
+    Error::Storage(format!("no such table: {}", table_name).into())
+}
+
+fn corrupted() -> Error {
+    Error::Storage("corrupted row in redb storage".into())
+}
+
+fn data_type(tag: u8) -> Result<DataType> {
+    Ok(match tag {
+        0 => DataType::String,
+        1 => DataType::Bytes,
+        2 => DataType::Int64,
+        3 => DataType::Float64,
+        4 => DataType::Bool,
+        _ => return Err(corrupted()),
+    })
+}
+
+// Each value is a type tag followed by 8 little-endian bytes for numbers,
+// one byte for booleans, or a u32 length and the raw bytes otherwise.
+fn encode_row(row: &RowSlice) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for value in row {
+        match value {
+            Value::String(s) => {
+                bytes.push(DataType::String as u8);
+                bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(s.as_bytes());
+            }
+            Value::Bytes(b) => {
+                bytes.push(DataType::Bytes as u8);
+                bytes.extend_from_slice(&(b.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(b);
+            }
+            Value::Int64(i) => {
+                bytes.push(DataType::Int64 as u8);
+                bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::Float64(f) => {
+                bytes.push(DataType::Float64 as u8);
+                bytes.extend_from_slice(&f.to_le_bytes());
+            }
+            Value::Bool(b) => {
+                bytes.push(DataType::Bool as u8);
+                bytes.push(*b as u8);
+            }
+        }
+    }
+    bytes
+}
+
+fn decode_row(mut bytes: &[u8]) -> Result<Row<'static>> {
+    fn take<'b>(bytes: &mut &'b [u8], len: usize) -> Result<&'b [u8]> {
+        if bytes.len() < len {
+            return Err(corrupted());
+        }
+        let (head, tail) = bytes.split_at(len);
+        *bytes = tail;
+        Ok(head)
+    }
+    fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+        Ok(take(bytes, N)?.try_into().unwrap())
+    }
+
+    let mut row = Row::new();
+    while let Some((&tag, rest)) = bytes.split_first() {
+        bytes = rest;
+        let value = match data_type(tag)? {
+            DataType::String => {
+                let len = u32::from_le_bytes(take_array(&mut bytes)?) as usize;
+                let s =
+                    String::from_utf8(take(&mut bytes, len)?.to_vec()).map_err(|_| corrupted())?;
+                Value::String(Cow::Owned(s))
+            }
+            DataType::Bytes => {
+                let len = u32::from_le_bytes(take_array(&mut bytes)?) as usize;
+                Value::Bytes(Cow::Owned(take(&mut bytes, len)?.to_vec()))
+            }
+            DataType::Int64 => Value::Int64(i64::from_le_bytes(take_array(&mut bytes)?)),
+            DataType::Float64 => Value::Float64(f64::from_le_bytes(take_array(&mut bytes)?)),
+            DataType::Bool => Value::Bool(take(&mut bytes, 1)?[0] != 0),
+        };
+        row.push(value);
+    }
+    Ok(row)
+}
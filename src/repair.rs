@@ -0,0 +1,169 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    dynamic::DynamicSchema,
+    error::{Error, Mismatch, Result},
+    memory::default_value,
+    object::{Object, Schema},
+    Transaction,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// How Transaction::repair_schema reconciles a stored table with the schema
+// of its type, for applications upgraded across many versions at once.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RepairPolicy {
+    // Nothing is altered.
+    ReportOnly,
+    // Missing columns are added; columns of another type are left as they
+    // are.
+    AddColumns,
+    // As AddColumns, but with columns of another type the table is
+    // recreated and its rows copied over with their ids, each value
+    // converted to the declared type. Columns the type no longer declares
+    // are dropped with the old table.
+    Recreate,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SchemaRepair {
+    // The differences found, before any repair.
+    pub mismatches: Vec<Mismatch>,
+    pub added: Vec<&'static str>,
+    // Also set when the table was missing and has been created.
+    pub recreated: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn repair_schema<T: Object>(&self, policy: RepairPolicy) -> Result<SchemaRepair> {
+        let schema = T::TABLE;
+        let mut repair = SchemaRepair {
+            mismatches: self.schema_mismatches(schema)?,
+            ..SchemaRepair::default()
+        };
+        if policy == RepairPolicy::ReportOnly || repair.mismatches.is_empty() {
+            return Ok(repair);
+        }
+        self.ensure_writable_table(schema)?;
+        self.flush()?;
+        let retyped = repair
+            .mismatches
+            .iter()
+            .any(|m| matches!(m, Mismatch::Type { .. }));
+        if repair.mismatches.contains(&Mismatch::MissingTable) {
+            self.ensure_table::<T>()?;
+            repair.recreated = true;
+        } else if policy == RepairPolicy::Recreate && retyped {
+            self.recreate_with_rows(schema)?;
+            repair.recreated = true;
+        } else {
+            for column in schema.columns {
+                let missing = Mismatch::MissingColumn {
+                    column: column.column_name.to_string(),
+                };
+                if repair.mismatches.contains(&missing) {
+                    self.storage().add_column(schema, column)?;
+                    repair.added.push(column.column_name);
+                }
+            }
+            self.store_fingerprint(schema)?;
+        }
+        self.forget_checked(schema.table_name);
+        self.forget_all::<T>();
+        Ok(repair)
+    }
+
+    // The rows are read and written as stored, bypassing encryption and
+    // compression, so the values of such columns are copied as they are.
+    fn recreate_with_rows(&self, schema: &'static Schema) -> Result<()> {
+        let info = self.storage().table_info(schema.table_name)?;
+        let kept: Vec<_> = schema
+            .columns
+            .iter()
+            .filter_map(|column| {
+                let stored = info.column(column.column_name)?;
+                Some((column, stored.typ.unwrap_or(column.stored_type())))
+            })
+            .collect();
+        let old = kept
+            .iter()
+            .fold(
+                DynamicSchema::new(schema.table_name),
+                |old, (column, typ)| old.column(column.column_name, *typ),
+            )
+            .schema();
+        let new = schema
+            .columns
+            .iter()
+            .fold(DynamicSchema::new(schema.table_name), |new, column| {
+                new.column(column.column_name, column.stored_type())
+            })
+            .schema();
+
+        let mut rows = Vec::new();
+        for (id, row) in self.storage().select_rows(old, "", &[])? {
+            let mut values = kept.iter().zip(row);
+            let row = schema
+                .columns
+                .iter()
+                .map(|column| {
+                    let typ = column.stored_type();
+                    match values.next() {
+                        Some(((kept, _), value)) if kept.column_name == column.column_name => {
+                            convert(value, typ).ok_or_else(|| {
+                                Error::Storage(
+                                    format!(
+                                        "cannot convert {}.{} of row {} to {:?}",
+                                        schema.table_name,
+                                        column.column_name,
+                                        id.into_i64(),
+                                        typ
+                                    )
+                                    .into(),
+                                )
+                            })
+                        }
+                        _ => Ok(default_value(typ)),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            rows.push((id, row));
+        }
+
+        self.storage().drop_table(old)?;
+        self.create_table(schema)?;
+        for (id, row) in &rows {
+            self.storage().insert_row_with_id(*id, new, row)?;
+        }
+        self.store_fingerprint(schema)
+    }
+}
+
+fn convert(value: Value<'static>, typ: DataType) -> Option<Value<'static>> {
+    if value.data_type() == typ {
+        return Some(value);
+    }
+    match (value, typ) {
+        (Value::Int64(i), DataType::Float64) => Some(Value::from(i as f64)),
+        (Value::Float64(f), DataType::Int64) if f.fract() == 0. => Some(Value::from(f as i64)),
+        (Value::Int64(i), DataType::Bool) => Some(Value::from(i != 0)),
+        (Value::Bool(b), DataType::Int64) => Some(Value::from(b as i64)),
+        (Value::Bool(b), DataType::Float64) => Some(Value::from(b as i64 as f64)),
+        (Value::String(s), DataType::Int64) => s.trim().parse::<i64>().ok().map(Value::from),
+        (Value::String(s), DataType::Float64) => s.trim().parse::<f64>().ok().map(Value::from),
+        (Value::String(s), DataType::Bool) => match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => Some(Value::from(true)),
+            "false" | "0" => Some(Value::from(false)),
+            _ => None,
+        },
+        (Value::String(s), DataType::Bytes) => Some(Value::from(s.into_owned().into_bytes())),
+        (Value::Bytes(b), DataType::String) => {
+            String::from_utf8(b.into_owned()).ok().map(Value::from)
+        }
+        (Value::Int64(i), DataType::String) => Some(Value::from(i.to_string())),
+        (Value::Float64(f), DataType::String) => Some(Value::from(f.to_string())),
+        (Value::Bool(b), DataType::String) => Some(Value::from(b.to_string())),
+        _ => None,
+    }
+}
@@ -0,0 +1,395 @@
+#![forbid(unsafe_code)]
+use crate::{
+    audit::history_schema,
+    error::{Error, NotFoundError, Result},
+    object::{Column, Object, Schema},
+    predicate::{column, Predicate},
+    storage::{OnConflict, Row, RowSlice, StorageTransaction, TableInfo},
+    ObjectId,
+};
+use std::time::Instant;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Row filters every statement of a scoped transaction is held to, such as
+// `tenant_id = ?` or `deleted_at IS NULL`; see Connection::scoped_transaction.
+// A table is subject to the predicates added for its type and to those of
+// the columns it has, and the history of an audited type to those of its
+// type. Rows outside the scope are not found, counted, updated or deleted,
+// and a statement writing rows outside it fails and leaves nothing behind.
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    pub(crate) tables: Vec<(&'static str, Predicate)>,
+    pub(crate) columns: Vec<(&'static str, Predicate)>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn table<T: Object>(mut self, predicate: Predicate) -> Self {
+        if T::TABLE.audited {
+            let history = history_schema(T::TABLE);
+            self.tables.push((history.table_name, predicate.clone()));
+        }
+        self.tables.push((T::TABLE.table_name, predicate));
+        self
+    }
+
+    // For every table with the column, such as a tenant id.
+    pub fn tables_with(mut self, column: &'static str, predicate: Predicate) -> Self {
+        self.columns.push((column, predicate));
+        self
+    }
+
+    fn predicate(&self, schema: &Schema) -> Option<Predicate> {
+        let tables = self
+            .tables
+            .iter()
+            .filter(|(table, _)| *table == schema.table_name);
+        let columns = self
+            .columns
+            .iter()
+            .filter(|(column, _)| schema.column(column).is_some());
+        tables
+            .chain(columns)
+            .map(|(_, predicate)| predicate.clone())
+            .reduce(Predicate::and)
+    }
+}
+
+const SAVEPOINT: &str = "orm_scope_check";
+
+pub(crate) fn scoped<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+    scope: Scope,
+) -> Box<dyn StorageTransaction + 'a> {
+    Box::new(ScopedTransaction { inner, scope })
+}
+
+struct ScopedTransaction<'a> {
+    inner: Box<dyn StorageTransaction + 'a>,
+    scope: Scope,
+}
+
+impl<'a> ScopedTransaction<'a> {
+    // The where clause with the scope of the table added.
+    fn filter(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> (String, Row<'static>) {
+        let mut params: Row<'static> = params.iter().map(|v| v.clone().into_owned()).collect();
+        let (scope_sql, scope_params) = match self.scope.predicate(schema) {
+            Some(predicate) => predicate.to_sql(),
+            None => return (where_sql.to_string(), params),
+        };
+        params.extend(scope_params);
+        match where_sql.is_empty() {
+            true => (scope_sql, params),
+            false => (format!("({}) AND ({})", where_sql, scope_sql), params),
+        }
+    }
+
+    fn in_scope(&self, id: ObjectId, schema: &Schema) -> Result<bool> {
+        if self.scope.predicate(schema).is_none() {
+            return Ok(true);
+        }
+        let (where_sql, params) = column("id").eq(id.into_i64()).to_sql();
+        let (where_sql, params) = self.filter(schema, &where_sql, &params);
+        Ok(self.inner.count_rows(schema, &where_sql, &params)? > 0)
+    }
+
+    // Rows are written in a savepoint and checked as they are stored; the
+    // write is rolled back if one of them is outside the scope, so a caller
+    // going on after the error does not commit it.
+    fn checked<R>(
+        &self,
+        schema: &Schema,
+        write: impl FnOnce() -> Result<R>,
+        written: impl FnOnce(&R) -> Vec<ObjectId>,
+    ) -> Result<R> {
+        if self.scope.predicate(schema).is_none() {
+            return write();
+        }
+        self.inner.savepoint(SAVEPOINT)?;
+        let result = write().and_then(|result| {
+            for id in written(&result) {
+                self.check_written(id, schema)?;
+            }
+            Ok(result)
+        });
+        if result.is_err() {
+            self.inner.rollback_to_savepoint(SAVEPOINT)?;
+        }
+        self.inner.release_savepoint(SAVEPOINT)?;
+        result
+    }
+
+    fn check_written(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        match self.in_scope(id, schema)? {
+            true => Ok(()),
+            false => Err(Error::Storage(
+                format!(
+                    "row {} of {} is outside the scope of the transaction",
+                    id.into_i64(),
+                    schema.table_name
+                )
+                .into(),
+            )),
+        }
+    }
+}
+
+// SQL passed to execute is not filtered.
+impl<'a> StorageTransaction for ScopedTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.inner.table_exists(table)
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.create_table(schema)
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.inner.drop_table(schema)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        self.inner.table_names()
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        self.inner.table_info(table)
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        self.inner.add_column(schema, column)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_table(from, to)
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.inner.rename_column(table, from, to)
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.inner.drop_column(table, column)
+    }
+
+    // Only the rows in scope are removed.
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        self.delete_where(schema, "", &[])
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        self.checked(
+            schema,
+            || self.inner.insert_row_with(schema, row, on_conflict),
+            |id| id.iter().copied().collect(),
+        )
+    }
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        self.checked(
+            schema,
+            || self.inner.insert_row(schema, row),
+            |id| vec![*id],
+        )
+    }
+
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        self.checked(
+            schema,
+            || self.inner.insert_rows(schema, rows),
+            |ids| ids.clone(),
+        )
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        self.checked(
+            schema,
+            || self.inner.insert_row_with_id(id, schema, row),
+            |_| vec![id],
+        )
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        if !self.in_scope(id, schema)? {
+            return Ok(0);
+        }
+        self.checked(
+            schema,
+            || self.inner.update_row(id, schema, row),
+            |_| vec![id],
+        )
+    }
+
+    // Rows are checked again only if a column of the scope changes.
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        let (where_sql, params) = self.filter(schema, where_sql, params);
+        let rescoped = self
+            .scope
+            .predicate(schema)
+            .is_some_and(|predicate| predicate.columns().iter().any(|c| columns.contains(c)));
+        let ids: Vec<_> = match rescoped {
+            true => self
+                .inner
+                .select_rows(schema, &where_sql, &params)?
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect(),
+            false => Vec::new(),
+        };
+        self.checked(
+            schema,
+            || {
+                self.inner
+                    .update_where(schema, columns, values, &where_sql, &params)
+            },
+            |_| ids,
+        )
+    }
+
+    // Each row is updated as update_where of its id, which checks it.
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        let mut changes = 0;
+        for (id, values) in rows {
+            let (where_sql, params) = column("id").eq(id.into_i64()).to_sql();
+            changes += self.update_where(schema, columns, values, &where_sql, &params)?;
+        }
+        Ok(changes)
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        if self.scope.predicate(schema).is_none() {
+            return self.inner.select_row(id, schema);
+        }
+        let (where_sql, params) = column("id").eq(id.into_i64()).to_sql();
+        match self.select_rows(schema, &where_sql, &params)?.pop() {
+            Some((_, row)) => Ok(row),
+            None => Err(not_found(id, schema)),
+        }
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let (where_sql, params) = self.filter(schema, where_sql, params);
+        self.inner.for_each_row(schema, &where_sql, &params, f)
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let (where_sql, params) = self.filter(schema, where_sql, params);
+        self.inner.select_rows(schema, &where_sql, &params)
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        let (where_sql, params) = self.filter(schema, where_sql, params);
+        self.inner.for_each_row_ref(schema, &where_sql, &params, f)
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let (where_sql, params) = self.filter(schema, where_sql, params);
+        self.inner.count_rows(schema, &where_sql, &params)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        match self.in_scope(id, schema)? {
+            true => self.inner.delete_row(id, schema),
+            false => Err(not_found(id, schema)),
+        }
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let (where_sql, params) = self.filter(schema, where_sql, params);
+        self.inner.delete_where(schema, &where_sql, &params)
+    }
+
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        for id in ids {
+            self.delete_row(*id, schema)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner.execute(sql, params)
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.inner.savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.release_savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.inner.rollback_to_savepoint(name)
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.inner.commit()
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.inner.rollback()
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.inner.commit_and_continue()
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        self.inner.take_statement_log()
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+}
+
+fn not_found(id: ObjectId, schema: &Schema) -> Error {
+    Error::NotFound(Box::new(NotFoundError::new(id, schema.type_name)))
+}
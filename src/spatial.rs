@@ -0,0 +1,256 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, FromValue, ObjectType, Value},
+    error::{Error, Result},
+    object::Object,
+    predicate::quote,
+    storage::{Row, ScalarFunction},
+    Transaction,
+};
+use std::borrow::Cow;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Geometry columns are BLOBs of little-endian doubles: x and y for a point,
+// min x, min y, max x and max y for a box. The SQLite backend has an SQL
+// function for each bound, orm_min_x and so on, which the triggers of a
+// spatial index use; other tools writing to an indexed table need them too.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct BBox {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+}
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
+    }
+}
+
+impl BBox {
+    pub fn new(min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> Self {
+        Self {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+        }
+    }
+
+    // Edges included.
+    pub fn contains(&self, point: Point) -> bool {
+        self.contains_bbox(&point.into())
+    }
+
+    pub fn contains_bbox(&self, other: &BBox) -> bool {
+        self.min_x <= other.min_x
+            && self.min_y <= other.min_y
+            && other.max_x <= self.max_x
+            && other.max_y <= self.max_y
+    }
+
+    pub fn intersects(&self, other: &BBox) -> bool {
+        self.min_x <= other.max_x
+            && other.min_x <= self.max_x
+            && self.min_y <= other.max_y
+            && other.min_y <= self.max_y
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [self.min_x, self.min_y, self.max_x, self.max_y]
+            .into_iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect()
+    }
+}
+
+impl From<Point> for BBox {
+    fn from(point: Point) -> Self {
+        Self::new(point.x, point.y, point.x, point.y)
+    }
+}
+
+fn read_f64s<const N: usize>(bytes: &[u8]) -> Option<[f64; N]> {
+    if bytes.len() != N * 8 {
+        return None;
+    }
+    let mut values = [0.; N];
+    for (value, chunk) in values.iter_mut().zip(bytes.chunks_exact(8)) {
+        *value = f64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    Some(values)
+}
+
+// The bounds of a stored point or box.
+fn bounds(bytes: &[u8]) -> Option<BBox> {
+    match bytes.len() {
+        16 => read_f64s::<2>(bytes).map(|[x, y]| Point::new(x, y).into()),
+        _ => read_f64s::<4>(bytes)
+            .map(|[min_x, min_y, max_x, max_y]| BBox::new(min_x, min_y, max_x, max_y)),
+    }
+}
+
+impl ObjectType for Point {
+    const TYPE: DataType = DataType::Bytes;
+}
+
+impl ObjectType for BBox {
+    const TYPE: DataType = DataType::Bytes;
+}
+
+impl<'a> From<&'a Point> for Value<'static> {
+    fn from(point: &'a Point) -> Self {
+        let bytes = [point.x, point.y].into_iter().flat_map(|f| f.to_le_bytes());
+        Value::Bytes(Cow::Owned(bytes.collect()))
+    }
+}
+
+impl<'a> From<&'a BBox> for Value<'static> {
+    fn from(bbox: &'a BBox) -> Self {
+        Value::Bytes(Cow::Owned(bbox.to_bytes()))
+    }
+}
+
+impl FromValue for Point {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Bytes(bytes) => read_f64s::<2>(&bytes).map(|[x, y]| Point::new(x, y)),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for BBox {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Bytes(bytes) => read_f64s::<4>(&bytes)
+                .map(|[min_x, min_y, max_x, max_y]| BBox::new(min_x, min_y, max_x, max_y)),
+            _ => None,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type Bound = fn(&BBox) -> f64;
+
+const BOUNDS: [(&str, Bound); 4] = [
+    ("orm_min_x", |bbox| bbox.min_x),
+    ("orm_max_x", |bbox| bbox.max_x),
+    ("orm_min_y", |bbox| bbox.min_y),
+    ("orm_max_y", |bbox| bbox.max_y),
+];
+
+pub(crate) fn functions() -> impl Iterator<Item = (&'static str, ScalarFunction)> {
+    BOUNDS.into_iter().map(|(name, bound)| {
+        let function: ScalarFunction = Box::new(move |args| match args {
+            [Value::Bytes(bytes)] => bounds(bytes)
+                .map(|bbox| Value::Float64(bound(&bbox)))
+                .ok_or_else(|| Error::Storage(format!("{}: not a geometry", name).into())),
+            _ => Err(Error::Storage(
+                format!("{} takes one geometry", name).into(),
+            )),
+        });
+        (name, function)
+    })
+}
+
+pub(crate) fn index_name(table: &str, column: &str) -> String {
+    format!("{}_{}_rtree", table, column)
+}
+
+// The SQL of Predicate::WithinBBox: the bounds of the column, and with an
+// index first the candidates from its R*Tree, whose boxes are rounded
+// outwards to single precision and so overlap the query box.
+pub(crate) fn within_sql(
+    column: &str,
+    bbox: &BBox,
+    index: Option<&str>,
+    sql: &mut String,
+    params: &mut Row<'static>,
+) {
+    let column = quote(column);
+    sql.push('(');
+    if let Some(index) = index {
+        sql.push_str(&format!(
+            "\"id\" IN (SELECT id FROM {} WHERE max_x >= ? AND min_x <= ? AND max_y >= ? AND min_y <= ?) AND ",
+            quote(index)
+        ));
+        params.extend([bbox.min_x, bbox.max_x, bbox.min_y, bbox.max_y].map(Value::from));
+    }
+    sql.push_str(&format!(
+        "orm_min_x({0}) >= ? AND orm_max_x({0}) <= ? AND orm_min_y({0}) >= ? AND orm_max_y({0}) <= ?)",
+        column
+    ));
+    params.extend([bbox.min_x, bbox.max_x, bbox.min_y, bbox.max_y].map(Value::from));
+}
+
+impl<'a> Transaction<'a> {
+    // An R*Tree over the bounds of a Point or BBox column, kept up to date
+    // by triggers and used by the within_bbox predicates of the column from
+    // then on. Existing rows are indexed right away; creating an index that
+    // exists does nothing. Tables of tenant transactions are not indexed.
+    pub fn create_spatial_index<T: Object>(&self, column: &str) -> Result<()> {
+        let schema = T::TABLE;
+        match schema.column(column) {
+            Some(found) if found.typ == DataType::Bytes => {}
+            _ => {
+                return Err(Error::Storage(
+                    format!("{} has no geometry column {}", schema.table_name, column).into(),
+                ))
+            }
+        }
+        self.ensure_table::<T>()?;
+        let index = index_name(schema.table_name, column);
+        if self.storage().table_exists(&index)? {
+            return Ok(());
+        }
+        let (table, rtree, column) = (quote(schema.table_name), quote(&index), quote(column));
+        let entry = |row: &str| {
+            format!(
+                "{row}id, orm_min_x({row}{0}), orm_max_x({row}{0}), orm_min_y({row}{0}), orm_max_y({row}{0})",
+                column
+            )
+        };
+        let statements = [
+            format!(
+                "CREATE VIRTUAL TABLE {} USING rtree(id, min_x, max_x, min_y, max_y)",
+                rtree
+            ),
+            format!("INSERT INTO {} SELECT {} FROM {}", rtree, entry(""), table),
+            format!(
+                "CREATE TRIGGER {} AFTER INSERT ON {} BEGIN INSERT INTO {} VALUES ({}); END",
+                quote(&format!("{}_insert", index)),
+                table,
+                rtree,
+                entry("NEW.")
+            ),
+            format!(
+                "CREATE TRIGGER {} AFTER UPDATE OF {} ON {} BEGIN INSERT OR REPLACE INTO {} VALUES ({}); END",
+                quote(&format!("{}_update", index)),
+                column,
+                table,
+                rtree,
+                entry("NEW.")
+            ),
+            format!(
+                "CREATE TRIGGER {} AFTER DELETE ON {} BEGIN DELETE FROM {} WHERE id = OLD.id; END",
+                quote(&format!("{}_delete", index)),
+                table,
+                rtree
+            ),
+        ];
+        for statement in &statements {
+            self.execute(statement, &[])?;
+        }
+        Ok(())
+    }
+}
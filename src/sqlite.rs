@@ -0,0 +1,1650 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    database::DatabaseOptions,
+    error::{
+        Error, MissingColumnError, NotFoundError, Result, StatementError, UnexpectedTypeError,
+    },
+    instrument::{
+        timed, Instrument, Operation, SharedInstrument, SlowQuery, SlowQueryLog, SqlTrace,
+        SqlTraceHook, Stopwatch,
+    },
+    object::{Column, Schema},
+    pool::{Pool, PoolOptions},
+    predicate::{column, quote},
+    prefix::PrefixedStorage,
+    storage::{
+        BackupProgress, ChangeHook, ChangeOp, Checkpoint, CheckpointMode, ColumnInfo,
+        IntegrityReport, OnConflict, Row, RowSlice, ScalarFunction, StatementCacheStats,
+        StorageConnection, StorageTransaction, TableInfo, TransactionBehavior,
+    },
+    Connection,
+    Error::{NotFound, UnexpectedType},
+    ObjectId,
+};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::hooks::Action;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::{params, CachedStatement, OpenFlags, OptionalExtension, ToSql};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashSet, VecDeque},
+    panic::AssertUnwindSafe,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl StorageConnection for rusqlite::Connection {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(SqliteTransaction {
+            tx: self.transaction()?,
+            cache: None,
+            strict_tables: false,
+            parameter_values: false,
+            deadline: Cell::new(None),
+            instrument: None,
+            slow_queries: None,
+            sql_trace: None,
+        }))
+    }
+
+    fn new_transaction_with(
+        &mut self,
+        behavior: TransactionBehavior,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(SqliteTransaction {
+            tx: self.transaction_with_behavior(to_rusqlite_behavior(behavior))?,
+            cache: None,
+            strict_tables: false,
+            parameter_values: false,
+            deadline: Cell::new(None),
+            instrument: None,
+            slow_queries: None,
+            sql_trace: None,
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        let changes: i64 = self.query_row("SELECT total_changes()", [], |row| row.get(0))?;
+        Ok(changes as usize)
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn attach(&mut self, path: &Path, alias: &str) -> Result<()> {
+        let path = path.to_string_lossy();
+        rusqlite::Connection::execute(self, "ATTACH DATABASE ? AS ?", [path.as_ref(), alias])?;
+        Ok(())
+    }
+
+    fn detach(&mut self, alias: &str) -> Result<()> {
+        rusqlite::Connection::execute(self, "DETACH DATABASE ?", [alias])?;
+        Ok(())
+    }
+
+    fn backup_to(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()> {
+        const PAGES_PER_STEP: i32 = 64;
+
+        let mut dst = rusqlite::Connection::open(path)?;
+        let backup = Backup::new(self, &mut dst)?;
+        loop {
+            let step = backup.step(PAGES_PER_STEP)?;
+            let current = backup.progress();
+            progress(BackupProgress {
+                remaining: current.remaining as usize,
+                page_count: current.pagecount as usize,
+            });
+            match step {
+                StepResult::Done => return Ok(()),
+                StepResult::More => {}
+                // The source is busy writing; give it a moment before retrying.
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        self.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> Result<()> {
+        match max_pages {
+            Some(pages) => self.execute_batch(&format!("PRAGMA incremental_vacuum({})", pages))?,
+            None => self.execute_batch("PRAGMA incremental_vacuum")?,
+        }
+        Ok(())
+    }
+
+    fn optimize(&mut self) -> Result<()> {
+        self.execute_batch("PRAGMA optimize")?;
+        Ok(())
+    }
+
+    fn analyze(&mut self) -> Result<()> {
+        self.execute_batch("ANALYZE")?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, mode: CheckpointMode) -> Result<Checkpoint> {
+        let sql = format!("PRAGMA wal_checkpoint({})", mode.as_str());
+        let (busy, log, checkpointed): (i64, i64, i64) =
+            self.query_row(&sql, [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        // Outside WAL mode both frame counts are reported as -1.
+        Ok(Checkpoint {
+            busy: busy != 0,
+            log_frames: log.max(0) as usize,
+            checkpointed_frames: checkpointed.max(0) as usize,
+        })
+    }
+
+    fn create_scalar_function(&mut self, name: &str, function: ScalarFunction) -> Result<()> {
+        // A panicking function aborts the statement rather than leaving SQLite
+        // in an inconsistent state, which is what rusqlite's UnwindSafe asks for.
+        let function = AssertUnwindSafe(function);
+        rusqlite::Connection::create_scalar_function(
+            self,
+            name,
+            -1,
+            FunctionFlags::SQLITE_UTF8,
+            move |ctx| {
+                let args = (0..ctx.len())
+                    .map(|i| from_value_ref(ctx.get_raw(i)))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                function(&args).map_err(|err| rusqlite::Error::UserFunctionError(Box::new(err)))
+            },
+        )?;
+        Ok(())
+    }
+
+    fn integrity_check(&mut self, quick: bool) -> Result<IntegrityReport> {
+        let pragma = if quick {
+            "PRAGMA quick_check"
+        } else {
+            "PRAGMA integrity_check"
+        };
+        let problems = self.prepare(pragma).and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        });
+        // Damage to the schema can keep the check itself from running.
+        let mut problems = match problems {
+            Err(rusqlite::Error::SqliteFailure(err, message))
+                if matches!(
+                    err.code,
+                    rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase
+                ) =>
+            {
+                vec![message.unwrap_or_else(|| err.to_string())]
+            }
+            problems => problems?,
+        };
+        if problems == ["ok"] {
+            problems.clear();
+        }
+        Ok(IntegrityReport { problems })
+    }
+
+    fn set_foreign_keys(&mut self, enabled: bool) -> Result<()> {
+        pragma(self, "foreign_keys", if enabled { "ON" } else { "OFF" })
+    }
+
+    // SQLite reports writes as they happen, so changes that are rolled back
+    // later have been reported as well.
+    fn set_change_hook(&mut self, hook: Option<ChangeHook>) -> Result<()> {
+        let mut hook = match hook {
+            Some(hook) => hook,
+            None => {
+                self.update_hook(None::<fn(Action, &str, &str, i64)>);
+                return Ok(());
+            }
+        };
+        self.update_hook(Some(move |action, db: &str, table: &str, id| {
+            let op = match action {
+                Action::SQLITE_INSERT => ChangeOp::Insert,
+                Action::SQLITE_UPDATE => ChangeOp::Update,
+                Action::SQLITE_DELETE => ChangeOp::Delete,
+                _ => return,
+            };
+            if db == "main" {
+                hook(table, op, ObjectId::from(id));
+            } else {
+                hook(&format!("{}.{}", db, table), op, ObjectId::from(id));
+            }
+        }));
+        Ok(())
+    }
+
+    // SQLite's trace callback takes no state, so the log is kept per thread.
+    fn set_statement_log(&mut self, enabled: bool) -> Result<()> {
+        self.trace(enabled.then_some(log_statement as fn(&str)));
+        STATEMENT_LOG.with(|log| log.borrow_mut().clear());
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    fn rekey(&mut self, key: &str) -> Result<()> {
+        pragma(self, "rekey", &quote_literal(key))
+    }
+}
+
+fn from_value_ref(value: ValueRef<'_>) -> rusqlite::Result<Value<'_>> {
+    Ok(match value {
+        ValueRef::Integer(i) => Value::Int64(i),
+        ValueRef::Real(f) => Value::Float64(f),
+        ValueRef::Text(s) => Value::String(Cow::Borrowed(
+            std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?,
+        )),
+        ValueRef::Blob(b) => Value::Bytes(Cow::Borrowed(b)),
+        ValueRef::Null => {
+            return Err(rusqlite::Error::UserFunctionError(
+                "NULL arguments are not supported".into(),
+            ))
+        }
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The storage behind Connection::open_sqlite_* and friends. It keeps
+// statistics of the prepared-statement cache on top of a plain connection.
+pub(crate) struct SqliteStorage {
+    conn: rusqlite::Connection,
+    cache: StatementCache,
+    optimize_on_close: bool,
+    strict_tables: bool,
+    parameter_values: bool,
+    instrument: Option<SharedInstrument>,
+    slow_queries: Option<SlowQueryLog>,
+    sql_trace: Option<SqlTraceHook>,
+    bulk_load: Option<BulkLoad>,
+}
+
+// The settings bulk load mode replaced, and the indexes it dropped.
+struct BulkLoad {
+    synchronous: i64,
+    journal_mode: String,
+    cache_size: i64,
+    temp_store: i64,
+    indexes: Vec<String>,
+}
+
+impl SqliteStorage {
+    fn new(conn: rusqlite::Connection) -> Self {
+        let mut storage = Self {
+            conn,
+            cache: StatementCache::new(DEFAULT_STATEMENT_CACHE_CAPACITY),
+            optimize_on_close: false,
+            strict_tables: false,
+            parameter_values: false,
+            instrument: None,
+            slow_queries: None,
+            sql_trace: None,
+            bulk_load: None,
+        };
+        storage.set_statement_cache_capacity(DEFAULT_STATEMENT_CACHE_CAPACITY);
+        #[cfg(feature = "spatial")]
+        for (name, function) in crate::spatial::functions() {
+            StorageConnection::create_scalar_function(&mut storage, name, function)
+                .expect("the spatial functions register");
+        }
+        storage
+    }
+
+    fn set_statement_cache_capacity(&mut self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+        self.conn
+            .set_prepared_statement_cache_capacity(self.cache.connection_capacity());
+    }
+}
+
+impl StorageConnection for SqliteStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(SqliteTransaction {
+            tx: self.conn.transaction()?,
+            cache: Some(&self.cache),
+            strict_tables: self.strict_tables,
+            parameter_values: self.parameter_values,
+            deadline: Cell::new(None),
+            instrument: self.instrument.as_deref(),
+            slow_queries: self.slow_queries.as_ref(),
+            sql_trace: self.sql_trace.as_deref(),
+        }))
+    }
+
+    fn new_transaction_with(
+        &mut self,
+        behavior: TransactionBehavior,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(SqliteTransaction {
+            tx: self
+                .conn
+                .transaction_with_behavior(to_rusqlite_behavior(behavior))?,
+            cache: Some(&self.cache),
+            strict_tables: self.strict_tables,
+            parameter_values: self.parameter_values,
+            deadline: Cell::new(None),
+            instrument: self.instrument.as_deref(),
+            slow_queries: self.slow_queries.as_ref(),
+            sql_trace: self.sql_trace.as_deref(),
+        }))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        self.conn.total_changes()
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.conn.ping()
+    }
+
+    fn attach(&mut self, path: &Path, alias: &str) -> Result<()> {
+        self.conn.attach(path, alias)
+    }
+
+    fn detach(&mut self, alias: &str) -> Result<()> {
+        self.conn.detach(alias)
+    }
+
+    fn backup_to(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()> {
+        self.conn.backup_to(path, progress)
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        self.conn.vacuum()
+    }
+
+    fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> Result<()> {
+        self.conn.incremental_vacuum(max_pages)
+    }
+
+    fn optimize(&mut self) -> Result<()> {
+        self.conn.optimize()
+    }
+
+    fn analyze(&mut self) -> Result<()> {
+        self.conn.analyze()
+    }
+
+    fn checkpoint(&mut self, mode: CheckpointMode) -> Result<Checkpoint> {
+        self.conn.checkpoint(mode)
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    fn rekey(&mut self, key: &str) -> Result<()> {
+        self.conn.rekey(key)
+    }
+
+    fn statement_cache_stats(&self) -> Option<StatementCacheStats> {
+        Some(self.cache.stats())
+    }
+
+    fn set_instrument(&mut self, instrument: Option<SharedInstrument>) -> Result<()> {
+        self.instrument = instrument;
+        Ok(())
+    }
+
+    fn set_slow_query_log(&mut self, log: Option<SlowQueryLog>) -> Result<()> {
+        self.slow_queries = log;
+        Ok(())
+    }
+
+    fn set_sql_trace(&mut self, hook: Option<SqlTraceHook>) -> Result<()> {
+        self.sql_trace = hook;
+        Ok(())
+    }
+
+    fn create_scalar_function(&mut self, name: &str, function: ScalarFunction) -> Result<()> {
+        StorageConnection::create_scalar_function(&mut self.conn, name, function)
+    }
+
+    fn set_foreign_keys(&mut self, enabled: bool) -> Result<()> {
+        self.conn.set_foreign_keys(enabled)
+    }
+
+    fn set_change_hook(&mut self, hook: Option<ChangeHook>) -> Result<()> {
+        self.conn.set_change_hook(hook)
+    }
+
+    fn set_statement_log(&mut self, enabled: bool) -> Result<()> {
+        self.conn.set_statement_log(enabled)
+    }
+
+    fn integrity_check(&mut self, quick: bool) -> Result<IntegrityReport> {
+        self.conn.integrity_check(quick)
+    }
+
+    // Commits are not synced and, outside WAL mode, the rollback journal is
+    // kept in memory, so a crash during the load can corrupt the file. The
+    // indexes that are not UNIQUE are dropped and built once at the end;
+    // unique ones stay, so that duplicates are still rejected as they are
+    // written.
+    fn set_bulk_load(&mut self, enabled: bool) -> Result<()> {
+        match (enabled, self.bulk_load.take()) {
+            (true, None) => self.begin_bulk_load(),
+            (false, Some(bulk_load)) => self.end_bulk_load(bulk_load),
+            (_, bulk_load) => {
+                self.bulk_load = bulk_load;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl SqliteStorage {
+    // The previous settings are kept before the first one is changed, so
+    // that turning bulk load off undoes an enable that failed half way.
+    fn begin_bulk_load(&mut self) -> Result<()> {
+        let get = |name: &str| -> rusqlite::Result<i64> {
+            self.conn
+                .query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+        };
+        let bulk_load = BulkLoad {
+            synchronous: get("synchronous")?,
+            cache_size: get("cache_size")?,
+            temp_store: get("temp_store")?,
+            journal_mode: self
+                .conn
+                .query_row("PRAGMA journal_mode", [], |row| row.get(0))?,
+            indexes: Vec::new(),
+        };
+        let wal = bulk_load.journal_mode.eq_ignore_ascii_case("wal");
+        let bulk_load = self.bulk_load.insert(bulk_load);
+
+        pragma(&self.conn, "synchronous", "OFF")?;
+        pragma(&self.conn, "cache_size", &BULK_LOAD_CACHE_SIZE.to_string())?;
+        pragma(&self.conn, "temp_store", "MEMORY")?;
+        if !wal {
+            pragma(&self.conn, "journal_mode", "MEMORY")?;
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT name, sql FROM sqlite_master WHERE type = 'index' \
+            AND sql IS NOT NULL AND sql NOT LIKE 'CREATE UNIQUE%'",
+        )?;
+        let indexes: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (name, sql) in indexes {
+            self.conn
+                .execute_batch(&format!("DROP INDEX {}", quote(&name)))?;
+            bulk_load.indexes.push(sql);
+        }
+        Ok(())
+    }
+
+    // Every setting is put back even if an index can not be built.
+    fn end_bulk_load(&mut self, bulk_load: BulkLoad) -> Result<()> {
+        let mut built: Result<()> = Ok(());
+        for sql in &bulk_load.indexes {
+            if let Err(err) = self.conn.execute_batch(sql) {
+                built = built.and(Err(err.into()));
+            }
+        }
+        pragma(&self.conn, "journal_mode", &bulk_load.journal_mode)?;
+        pragma(
+            &self.conn,
+            "synchronous",
+            &bulk_load.synchronous.to_string(),
+        )?;
+        pragma(&self.conn, "cache_size", &bulk_load.cache_size.to_string())?;
+        pragma(&self.conn, "temp_store", &bulk_load.temp_store.to_string())?;
+        built
+    }
+}
+
+// In KiB, as a negative cache_size is.
+const BULK_LOAD_CACHE_SIZE: i64 = -256 * 1024;
+
+// There is nobody to report a failure to at this point.
+impl Drop for SqliteStorage {
+    fn drop(&mut self) {
+        if self.optimize_on_close {
+            let _ = self.conn.optimize();
+        }
+    }
+}
+
+// rusqlite's own default.
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 16;
+
+// rusqlite does not report cache hits, so the LRU order of its cache is
+// mirrored here by SQL text. The per-object statements of the schemas are
+// pinned: the connection's cache has room for all of them besides the
+// `capacity` most recent other statements, and the other statements that
+// fall out of those are discarded, so queries never push out the statements
+// of get, create, update and delete. With a capacity of 0 nothing is cached.
+struct StatementCache {
+    capacity: usize,
+    recent: RefCell<VecDeque<String>>,
+    pinned: RefCell<HashSet<&'static str>>,
+    evicted: RefCell<Option<String>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl StatementCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: RefCell::default(),
+            pinned: RefCell::default(),
+            evicted: RefCell::default(),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let recent = self.recent.get_mut();
+        while recent.len() > capacity {
+            recent.pop_front();
+        }
+        if capacity == 0 {
+            self.pinned.get_mut().clear();
+        }
+    }
+
+    // One more than the statements kept, for the one evicted last, which is
+    // only discarded on the next prepare as it may still be in use.
+    fn connection_capacity(&self) -> usize {
+        match self.capacity {
+            0 => 0,
+            capacity => capacity + self.pinned.borrow().len() + 1,
+        }
+    }
+
+    fn record(&self, sql: &str) {
+        let mut recent = self.recent.borrow_mut();
+        if let Some(pos) = recent.iter().position(|cached| cached == sql) {
+            let cached = recent.remove(pos).unwrap();
+            recent.push_back(cached);
+            self.hits.set(self.hits.get() + 1);
+            return;
+        }
+        self.misses.set(self.misses.get() + 1);
+        if self.capacity > 0 {
+            if recent.len() == self.capacity {
+                *self.evicted.borrow_mut() = recent.pop_front();
+            }
+            recent.push_back(sql.to_string());
+        }
+    }
+
+    // Returns whether the statement is new, so the connection's cache needs
+    // to grow.
+    fn pin(&self, sql: &'static str) -> bool {
+        if self.capacity == 0 {
+            self.record(sql);
+            return false;
+        }
+        if self.pinned.borrow_mut().insert(sql) {
+            self.misses.set(self.misses.get() + 1);
+            true
+        } else {
+            self.hits.set(self.hits.get() + 1);
+            false
+        }
+    }
+
+    fn take_evicted(&self) -> Option<String> {
+        self.evicted.borrow_mut().take()
+    }
+
+    fn stats(&self) -> StatementCacheStats {
+        StatementCacheStats {
+            capacity: self.capacity,
+            pinned: self.pinned.borrow().len(),
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Splits "<schema>.<table>" so catalog lookups go to the attached database.
+fn split_table_name(table: &str) -> (&str, &str) {
+    match table.split_once('.') {
+        Some((schema, name)) => (schema, name),
+        None => ("main", table),
+    }
+}
+
+// Only the names the ORM itself declares; anything else is left unchecked.
+fn declared_type(declared: &str) -> Option<DataType> {
+    match declared.to_ascii_uppercase().as_str() {
+        "TEXT" => Some(DataType::String),
+        "BLOB" => Some(DataType::Bytes),
+        "INTEGER" => Some(DataType::Int64),
+        "REAL" => Some(DataType::Float64),
+        _ => None,
+    }
+}
+
+// A table name for SQL, with the database it is in kept apart.
+fn quote_table(table: &str) -> String {
+    match table.split_once('.') {
+        Some((schema, name)) => format!("{}.{}", quote(schema), quote(name)),
+        None => quote(table),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Connection {
+    pub fn open_sqlite_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_storage(SqliteStorage::new(
+            rusqlite::Connection::open(path)?,
+        )))
+    }
+
+    pub fn open_sqlite_file_with<P: AsRef<Path>>(
+        path: P,
+        options: &DatabaseOptions,
+    ) -> Result<Self> {
+        let mut storage = SqliteStorage::new(rusqlite::Connection::open(path)?);
+        apply_options(&storage.conn, options)?;
+        if let Some(capacity) = options.statement_cache_capacity {
+            storage.set_statement_cache_capacity(capacity);
+        }
+        storage.optimize_on_close = options.optimize_on_close;
+        storage.strict_tables = options.strict_tables;
+        storage.parameter_values = options.log_parameter_values;
+        Ok(match &options.table_prefix {
+            Some(prefix) => Self::from_storage(PrefixedStorage::new(storage, prefix)),
+            None => Self::from_storage(storage),
+        })
+    }
+
+    pub fn open_sqlite_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = rusqlite::Connection::open_with_flags(path, flags)?;
+        Ok(Self::from_storage(SqliteStorage::new(conn)).read_only())
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Ok(Self::from_storage(SqliteStorage::new(
+            rusqlite::Connection::open_in_memory()?,
+        )))
+    }
+
+    // Every connection opened with the same name shares one in-memory
+    // database, which lives until the last of them is closed.
+    pub fn open_shared_memory(name: &str) -> Result<Self> {
+        let uri = format!("file:{}?mode=memory&cache=shared", name);
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = rusqlite::Connection::open_with_flags(uri, flags)?;
+        Ok(Self::from_storage(SqliteStorage::new(conn)))
+    }
+}
+
+fn to_rusqlite_behavior(behavior: TransactionBehavior) -> rusqlite::TransactionBehavior {
+    match behavior {
+        TransactionBehavior::Deferred => rusqlite::TransactionBehavior::Deferred,
+        TransactionBehavior::Immediate => rusqlite::TransactionBehavior::Immediate,
+        TransactionBehavior::Exclusive => rusqlite::TransactionBehavior::Exclusive,
+    }
+}
+
+// Tables are read with the prefix of the database, or none, as
+// DatabaseOptions::table_prefix.
+pub(crate) type ReadStorage = PrefixedStorage<SqliteStorage>;
+
+pub(crate) fn open_reader(path: &Path, options: &DatabaseOptions) -> Result<ReadStorage> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let mut storage = SqliteStorage::new(rusqlite::Connection::open_with_flags(path, flags)?);
+    apply_connection_options(&storage.conn, options)?;
+    if let Some(capacity) = options.statement_cache_capacity {
+        storage.set_statement_cache_capacity(capacity);
+    }
+    storage.parameter_values = options.log_parameter_values;
+    let prefix = options.table_prefix.clone().unwrap_or_default();
+    Ok(PrefixedStorage::new(storage, prefix))
+}
+
+fn apply_options(conn: &rusqlite::Connection, options: &DatabaseOptions) -> Result<()> {
+    apply_connection_options(conn, options)?;
+    // Switching an existing database takes effect on the next VACUUM.
+    if let Some(mode) = options.auto_vacuum {
+        pragma(conn, "auto_vacuum", mode.as_str())?;
+    }
+    if let Some(mode) = options.journal_mode {
+        pragma(conn, "journal_mode", mode.as_str())?;
+    }
+    if let Some(synchronous) = options.synchronous {
+        pragma(conn, "synchronous", synchronous.as_str())?;
+    }
+    if let Some(enabled) = options.foreign_keys {
+        pragma(conn, "foreign_keys", if enabled { "ON" } else { "OFF" })?;
+    }
+    Ok(())
+}
+
+// The settings that also apply to read-only connections of the file.
+fn apply_connection_options(conn: &rusqlite::Connection, options: &DatabaseOptions) -> Result<()> {
+    // The key has to be set before anything reads the database.
+    #[cfg(feature = "sqlcipher")]
+    if let Some(key) = &options.key {
+        pragma(conn, "key", &quote_literal(key))?;
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))?;
+    }
+    if let Some(timeout) = options.busy_timeout {
+        conn.busy_timeout(timeout)?;
+    }
+    if let Some(size) = options.cache_size {
+        pragma(conn, "cache_size", &size.to_string())?;
+    }
+    if let Some(bytes) = options.mmap_size {
+        pragma(conn, "mmap_size", &bytes.to_string())?;
+    }
+    Ok(())
+}
+
+// Some pragmas report the resulting value as a row, which is ignored here.
+fn pragma(conn: &rusqlite::Connection, name: &str, value: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA {} = {}", name, value))?;
+    stmt.query([])?.next()?;
+    Ok(())
+}
+
+#[cfg(feature = "sqlcipher")]
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+impl Pool<rusqlite::Connection> {
+    pub fn open_sqlite_file<P: AsRef<Path>>(path: P, options: PoolOptions) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self::new(options, move || Ok(rusqlite::Connection::open(&path)?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl ToSql for ObjectId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.into_i64()))
+    }
+}
+
+impl<'a> ToSql for Value<'a> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            Value::String(s) => Ok(ToSqlOutput::from(s.as_ref())),
+            Value::Bytes(b) => Ok(ToSqlOutput::from(b.as_ref())),
+            Value::Int64(i) => Ok(ToSqlOutput::from(*i)),
+            Value::Float64(f) => Ok(ToSqlOutput::from(*f)),
+            Value::Bool(b) => Ok(ToSqlOutput::from(*b)),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct SqliteTransaction<'a> {
+    tx: rusqlite::Transaction<'a>,
+    cache: Option<&'a StatementCache>,
+    strict_tables: bool,
+    parameter_values: bool,
+    deadline: Cell<Option<Instant>>,
+    instrument: Option<&'a dyn Instrument>,
+    slow_queries: Option<&'a SlowQueryLog>,
+    sql_trace: Option<&'a (dyn Fn(&SqlTrace) + Send + Sync)>,
+}
+
+// How many virtual machine instructions run between checks of the deadline.
+const DEADLINE_CHECK_OPS: i32 = 1000;
+
+impl<'a> SqliteTransaction<'a> {
+    fn prepare_cached(&self, sql: &str) -> rusqlite::Result<CachedStatement<'_>> {
+        if let Some(cache) = self.cache {
+            self.discard_evicted(cache);
+            cache.record(sql);
+        }
+        timed(self.instrument, Operation::Prepare, sql, || {
+            self.tx.prepare_cached(sql)
+        })
+    }
+
+    fn execute_cached(&self, sql: &str, params: &[&dyn ToSql]) -> rusqlite::Result<usize> {
+        let mut stmt = self.prepare_cached(sql)?;
+        self.timed(sql, params, || stmt.execute(params))
+    }
+
+    // For the per-object statements of a schema, which stay prepared on the
+    // connection across transactions.
+    fn prepare_pinned(&self, sql: &'static str) -> rusqlite::Result<CachedStatement<'_>> {
+        if let Some(cache) = self.cache {
+            self.discard_evicted(cache);
+            if cache.pin(sql) {
+                self.tx
+                    .set_prepared_statement_cache_capacity(cache.connection_capacity());
+            }
+        }
+        timed(self.instrument, Operation::Prepare, sql, || {
+            self.tx.prepare_cached(sql)
+        })
+    }
+
+    fn execute_pinned(&self, sql: &'static str, params: &[&dyn ToSql]) -> rusqlite::Result<usize> {
+        let mut stmt = self.prepare_pinned(sql)?;
+        self.timed(sql, params, || stmt.execute(params))
+    }
+
+    // Times a statement for the instrument, the slow query log and the SQL
+    // trace; the clock is left alone when there is none of them.
+    fn timed<R>(&self, sql: &str, params: &[&dyn ToSql], f: impl FnOnce() -> R) -> R {
+        let mut stopwatch = self.stopwatch();
+        let result = stopwatch.time(f);
+        self.report(stopwatch, sql, params);
+        result
+    }
+
+    fn stopwatch(&self) -> Stopwatch {
+        Stopwatch::new(
+            self.instrument.is_some() || self.slow_queries.is_some() || self.sql_trace.is_some(),
+        )
+    }
+
+    fn report(&self, stopwatch: Stopwatch, sql: &str, params: &[&dyn ToSql]) {
+        let elapsed = match stopwatch.elapsed() {
+            Some(elapsed) => elapsed,
+            None => return,
+        };
+        if let Some(instrument) = self.instrument {
+            instrument.record(Operation::Execute, sql, elapsed);
+        }
+        if let Some(log) = self.slow_queries.filter(|log| elapsed >= log.threshold) {
+            let params = params
+                .iter()
+                .map(|param| describe_param(*param, self.parameter_values))
+                .collect();
+            (log.hook)(&SlowQuery {
+                sql,
+                params,
+                elapsed,
+            });
+        }
+        if let Some(hook) = self.sql_trace {
+            let outputs: Vec<_> = params
+                .iter()
+                .filter_map(|param| param.to_sql().ok())
+                .collect();
+            let params = outputs
+                .iter()
+                .filter_map(|output| match output {
+                    ToSqlOutput::Borrowed(value) => from_value_ref(*value).ok(),
+                    ToSqlOutput::Owned(value) => from_value_ref(value.into()).ok(),
+                    _ => None,
+                })
+                .collect();
+            hook(&SqlTrace {
+                sql,
+                params,
+                elapsed,
+            });
+        }
+    }
+
+    fn discard_evicted(&self, cache: &StatementCache) {
+        if let Some(sql) = cache.take_evicted() {
+            if let Ok(stmt) = self.tx.prepare_cached(&sql) {
+                stmt.discard();
+            }
+        }
+    }
+
+    // Errors without a variant of their own carry the failed statement.
+    fn with_statement<'v, 'w: 'v>(
+        &self,
+        err: Error,
+        sql: &str,
+        params: impl IntoIterator<Item = &'v Value<'w>>,
+    ) -> Error {
+        match err {
+            Error::Storage(source) if self.is_past_deadline(source.as_ref()) => Error::Timeout,
+            Error::Storage(source) => {
+                let params = params
+                    .into_iter()
+                    .map(|value| match self.parameter_values {
+                        true => format!("{:?}", value),
+                        false => format!("{:?}", value.data_type()),
+                    })
+                    .collect();
+                Error::Storage(Box::new(StatementError::new(
+                    sql.to_string(),
+                    params,
+                    source,
+                )))
+            }
+            err => err,
+        }
+    }
+
+    fn is_past_deadline(&self, source: &(dyn std::error::Error + 'static)) -> bool {
+        let interrupted = matches!(
+            source.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == rusqlite::ErrorCode::OperationInterrupted
+        );
+        interrupted && self.deadline.get().is_some_and(|d| Instant::now() >= d)
+    }
+
+    // The generalized ALTER TABLE procedure from the SQLite documentation.
+    // The new table is declared from PRAGMA table_info, which covers what the
+    // ORM declares itself; table constraints of hand-written tables are lost.
+    fn rebuild_without(&self, table: &str, column: &str) -> Result<()> {
+        let (db, name) = split_table_name(table);
+        let master = format!("{}.sqlite_master", db);
+        let create_sql: String = self.tx.query_row(
+            &format!(
+                "SELECT sql FROM {} WHERE type IN ('table', 'view') AND name = ?",
+                master
+            ),
+            [name],
+            |row| row.get(0),
+        )?;
+        let create_sql = create_sql.to_ascii_uppercase();
+
+        let mut columns = self.table_info(table)?.columns;
+        if !columns.iter().any(|c| c.name == column) {
+            return Err(Error::Storage(format!("no such column: {}", column).into()));
+        }
+        columns.retain(|c| c.name != column);
+
+        // Indexes on the dropped column go with it; triggers are recreated.
+        let mut stmt = self.tx.prepare(&format!(
+            "SELECT type, name, sql FROM {} \
+            WHERE tbl_name = ? AND type IN ('index', 'trigger') AND sql IS NOT NULL",
+            master
+        ))?;
+        let dependents = stmt
+            .query_map([name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut recreate: Vec<String> = Vec::new();
+        for (typ, dependent, sql) in dependents {
+            if typ == "index" {
+                let mut stmt =
+                    self.tx
+                        .prepare(&format!("PRAGMA {}.index_info({})", db, quote(&dependent)))?;
+                let indexed = stmt
+                    .query_map([], |row| row.get::<_, Option<String>>(2))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                if indexed.iter().any(|c| c.as_deref() == Some(column)) {
+                    continue;
+                }
+            }
+            recreate.push(sql);
+        }
+
+        let pk: Vec<_> = columns.iter().filter(|c| c.primary_key).collect();
+        let mut definitions: Vec<_> = columns
+            .iter()
+            .map(|c| {
+                let typ = c.declared_type.as_deref().unwrap_or_default();
+                let mut definition = format!("{} {}", quote(&c.name), typ);
+                if pk.len() == 1 && c.primary_key {
+                    definition.push_str(" PRIMARY KEY");
+                    if create_sql.contains("AUTOINCREMENT") {
+                        definition.push_str(" AUTOINCREMENT");
+                    }
+                }
+                if c.not_null {
+                    definition.push_str(" NOT NULL");
+                }
+                if let Some(default) = &c.default {
+                    definition.push_str(&format!(" DEFAULT {}", default));
+                }
+                definition
+            })
+            .collect();
+        if pk.len() > 1 {
+            let names: Vec<_> = pk.iter().map(|c| quote(&c.name)).collect();
+            definitions.push(format!("PRIMARY KEY ({})", names.join(", ")));
+        }
+        let rebuilt = format!("_orm_rebuild_{}", name);
+        let mut create_q = format!(
+            "CREATE TABLE {}.{} ({})",
+            db,
+            quote(&rebuilt),
+            definitions.join(", ")
+        );
+        // Table options such as STRICT and WITHOUT ROWID follow the last
+        // parenthesis.
+        let options = create_sql
+            .rfind(')')
+            .map_or("", |i| create_sql[i + 1..].trim());
+        if !options.is_empty() {
+            create_q.push(' ');
+            create_q.push_str(options);
+        }
+
+        let sequence = format!("{}.sqlite_sequence", db);
+        let seq: Option<i64> = if self.table_exists(&sequence)? {
+            self.tx
+                .query_row(
+                    &format!("SELECT seq FROM {} WHERE name = ?", sequence),
+                    [name],
+                    |row| row.get(0),
+                )
+                .optional()?
+        } else {
+            None
+        };
+
+        // Dropping the old table must not trip foreign keys that point at it.
+        let deferred: bool = self
+            .tx
+            .query_row("PRAGMA defer_foreign_keys", [], |row| row.get(0))?;
+        pragma(&self.tx, "defer_foreign_keys", "ON")?;
+
+        let names: Vec<_> = columns.iter().map(|c| quote(&c.name)).collect();
+        let names = names.join(", ");
+        self.tx.execute(&create_q, [])?;
+        self.tx.execute(
+            &format!(
+                "INSERT INTO {}.{} ({}) SELECT {} FROM {}",
+                db,
+                quote(&rebuilt),
+                names,
+                names,
+                table
+            ),
+            [],
+        )?;
+        self.tx.execute(&format!("DROP TABLE {}", table), [])?;
+        self.tx.execute(
+            &format!("ALTER TABLE {}.{} RENAME TO {}", db, quote(&rebuilt), name),
+            [],
+        )?;
+        for sql in recreate {
+            self.tx.execute(&sql, [])?;
+        }
+        if let Some(seq) = seq {
+            self.tx
+                .execute(&format!("DELETE FROM {} WHERE name = ?", sequence), [name])?;
+            self.tx.execute(
+                &format!("INSERT INTO {} (name, seq) VALUES (?, ?)", sequence),
+                params![name, seq],
+            )?;
+        }
+
+        pragma(
+            &self.tx,
+            "defer_foreign_keys",
+            if deferred { "ON" } else { "OFF" },
+        )
+    }
+}
+
+impl<'a> StorageTransaction for SqliteTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        let (schema, name) = split_table_name(table);
+        let select_q = format!(
+            "SELECT name FROM {}.sqlite_master WHERE type IN ('table', 'view') AND name=?",
+            schema
+        );
+        let mut res = self.prepare_cached(&select_q)?;
+
+        let exists = match res.query_row([name], |_| Ok(())) {
+            Ok(_) => true,
+            Err(rusqlite::Error::QueryReturnedNoRows) => false,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(exists)
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let create_q = schema.create_text_with(self.strict_tables);
+        if let Err(e) = self.tx.execute(&create_q, []) {
+            Err(e.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.tx.execute(&schema.drop_text(), [])?;
+        Ok(())
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        let mut stmt = self.tx.prepare(
+            "SELECT name FROM sqlite_master \
+            WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?;
+        let names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        let (schema, name) = split_table_name(table);
+        let mut stmt =
+            self.tx
+                .prepare(&format!("PRAGMA {}.table_info({})", schema, quote(name)))?;
+        let columns = stmt
+            .query_map([], |row| {
+                let declared: String = row.get(2)?;
+                Ok(ColumnInfo {
+                    name: row.get(1)?,
+                    typ: declared_type(&declared),
+                    declared_type: Some(declared),
+                    not_null: row.get(3)?,
+                    default: row.get(4)?,
+                    primary_key: row.get::<_, i64>(5)? > 0,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if columns.is_empty() {
+            return Err(Error::Storage(format!("no such table: {}", table).into()));
+        }
+        Ok(TableInfo {
+            name: table.to_string(),
+            columns,
+        })
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        self.tx.execute(&schema.add_column_text(column), [])?;
+        Ok(())
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        let (_, name) = split_table_name(to);
+        self.tx
+            .execute(&format!("ALTER TABLE {} RENAME TO {}", from, name), [])?;
+        Ok(())
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.tx.execute(
+            &format!("ALTER TABLE {} RENAME COLUMN {} TO {}", table, from, to),
+            [],
+        )?;
+        Ok(())
+    }
+
+    // SQLite drops a column in place unless something depends on it, such as
+    // an index; then the table is rebuilt without it.
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        let drop_q = format!("ALTER TABLE {} DROP COLUMN {}", table, column);
+        match self.tx.execute(&drop_q, []) {
+            Ok(_) => Ok(()),
+            Err(rusqlite::Error::SqliteFailure(..)) => self.rebuild_without(table, column),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes = self.execute_cached(&schema.delete_where_text(""), &[])?;
+        let (db, name) = split_table_name(schema.table_name);
+        if self.table_exists(&format!("{}.sqlite_sequence", db))? {
+            self.tx.execute(
+                &format!("DELETE FROM {}.sqlite_sequence WHERE name = ?", db),
+                [name],
+            )?;
+        }
+        Ok(changes)
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        let args: Vec<_> = row.iter().map(|value| value as &dyn ToSql).collect();
+        let sql = schema.insert_text_with(on_conflict);
+        match self.execute_pinned(sql, &args[..]) {
+            Ok(0) if on_conflict == OnConflict::Ignore => Ok(None),
+            Ok(1) if schema.without_rowid => Ok(Some(ObjectId::from(self.tx.query_row(
+                &schema.last_id_text(),
+                [],
+                |row| row.get::<_, i64>(0),
+            )?))),
+            Ok(1) => Ok(Some(ObjectId::from(self.tx.last_insert_rowid()))),
+            Err(e) => MissingColumnError::get_error_from_text(&e.to_string(), schema)
+                .map_or_else(|| Err(self.with_statement(e.into(), sql, row)), Err),
+            _ => unreachable!(),
+        }
+    }
+
+    // As many rows per INSERT as the parameter limit allows. The rows of one
+    // INSERT get consecutive ids, as the transaction holds the write lock and
+    // SQLite takes the rowid after the largest one; WITHOUT ROWID tables pick
+    // their ids in the statement and are inserted one by one.
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        if schema.without_rowid || schema.columns.is_empty() || rows.len() < 2 {
+            return rows
+                .iter()
+                .map(|row| self.insert_row(schema, row))
+                .collect();
+        }
+        let mut ids = Vec::with_capacity(rows.len());
+        for rows in rows.chunks((max_variables() / schema.columns.len()).max(1)) {
+            let args: Vec<_> = rows
+                .iter()
+                .flatten()
+                .map(|value| value as &dyn ToSql)
+                .collect();
+            let sql = schema.insert_rows_text(rows.len());
+            if let Err(e) = self.execute_cached(&sql, &args[..]) {
+                return Err(
+                    match MissingColumnError::get_error_from_text(&e.to_string(), schema) {
+                        Some(err) => err,
+                        None => self.with_statement(e.into(), &sql, rows.iter().flatten()),
+                    },
+                );
+            }
+            let last = self.tx.last_insert_rowid();
+            ids.extend((last - rows.len() as i64 + 1..=last).map(ObjectId::from));
+        }
+        Ok(ids)
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        let mut args = Vec::with_capacity(row.len() + 1);
+        args.push(&id as &dyn ToSql);
+        args.extend(row.iter().map(|value| value as &dyn ToSql));
+        let sql = schema.insert_with_id_text();
+        match self.execute_pinned(sql, &args[..]) {
+            Ok(_) => Ok(()),
+            Err(e) => MissingColumnError::get_error_from_text(&e.to_string(), schema).map_or_else(
+                || {
+                    let id = Value::from(id.into_i64());
+                    Err(self.with_statement(e.into(), sql, std::iter::once(&id).chain(row)))
+                },
+                Err,
+            ),
+        }
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        if schema.columns.is_empty() {
+            return Ok(0);
+        }
+        let mut args = Vec::with_capacity(row.len() + 1);
+        args.extend(row.iter().map(|value| value as &dyn ToSql));
+        args.push(&id as &dyn ToSql);
+        let sql = schema.update_text();
+        self.execute_pinned(sql, &args[..]).map_err(|err| {
+            let id = Value::from(id.into_i64());
+            self.with_statement(err.into(), sql, row.iter().chain([&id]))
+        })
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        if columns.is_empty() {
+            return Ok(0);
+        }
+        let args: Vec<_> = values
+            .iter()
+            .chain(params.iter())
+            .map(|value| value as &dyn ToSql)
+            .collect();
+        let sql = schema.update_where_text(columns, where_sql);
+        self.execute_cached(&sql, &args[..]).map_err(|err| {
+            let err = map_read_error(err, schema, 0);
+            self.with_statement(err, &sql, values.iter().chain(params))
+        })
+    }
+
+    // As delete_rows, with one statement for all of the rows.
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        let first = match rows.first() {
+            Some((_, values)) if !columns.is_empty() => values,
+            _ => return Ok(0),
+        };
+        let (where_sql, _) = column("id").eq(0).to_sql();
+        let sql = schema.update_where_text(columns, &where_sql);
+        let mut stmt = self
+            .prepare_cached(&sql)
+            .map_err(|err| self.with_statement(err.into(), &sql, first))?;
+        let mut changes = 0;
+        for (id, values) in rows {
+            let args: Vec<_> = values
+                .iter()
+                .map(|value| value as &dyn ToSql)
+                .chain([id as &dyn ToSql])
+                .collect();
+            changes += self
+                .timed(&sql, &args, || stmt.execute(&args[..]))
+                .map_err(|err| {
+                    let id = Value::from(id.into_i64());
+                    let err = map_read_error(err, schema, 0);
+                    self.with_statement(err, &sql, values.iter().chain([&id]))
+                })?;
+        }
+        Ok(changes)
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let sql = schema.select_text();
+        let select_q = self.prepare_pinned(sql);
+        let args = [&id as &dyn ToSql];
+        match select_q {
+            Ok(mut result) => self.timed(sql, &args, || {
+                result.query_row(&args[..], |row| read_row(row, schema, 0))
+            }),
+            Err(err) => Err(err),
+        }
+        .map_err(|err| match err {
+            rusqlite::Error::InvalidColumnType(..) | rusqlite::Error::SqliteFailure(..) => {
+                let id = Value::from(id.into_i64());
+                self.with_statement(map_read_error(err, schema, 0), sql, [&id])
+            }
+            _ => NotFound(Box::new(NotFoundError::new(id, schema.type_name))),
+        })
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let sql = schema.select_where_text(where_sql);
+        let map_err = |err| self.with_statement(map_read_error(err, schema, 1), &sql, params);
+        let mut stmt = self.prepare_cached(&sql).map_err(map_err)?;
+        let args: Vec<_> = params.iter().map(|value| value as &dyn ToSql).collect();
+        let mut stopwatch = self.stopwatch();
+        let mut rows = stopwatch.time(|| stmt.query(&args[..])).map_err(map_err)?;
+
+        while let Some(row) = stopwatch.time(|| rows.next()).map_err(map_err)? {
+            let id = row.get::<_, i64>(0).map_err(map_err)?;
+            let line = read_row(row, schema, 1).map_err(map_err)?;
+            f(ObjectId::from(id), line)?;
+        }
+        self.report(stopwatch, &sql, &args);
+        Ok(())
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        let sql = schema.select_where_text(where_sql);
+        let map_err = |err| self.with_statement(map_read_error(err, schema, 1), &sql, params);
+        let mut stmt = self.prepare_cached(&sql).map_err(map_err)?;
+        let args: Vec<_> = params.iter().map(|value| value as &dyn ToSql).collect();
+        let mut stopwatch = self.stopwatch();
+        let mut rows = stopwatch.time(|| stmt.query(&args[..])).map_err(map_err)?;
+
+        while let Some(row) = stopwatch.time(|| rows.next()).map_err(map_err)? {
+            let id = row.get::<_, i64>(0).map_err(map_err)?;
+            let line = read_row_ref(row, schema, 1).map_err(map_err)?;
+            f(ObjectId::from(id), &line)?;
+        }
+        self.report(stopwatch, &sql, &args);
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let args: Vec<_> = params.iter().map(|value| value as &dyn ToSql).collect();
+        let sql = schema.count_text(where_sql);
+        let count: i64 = self
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                self.timed(&sql, &args, || stmt.query_row(&args[..], |row| row.get(0)))
+            })
+            .map_err(|err| self.with_statement(map_read_error(err, schema, 0), &sql, params))?;
+        Ok(count as usize)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        let sql = schema.delete_text();
+        let changes = self
+            .execute_pinned(sql, &[&id as &dyn ToSql])
+            .map_err(|err| self.with_statement(err.into(), sql, [&Value::from(id.into_i64())]))?;
+
+        if changes == 0 {
+            return Err(NotFound(Box::new(NotFoundError::new(id, schema.type_name))));
+        }
+
+        Ok(())
+    }
+
+    // One statement for all of the ids, run for each in turn so that the rows
+    // go in the order given.
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        let first = match ids.first() {
+            Some(id) => Value::from(id.into_i64()),
+            None => return Ok(()),
+        };
+        let sql = format!(
+            "DELETE FROM {} WHERE id = ?",
+            quote_table(schema.table_name)
+        );
+        let mut stmt = self
+            .prepare_cached(&sql)
+            .map_err(|err| self.with_statement(err.into(), &sql, [&first]))?;
+        for id in ids {
+            let args = [id as &dyn ToSql];
+            let changes = self
+                .timed(&sql, &args, || stmt.execute(&args[..]))
+                .map_err(|err| {
+                    self.with_statement(err.into(), &sql, [&Value::from(id.into_i64())])
+                })?;
+            if changes == 0 {
+                return Err(NotFound(Box::new(NotFoundError::new(
+                    *id,
+                    schema.type_name,
+                ))));
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let args: Vec<_> = params.iter().map(|value| value as &dyn ToSql).collect();
+        let sql = schema.delete_where_text(where_sql);
+        self.execute_cached(&sql, &args[..])
+            .map_err(|err| self.with_statement(map_read_error(err, schema, 0), &sql, params))
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        let args: Vec<_> = params.iter().map(|value| value as &dyn ToSql).collect();
+        self.timed(sql, &args, || self.tx.execute(sql, &args[..]))
+            .map_err(|err| self.with_statement(err.into(), sql, params))
+    }
+
+    fn commit(&self) -> Result<()> {
+        if let Err(e) = self.tx.execute("COMMIT", []) {
+            Err(e.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn rollback(&self) -> Result<()> {
+        if let Err(e) = self.tx.execute("ROLLBACK", []) {
+            Err(e.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        pragma(
+            &self.tx,
+            "defer_foreign_keys",
+            if deferred { "ON" } else { "OFF" },
+        )
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.commit()?;
+        self.tx.execute("BEGIN", [])?;
+        Ok(())
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        Ok(STATEMENT_LOG.with(|log| log.take()))
+    }
+
+    // A progress handler interrupts the running statement once the deadline
+    // has passed, the same as sqlite3_interrupt.
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.deadline.set(deadline);
+        match deadline {
+            Some(deadline) => self
+                .tx
+                .progress_handler(DEADLINE_CHECK_OPS, Some(move || Instant::now() >= deadline)),
+            None => self.tx.progress_handler(0, None::<fn() -> bool>),
+        }
+        Ok(())
+    }
+}
+
+// The handler belongs to the connection and would outlive the transaction.
+impl<'a> Drop for SqliteTransaction<'a> {
+    fn drop(&mut self) {
+        if self.deadline.get().is_some() {
+            self.tx.progress_handler(0, None::<fn() -> bool>);
+        }
+    }
+}
+
+thread_local! {
+    static STATEMENT_LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn log_statement(sql: &str) {
+    STATEMENT_LOG.with(|log| log.borrow_mut().push(sql.to_string()));
+}
+
+fn describe_param(param: &dyn ToSql, with_value: bool) -> String {
+    let output = match param.to_sql() {
+        Ok(output) => output,
+        Err(_) => return "?".to_string(),
+    };
+    let value = match &output {
+        ToSqlOutput::Borrowed(value) => *value,
+        ToSqlOutput::Owned(value) => ValueRef::from(value),
+        _ => return "?".to_string(),
+    };
+    if !with_value {
+        return value.data_type().to_string();
+    }
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(s) => format!("{:?}", String::from_utf8_lossy(s)),
+        ValueRef::Blob(b) => format!("<{} bytes>", b.len()),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn read_row(row: &rusqlite::Row, schema: &Schema, offset: usize) -> rusqlite::Result<Row<'static>> {
+    let mut line = Row::with_capacity(schema.columns.len());
+    for (i, column) in schema.columns.iter().enumerate() {
+        let index = i + offset;
+        let value = match column.typ {
+            DataType::Bytes => Value::Bytes(Cow::Owned(row.get(index)?)),
+            DataType::Int64 => Value::Int64(row.get(index)?),
+            DataType::String => Value::String(Cow::Owned(row.get(index)?)),
+            DataType::Float64 => Value::Float64(row.get(index)?),
+            DataType::Bool => Value::Bool(row.get(index)?),
+        };
+        line.push(value);
+    }
+    Ok(line)
+}
+
+// The most parameters a statement may have.
+fn max_variables() -> usize {
+    match rusqlite::version_number() {
+        n if n >= 3_032_000 => 32766,
+        _ => 999,
+    }
+}
+
+// Text and blobs borrow from the statement's current row. Values of another
+// type are read as read_row does, for the same errors.
+fn read_row_ref<'r>(
+    row: &'r rusqlite::Row,
+    schema: &Schema,
+    offset: usize,
+) -> rusqlite::Result<Row<'r>> {
+    let mut line = Row::with_capacity(schema.columns.len());
+    for (i, column) in schema.columns.iter().enumerate() {
+        let index = i + offset;
+        let value = match (column.typ, row.get_ref(index)?) {
+            (DataType::String, ValueRef::Text(text)) => {
+                Value::String(Cow::Borrowed(std::str::from_utf8(text)?))
+            }
+            (DataType::Bytes, ValueRef::Blob(blob)) => Value::Bytes(Cow::Borrowed(blob)),
+            (DataType::Bytes, _) => Value::Bytes(Cow::Owned(row.get(index)?)),
+            (DataType::Int64, _) => Value::Int64(row.get(index)?),
+            (DataType::String, _) => Value::String(Cow::Owned(row.get(index)?)),
+            (DataType::Float64, _) => Value::Float64(row.get(index)?),
+            (DataType::Bool, _) => Value::Bool(row.get(index)?),
+        };
+        line.push(value);
+    }
+    Ok(line)
+}
+
+fn map_read_error(err: rusqlite::Error, schema: &Schema, offset: usize) -> Error {
+    match err {
+        rusqlite::Error::InvalidColumnType(i, _, type_n) if i >= offset => {
+            let column = &schema.columns[i - offset];
+            UnexpectedType(Box::new(UnexpectedTypeError::new(
+                schema.type_name,
+                column.attr_name,
+                schema.table_name,
+                column.column_name,
+                column.typ,
+                type_n.to_string(),
+            )))
+        }
+        rusqlite::Error::SqliteFailure(_, Some(ref text)) => {
+            MissingColumnError::get_error_from_text(text, schema).unwrap_or_else(|| err.into())
+        }
+        err => err.into(),
+    }
+}
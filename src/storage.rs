@@ -1,154 +1,470 @@
 #![forbid(unsafe_code)]
 
-use crate::error::NotFoundError;
-use crate::Error::{NotFound, UnexpectedType};
 use crate::{
     data::{DataType, Value},
-    error::*,
-    object::Schema,
+    error::{Error, Result},
+    instrument::{SharedInstrument, SlowQueryLog, SqlTraceHook},
+    object::{Column, Schema},
+    predicate::column,
     ObjectId,
 };
-use rusqlite::ToSql;
-use std::borrow::Cow;
+use std::{path::Path, time::Instant};
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[cfg(not(feature = "smallvec"))]
 pub type Row<'a> = Vec<Value<'a>>;
+// Rows of up to ROW_INLINE values stay off the heap.
+#[cfg(feature = "smallvec")]
+pub type Row<'a> = smallvec::SmallVec<[Value<'a>; ROW_INLINE]>;
+#[cfg(feature = "smallvec")]
+pub const ROW_INLINE: usize = 8;
 pub type RowSlice<'a> = [Value<'a>];
 
-////////////////////////////////////////////////////////////////////////////////
+// Builds a Row whichever container backs it, as vec! does for a Vec.
+#[macro_export]
+macro_rules! row {
+    ($($value:expr),* $(,)?) => {
+        <$crate::storage::Row as ::std::iter::FromIterator<_>>::from_iter([$($value),*])
+    };
+}
 
-pub(crate) trait StorageTransaction {
-    fn table_exists(&self, table: &str) -> Result<bool>;
-    fn create_table(&self, schema: &Schema) -> Result<()>;
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum OnConflict {
+    #[default]
+    Abort,
+    Ignore,
+    Replace,
+}
 
-    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId>;
-    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()>;
-    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>>;
+// When the transaction takes its locks: on first access, or right away so
+// that a write transaction cannot fail with Error::LockConflict later on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TransactionBehavior {
+    #[default]
+    Deferred,
+    Immediate,
+    Exclusive,
+}
 
-    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()>;
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BackupProgress {
+    pub remaining: usize,
+    pub page_count: usize,
+}
 
-    fn commit(&self) -> Result<()>;
-    fn rollback(&self) -> Result<()>;
+// Arguments and result of an application-defined SQL function.
+pub type ScalarFunction = Box<dyn Fn(&[Value<'_>]) -> Result<Value<'static>> + Send>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
 }
 
-impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
-    fn table_exists(&self, table: &str) -> Result<bool> {
-        let select_q = format!(
-            "SELECT name FROM sqlite_master WHERE type='table' AND name='{}'",
-            table
-        );
-        let mut res = self.prepare_cached(&select_q)?;
+// Called with the table name, as in Schema::table_name, for every row written.
+pub type ChangeHook = Box<dyn FnMut(&str, ChangeOp, ObjectId) + Send>;
 
-        let exists = match res.query_row([], |_| Ok(())) {
-            Ok(_) => true,
-            Err(rusqlite::Error::QueryReturnedNoRows) => false,
-            Err(e) => return Err(e.into()),
-        };
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IntegrityReport {
+    pub problems: Vec<String>,
+}
 
-        Ok(exists)
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
     }
+}
 
-    fn create_table(&self, schema: &Schema) -> Result<()> {
-        if let Err(e) = self.execute(&schema.create_text(), []) {
-            Err(e.into())
-        } else {
-            Ok(())
-        }
+// A table as it is stored, including the id column.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TableInfo {
+    pub name: String,
+    pub columns: Vec<ColumnInfo>,
+}
+
+impl TableInfo {
+    pub fn column(&self, name: &str) -> Option<&ColumnInfo> {
+        self.columns.iter().find(|c| c.name == name)
     }
+}
 
-    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
-        let (q, args) = if row.is_empty() {
-            (
-                format!("INSERT INTO {} DEFAULT VALUES", schema.table_name),
-                Vec::new(),
-            )
-        } else {
-            (
-                schema.insert_text(),
-                row.iter().map(|value| value as &dyn ToSql).collect(),
-            )
-        };
-        match self.execute(&q, &args[..]) {
-            Ok(result) if result == 1 => Ok(ObjectId::from(self.last_insert_rowid())),
-            Err(e) => MissingColumnError::get_error_from_text(&e.to_string(), schema)
-                .map_or_else(|| Err(e.into()), Err),
-            _ => unreachable!(),
+// Transaction::table_stats. The column count leaves out the id.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TableStats {
+    pub name: String,
+    pub rows: usize,
+    pub columns: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ColumnInfo {
+    pub name: String,
+    // The SQL type as declared, for backends that have one.
+    pub declared_type: Option<String>,
+    // None when the declared type is not one the ORM maps to.
+    pub typ: Option<DataType>,
+    pub not_null: bool,
+    pub default: Option<String>,
+    pub primary_key: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StatementCacheStats {
+    pub capacity: usize,
+    // Per-object statements, which are kept besides the capacity.
+    pub pinned: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CheckpointMode {
+    #[default]
+    Passive,
+    Full,
+    Restart,
+    Truncate,
+}
+
+impl CheckpointMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Passive => "PASSIVE",
+            Self::Full => "FULL",
+            Self::Restart => "RESTART",
+            Self::Truncate => "TRUNCATE",
         }
     }
+}
 
-    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
-        if !schema.columns.is_empty() {
-            let mut args = Vec::with_capacity(row.len() + 1);
-            args.extend(row.iter().map(|value| value as &dyn ToSql));
-            args.push(&id as &dyn ToSql);
-            self.execute(&schema.update_text(), &args[..])?;
-        }
-        Ok(())
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Checkpoint {
+    pub busy: bool,
+    pub log_frames: usize,
+    pub checkpointed_frames: usize,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub trait StorageConnection {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>>;
+    // A read transaction that sees the database as of this call, however
+    // long it stays open. Beginning a transaction and reading from it pins
+    // the snapshot in SQLite's WAL mode and in the in-memory storage.
+    fn new_snapshot(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let tx = self.new_transaction()?;
+        tx.table_exists("")?;
+        Ok(tx)
     }
 
-    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
-        let select_q = self.prepare_cached(&schema.select_text());
-        match select_q {
-            Ok(mut result) => result.query_row([id.into_i64()], |row| {
-                let mut line = vec![];
-                let size = schema.columns.len();
-                for i in 0..size {
-                    let d_type = schema.columns[i].typ;
-                    let value = match d_type {
-                        DataType::Bytes => Value::Bytes(Cow::Owned(row.get(i)?)),
-                        DataType::Int64 => Value::Int64(row.get(i)?),
-                        DataType::String => Value::String(Cow::Owned(row.get(i)?)),
-                        DataType::Float64 => Value::Float64(row.get(i)?),
-                        DataType::Bool => Value::Bool(row.get(i)?),
-                    };
-                    line.push(value);
-                }
-                Ok(line)
-            }),
-            Err(err) => Err(err),
-        }
-        .map_err(|err| match err {
-            rusqlite::Error::InvalidColumnType(i, _, type_n) => {
-                UnexpectedType(Box::new(UnexpectedTypeError::new(
-                    schema.type_name,
-                    schema.columns[i].attr_name,
-                    schema.table_name,
-                    schema.columns[i].column_name,
-                    schema.columns[i].typ,
-                    type_n.to_string(),
-                )))
-            }
-            rusqlite::Error::SqliteFailure(_, text) => {
-                MissingColumnError::get_error_from_text(text.unwrap().as_str(), schema).unwrap()
-            }
-            _ => NotFound(Box::new(NotFoundError::new(id, schema.type_name))),
-        })
-    }
-
-    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
-        let changes = self.execute(&schema.delete_text(), [id.into_i64()])?;
-
-        if changes == 0 {
-            return Err(NotFound(Box::new(NotFoundError::new(id, schema.type_name))));
+    fn total_changes(&self) -> Result<usize>;
+
+    fn new_transaction_with(
+        &mut self,
+        behavior: TransactionBehavior,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        match behavior {
+            TransactionBehavior::Deferred => self.new_transaction(),
+            _ => Err(unsupported("immediate and exclusive transactions")),
         }
+    }
 
+    fn ping(&mut self) -> Result<()> {
         Ok(())
     }
 
-    fn commit(&self) -> Result<()> {
-        if let Err(e) = self.execute("COMMIT", []) {
-            Err(e.into())
-        } else {
-            Ok(())
+    // Tables of an attached database are addressed as "<alias>.<table>".
+    fn attach(&mut self, _path: &Path, _alias: &str) -> Result<()> {
+        Err(unsupported("attached databases"))
+    }
+
+    fn detach(&mut self, _alias: &str) -> Result<()> {
+        Err(unsupported("attached databases"))
+    }
+
+    // Copies the live database into the file at path, reporting progress after
+    // every step.
+    fn backup_to(&self, _path: &Path, _progress: &mut dyn FnMut(BackupProgress)) -> Result<()> {
+        Err(unsupported("backups"))
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        Err(unsupported("vacuuming"))
+    }
+
+    // Frees up to max_pages pages of a database in incremental auto-vacuum
+    // mode, or all free pages if None.
+    fn incremental_vacuum(&mut self, _max_pages: Option<usize>) -> Result<()> {
+        Err(unsupported("vacuuming"))
+    }
+
+    // Refreshes the query planner statistics where SQLite thinks they are
+    // stale.
+    fn optimize(&mut self) -> Result<()> {
+        Err(unsupported("planner statistics"))
+    }
+
+    // Gathers statistics for every table and index.
+    fn analyze(&mut self) -> Result<()> {
+        Err(unsupported("planner statistics"))
+    }
+
+    fn checkpoint(&mut self, _mode: CheckpointMode) -> Result<Checkpoint> {
+        Err(unsupported("WAL checkpoints"))
+    }
+
+    fn statement_cache_stats(&self) -> Option<StatementCacheStats> {
+        None
+    }
+
+    // A quick check skips verifying that indexes match their tables.
+    fn integrity_check(&mut self, _quick: bool) -> Result<IntegrityReport> {
+        Err(unsupported("integrity checks"))
+    }
+
+    fn create_scalar_function(&mut self, _name: &str, _function: ScalarFunction) -> Result<()> {
+        Err(unsupported("user-defined functions"))
+    }
+
+    // Takes effect for the next transaction; SQLite ignores it inside one.
+    fn set_foreign_keys(&mut self, _enabled: bool) -> Result<()> {
+        Err(unsupported("foreign key controls"))
+    }
+
+    // Replaces the previous hook; None removes it.
+    fn set_change_hook(&mut self, _hook: Option<ChangeHook>) -> Result<()> {
+        Err(unsupported("change notifications"))
+    }
+
+    // While enabled, the SQL of every statement run is kept for
+    // StorageTransaction::take_statement_log.
+    fn set_statement_log(&mut self, _enabled: bool) -> Result<()> {
+        Err(unsupported("statement logs"))
+    }
+
+    // Replaces the previous instrument; None removes it. Backends time their
+    // statements with it, see instrument::Instrument.
+    fn set_instrument(&mut self, _instrument: Option<SharedInstrument>) -> Result<()> {
+        Err(unsupported("instrumentation"))
+    }
+
+    // Replaces the previous slow query log; None removes it. See
+    // instrument::SlowQuery.
+    fn set_slow_query_log(&mut self, _log: Option<SlowQueryLog>) -> Result<()> {
+        Err(unsupported("slow query log"))
+    }
+
+    // Replaces the previous SQL trace; None removes it. The hook is told about
+    // every query and DML statement once it has run.
+    fn set_sql_trace(&mut self, _hook: Option<SqlTraceHook>) -> Result<()> {
+        Err(unsupported("SQL traces"))
+    }
+
+    // Trades durability for write speed until it is turned off again, which
+    // restores the previous settings. See Database::bulk_load_mode.
+    fn set_bulk_load(&mut self, _enabled: bool) -> Result<()> {
+        Err(unsupported("bulk load mode"))
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    fn rekey(&mut self, _key: &str) -> Result<()> {
+        Err(unsupported("encryption"))
+    }
+}
+
+#[derive(Debug)]
+struct Unsupported(String);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "this storage does not support {}", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+pub(crate) fn unsupported(what: &str) -> Error {
+    Error::Storage(Box::new(Unsupported(what.to_string())))
+}
+
+pub(crate) fn is_unsupported(err: &Error) -> bool {
+    matches!(err, Error::Storage(source) if source.is::<Unsupported>())
+}
+
+// Every row of the table with its lazy columns read too, for the copies of
+// whole tables.
+pub(crate) fn select_stored(
+    storage: &dyn StorageTransaction,
+    schema: &Schema,
+) -> Result<Vec<(ObjectId, Row<'static>)>> {
+    storage.select_rows(schema.eager(), "", &[])
+}
+
+pub trait StorageTransaction {
+    fn table_exists(&self, table: &str) -> Result<bool>;
+    fn create_table(&self, schema: &Schema) -> Result<()>;
+    fn drop_table(&self, schema: &Schema) -> Result<()>;
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        Err(unsupported("listing tables"))
+    }
+
+    fn table_info(&self, _table: &str) -> Result<TableInfo> {
+        Err(unsupported("reading table columns"))
+    }
+
+    fn add_column(&self, _schema: &Schema, _column: &Column) -> Result<()> {
+        Err(unsupported("adding columns"))
+    }
+
+    fn rename_table(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(unsupported("renaming tables"))
+    }
+
+    fn rename_column(&self, _table: &str, _from: &str, _to: &str) -> Result<()> {
+        Err(unsupported("renaming columns"))
+    }
+
+    fn drop_column(&self, _table: &str, _column: &str) -> Result<()> {
+        Err(unsupported("dropping columns"))
+    }
+    fn clear_table(&self, schema: &Schema) -> Result<usize>;
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>>;
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        let id = self.insert_row_with(schema, row, OnConflict::Abort)?;
+        Ok(id.expect("plain INSERT either inserts a row or fails"))
+    }
+
+    // Returns the ids in the order of the rows.
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        rows.iter()
+            .map(|row| self.insert_row(schema, row))
+            .collect()
+    }
+    // Fails with a ConstraintKind::PrimaryKey error if the id is taken.
+    fn insert_row_with_id(&self, _id: ObjectId, _schema: &Schema, _row: &RowSlice) -> Result<()> {
+        Err(unsupported("explicit ids"))
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize>;
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize>;
+    // Sets the columns of each row to its values, as update_where of its id
+    // would, in the order given. Returns the number of rows changed.
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        let mut changes = 0;
+        for (id, values) in rows {
+            let (where_sql, params) = column("id").eq(id.into_i64()).to_sql();
+            changes += self.update_where(schema, columns, values, &where_sql, &params)?;
         }
+        Ok(changes)
     }
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>>;
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()>;
 
-    fn rollback(&self) -> Result<()> {
-        if let Err(e) = self.execute("ROLLBACK", []) {
-            Err(e.into())
-        } else {
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let mut rows = Vec::new();
+        self.for_each_row(schema, where_sql, params, &mut |id, row| {
+            rows.push((id, row));
             Ok(())
-        }
+        })?;
+        Ok(rows)
+    }
+
+    // Like for_each_row, with values that borrow from the backend's buffers
+    // where it can, so that scans which only look at them do not allocate.
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each_row(schema, where_sql, params, &mut |id, row| f(id, &row))
+    }
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize>;
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()>;
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize>;
+
+    // Fails with NotFound for the first id without a row, as delete_row.
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        ids.iter().try_for_each(|id| self.delete_row(*id, schema))
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize>;
+
+    // Savepoint names are generated by the ORM and are valid identifiers.
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.execute(&format!("SAVEPOINT {}", name), &[])?;
+        Ok(())
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.execute(&format!("RELEASE SAVEPOINT {}", name), &[])?;
+        Ok(())
+    }
+
+    // Keeps the savepoint itself, as ROLLBACK TO does in SQL.
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.execute(&format!("ROLLBACK TO SAVEPOINT {}", name), &[])?;
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()>;
+    fn rollback(&self) -> Result<()>;
+
+    // Deferred foreign keys are only checked at commit, for the rest of the
+    // transaction.
+    fn defer_foreign_keys(&self, _deferred: bool) -> Result<()> {
+        Err(unsupported("foreign key controls"))
+    }
+
+    // Commits and starts a new transaction in place, so the caller keeps the
+    // same handle.
+    fn commit_and_continue(&self) -> Result<()> {
+        Err(unsupported("continuing after a commit"))
+    }
+
+    // The statements logged since the last call, oldest first.
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        Err(unsupported("statement logs"))
+    }
+
+    // Statements still running at the deadline are interrupted and fail
+    // with Error::Timeout.
+    fn set_deadline(&self, _deadline: Option<Instant>) -> Result<()> {
+        Err(unsupported("deadlines"))
     }
 }
@@ -0,0 +1,154 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::Value,
+    error::{Error, Result},
+    object::Schema,
+    storage::{is_unsupported, select_stored, Row},
+    Database, ObjectId,
+};
+use std::cmp::Ordering;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// How Database::sync_from tells which copy of a row is newer, by a column
+// holding a timestamp or a version number that every write increases. The
+// row with the greater value wins either way. Rows with the same value and
+// different contents are left alone when going by last-modified times, as
+// they were changed at the same moment, and reported as conflicts when going
+// by versions, as they were changed independently.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncStrategy {
+    LastModified(&'static str),
+    Version(&'static str),
+}
+
+impl SyncStrategy {
+    fn column(self) -> &'static str {
+        match self {
+            SyncStrategy::LastModified(column) | SyncStrategy::Version(column) => column,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SyncReport {
+    pub inserted: usize,
+    pub updated: usize,
+    // Rows whose copy here is as new or newer.
+    pub skipped: usize,
+    pub conflicts: Vec<(&'static str, ObjectId)>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Database {
+    // Copies the rows of the tables that are missing here, by id, or newer in
+    // the other database, in one transaction. Rows are only ever added or
+    // overwritten: deleting a row on one side does not delete it on the
+    // other. Tables missing here are created, those missing there skipped.
+    // Objects are written as stored, without hooks or observers.
+    pub fn sync_from(
+        &mut self,
+        other: &mut Database,
+        tables: &[&'static Schema],
+        strategy: SyncStrategy,
+    ) -> Result<SyncReport> {
+        let mut sources = Vec::with_capacity(tables.len());
+        for &schema in tables {
+            let position = check_schema(schema, strategy)?;
+            let rows = other.read(|tx| {
+                let storage = tx.transaction().storage();
+                if !storage.table_exists(schema.table_name)? {
+                    return Ok(Vec::new());
+                }
+                select_stored(storage, schema)
+            })?;
+            sources.push((schema, position, rows));
+        }
+
+        self.transaction(|tx| {
+            match tx.defer_foreign_keys(true) {
+                Err(err) if !is_unsupported(&err) => return Err(err),
+                _ => {}
+            }
+            let storage = tx.storage();
+            let mut report = SyncReport::default();
+            for (schema, position, rows) in &sources {
+                if !storage.table_exists(schema.table_name)? {
+                    tx.create_table(schema)?;
+                    tx.store_fingerprint(schema)?;
+                }
+                // Lazy columns are compared and copied with their values.
+                let eager = schema.eager();
+                for (id, row) in rows {
+                    let local = match storage.select_row(*id, eager) {
+                        Ok(local) => local,
+                        Err(Error::NotFound(_)) => {
+                            storage.insert_row_with_id(*id, eager, row)?;
+                            report.inserted += 1;
+                            continue;
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    match compare(schema, *position, row, &local)? {
+                        Ordering::Greater => {
+                            storage.update_row(*id, eager, row)?;
+                            report.updated += 1;
+                        }
+                        Ordering::Equal
+                            if matches!(strategy, SyncStrategy::Version(_)) && *row != local =>
+                        {
+                            report.conflicts.push((schema.table_name, *id));
+                        }
+                        _ => report.skipped += 1,
+                    }
+                }
+            }
+            tx.record_changes(report.inserted + report.updated);
+            Ok(report)
+        })
+    }
+}
+
+fn check_schema(schema: &Schema, strategy: SyncStrategy) -> Result<usize> {
+    if schema.view.is_some() {
+        return Err(Error::Storage(
+            format!(
+                "{} is backed by a view and cannot be synced",
+                schema.type_name
+            )
+            .into(),
+        ));
+    }
+    schema
+        .columns
+        .iter()
+        .position(|c| c.column_name == strategy.column())
+        .ok_or_else(|| {
+            Error::Storage(
+                format!("{} has no column {}", schema.table_name, strategy.column()).into(),
+            )
+        })
+}
+
+// Numbers compare by value whatever their type, text as ISO 8601 timestamps
+// would.
+fn compare(schema: &Schema, position: usize, theirs: &Row, ours: &Row) -> Result<Ordering> {
+    let ordering = match (&theirs[position], &ours[position]) {
+        (Value::Int64(a), Value::Int64(b)) => Some(a.cmp(b)),
+        (Value::Int64(a), Value::Float64(b)) => (*a as f64).partial_cmp(b),
+        (Value::Float64(a), Value::Int64(b)) => a.partial_cmp(&(*b as f64)),
+        (Value::Float64(a), Value::Float64(b)) => a.partial_cmp(b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    ordering.ok_or_else(|| {
+        Error::Storage(
+            format!(
+                "{}.{} cannot be compared",
+                schema.table_name, schema.columns[position].column_name
+            )
+            .into(),
+        )
+    })
+}
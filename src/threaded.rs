@@ -0,0 +1,188 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::{Error, Result},
+    object::Object,
+    predicate::Predicate,
+    Connection, ObjectId,
+};
+use std::{sync::mpsc, thread};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The blocking counterpart of `asynch`: the connection and its transactions
+// stay on a worker thread, and the handles, which are Send, Sync and Clone,
+// ship closures to it. Objects cross threads as owned copies.
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+enum TxJob {
+    Run(Box<dyn FnOnce(&crate::Transaction) + Send>),
+    Commit(mpsc::Sender<Result<()>>),
+    Rollback(mpsc::Sender<Result<()>>),
+}
+
+#[derive(Clone)]
+pub struct Database {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Database {
+    #[cfg(feature = "sqlite")]
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Self::spawn(move || Connection::open_sqlite_file(path))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::spawn(Connection::open_in_memory)
+    }
+
+    pub fn spawn<F>(open: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<Connection> + Send + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (ready, opened) = mpsc::channel();
+        thread::spawn(move || {
+            let mut conn = match open() {
+                Ok(conn) => conn,
+                Err(err) => {
+                    let _ = ready.send(Err(err));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            for job in receiver {
+                job(&mut conn);
+            }
+        });
+
+        opened.recv().map_err(|_| stopped())??;
+        Ok(Self { jobs })
+    }
+
+    pub fn call<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+    {
+        let (reply, result) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |conn| {
+                let _ = reply.send(f(conn));
+            }))
+            .map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())?
+    }
+
+    pub fn total_changes(&self) -> Result<usize> {
+        self.call(|conn| conn.total_changes())
+    }
+
+    // Other jobs for the database wait on the worker until the transaction is
+    // committed, rolled back or all of its handles are dropped.
+    pub fn transaction(&self) -> Result<Transaction> {
+        let (jobs, receiver) = mpsc::channel::<TxJob>();
+        let (started, begun) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |conn| {
+                let tx = match conn.new_transaction() {
+                    Ok(tx) => tx,
+                    Err(err) => {
+                        let _ = started.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = started.send(Ok(()));
+
+                for job in receiver {
+                    match job {
+                        TxJob::Run(f) => f(&tx),
+                        TxJob::Commit(reply) => {
+                            let _ = reply.send(tx.commit());
+                            return;
+                        }
+                        TxJob::Rollback(reply) => {
+                            let _ = reply.send(tx.rollback());
+                            return;
+                        }
+                    }
+                }
+            }))
+            .map_err(|_| stopped())?;
+
+        begun.recv().map_err(|_| stopped())??;
+        Ok(Transaction { jobs })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone)]
+pub struct Transaction {
+    jobs: mpsc::Sender<TxJob>,
+}
+
+impl Transaction {
+    pub fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&crate::Transaction) -> Result<R> + Send + 'static,
+    {
+        let (reply, result) = mpsc::channel();
+        self.jobs
+            .send(TxJob::Run(Box::new(move |tx| {
+                let _ = reply.send(f(tx));
+            })))
+            .map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())?
+    }
+
+    pub fn create<T: Object + Send>(&self, obj: T) -> Result<ObjectId> {
+        self.run(move |tx| Ok(tx.create(obj)?.id().raw()))
+    }
+
+    pub fn get<T: Object + Send>(&self, id: ObjectId) -> Result<T> {
+        self.run(move |tx| Ok(tx.get::<T>(id)?.into_owned()))
+    }
+
+    pub fn update<T: Object + Send>(&self, id: ObjectId, obj: T) -> Result<()> {
+        self.run(move |tx| {
+            *tx.get::<T>(id)?.try_borrow_mut()? = obj;
+            Ok(())
+        })
+    }
+
+    pub fn delete<T: Object>(&self, id: ObjectId) -> Result<()> {
+        self.run(move |tx| tx.get::<T>(id)?.try_delete())
+    }
+
+    pub fn select<T: Object + Send>(&self, predicate: Predicate) -> Result<Vec<(ObjectId, T)>> {
+        self.run(move |tx| {
+            Ok(tx
+                .select::<T>(&predicate)?
+                .into_iter()
+                .map(|obj| (obj.id().raw(), obj.into_owned()))
+                .collect())
+        })
+    }
+
+    // Handles cloned from this one fail once it is committed or rolled back.
+    pub fn commit(self) -> Result<()> {
+        self.finish(TxJob::Commit)
+    }
+
+    pub fn rollback(self) -> Result<()> {
+        self.finish(TxJob::Rollback)
+    }
+
+    fn finish(self, job: fn(mpsc::Sender<Result<()>>) -> TxJob) -> Result<()> {
+        let (reply, result) = mpsc::channel();
+        self.jobs.send(job(reply)).map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> Error {
+    Error::Storage("database worker has stopped".into())
+}
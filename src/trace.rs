@@ -0,0 +1,363 @@
+#![forbid(unsafe_code)]
+use crate::{
+    error::Result,
+    object::{Column, Schema},
+    storage::{OnConflict, Row, RowSlice, StorageTransaction, TableInfo},
+    ObjectId,
+};
+use std::{cell::Cell, time::Instant};
+use tracing::Span;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Behind the `tracing` feature every transaction is an "orm" span, with a
+// "begin" event when it starts and a "commit" or "rollback" event with its
+// duration when it ends. In between, an event is emitted for each storage
+// operation with its table, the rows it returned or changed and how long it
+// took, in microseconds. Failed operations carry the error.
+pub(crate) fn traced<'a>(
+    inner: Box<dyn StorageTransaction + 'a>,
+) -> Box<dyn StorageTransaction + 'a> {
+    let span = tracing::debug_span!(target: "orm", "transaction");
+    tracing::debug!(target: "orm", parent: &span, "begin");
+    Box::new(TracedTransaction {
+        inner,
+        span,
+        started: Instant::now(),
+    })
+}
+
+struct TracedTransaction<'a> {
+    inner: Box<dyn StorageTransaction + 'a>,
+    span: Span,
+    started: Instant,
+}
+
+impl<'a> TracedTransaction<'a> {
+    fn trace<R>(
+        &self,
+        operation: &'static str,
+        table: &str,
+        rows: impl FnOnce(&R) -> usize,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        self.trace_sql(operation, table, None, rows, f)
+    }
+
+    fn trace_sql<R>(
+        &self,
+        operation: &'static str,
+        table: &str,
+        sql: Option<&str>,
+        rows: impl FnOnce(&R) -> usize,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        let start = Instant::now();
+        let result = f();
+        let elapsed_us = start.elapsed().as_micros() as u64;
+        match &result {
+            Ok(value) => tracing::debug!(
+                target: "orm",
+                parent: &self.span,
+                operation,
+                table,
+                sql,
+                rows = rows(value),
+                elapsed_us,
+                "statement"
+            ),
+            Err(err) => tracing::debug!(
+                target: "orm",
+                parent: &self.span,
+                operation,
+                table,
+                sql,
+                elapsed_us,
+                error = %err,
+                "statement"
+            ),
+        }
+        result
+    }
+
+    fn finish(&self, event: &'static str, result: Result<()>) -> Result<()> {
+        let elapsed_us = self.started.elapsed().as_micros() as u64;
+        match &result {
+            Ok(()) => tracing::debug!(target: "orm", parent: &self.span, elapsed_us, "{}", event),
+            Err(err) => {
+                tracing::debug!(target: "orm", parent: &self.span, elapsed_us, error = %err, "{}", event)
+            }
+        }
+        result
+    }
+}
+
+fn none<R>(_: &R) -> usize {
+    0
+}
+
+fn one<R>(_: &R) -> usize {
+    1
+}
+
+fn count(rows: &usize) -> usize {
+    *rows
+}
+
+impl<'a> StorageTransaction for TracedTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        self.trace("table_exists", table, none, || {
+            self.inner.table_exists(table)
+        })
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.trace("create_table", schema.table_name, none, || {
+            self.inner.create_table(schema)
+        })
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.trace("drop_table", schema.table_name, none, || {
+            self.inner.drop_table(schema)
+        })
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        self.trace("table_names", "", Vec::len, || self.inner.table_names())
+    }
+
+    fn table_info(&self, table: &str) -> Result<TableInfo> {
+        self.trace("table_info", table, none, || self.inner.table_info(table))
+    }
+
+    fn add_column(&self, schema: &Schema, column: &Column) -> Result<()> {
+        self.trace("add_column", schema.table_name, none, || {
+            self.inner.add_column(schema, column)
+        })
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<()> {
+        self.trace("rename_table", from, none, || {
+            self.inner.rename_table(from, to)
+        })
+    }
+
+    fn rename_column(&self, table: &str, from: &str, to: &str) -> Result<()> {
+        self.trace("rename_column", table, none, || {
+            self.inner.rename_column(table, from, to)
+        })
+    }
+
+    fn drop_column(&self, table: &str, column: &str) -> Result<()> {
+        self.trace("drop_column", table, none, || {
+            self.inner.drop_column(table, column)
+        })
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        self.trace("clear_table", schema.table_name, count, || {
+            self.inner.clear_table(schema)
+        })
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        self.trace(
+            "insert",
+            schema.table_name,
+            |id: &Option<ObjectId>| id.is_some() as usize,
+            || self.inner.insert_row_with(schema, row, on_conflict),
+        )
+    }
+
+    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
+        self.trace("insert", schema.table_name, one, || {
+            self.inner.insert_row(schema, row)
+        })
+    }
+
+    fn insert_rows(&self, schema: &Schema, rows: &[Row]) -> Result<Vec<ObjectId>> {
+        self.trace("insert", schema.table_name, Vec::len, || {
+            self.inner.insert_rows(schema, rows)
+        })
+    }
+
+    fn insert_row_with_id(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
+        self.trace("insert", schema.table_name, one, || {
+            self.inner.insert_row_with_id(id, schema, row)
+        })
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        self.trace("update", schema.table_name, count, || {
+            self.inner.update_row(id, schema, row)
+        })
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        self.trace("update", schema.table_name, count, || {
+            self.inner
+                .update_where(schema, columns, values, where_sql, params)
+        })
+    }
+
+    fn update_rows(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        rows: &[(ObjectId, Row)],
+    ) -> Result<usize> {
+        self.trace("update", schema.table_name, count, || {
+            self.inner.update_rows(schema, columns, rows)
+        })
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        self.trace("select", schema.table_name, one, || {
+            self.inner.select_row(id, schema)
+        })
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let rows = Cell::new(0);
+        self.trace(
+            "select",
+            schema.table_name,
+            |_| rows.get(),
+            || {
+                self.inner
+                    .for_each_row(schema, where_sql, params, &mut |id, row| {
+                        rows.set(rows.get() + 1);
+                        f(id, row)
+                    })
+            },
+        )
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        self.trace("select", schema.table_name, Vec::len, || {
+            self.inner.select_rows(schema, where_sql, params)
+        })
+    }
+
+    fn for_each_row_ref(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        let rows = Cell::new(0);
+        self.trace(
+            "select",
+            schema.table_name,
+            |_| rows.get(),
+            || {
+                self.inner
+                    .for_each_row_ref(schema, where_sql, params, &mut |id, row| {
+                        rows.set(rows.get() + 1);
+                        f(id, row)
+                    })
+            },
+        )
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.trace("count", schema.table_name, count, || {
+            self.inner.count_rows(schema, where_sql, params)
+        })
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.trace("delete", schema.table_name, one, || {
+            self.inner.delete_row(id, schema)
+        })
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.trace("delete", schema.table_name, count, || {
+            self.inner.delete_where(schema, where_sql, params)
+        })
+    }
+
+    fn delete_rows(&self, schema: &Schema, ids: &[ObjectId]) -> Result<()> {
+        self.trace(
+            "delete",
+            schema.table_name,
+            |_| ids.len(),
+            || self.inner.delete_rows(schema, ids),
+        )
+    }
+
+    // The only statement whose SQL is not the backend's own.
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.trace_sql("execute", "", Some(sql), count, || {
+            self.inner.execute(sql, params)
+        })
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.trace("savepoint", "", none, || self.inner.savepoint(name))
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.trace("release_savepoint", "", none, || {
+            self.inner.release_savepoint(name)
+        })
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.trace("rollback_to_savepoint", "", none, || {
+            self.inner.rollback_to_savepoint(name)
+        })
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.finish("commit", self.inner.commit())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.finish("rollback", self.inner.rollback())
+    }
+
+    fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    fn commit_and_continue(&self) -> Result<()> {
+        self.finish("commit", self.inner.commit_and_continue())?;
+        tracing::debug!(target: "orm", parent: &self.span, "begin");
+        Ok(())
+    }
+
+    fn take_statement_log(&self) -> Result<Vec<String>> {
+        self.inner.take_statement_log()
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+}
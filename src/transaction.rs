@@ -1,129 +1,2016 @@
 #![forbid(unsafe_code)]
 
-use crate::object::Schema;
-use crate::storage::Row;
+use crate::object::{Column, Hooks, Schema};
+use crate::predicate::{column, quote, Predicate};
+use crate::storage::{ChangeOp, OnConflict, Row, RowSlice, TableInfo, TableStats};
 use crate::{
-    data::ObjectId,
-    error::{Error, NotFoundError, Result},
+    audit::{history_row, history_schema, parse_operation, HistoryEntry},
+    data::{DataType, Id, ObjectId, Value},
+    dynamic::{
+        check_values, registered, registered_create, DynamicRow, DynamicSchema, DynamicTable,
+    },
+    error::{
+        BorrowedError, ConflictError, ConstraintKind, Error, Mismatch, MissingColumnError,
+        NotFoundError, Result, SchemaFingerprintMismatchError, SchemaMismatchError,
+    },
+    instrument::{timed, Operation, SharedInstrument},
     object::Object,
-    storage::StorageTransaction,
+    outbox::OutboxEntry,
+    storage::{is_unsupported, select_stored, StorageTransaction},
+    RetryPolicy,
 };
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::ops::Deref;
 use std::{
     any::{Any, TypeId},
     cell::{Cell, Ref, RefCell, RefMut},
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     marker::PhantomData,
     rc::Rc,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct Transaction<'a> {
-    cell_map: RefCell<HashMap<(TypeId, ObjectId), Rc<DataCell>>>,
+    cell_map: RefCell<KeyMap<(TypeId, ObjectId), Rc<DataCell>>>,
     state_map: RefCell<StateMap>,
+    last_changes: Cell<usize>,
+    total_changes: Cell<usize>,
+    checked_tables: RefCell<KeyMap<TypeId, &'static str>>,
+    shared_tables: Option<CheckedTables>,
+    written: RefCell<KeySet<(TypeId, ObjectId)>>,
+    check_conflicts: Cell<bool>,
+    lookup_conflicts: Cell<bool>,
+    // The savepoints not yet released or rolled back, innermost last, and
+    // the number of the last one, which names it.
+    savepoints: RefCell<Vec<u64>>,
+    last_savepoint: Cell<u64>,
+    // Set when a dropped savepoint could not roll back, which leaves only
+    // rolling the transaction back.
+    failed: Cell<bool>,
+    finished: Cell<bool>,
+    next_seq: Cell<u64>,
+    cache_limit: Cell<Option<usize>>,
+    actor: RefCell<Option<String>>,
+    next_eviction: Cell<usize>,
+    on_abandon: Option<AbandonHook>,
+    observers: RefCell<Vec<LocalObserver<'a>>>,
+    events: RefCell<Vec<ChangeEvent>>,
+    outbox: Cell<bool>,
+    read_only: bool,
+    instrument: Option<SharedInstrument>,
     inner: Box<dyn StorageTransaction + 'a>,
 }
 
 impl<'a> Transaction<'a> {
     pub(crate) fn new(inner: Box<dyn StorageTransaction + 'a>) -> Self {
+        #[cfg(feature = "tracing")]
+        let inner = crate::trace::traced(inner);
+        // Outside encryption, to compress the values before they are sealed.
+        #[cfg(feature = "compression")]
+        let inner = crate::compression::compressing(inner);
         Self {
+            read_only: false,
+            instrument: None,
             inner,
             cell_map: RefCell::default(),
             state_map: RefCell::default(),
+            last_changes: Cell::new(0),
+            total_changes: Cell::new(0),
+            checked_tables: RefCell::default(),
+            shared_tables: None,
+            written: RefCell::default(),
+            check_conflicts: Cell::new(false),
+            lookup_conflicts: Cell::new(false),
+            savepoints: RefCell::default(),
+            last_savepoint: Cell::new(0),
+            failed: Cell::new(false),
+            finished: Cell::new(false),
+            next_seq: Cell::new(0),
+            cache_limit: Cell::new(None),
+            actor: RefCell::new(None),
+            next_eviction: Cell::new(0),
+            on_abandon: None,
+            observers: RefCell::default(),
+            events: RefCell::default(),
+            outbox: Cell::new(false),
         }
     }
 
-    fn ensure_table<T: Object>(&self) -> Result<()> {
-        if self.inner.table_exists(T::TABLE.table_name)? {
+    pub(crate) fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub(crate) fn with_instrument(mut self, instrument: Option<SharedInstrument>) -> Self {
+        self.instrument = instrument;
+        self
+    }
+
+    pub(crate) fn with_abandon_hook(mut self, hook: Option<AbandonHook>) -> Self {
+        self.on_abandon = hook;
+        self
+    }
+
+    pub(crate) fn with_outbox(self, enabled: bool) -> Self {
+        self.outbox.set(enabled);
+        self
+    }
+
+    // Starts from the tables earlier transactions of the connection checked,
+    // and hands its own back once it commits.
+    pub(crate) fn with_checked_tables(mut self, shared: Option<CheckedTables>) -> Self {
+        if let Some(shared) = &shared {
+            *self.checked_tables.get_mut() = lock(shared).clone();
+        }
+        self.shared_tables = shared;
+        self
+    }
+
+    pub(crate) fn with_observers(self, observers: &[Observer]) -> Self {
+        for observer in observers {
+            let observer = observer.clone();
+            self.observe(move |event| observer(event));
+        }
+        self
+    }
+
+    // The observer gets every change written through the transaction from
+    // now on, once the commit has succeeded. Changes made by update_where,
+    // delete_where, clear_table and execute are not reported.
+    pub fn observe(&self, observer: impl Fn(&ChangeEvent) + 'a) {
+        self.observers.borrow_mut().push(Rc::new(observer));
+    }
+
+    // Once more objects than the limit are cached, the least recently used
+    // clean ones that no handle refers to are dropped from the cache and read
+    // again when asked for. Changed and deleted objects stay until they are
+    // written.
+    pub fn set_cache_limit(&self, limit: Option<usize>) {
+        self.cache_limit.set(limit);
+        self.next_eviction.set(0);
+        self.evict();
+    }
+
+    pub fn cached_objects(&self) -> usize {
+        self.cell_map.borrow().len()
+    }
+
+    // Handles to every cached object of type T, in the order of their ids,
+    // whatever their state but removed. Objects evicted from the cache are
+    // not among them.
+    pub fn tracked<T: Object>(&self) -> Vec<Tx<'_, T>> {
+        let state_map = self.state_map.borrow();
+        let mut tracked: Vec<_> = self
+            .cell_map
+            .borrow()
+            .iter()
+            .filter(|((type_id, _), _)| *type_id == TypeId::of::<T>())
+            .filter_map(|(key, cell)| {
+                let state = state_map.get(key)?;
+                (state.get() != ObjectState::Removed)
+                    .then(|| Tx::new(cell.clone(), key.1, state.clone(), PhantomData))
+            })
+            .collect();
+        tracked.sort_by_key(|obj| obj.id().raw().into_i64());
+        tracked
+    }
+
+    fn evict(&self) {
+        let Some(limit) = self.cache_limit.get() else {
+            return;
+        };
+        let mut cell_map = self.cell_map.borrow_mut();
+        if cell_map.len() <= limit.max(self.next_eviction.get()) {
+            return;
+        }
+        let mut state_map = self.state_map.borrow_mut();
+        let written = self.written.borrow();
+        let mut unpinned: Vec<_> = cell_map
+            .iter()
+            .filter(|(key, cell)| {
+                let state = &state_map[*key];
+                Rc::strong_count(cell) == 1
+                    && Rc::strong_count(state) == 1
+                    && state.get() == ObjectState::Clean
+                    && !(written.contains(*key) && cell.content.borrow_mut().as_hooks().is_some())
+            })
+            .map(|(key, cell)| (cell.used.get(), *key))
+            .collect();
+        let pinned = cell_map.len() - unpinned.len();
+
+        // Down to three quarters of the limit, so that the cache is scanned
+        // once every so many objects rather than for each one.
+        unpinned.sort_unstable_by_key(|(used, _)| *used);
+        let excess = cell_map.len().saturating_sub(limit - limit / 4);
+        for (_, key) in unpinned.into_iter().take(excess) {
+            cell_map.remove(&key);
+            state_map.remove(&key);
+        }
+        // Pinned objects are not rescanned for every object loaded after them.
+        self.next_eviction.set(pinned * 2);
+    }
+
+    fn tick(&self) -> u64 {
+        let now = self.next_seq.get();
+        self.next_seq.set(now + 1);
+        now
+    }
+
+    // Recorded with the changes of `#[audited]` types written from now on.
+    pub fn set_actor(&self, actor: Option<&str>) {
+        *self.actor.borrow_mut() = actor.map(str::to_string);
+    }
+
+    // Only changes made through objects, create and Transaction::delete are
+    // recorded; update_where, delete_where and clear_table are not.
+    fn audit(
+        &self,
+        schema: &'static Schema,
+        id: ObjectId,
+        op: ChangeOp,
+        old: &RowSlice,
+    ) -> Result<()> {
+        if !schema.audited {
             return Ok(());
         }
-        self.inner.create_table(T::TABLE)?;
+        let history = history_schema(schema);
+        if !self.inner.table_exists(history.table_name)? {
+            self.inner.create_table(history)?;
+        }
+        let old = old.iter().cloned().map(Value::into_owned).collect();
+        let row = history_row(id, op, self.actor.borrow().as_deref(), old);
+        self.inner.insert_row(history, &row)?;
+        Ok(())
+    }
+
+    // Oldest first.
+    pub fn history<T: Object>(&self, id: impl Into<Id<T>>) -> Result<Vec<HistoryEntry<T>>> {
+        let id = id.into().raw();
+        let history = history_schema(T::TABLE);
+        if !self.inner.table_exists(history.table_name)? {
+            return Ok(Vec::new());
+        }
+        let (where_sql, params) = column("audit_object_id").eq(id.into_i64()).to_sql();
+        let mut rows = self.inner.select_rows(history, &where_sql, &params)?;
+        rows.sort_by_key(|(history_id, _)| history_id.into_i64());
+        rows.into_iter()
+            .map(|(_, row)| {
+                let mut values = row.into_iter();
+                values.next();
+                let operation = parse_operation(&String::from(values.next().unwrap()));
+                let millis = i64::from(values.next().unwrap()) as u64;
+                Ok(HistoryEntry {
+                    operation,
+                    changed_at: UNIX_EPOCH + Duration::from_millis(millis),
+                    actor: values.next().unwrap().into(),
+                    old: T::deserialize(values.collect())?,
+                })
+            })
+            .collect()
+    }
+
+    // The object as it was at the given time, read from the history of an
+    // `#[audited]` type. Objects that existed before their type was audited
+    // are taken to have always existed.
+    pub fn get_as_of<T: Object>(&self, id: impl Into<Id<T>>, at: SystemTime) -> Result<T> {
+        let id = id.into().raw();
+        let not_found = || Error::NotFound(Box::new(NotFoundError::new(id, T::TABLE.type_name)));
+        let history = self.history::<T>(id)?;
+        let deleted = matches!(history.last(), Some(entry) if entry.operation == ChangeOp::Delete);
+        match history.into_iter().find(|entry| entry.changed_at > at) {
+            Some(entry) if entry.operation == ChangeOp::Insert => Err(not_found()),
+            Some(entry) => Ok(entry.old),
+            None if deleted => Err(not_found()),
+            None => self
+                .ensure_table::<T>()
+                .and_then(|_| self.deserialize(self.inner.select_row(id, T::TABLE)?)),
+        }
+    }
+
+    fn records_events(&self) -> bool {
+        self.outbox.get() || !self.observers.borrow().is_empty()
+    }
+
+    fn record_event(&self, event: impl FnOnce() -> ChangeEvent) {
+        if self.records_events() {
+            let event = event();
+            if event.table() != OutboxEntry::TABLE.table_name {
+                self.events.borrow_mut().push(event);
+            }
+        }
+    }
+
+    // Whether the changes of the transaction are recorded in the outbox as
+    // it commits, see outbox::OutboxEvent. Starts out as the connection's
+    // setting.
+    pub fn set_outbox(&self, enabled: bool) {
+        self.outbox.set(enabled);
+    }
+
+    fn write_outbox(&self) -> Result<()> {
+        if !self.outbox.get() || self.events.borrow().is_empty() {
+            return Ok(());
+        }
+        self.ensure_table::<OutboxEntry>()?;
+        for event in self.events.borrow().iter() {
+            let entry = OutboxEntry::new(event.table(), event.id, event.op);
+            self.inner
+                .insert_row(OutboxEntry::TABLE, &entry.serialize())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn storage(&self) -> &dyn StorageTransaction {
+        self.inner.as_ref()
+    }
+
+    pub(crate) fn record_changes(&self, changes: usize) -> usize {
+        self.last_changes.set(changes);
+        self.total_changes.set(self.total_changes.get() + changes);
+        changes
+    }
+
+    pub fn last_changes(&self) -> usize {
+        self.last_changes.get()
+    }
+
+    pub fn total_changes(&self) -> usize {
+        self.total_changes.get()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // With the check on, commit first re-reads every cached object and fails
+    // with Error::Conflict if a row no longer matches what was loaded.
+    pub fn set_conflict_check(&self, enabled: bool) {
+        self.check_conflicts.set(enabled);
+    }
+
+    // With the lookup on, a UNIQUE or PRIMARY KEY violation of the create
+    // methods fills in ConstraintError::existing, at the cost of a query.
+    pub fn set_conflict_lookup(&self, enabled: bool) {
+        self.lookup_conflicts.set(enabled);
+    }
+
+    // Statements still running at the deadline are interrupted and fail with
+    // Error::Timeout, so one runaway query cannot hold the caller for good.
+    pub fn set_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        self.inner.set_deadline(deadline)
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.set_deadline(Some(Instant::now() + timeout))
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn ensure_writable_table(&self, schema: &Schema) -> Result<()> {
+        self.ensure_writable()?;
+        if schema.view.is_some() {
+            return Err(Error::Storage(
+                format!("{} is backed by a view and is read-only", schema.type_name).into(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Once a table is checked it is taken to exist until the ORM drops it, so
+    // tables changed with execute are checked again.
+    pub(crate) fn ensure_table<T: Object>(&self) -> Result<()> {
+        if self
+            .checked_tables
+            .borrow()
+            .contains_key(&TypeId::of::<T>())
+        {
+            return Ok(());
+        }
+        if !self.inner.table_exists(T::TABLE.table_name)? {
+            self.ensure_writable()?;
+            self.create_table(T::TABLE)?;
+            self.store_fingerprint(T::TABLE)?;
+        } else {
+            self.check_fingerprint(T::TABLE)?;
+            self.check_columns(T::TABLE)?;
+        }
+        self.mark_checked::<T>();
+        Ok(())
+    }
+
+    // Catches a column that was dropped, or never added, outside the ORM
+    // before the first statement trips over it, and columns declared with
+    // another type before rows fail one at a time with UnexpectedType. Only
+    // missing columns give MissingColumn for the first of them, as before;
+    // with type mismatches all of them are listed. Backends that cannot
+    // describe their tables report it from the failing statement instead.
+    // Extra columns are fine, as they may have defaults.
+    fn check_columns(&self, schema: &Schema) -> Result<()> {
+        let info = match self.inner.table_info(schema.table_name) {
+            Err(err) if is_unsupported(&err) => return Ok(()),
+            info => info?,
+        };
+        let mismatches: Vec<_> = column_mismatches(schema, &info)
+            .into_iter()
+            .filter(|m| !matches!(m, Mismatch::ExtraColumn { .. }))
+            .collect();
+        match mismatches.first() {
+            None => Ok(()),
+            Some(Mismatch::MissingColumn { column })
+                if mismatches
+                    .iter()
+                    .all(|m| matches!(m, Mismatch::MissingColumn { .. })) =>
+            {
+                let column = schema
+                    .columns
+                    .iter()
+                    .find(|c| c.column_name == column)
+                    .unwrap();
+                Err(Error::MissingColumn(Box::new(MissingColumnError::new(
+                    schema.type_name,
+                    column.attr_name,
+                    schema.table_name,
+                    column.column_name,
+                ))))
+            }
+            Some(_) => Err(schema_mismatch(schema, mismatches)),
+        }
+    }
+
+    // Views are created and dropped with plain SQL, which the backends
+    // without SQL reject.
+    pub(crate) fn create_table(&self, schema: &Schema) -> Result<()> {
+        if schema.view.is_some() {
+            self.inner.execute(&schema.create_text(), &[])?;
+        } else {
+            self.inner.create_table(schema)?;
+        }
+        for trigger in schema.triggers {
+            self.inner.execute(trigger, &[])?;
+        }
+        Ok(())
+    }
+
+    fn drop_table_or_view(&self, schema: &Schema) -> Result<()> {
+        if schema.view.is_some() {
+            self.inner.execute(&schema.drop_text(), &[])?;
+            return Ok(());
+        }
+        self.inner.drop_table(schema)
+    }
+
+    pub(crate) fn store_fingerprint(&self, schema: &Schema) -> Result<()> {
+        if !self.inner.table_exists(FINGERPRINTS.table_name)? {
+            self.inner.create_table(FINGERPRINTS)?;
+        }
+        let (where_sql, params) = column("table_name").eq(schema.table_name).to_sql();
+        self.inner.delete_where(FINGERPRINTS, &where_sql, &params)?;
+        self.inner.insert_row(
+            FINGERPRINTS,
+            &[schema.table_name.into(), (schema.fingerprint as i64).into()],
+        )?;
+        Ok(())
+    }
+
+    fn forget_fingerprint(&self, table_name: &str) -> Result<()> {
+        if self.inner.table_exists(FINGERPRINTS.table_name)? {
+            let (where_sql, params) = column("table_name").eq(table_name).to_sql();
+            self.inner.delete_where(FINGERPRINTS, &where_sql, &params)?;
+        }
+        Ok(())
+    }
+
+    // Tables created before fingerprints were recorded have no entry and are
+    // accepted as they are.
+    fn check_fingerprint(&self, schema: &Schema) -> Result<()> {
+        if !self.inner.table_exists(FINGERPRINTS.table_name)? {
+            return Ok(());
+        }
+        let (where_sql, params) = column("table_name").eq(schema.table_name).to_sql();
+        let rows = self.inner.select_rows(FINGERPRINTS, &where_sql, &params)?;
+        let found = match rows.into_iter().next() {
+            Some((_, row)) => i64::from(row[1].clone()) as u64,
+            None => return Ok(()),
+        };
+        if found != schema.fingerprint {
+            return Err(Error::SchemaFingerprintMismatch(Box::new(
+                SchemaFingerprintMismatchError::new(
+                    schema.type_name,
+                    schema.table_name,
+                    schema.fingerprint,
+                    found,
+                ),
+            )));
+        }
+        Ok(())
+    }
+
+    fn track<T: Object>(&self, id: ObjectId, obj: T) -> Tx<'_, T> {
+        let map_key = (TypeId::of::<T>(), id);
+        let existing = self.cell_map.borrow().get(&map_key).cloned();
+        if let Some(cell) = existing {
+            cell.used.set(self.tick());
+            *cell.original.borrow_mut() = owned_row(&obj);
+            *cell.content.borrow_mut() = Box::new(obj);
+            let state = self.state_map.borrow().get(&map_key).cloned().unwrap();
+            state.set(ObjectState::Clean);
+            return Tx::new(cell, id, state, PhantomData);
+        }
+
+        let seq = self.tick();
+        let cell = Rc::new(DataCell {
+            id,
+            seq,
+            used: Cell::new(seq),
+            schema: T::TABLE,
+            original: RefCell::new(owned_row(&obj)),
+            stored: Cell::new(true),
+            content: RefCell::new(Box::new(obj)),
+        });
+        self.cell_map.borrow_mut().insert(map_key, cell.clone());
+        let state = Rc::new(Cell::new(ObjectState::Clean));
+        self.state_map.borrow_mut().insert(map_key, state.clone());
+        self.evict();
+        Tx::new(cell, id, state, PhantomData)
+    }
+
+    fn cached<T: Object>(&self, id: ObjectId) -> Option<Result<Tx<'_, T>>> {
+        let map_key = (TypeId::of::<T>(), id);
+        let state = self.state_map.borrow().get(&map_key).cloned()?;
+        if let ObjectState::Removed = state.deref().get() {
+            return Some(Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                T::TABLE.type_name,
+            )))));
+        }
+        let object = self.cell_map.borrow().get(&map_key).cloned()?;
+        object.used.set(self.tick());
+        Some(Ok(Tx::new(object, id, state, PhantomData)))
+    }
+
+    pub fn create<T: Object>(&self, mut src_obj: T) -> Result<Tx<'_, T>> {
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        prepare_save(&mut src_obj)?;
+        let row = src_obj.serialize();
+        let id = self
+            .inner
+            .insert_row(T::TABLE, &row)
+            .map_err(|err| self.with_existing(err, T::TABLE, &row, None))?;
+        self.record_changes(1);
+        // A SmallVec row borrows src_obj until it is dropped.
+        drop(row);
+        let obj = self.track(id, src_obj);
+        self.after_create(&obj)?;
+        Ok(obj)
+    }
+
+    pub fn create_with<T: Object>(
+        &self,
+        mut src_obj: T,
+        on_conflict: OnConflict,
+    ) -> Result<Option<Tx<'_, T>>> {
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        prepare_save(&mut src_obj)?;
+        let row = src_obj.serialize();
+        let id = self
+            .inner
+            .insert_row_with(T::TABLE, &row, on_conflict)
+            .map_err(|err| self.with_existing(err, T::TABLE, &row, None))?;
+        self.record_changes(id.is_some() as usize);
+
+        if on_conflict == OnConflict::Replace {
+            self.forget_replaced::<T>()?;
+        }
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        drop(row);
+        let obj = self.track(id, src_obj);
+        self.after_create(&obj)?;
+        Ok(Some(obj))
+    }
+
+    // Like create for every object, with the rows written in as few
+    // statements as the backend allows.
+    pub fn create_many<T: Object>(
+        &self,
+        objects: impl IntoIterator<Item = T>,
+    ) -> Result<Vec<Tx<'_, T>>> {
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        let mut objects: Vec<T> = objects.into_iter().collect();
+        for obj in &mut objects {
+            prepare_save(obj)?;
+        }
+        let rows: Vec<_> = objects.iter().map(Object::serialize).collect();
+        let ids = self.inner.insert_rows(T::TABLE, &rows)?;
+        drop(rows);
+        self.record_changes(ids.len());
+
+        let mut created = Vec::with_capacity(objects.len());
+        for (obj, id) in objects.into_iter().zip(ids) {
+            let obj = self.track(id, obj);
+            self.after_create(&obj)?;
+            created.push(obj);
+        }
+        Ok(created)
+    }
+
+    // For ids that come from elsewhere, such as another system or a
+    // replication stream. A taken id fails with ConstraintKind::PrimaryKey.
+    pub fn create_with_id<T: Object>(
+        &self,
+        id: impl Into<Id<T>>,
+        mut src_obj: T,
+    ) -> Result<Tx<'_, T>> {
+        let id = id.into().raw();
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        prepare_save(&mut src_obj)?;
+        let row = src_obj.serialize();
+        self.inner
+            .insert_row_with_id(id, T::TABLE, &row)
+            .map_err(|err| self.with_existing(err, T::TABLE, &row, Some(id)))?;
+        self.record_changes(1);
+        drop(row);
+        let obj = self.track(id, src_obj);
+        self.after_create(&obj)?;
+        Ok(obj)
+    }
+
+    // Finds the row that holds the values of the violated columns. Failing
+    // to find it leaves the error as it is: PostgreSQL, for one, refuses
+    // any query once a statement of the transaction has failed.
+    fn with_existing(
+        &self,
+        err: Error,
+        schema: &Schema,
+        row: &RowSlice,
+        id: Option<ObjectId>,
+    ) -> Error {
+        let Error::Constraint(mut err) = err else {
+            return err;
+        };
+        if !self.lookup_conflicts.get()
+            || !matches!(
+                err.kind,
+                ConstraintKind::Unique | ConstraintKind::PrimaryKey
+            )
+        {
+            return Error::Constraint(err);
+        }
+
+        let mut predicate: Option<Predicate> = None;
+        for name in &err.columns {
+            let condition = match schema.columns.iter().position(|c| c.column_name == name) {
+                Some(i) => column(schema.columns[i].column_name).eq(row[i].clone()),
+                None => match id {
+                    Some(id) if name == "id" => column("id").eq(id.into_i64()),
+                    _ => return Error::Constraint(err),
+                },
+            };
+            predicate = Some(match predicate {
+                Some(predicate) => predicate.and(condition),
+                None => condition,
+            });
+        }
+        let Some(predicate) = predicate else {
+            return Error::Constraint(err);
+        };
+
+        let (where_sql, params) = predicate.to_sql();
+        let mut existing = None;
+        let found = self
+            .inner
+            .for_each_row(schema, &where_sql, &params, &mut |id, _| {
+                existing.get_or_insert(id);
+                Ok(())
+            });
+        if found.is_ok() {
+            err.existing = existing;
+        }
+        Error::Constraint(err)
+    }
+
+    fn after_create<T: Object>(&self, obj: &Tx<'_, T>) -> Result<()> {
+        self.audit(
+            T::TABLE,
+            obj.id,
+            ChangeOp::Insert,
+            &obj.cell.original.borrow(),
+        )?;
+        self.record_event(|| ChangeEvent::insert(obj));
+        self.written
+            .borrow_mut()
+            .insert((TypeId::of::<T>(), obj.id));
+        if let Some(hooks) = obj.cell.content.borrow_mut().as_hooks() {
+            hooks.after_create(self, obj.id)?;
+        }
+        Ok(())
+    }
+
+    // REPLACE silently deletes the rows it conflicted with, so cached objects
+    // whose rows are gone are marked removed without scheduling another DELETE.
+    fn forget_replaced<T: Object>(&self) -> Result<()> {
+        let ids: Vec<_> = self
+            .state_map
+            .borrow()
+            .iter()
+            .filter(|((type_id, _), state)| {
+                *type_id == TypeId::of::<T>() && state.get() != ObjectState::Removed
+            })
+            .map(|((_, id), _)| *id)
+            .collect();
+        // Only the ids are read.
+        let schema = Schema {
+            columns: &[],
+            ..*T::TABLE
+        };
+        let mut alive = HashSet::new();
+        for ids in ids.chunks(PREFETCH_CHUNK) {
+            let where_sql = format!("id IN ({})", vec!["?"; ids.len()].join(", "));
+            let params: Row = ids.iter().map(|id| Value::Int64(id.into_i64())).collect();
+            for (id, _) in self.inner.select_rows(&schema, &where_sql, &params)? {
+                alive.insert(id);
+            }
+        }
+
+        for id in ids.into_iter().filter(|id| !alive.contains(id)) {
+            self.forget((TypeId::of::<T>(), id));
+        }
+        Ok(())
+    }
+
+    fn forget(&self, map_key: (TypeId, ObjectId)) {
+        if let Some(cell) = self.cell_map.borrow_mut().remove(&map_key) {
+            cell.stored.set(false);
+        }
+        if let Some(state) = self.state_map.borrow().get(&map_key) {
+            state.set(ObjectState::Removed);
+        }
+    }
+
+    pub(crate) fn forget_all<T: Object>(&self) {
+        let keys: Vec<_> = self
+            .state_map
+            .borrow()
+            .keys()
+            .filter(|(type_id, _)| *type_id == TypeId::of::<T>())
+            .copied()
+            .collect();
+        for map_key in keys {
+            self.forget(map_key);
+        }
+    }
+
+    pub fn clear_table<T: Object>(&self) -> Result<usize> {
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        let changes = self.inner.clear_table(T::TABLE)?;
+        self.forget_all::<T>();
+        Ok(self.record_changes(changes))
+    }
+
+    // The SQL name for clear_table.
+    pub fn truncate<T: Object>(&self) -> Result<usize> {
+        self.clear_table::<T>()
+    }
+
+    // Does nothing if the table does not exist.
+    pub fn drop_table<T: Object>(&self) -> Result<()> {
+        self.ensure_writable()?;
+        if self.inner.table_exists(T::TABLE.table_name)? {
+            self.drop_table_or_view(T::TABLE)?;
+        }
+        self.forget_fingerprint(T::TABLE.table_name)?;
+        self.forget_checked(T::TABLE.table_name);
+        self.forget_all::<T>();
         Ok(())
     }
 
-    pub fn create<T: Object>(&self, src_obj: T) -> Result<Tx<'_, T>> {
+    // Schema changes for migrations, which keep the stored rows. T describes
+    // the table after the change and has to match it.
+    pub fn rename_table<T: Object>(&self, from: &str) -> Result<()> {
+        self.ensure_writable()?;
+        self.inner.rename_table(from, T::TABLE.table_name)?;
+        self.forget_fingerprint(from)?;
+        self.forget_checked(from);
+        self.adopt_table::<T>()
+    }
+
+    pub fn rename_column<T: Object>(&self, from: &str, to: &str) -> Result<()> {
+        self.ensure_writable()?;
+        self.inner.rename_column(T::TABLE.table_name, from, to)?;
+        self.adopt_table::<T>()
+    }
+
+    pub fn drop_column<T: Object>(&self, column: &str) -> Result<()> {
+        self.ensure_writable()?;
+        self.inner.drop_column(T::TABLE.table_name, column)?;
+        self.adopt_table::<T>()
+    }
+
+    // Rewrites every row of Old as a New with the same id, for migrations
+    // that reshape data through the Object API, such as splitting a column.
+    // The table is recreated as New declares it, and nothing is changed if f
+    // fails. Returns the number of rows.
+    pub fn rewrite_table<Old: Object, New: Object>(
+        &self,
+        mut f: impl FnMut(Old) -> Result<New>,
+    ) -> Result<usize> {
+        self.ensure_writable_table(New::TABLE)?;
+        self.ensure_table::<Old>()?;
+        if New::TABLE.table_name != Old::TABLE.table_name
+            && self.inner.table_exists(New::TABLE.table_name)?
+        {
+            return Err(Error::Storage(
+                format!("table {} already exists", New::TABLE.table_name).into(),
+            ));
+        }
+        self.flush()?;
+        let rewritten = select_stored(self.storage(), Old::TABLE)?
+            .into_iter()
+            .map(|(id, row)| Ok((id, f(self.deserialize(row)?)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.drop_table::<Old>()?;
+        self.forget_checked(New::TABLE.table_name);
+        self.ensure_table::<New>()?;
+        for (id, obj) in &rewritten {
+            self.inner
+                .insert_row_with_id(*id, New::TABLE, &obj.serialize())?;
+        }
+        self.forget_all::<New>();
+        Ok(self.record_changes(rewritten.len()))
+    }
+
+    fn adopt_table<T: Object>(&self) -> Result<()> {
+        self.validate_schema::<T>()?;
+        self.store_fingerprint(T::TABLE)?;
+        self.forget_checked(T::TABLE.table_name);
+        self.mark_checked::<T>();
+        self.forget_all::<T>();
+        Ok(())
+    }
+
+    // Other types stored in the same table are checked again on next use.
+    pub(crate) fn forget_checked(&self, table_name: &str) {
+        self.checked_tables
+            .borrow_mut()
+            .retain(|_, table| *table != table_name);
+    }
+
+    fn mark_checked<T: Object>(&self) {
+        self.checked_tables
+            .borrow_mut()
+            .insert(TypeId::of::<T>(), T::TABLE.table_name);
+    }
+
+    pub fn recreate_table<T: Object>(&self) -> Result<()> {
+        self.ensure_writable()?;
+        if self.inner.table_exists(T::TABLE.table_name)? {
+            self.drop_table_or_view(T::TABLE)?;
+        }
+        self.create_table(T::TABLE)?;
+        self.store_fingerprint(T::TABLE)?;
+        self.forget_checked(T::TABLE.table_name);
+        self.mark_checked::<T>();
+        self.forget_all::<T>();
+        Ok(())
+    }
+
+    // Adds the columns T declares that the table lacks and returns their
+    // names. Stored columns T no longer declares, or whose type differs, are
+    // reported as Error::SchemaMismatch and nothing is altered.
+    pub fn sync_schema<T: Object>(&self) -> Result<Vec<&'static str>> {
+        self.ensure_writable()?;
+        let schema = T::TABLE;
+        if !self.inner.table_exists(schema.table_name)? {
+            self.ensure_table::<T>()?;
+            return Ok(Vec::new());
+        }
+        let added = self.sync_columns(schema)?;
+        self.forget_checked(schema.table_name);
+        self.mark_checked::<T>();
+        Ok(added)
+    }
+
+    fn sync_columns(&self, schema: &'static Schema) -> Result<Vec<&'static str>> {
+        let (missing, mismatches): (Vec<_>, Vec<_>) = self
+            .schema_mismatches(schema)?
+            .into_iter()
+            .partition(|mismatch| matches!(mismatch, Mismatch::MissingColumn { .. }));
+        if !mismatches.is_empty() {
+            return Err(schema_mismatch(schema, mismatches));
+        }
+
+        let mut added = Vec::new();
+        for column in schema.columns {
+            if missing.contains(&Mismatch::MissingColumn {
+                column: column.column_name.to_string(),
+            }) {
+                self.inner.add_column(schema, column)?;
+                added.push(column.column_name);
+            }
+        }
+        self.store_fingerprint(schema)?;
+        Ok(added)
+    }
+
+    // A table defined at runtime. It is created if missing, and columns added
+    // to the definition since are added to it as in sync_schema.
+    pub fn dynamic_table(&self, schema: &DynamicSchema) -> Result<DynamicTable<'_, 'a>> {
+        let schema = schema.schema();
+        if !self.inner.table_exists(schema.table_name)? {
+            self.ensure_writable()?;
+            self.create_table(schema)?;
+            self.store_fingerprint(schema)?;
+        } else {
+            match self.check_fingerprint(schema) {
+                Err(Error::SchemaFingerprintMismatch(_)) => {
+                    self.ensure_writable()?;
+                    self.sync_columns(schema)?;
+                }
+                result => result?,
+            }
+        }
+        Ok(DynamicTable::new(self, schema))
+    }
+
+    // Every stored table, as the backend describes it.
+    pub fn introspect(&self) -> Result<Vec<TableInfo>> {
+        self.inner
+            .table_names()?
+            .iter()
+            .map(|name| self.inner.table_info(name))
+            .collect()
+    }
+
+    // The row count and number of columns of every stored table, including
+    // the ORM's own, in the order of Transaction::introspect.
+    pub fn table_stats(&self) -> Result<Vec<TableStats>> {
+        self.flush()?;
+        self.introspect()?
+            .into_iter()
+            .map(|info| {
+                let schema = DynamicSchema::new(info.name.as_str()).schema();
+                Ok(TableStats {
+                    rows: self.inner.count_rows(schema, "", &[])?,
+                    columns: info.columns.iter().filter(|c| c.name != "id").count(),
+                    name: info.name,
+                })
+            })
+            .collect()
+    }
+
+    // Checks the stored table of T without creating or altering it.
+    pub fn validate_schema<T: Object>(&self) -> Result<()> {
+        let mismatches = self.schema_mismatches(T::TABLE)?;
+        if !mismatches.is_empty() {
+            return Err(schema_mismatch(T::TABLE, mismatches));
+        }
+        Ok(())
+    }
+
+    // Checks every table and returns one error per table that differs.
+    pub fn validate_schemas(
+        &self,
+        schemas: &[&'static Schema],
+    ) -> Result<Vec<SchemaMismatchError>> {
+        let mut errors = Vec::new();
+        for schema in schemas {
+            let mismatches = self.schema_mismatches(schema)?;
+            if !mismatches.is_empty() {
+                errors.push(SchemaMismatchError::new(
+                    schema.type_name,
+                    schema.table_name,
+                    mismatches,
+                ));
+            }
+        }
+        Ok(errors)
+    }
+
+    pub(crate) fn schema_mismatches(&self, schema: &Schema) -> Result<Vec<Mismatch>> {
+        if !self.inner.table_exists(schema.table_name)? {
+            return Ok(vec![Mismatch::MissingTable]);
+        }
+
+        Ok(column_mismatches(
+            schema,
+            &self.inner.table_info(schema.table_name)?,
+        ))
+    }
+
+    pub fn get<T: Object>(&self, id: impl Into<Id<T>>) -> Result<Tx<'_, T>> {
+        let id = id.into().raw();
+        self.ensure_table::<T>()?;
+        if let Some(cached) = self.cached(id) {
+            return cached;
+        }
+
+        let obj = self.deserialize(self.inner.select_row(id, T::TABLE)?)?;
+        Ok(self.track(id, obj))
+    }
+
+    fn deserialize<T: Object>(&self, row: Row) -> Result<T> {
+        let instrument = self.instrument.as_deref();
+        Ok(timed(
+            instrument,
+            Operation::Deserialize,
+            T::TABLE.type_name,
+            || T::deserialize(row),
+        )?)
+    }
+
+    // Reads the stored row of a type registered with dynamic::register,
+    // without pending changes of a cached object.
+    pub fn get_dynamic(&self, table_name: &str, id: ObjectId) -> Result<DynamicRow> {
+        let schema = registered(table_name).ok_or_else(|| {
+            Error::Storage(format!("table {} is not registered", table_name).into())
+        })?;
+        if !self.inner.table_exists(schema.table_name)? {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            ))));
+        }
+        Ok(DynamicRow {
+            schema,
+            id,
+            values: self.inner.select_row(id, schema)?,
+        })
+    }
+
+    // Creates an object of a type registered with dynamic::register from its
+    // values in schema column order, as create would.
+    pub fn create_dynamic(&self, table_name: &str, values: Row<'static>) -> Result<ObjectId> {
+        let (schema, create) = registered_create(table_name).ok_or_else(|| {
+            Error::Storage(format!("table {} is not registered", table_name).into())
+        })?;
+        check_values(schema, &values)?;
+        create(self, None, values)
+    }
+
+    pub fn try_get<T: Object>(&self, id: impl Into<Id<T>>) -> Result<Option<Tx<'_, T>>> {
+        match self.get(id) {
+            Ok(obj) => Ok(Some(obj)),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Replaces the cached content with the stored row, dropping pending
+    // changes of the object, including a pending delete.
+    pub fn reload<T: Object>(&self, obj: &Tx<'_, T>) -> Result<()> {
+        match self.inner.select_row(obj.id, T::TABLE) {
+            Ok(row) => {
+                self.track(obj.id, self.deserialize::<T>(row)?);
+                Ok(())
+            }
+            Err(err @ Error::NotFound(_)) => {
+                self.forget((TypeId::of::<T>(), obj.id));
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Reads a `#[lazy]` field of the object. A field changed before it is
+    // loaded keeps the new value, and is still written at the next flush.
+    pub fn load_field<T: Object>(&self, obj: &Tx<'_, T>, attr_name: &str) -> Result<()> {
+        let schema = T::TABLE;
+        let index = schema
+            .columns
+            .iter()
+            .position(|c| c.attr_name == attr_name)
+            .ok_or_else(|| {
+                Error::Storage(format!("{} has no field {}", schema.type_name, attr_name).into())
+            })?;
+        let value = self
+            .inner
+            .select_row(obj.id, schema.eager())?
+            .swap_remove(index);
+
+        let mut content = obj
+            .cell
+            .content
+            .try_borrow_mut()
+            .map_err(|_| obj.borrowed())?;
+        let mut original = obj.cell.original.borrow_mut();
+        let mut row = owned_row(content.as_ref());
+        if row[index] == original[index] {
+            row[index] = value.clone();
+            content.restore(row);
+        }
+        original[index] = value;
+        Ok(())
+    }
+
+    // Predicates on geometry columns read the spatial index of the column
+    // where there is one.
+    #[cfg(feature = "spatial")]
+    fn predicate_sql(
+        &self,
+        schema: &Schema,
+        predicate: &Predicate,
+    ) -> Result<(String, Row<'static>)> {
+        predicate.check_columns(schema)?;
+        let mut indexed = Vec::new();
+        for column in predicate.geometry_columns() {
+            let index = crate::spatial::index_name(schema.table_name, column);
+            if self.inner.table_exists(&index)? {
+                indexed.push((column, index));
+            }
+        }
+        Ok(predicate.to_sql_with(&|column| {
+            indexed
+                .iter()
+                .find(|(name, _)| *name == column)
+                .map(|(_, index)| index.clone())
+        }))
+    }
+
+    #[cfg(not(feature = "spatial"))]
+    fn predicate_sql(
+        &self,
+        schema: &Schema,
+        predicate: &Predicate,
+    ) -> Result<(String, Row<'static>)> {
+        predicate.check_columns(schema)?;
+        Ok(predicate.to_sql())
+    }
+
+    pub fn select<T: Object>(&self, predicate: &Predicate) -> Result<Vec<Tx<'_, T>>> {
+        let (where_sql, params) = self.predicate_sql(T::TABLE, predicate)?;
+        self.select_where(&where_sql, &params)
+    }
+
+    pub fn select_all<T: Object>(&self) -> Result<Vec<Tx<'_, T>>> {
+        self.select_where("", &[])
+    }
+
+    pub fn select_map<T: Object>(
+        &self,
+        predicate: &Predicate,
+    ) -> Result<HashMap<ObjectId, Tx<'_, T>>> {
+        let objects = self.select::<T>(predicate)?;
+        Ok(objects
+            .into_iter()
+            .map(|obj| (obj.id().raw(), obj))
+            .collect())
+    }
+
+    pub fn select_map_by<T: Object, K: Eq + Hash>(
+        &self,
+        predicate: &Predicate,
+        key: impl Fn(&T) -> K,
+    ) -> Result<HashMap<K, Tx<'_, T>>> {
+        let objects = self.select::<T>(predicate)?;
+        Ok(objects
+            .into_iter()
+            .map(|obj| {
+                let key = key(&obj.borrow());
+                (key, obj)
+            })
+            .collect())
+    }
+
+    // Loads the objects of the ids that are not cached yet, in as few SELECTs
+    // as the parameter limit allows, so that a get of any of them afterwards
+    // is served from the cache. Ids without a row are skipped. Returns how
+    // many objects were loaded.
+    pub fn prefetch<T: Object>(&self, ids: &[ObjectId]) -> Result<usize> {
+        self.ensure_table::<T>()?;
+        let mut seen = HashSet::new();
+        let missing: Vec<_> = {
+            let state_map = self.state_map.borrow();
+            ids.iter()
+                .copied()
+                .filter(|id| !state_map.contains_key(&(TypeId::of::<T>(), *id)) && seen.insert(*id))
+                .collect()
+        };
+
+        let mut loaded = 0;
+        for ids in missing.chunks(PREFETCH_CHUNK) {
+            let where_sql = format!("id IN ({})", vec!["?"; ids.len()].join(", "));
+            let params: Row = ids.iter().map(|id| Value::Int64(id.into_i64())).collect();
+            loaded += self.load_where::<T>(&where_sql, &params, |_, _| {})?;
+        }
+        Ok(loaded)
+    }
+
+    // Loads the objects of C whose `column` holds the id of one of the
+    // parents, as prefetch does, and returns the ids of the children of every
+    // parent, in id order. Parents without children are left out.
+    pub fn prefetch_children<C: Object>(
+        &self,
+        column: &str,
+        parents: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Vec<ObjectId>>> {
+        self.ensure_table::<C>()?;
+        let schema = C::TABLE;
+        let position = schema
+            .columns
+            .iter()
+            .position(|c| c.column_name == column && c.typ == DataType::Int64)
+            .ok_or_else(|| {
+                Error::Storage(
+                    format!("{} has no integer column {}", schema.type_name, column).into(),
+                )
+            })?;
+        let parents: Vec<_> = parents
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut children: HashMap<ObjectId, Vec<ObjectId>> = HashMap::new();
+        for parents in parents.chunks(PREFETCH_CHUNK) {
+            let where_sql = format!(
+                "{} IN ({})",
+                quote(column),
+                vec!["?"; parents.len()].join(", ")
+            );
+            let params: Row = parents
+                .iter()
+                .map(|id| Value::Int64(id.into_i64()))
+                .collect();
+            self.load_where::<C>(&where_sql, &params, |id, row| {
+                if let Value::Int64(parent) = row[position] {
+                    children.entry(ObjectId::from(parent)).or_default().push(id);
+                }
+            })?;
+        }
+        children
+            .values_mut()
+            .for_each(|ids| ids.sort_unstable_by_key(|id| id.into_i64()));
+        Ok(children)
+    }
+
+    // Tracks the selected objects that are not cached yet, and passes every
+    // selected row to `f` first.
+    fn load_where<T: Object>(
+        &self,
+        where_sql: &str,
+        params: &RowSlice,
+        mut f: impl FnMut(ObjectId, &RowSlice),
+    ) -> Result<usize> {
+        let rows = self.inner.select_rows(T::TABLE, where_sql, params)?;
+        let mut loaded = 0;
+        for (id, row) in rows {
+            f(id, &row);
+            if !self
+                .state_map
+                .borrow()
+                .contains_key(&(TypeId::of::<T>(), id))
+            {
+                self.track(id, self.deserialize::<T>(row)?);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn select_where<T: Object>(
+        &self,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<Tx<'_, T>>> {
+        self.ensure_table::<T>()?;
+        let rows = self.inner.select_rows(T::TABLE, where_sql, params)?;
+
+        let mut objects = Vec::with_capacity(rows.len());
+        for (id, row) in rows {
+            match self.cached(id) {
+                Some(Ok(object)) => objects.push(object),
+                Some(Err(_)) => {}
+                None => objects.push(self.track(id, self.deserialize(row)?)),
+            }
+        }
+        Ok(objects)
+    }
+
+    // Passes the stored rows to `f` without loading objects, in the order of
+    // T::TABLE.columns. On SQLite, text and blobs borrow from the statement
+    // and are only valid for the call. Changes not yet flushed are not seen.
+    pub fn scan<T: Object>(
+        &self,
+        predicate: &Predicate,
+        f: impl FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        let (where_sql, params) = self.predicate_sql(T::TABLE, predicate)?;
+        self.scan_where::<T>(&where_sql, &params, f)
+    }
+
+    pub(crate) fn scan_where<T: Object>(
+        &self,
+        where_sql: &str,
+        params: &RowSlice,
+        mut f: impl FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        self.ensure_table::<T>()?;
+        self.inner
+            .for_each_row_ref(T::TABLE, where_sql, params, &mut f)
+    }
+
+    // Every stored row of T with its lazy columns read too, for the exports.
+    #[cfg(any(feature = "serde", feature = "csv"))]
+    pub(crate) fn scan_stored<T: Object>(
+        &self,
+        mut f: impl FnMut(ObjectId, &RowSlice) -> Result<()>,
+    ) -> Result<()> {
+        self.ensure_table::<T>()?;
+        self.inner
+            .for_each_row_ref(T::TABLE.eager(), "", &[], &mut f)
+    }
+
+    pub fn count_where<T: Object>(&self, predicate: &Predicate) -> Result<usize> {
         self.ensure_table::<T>()?;
-        let map_key = (
-            TypeId::of::<T>(),
-            self.inner.insert_row(T::TABLE, &src_obj.serialize())?,
-        );
-        let cell = Rc::new(DataCell {
-            id: map_key.1,
-            content: RefCell::new(Box::new(src_obj)),
-        });
-        self.cell_map.borrow_mut().insert(map_key, cell.clone());
-        let state = Rc::new(Cell::new(ObjectState::Clean));
-        self.state_map.borrow_mut().insert(map_key, state.clone());
-        Ok(Tx::new(cell, map_key.1, state, PhantomData))
+        let (where_sql, params) = self.predicate_sql(T::TABLE, predicate)?;
+        self.inner.count_rows(T::TABLE, &where_sql, &params)
+    }
+
+    pub fn update_where<T: Object>(
+        &self,
+        predicate: &Predicate,
+        assignments: &[(&str, Value)],
+    ) -> Result<usize> {
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        let (where_sql, params) = self.predicate_sql(T::TABLE, predicate)?;
+        let (columns, values): (Vec<_>, Vec<_>) = assignments.iter().cloned().unzip();
+        self.flush()?;
+        let changes = self
+            .inner
+            .update_where(T::TABLE, &columns, &values, &where_sql, &params)?;
+        self.refresh_cached::<T>()?;
+        Ok(self.record_changes(changes))
     }
 
-    pub fn get<T: Object>(&self, id: ObjectId) -> Result<Tx<'_, T>> {
+    // Deletes the row right away, without loading it first.
+    pub fn delete<T: Object>(&self, id: impl Into<Id<T>>) -> Result<()> {
+        let id = id.into().raw();
+        self.ensure_writable_table(T::TABLE)?;
         self.ensure_table::<T>()?;
         let map_key = (TypeId::of::<T>(), id);
+        let cached = match self.cached::<T>(id) {
+            Some(Err(err)) => return Err(err),
+            cached => cached,
+        };
+        // Observers are told the deleted row, read here when it is not cached.
+        let old = match cached {
+            _ if !self.records_events() && !T::TABLE.audited => None,
+            Some(Ok(obj)) => Some(obj.cell.original.borrow().clone()),
+            _ => Some(self.inner.select_row(id, T::TABLE)?),
+        };
+        self.inner.delete_row(id, T::TABLE)?;
+        self.record_changes(1);
+        if let Some(old) = &old {
+            self.audit(T::TABLE, id, ChangeOp::Delete, old)?;
+        }
+        self.record_event(|| ChangeEvent::delete(T::TABLE, id, old.unwrap()));
 
-        if let Some(state) = self.state_map.borrow().get(&map_key).cloned() {
-            if let ObjectState::Removed = state.deref().get() {
-                return Err(Error::NotFound(Box::new(NotFoundError::new(
-                    id,
-                    T::TABLE.type_name,
-                ))));
-            }
-            if let Some(object) = self.cell_map.borrow().get(&map_key).cloned() {
-                return Ok(Tx::new(object, id, state, PhantomData));
+        self.forget(map_key);
+        self.state_map
+            .borrow_mut()
+            .entry(map_key)
+            .or_insert_with(|| Rc::new(Cell::new(ObjectState::Removed)));
+        Ok(())
+    }
+
+    pub fn delete_where<T: Object>(&self, predicate: &Predicate) -> Result<usize> {
+        self.ensure_writable_table(T::TABLE)?;
+        self.ensure_table::<T>()?;
+        let (where_sql, params) = self.predicate_sql(T::TABLE, predicate)?;
+        self.flush()?;
+        let changes = self.inner.delete_where(T::TABLE, &where_sql, &params)?;
+        self.refresh_cached::<T>()?;
+        Ok(self.record_changes(changes))
+    }
+
+    // After statements that change rows behind the cache, which flush first
+    // so no pending change is lost, every cached T is read again, or
+    // forgotten if its row is gone.
+    fn refresh_cached<T: Object>(&self) -> Result<()> {
+        let ids: Vec<_> = self
+            .cell_map
+            .borrow()
+            .keys()
+            .filter(|(type_id, _)| *type_id == TypeId::of::<T>())
+            .map(|(_, id)| *id)
+            .collect();
+        for id in ids {
+            match self.inner.select_row(id, T::TABLE) {
+                Ok(row) => {
+                    self.track(id, self.deserialize::<T>(row)?);
+                }
+                Err(Error::NotFound(_)) => self.forget((TypeId::of::<T>(), id)),
+                Err(err) => return Err(err),
             }
         }
+        Ok(())
+    }
 
-        let cell = Rc::new(DataCell {
-            id,
-            content: RefCell::new(Box::new(T::deserialize(
-                self.inner.select_row(id, T::TABLE)?,
-            ))),
-        });
-        self.cell_map.borrow_mut().insert(map_key, cell.clone());
-        let state = Rc::new(Cell::new(ObjectState::Clean));
-        self.state_map.borrow_mut().insert(map_key, state.clone());
-        Ok(Tx::new(cell, id, state, PhantomData))
+    // Until commit, foreign key violations are only counted and commit fails
+    // with Error::Constraint if any remain.
+    pub fn defer_foreign_keys(&self, deferred: bool) -> Result<()> {
+        self.inner.defer_foreign_keys(deferred)
+    }
+
+    pub fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        let changes = self.inner.execute(sql, params)?;
+        self.checked_tables.borrow_mut().clear();
+        Ok(self.record_changes(changes))
+    }
+
+    // What commit or flush would write for the cached objects, ordered by
+    // type name and id.
+    pub fn pending_changes(&self) -> PendingChanges {
+        let state_map = self.state_map.borrow();
+        let mut changes: Vec<_> = self
+            .cell_map
+            .borrow()
+            .iter()
+            .filter_map(|(key, cell)| {
+                let state = state_map.get(key)?.get();
+                (state != ObjectState::Clean).then_some(PendingChange {
+                    type_name: cell.schema.type_name,
+                    id: cell.id,
+                    state,
+                })
+            })
+            .collect();
+        changes.sort_by_key(|change| (change.type_name, change.id.into_i64()));
+        PendingChanges { changes }
+    }
+
+    // Writes the pending changes of cached objects, so that raw SQL and
+    // queries later in the transaction see them.
+    pub fn flush(&self) -> Result<()> {
+        self.try_apply()
     }
 
+    // Updates run in the order the objects entered the transaction and
+    // deletes run after them in reverse, so rows referencing objects created
+    // earlier are updated against existing rows and removed before them.
+    // Updates of the same columns of a table and deletes from the same table
+    // that follow each other in that order go to storage together.
     fn try_apply(&self) -> Result<()> {
-        for (key, value) in self.cell_map.borrow().iter() {
-            let object = value.content.borrow();
-            let state = self.state_map.borrow().get(key).cloned().unwrap();
+        if self.failed.get() {
+            return Err(Error::Storage(
+                "a savepoint of the transaction could not be rolled back".into(),
+            ));
+        }
+        // Hooks may create objects, so the map is not borrowed while they run.
+        let mut cells: Vec<_> = self
+            .cell_map
+            .borrow()
+            .iter()
+            .map(|(key, cell)| (*key, cell.clone()))
+            .collect();
+        cells.sort_by_key(|(_, cell)| cell.seq);
+        let mut removed = Vec::new();
+        let mut updates = Vec::new();
+        for (key, value) in cells {
+            let state = self.state_map.borrow().get(&key).cloned().unwrap();
+            if state.get() != ObjectState::Clean {
+                self.ensure_writable_table(value.schema)?;
+            }
             match state.deref().get() {
-                ObjectState::Removed => self.inner.delete_row(value.id, object.get_table())?,
+                ObjectState::Removed => removed.push((key, value)),
                 ObjectState::Modified => {
-                    self.inner
-                        .update_row(value.id, object.get_table(), &object.serialize())?
+                    // A handle may still be borrowed, as flush takes &self.
+                    let borrowed = || {
+                        Error::Borrowed(Box::new(BorrowedError::new(
+                            value.id,
+                            value.schema.type_name,
+                        )))
+                    };
+                    value
+                        .content
+                        .try_borrow_mut()
+                        .map_err(|_| borrowed())?
+                        .prepare_save()?;
+                    let object = value.content.try_borrow().map_err(|_| borrowed())?;
+                    match changed_columns(&value, object.as_ref()) {
+                        Some(changes) => updates.push(Update {
+                            key,
+                            cell: value.clone(),
+                            state,
+                            changes,
+                        }),
+                        None => {
+                            self.record_changes(0);
+                            self.written.borrow_mut().insert(key);
+                            state.set(ObjectState::Clean);
+                        }
+                    }
                 }
                 _ => {}
             }
         }
+        for group in updates.chunk_by_mut(|a, b| {
+            std::ptr::eq(a.cell.schema, b.cell.schema) && a.changes.columns == b.changes.columns
+        }) {
+            let schema = group[0].cell.schema;
+            let rows: Vec<_> = group
+                .iter_mut()
+                .map(|update| (update.cell.id, std::mem::take(&mut update.changes.values)))
+                .collect();
+            let changes = self
+                .inner
+                .update_rows(schema, &group[0].changes.columns, &rows)?;
+            self.record_changes(changes);
+            for update in group {
+                let new = std::mem::take(&mut update.changes.row);
+                let old = update.cell.original.replace(new.clone());
+                self.audit(schema, update.cell.id, ChangeOp::Update, &old)?;
+                self.record_event(|| ChangeEvent {
+                    schema,
+                    id: update.cell.id,
+                    op: ChangeOp::Update,
+                    old: Some(old),
+                    new: Some(new),
+                });
+                self.written.borrow_mut().insert(update.key);
+                update.state.set(ObjectState::Clean);
+            }
+        }
+        removed.reverse();
+        for group in removed.chunk_by(|(_, a), (_, b)| std::ptr::eq(a.schema, b.schema)) {
+            let ids: Vec<_> = group.iter().map(|(_, value)| value.id).collect();
+            self.inner.delete_rows(group[0].1.schema, &ids)?;
+            self.record_changes(ids.len());
+            for (_, value) in group {
+                self.audit(
+                    value.schema,
+                    value.id,
+                    ChangeOp::Delete,
+                    &value.original.borrow(),
+                )?;
+                self.record_event(|| {
+                    ChangeEvent::delete(value.schema, value.id, value.original.borrow().clone())
+                });
+            }
+        }
+        for (key, _) in removed {
+            self.forget(key);
+        }
+        Ok(())
+    }
+
+    // Changes made through the savepoint, including ones to objects that were
+    // loaded before it, are undone by its rollback alone.
+    pub fn savepoint(&self) -> Result<Savepoint<'_, 'a>> {
+        let number = self.last_savepoint.get() + 1;
+        let name = format!("orm_savepoint_{}", number);
+        let snapshot = self.snapshot();
+        self.inner.savepoint(&name)?;
+        self.last_savepoint.set(number);
+        self.savepoints.borrow_mut().push(number);
+        Ok(Savepoint {
+            tx: self,
+            number,
+            name,
+            snapshot,
+            finished: false,
+        })
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let cells = self
+            .cell_map
+            .borrow()
+            .iter()
+            .map(|(key, cell)| {
+                let row = owned_row(cell.content.borrow().as_ref());
+                let original = cell.original.borrow().clone();
+                (*key, (cell.clone(), row, original))
+            })
+            .collect();
+        let states = self
+            .state_map
+            .borrow()
+            .iter()
+            .map(|(key, state)| (*key, state.get()))
+            .collect();
+        Snapshot {
+            cells,
+            states,
+            checked_tables: self.checked_tables.borrow().clone(),
+            written: self.written.borrow().clone(),
+            events: self.events.borrow().len(),
+        }
+    }
+
+    fn restore_snapshot(&self, snapshot: Snapshot) {
+        let mut cell_map = self.cell_map.borrow_mut();
+        cell_map.clear();
+        for (key, (cell, row, original)) in snapshot.cells {
+            cell.content.borrow_mut().restore(row);
+            *cell.original.borrow_mut() = original;
+            cell.stored.set(true);
+            cell_map.insert(key, cell);
+        }
+        // Objects first seen after the savepoint are dropped from the maps.
+        self.state_map
+            .borrow_mut()
+            .retain(|key, state| match snapshot.states.get(key) {
+                Some(saved) => {
+                    state.set(*saved);
+                    true
+                }
+                None => false,
+            });
+        *self.checked_tables.borrow_mut() = snapshot.checked_tables;
+        *self.written.borrow_mut() = snapshot.written;
+        self.events.borrow_mut().truncate(snapshot.events);
+    }
+
+    fn check_conflicts(&self) -> Result<()> {
+        if !self.check_conflicts.get() {
+            return Ok(());
+        }
+        let cells: Vec<_> = self.cell_map.borrow().values().cloned().collect();
+        let mut stale = Vec::new();
+        for cell in cells {
+            let schema = cell.content.borrow().get_table();
+            let unchanged = match self.inner.select_row(cell.id, schema) {
+                // Lazy columns read as placeholders and are not compared.
+                Ok(row) => schema
+                    .columns
+                    .iter()
+                    .zip(row.iter().zip(cell.original.borrow().iter()))
+                    .all(|(column, (stored, loaded))| column.lazy || stored == loaded),
+                Err(Error::NotFound(_)) => false,
+                Err(err) => return Err(err),
+            };
+            if !unchanged {
+                stale.push((schema.type_name, cell.id));
+            }
+        }
+        if !stale.is_empty() {
+            stale.sort_by_key(|(type_name, id)| (*type_name, id.into_i64()));
+            return Err(Error::Conflict(Box::new(ConflictError::new(stale))));
+        }
         Ok(())
     }
 
     pub fn commit(self) -> Result<()> {
+        self.check_conflicts()?;
         self.try_apply()?;
+        self.write_outbox()?;
         self.inner.commit()?;
+        self.finished.set(true);
+        self.after_commit();
         Ok(())
     }
 
+    fn after_commit(&self) {
+        if let Some(shared) = &self.shared_tables {
+            *lock(shared) = self.checked_tables.borrow().clone();
+        }
+        let events = self.events.take();
+        let observers = self.observers.borrow().clone();
+        for event in &events {
+            for observer in &observers {
+                observer(event);
+            }
+        }
+
+        let cells: Vec<_> = {
+            let cell_map = self.cell_map.borrow();
+            self.written
+                .borrow()
+                .iter()
+                .filter_map(|key| cell_map.get(key).cloned())
+                .collect()
+        };
+        for cell in cells {
+            if let Some(hooks) = cell.content.borrow_mut().as_hooks() {
+                hooks.after_commit(cell.id);
+            }
+        }
+    }
+
+    // Retries only the final COMMIT, which SQLite leaves pending while other
+    // connections still read; Database::transaction reruns a whole closure.
+    pub fn commit_with_retry(self, policy: &RetryPolicy) -> Result<()> {
+        self.check_conflicts()?;
+        self.try_apply()?;
+        self.write_outbox()?;
+        let mut attempt = 1;
+        loop {
+            match self.inner.commit() {
+                Err(err) if err.is_lock_wait() && attempt < policy.max_attempts => {
+                    thread::sleep(policy.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+                Ok(()) => {
+                    self.finished.set(true);
+                    self.after_commit();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Commits the work so far and goes on in a new transaction on the same
+    // connection. The object cache is cleared, as other connections may
+    // change rows once the commit is visible.
+    pub fn commit_and_continue(self) -> Result<Self> {
+        self.check_conflicts()?;
+        self.try_apply()?;
+        self.write_outbox()?;
+        self.inner.commit_and_continue()?;
+        self.after_commit();
+        self.cell_map.borrow_mut().clear();
+        self.state_map.borrow_mut().clear();
+        self.written.borrow_mut().clear();
+        self.savepoints.borrow_mut().clear();
+        Ok(self)
+    }
+
     pub fn rollback(self) -> Result<()> {
+        self.finished.set(true);
         self.inner.rollback()?;
         Ok(())
     }
+
+    pub(crate) fn finish<R>(self, result: Result<R>) -> Result<R> {
+        match result {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.rollback()?;
+                Err(err)
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const FINGERPRINTS: &Schema = &Schema {
+    table_name: "orm_fingerprints",
+    type_name: "Fingerprint",
+    columns: &[
+        Column {
+            column_name: "table_name",
+            attr_name: "table_name",
+            typ: DataType::String,
+            lazy: false,
+            encrypted: false,
+            compressed: false,
+        },
+        Column {
+            column_name: "fingerprint",
+            attr_name: "fingerprint",
+            typ: DataType::Int64,
+            lazy: false,
+            encrypted: false,
+            compressed: false,
+        },
+    ],
+    fingerprint: 0,
+    audited: false,
+    strict: false,
+    without_rowid: false,
+    triggers: &[],
+    view: None,
+};
+
+// A transaction dropped before commit or rollback finished, on an early
+// return, a failed commit or a panic, is rolled back here.
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if self.finished.get() {
+            return;
+        }
+        if let Some(hook) = &self.on_abandon {
+            // The maps can still be borrowed when unwinding from a panic.
+            if self.cell_map.try_borrow_mut().is_ok() && self.state_map.try_borrow_mut().is_ok() {
+                hook(&self.pending_changes());
+            }
+        }
+        let _ = self.inner.rollback();
+    }
+}
+
+// A row written through a transaction. Inserts have no old row and deletes
+// no new one; rows follow the column order of the schema.
+#[derive(Clone)]
+pub struct ChangeEvent {
+    pub schema: &'static Schema,
+    pub id: ObjectId,
+    pub op: ChangeOp,
+    pub old: Option<Row<'static>>,
+    pub new: Option<Row<'static>>,
+}
+
+impl ChangeEvent {
+    fn insert<T: Object>(obj: &Tx<'_, T>) -> Self {
+        Self {
+            schema: T::TABLE,
+            id: obj.id,
+            op: ChangeOp::Insert,
+            old: None,
+            new: Some(obj.cell.original.borrow().clone()),
+        }
+    }
+
+    fn delete(schema: &'static Schema, id: ObjectId, old: Row<'static>) -> Self {
+        Self {
+            schema,
+            id,
+            op: ChangeOp::Delete,
+            old: Some(old),
+            new: None,
+        }
+    }
+
+    pub fn table(&self) -> &'static str {
+        self.schema.table_name
+    }
+}
+
+pub type Observer = Arc<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+// The tables a connection has checked, shared by its transactions.
+pub(crate) type CheckedTables = Arc<Mutex<KeyMap<TypeId, &'static str>>>;
+
+fn lock(tables: &CheckedTables) -> std::sync::MutexGuard<'_, KeyMap<TypeId, &'static str>> {
+    tables.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+type LocalObserver<'a> = Rc<dyn Fn(&ChangeEvent) + 'a>;
+
+// Called with the changes that were still pending when a transaction was
+// abandoned.
+pub type AbandonHook = Arc<dyn Fn(&PendingChanges) + Send + Sync>;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PendingChange {
+    pub type_name: &'static str,
+    pub id: ObjectId,
+    pub state: ObjectState,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PendingChanges {
+    changes: Vec<PendingChange>,
+}
+
+impl PendingChanges {
+    pub fn iter(&self) -> std::slice::Iter<'_, PendingChange> {
+        self.changes.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn modified(&self) -> usize {
+        self.count(ObjectState::Modified)
+    }
+
+    pub fn removed(&self) -> usize {
+        self.count(ObjectState::Removed)
+    }
+
+    fn count(&self, state: ObjectState) -> usize {
+        self.changes.iter().filter(|c| c.state == state).count()
+    }
+}
+
+impl IntoIterator for PendingChanges {
+    type Item = PendingChange;
+    type IntoIter = std::vec::IntoIter<PendingChange>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.into_iter()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default)]
+struct Snapshot {
+    cells: KeyMap<(TypeId, ObjectId), (Rc<DataCell>, Row<'static>, Row<'static>)>,
+    states: KeyMap<(TypeId, ObjectId), ObjectState>,
+    checked_tables: KeyMap<TypeId, &'static str>,
+    written: KeySet<(TypeId, ObjectId)>,
+    events: usize,
+}
+
+// A nested transaction. Dropping it without release rolls it back.
+pub struct Savepoint<'t, 'a> {
+    tx: &'t Transaction<'a>,
+    number: u64,
+    name: String,
+    snapshot: Snapshot,
+    finished: bool,
+}
+
+impl<'t, 'a> Savepoint<'t, 'a> {
+    // Keeps the changes as part of the enclosing transaction. Savepoints
+    // taken within this one and still open are released with it, and can
+    // no longer be rolled back.
+    pub fn release(mut self) -> Result<()> {
+        self.finished = true;
+        self.close()?;
+        self.tx.inner.release_savepoint(&self.name)
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        self.rollback_in_place()
+    }
+
+    fn rollback_in_place(&mut self) -> Result<()> {
+        // Objects still borrowed could not be restored, so nothing is rolled
+        // back then.
+        for (cell, _, _) in self.snapshot.cells.values() {
+            if cell.content.try_borrow_mut().is_err() {
+                return Err(Error::Borrowed(Box::new(BorrowedError::new(
+                    cell.id,
+                    cell.schema.type_name,
+                ))));
+            }
+        }
+        self.finished = true;
+        self.close()?;
+        self.tx
+            .inner
+            .rollback_to_savepoint(&self.name)
+            .and_then(|()| self.tx.inner.release_savepoint(&self.name))?;
+        self.tx.restore_snapshot(std::mem::take(&mut self.snapshot));
+        Ok(())
+    }
+
+    // Ends the savepoint and those taken within it.
+    fn close(&self) -> Result<()> {
+        let mut savepoints = self.tx.savepoints.borrow_mut();
+        match savepoints.iter().position(|n| *n == self.number) {
+            Some(position) => {
+                savepoints.truncate(position);
+                Ok(())
+            }
+            None => Err(Error::Storage(
+                format!("{} was released with an enclosing savepoint", self.name).into(),
+            )),
+        }
+    }
+}
+
+impl<'t, 'a> Deref for Savepoint<'t, 'a> {
+    type Target = Transaction<'a>;
+
+    fn deref(&self) -> &Transaction<'a> {
+        self.tx
+    }
+}
+
+impl<'t, 'a> Drop for Savepoint<'t, 'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(Error::Borrowed(_)) = self.rollback_in_place() {
+                self.tx.failed.set(true);
+            }
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ObjectState {
     Clean,
     Modified,
     Removed,
 }
 
-#[derive(Clone)]
 pub struct Tx<'a, T> {
     cell: Rc<DataCell>,
     id: ObjectId,
@@ -131,6 +2018,18 @@ pub struct Tx<'a, T> {
     phantom: PhantomData<&'a T>,
 }
 
+// Handles share the object, so cloning one does not need T: Clone.
+impl<'a, T> Clone for Tx<'a, T> {
+    fn clone(&self) -> Self {
+        Self {
+            cell: self.cell.clone(),
+            id: self.id,
+            state: self.state.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 impl<'a, T: Any> Tx<'a, T> {
     fn new(
         cell: Rc<DataCell>,
@@ -146,8 +2045,8 @@ impl<'a, T: Any> Tx<'a, T> {
         }
     }
 
-    pub fn id(&self) -> ObjectId {
-        self.id
+    pub fn id(&self) -> Id<T> {
+        Id::new(self.id)
     }
 
     pub fn state(&self) -> ObjectState {
@@ -182,6 +2081,99 @@ impl<'a, T: Any> Tx<'a, T> {
             self.state.deref().set(ObjectState::Removed);
         }
     }
+
+    // Drops the pending changes, going back to the row as last read from or
+    // written to storage.
+    pub fn revert(&self) {
+        if let ObjectState::Removed = self.state.deref().get() {
+            panic!("cannot revert a removed object");
+        }
+        match self.cell.content.try_borrow_mut() {
+            Ok(mut content) => content.restore(self.cell.original.borrow().clone()),
+            Err(err) => panic!("cannot revert a borrowed object {}", err),
+        }
+        self.state.deref().set(ObjectState::Clean);
+    }
+
+    // Cancels a delete that has not been written yet; changes made before the
+    // delete are kept.
+    pub fn undelete(&self) {
+        if !self.cell.stored.get() {
+            panic!("cannot undelete an object that is deleted from storage");
+        }
+        if let ObjectState::Removed = self.state.deref().get() {
+            let modified =
+                owned_row(self.cell.content.borrow().as_ref()) != *self.cell.original.borrow();
+            self.state.deref().set(if modified {
+                ObjectState::Modified
+            } else {
+                ObjectState::Clean
+            });
+        }
+    }
+}
+
+// Error-returning variants of the accessors above, for code that must not
+// panic.
+impl<'a, T: Object> Tx<'a, T> {
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>> {
+        self.ensure_not_removed()?;
+        match self.cell.content.try_borrow() {
+            Ok(content) => Ok(Ref::map(content, |store| {
+                store.to_any().downcast_ref().unwrap()
+            })),
+            Err(_) => Err(self.borrowed()),
+        }
+    }
+
+    pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>> {
+        self.ensure_not_removed()?;
+        match self.cell.content.try_borrow_mut() {
+            Ok(content) => {
+                self.state.deref().set(ObjectState::Modified);
+                Ok(RefMut::map(content, |x| {
+                    x.to_any_mut().downcast_mut().unwrap()
+                }))
+            }
+            Err(_) => Err(self.borrowed()),
+        }
+    }
+
+    // The object is borrowed only while the closure runs, so no guard is left
+    // behind to clash with later calls.
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R> {
+        let mut object = self.try_borrow_mut()?;
+        Ok(f(&mut object))
+    }
+
+    pub fn try_delete(self) -> Result<()> {
+        self.ensure_not_removed()?;
+        if self.cell.content.try_borrow_mut().is_err() {
+            return Err(self.borrowed());
+        }
+        self.state.deref().set(ObjectState::Removed);
+        Ok(())
+    }
+
+    // A detached copy of the current content, pending changes included, that
+    // does not keep the transaction borrowed and needs no Clone.
+    pub fn into_owned(self) -> T {
+        T::deserialize(owned_row(&*self.borrow())).expect("a serialized object deserializes")
+    }
+
+    fn ensure_not_removed(&self) -> Result<()> {
+        if let ObjectState::Removed = self.state.deref().get() {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                self.id,
+                T::TABLE.type_name,
+            ))));
+        }
+        Ok(())
+    }
+
+    fn borrowed(&self) -> Error {
+        Error::Borrowed(Box::new(BorrowedError::new(self.id, T::TABLE.type_name)))
+    }
 }
 
 pub trait Table {
@@ -210,22 +2202,184 @@ pub trait ToAny {
 }
 
 pub trait Serialize {
-    fn serialize(&self) -> Row;
+    fn serialize_into(&self, row: &mut Row<'static>);
 }
 
 impl<T: Object> Serialize for T {
-    fn serialize(&self) -> Row {
-        self.serialize()
+    fn serialize_into(&self, row: &mut Row<'static>) {
+        self.serialize_into(row)
+    }
+}
+
+pub trait Restore {
+    fn restore(&mut self, row: Row);
+}
+
+impl<T: Object> Restore for T {
+    // The row is one serialized from an object of the same type.
+    fn restore(&mut self, row: Row) {
+        *self = T::deserialize(row).expect("a serialized object deserializes");
+    }
+}
+
+pub trait AsHooks {
+    fn as_hooks(&mut self) -> Option<&mut dyn Hooks>;
+
+    fn prepare_save(&mut self) -> Result<()>;
+}
+
+impl<T: Object> AsHooks for T {
+    fn as_hooks(&mut self) -> Option<&mut dyn Hooks> {
+        self.hooks()
+    }
+
+    fn prepare_save(&mut self) -> Result<()> {
+        prepare_save(self)
     }
 }
 
-pub trait Record: Table + Serialize + ToAny {}
+// Computed fields first, so that before_save sees them.
+fn prepare_save<T: Object>(obj: &mut T) -> Result<()> {
+    obj.compute();
+    match obj.hooks() {
+        Some(hooks) => hooks.before_save(),
+        None => Ok(()),
+    }
+}
+
+pub trait Record: Table + Serialize + Restore + AsHooks + ToAny {}
 
 impl<T: Object> Record for T {}
 
 pub(crate) struct DataCell {
     pub(crate) id: ObjectId,
+    // The order in which the object entered the transaction, and in which it
+    // was last asked for.
+    pub(crate) seq: u64,
+    pub(crate) used: Cell<u64>,
+    pub(crate) schema: &'static Schema,
     pub(crate) content: RefCell<Box<dyn Record>>,
+    // The row as last read from or written to storage.
+    pub(crate) original: RefCell<Row<'static>>,
+    // Cleared once the row is known to be gone from storage.
+    pub(crate) stored: Cell<bool>,
+}
+
+// An object try_apply writes.
+struct Update {
+    key: (TypeId, ObjectId),
+    cell: Rc<DataCell>,
+    state: Rc<Cell<ObjectState>>,
+    changes: Changes,
+}
+
+// The columns changed since the object was read or last written, with their
+// values, and the whole row as it will be stored.
+struct Changes {
+    columns: Vec<&'static str>,
+    values: Row<'static>,
+    row: Row<'static>,
+}
+
+// Only the columns that differ from the row as it was loaded are written;
+// None if the object is as it was read or last written.
+fn changed_columns(cell: &DataCell, object: &dyn Record) -> Option<Changes> {
+    let schema = object.get_table();
+    let mut row = Row::new();
+    object.serialize_into(&mut row);
+    let original = cell.original.borrow();
+    let (columns, values): (Vec<_>, Row) = schema
+        .columns
+        .iter()
+        .zip(row.iter().zip(original.iter()))
+        .filter(|(_, (value, loaded))| value != loaded)
+        .map(|(column, (value, _))| (column.column_name, value.clone()))
+        .unzip();
+    (!columns.is_empty()).then_some(Changes {
+        columns,
+        values,
+        row,
+    })
+}
+
+fn column_mismatches(schema: &Schema, info: &TableInfo) -> Vec<Mismatch> {
+    let stored: Vec<_> = info.columns.iter().filter(|c| c.name != "id").collect();
+    let mut mismatches = Vec::new();
+    for column in schema.columns {
+        match stored.iter().find(|c| c.name == column.column_name) {
+            None => mismatches.push(Mismatch::MissingColumn {
+                column: column.column_name.to_string(),
+            }),
+            Some(stored) => match stored.typ {
+                Some(found) if !same_storage(column.stored_type(), found) => {
+                    mismatches.push(Mismatch::Type {
+                        column: column.column_name.to_string(),
+                        expected: column.stored_type(),
+                        found,
+                    })
+                }
+                _ => {}
+            },
+        }
+    }
+    for stored in &stored {
+        if schema.column(&stored.name).is_none() {
+            mismatches.push(Mismatch::ExtraColumn {
+                column: stored.name.clone(),
+            });
+        }
+    }
+    mismatches
+}
+
+// SQLite declares booleans as INTEGER.
+fn same_storage(expected: DataType, found: DataType) -> bool {
+    expected == found || (expected == DataType::Bool && found == DataType::Int64)
+}
+
+fn schema_mismatch(schema: &Schema, mismatches: Vec<Mismatch>) -> Error {
+    Error::SchemaMismatch(Box::new(SchemaMismatchError::new(
+        schema.type_name,
+        schema.table_name,
+        mismatches,
+    )))
+}
+
+fn owned_row(object: &dyn Record) -> Row<'static> {
+    let mut row = Row::with_capacity(object.get_table().columns.len());
+    object.serialize_into(&mut row);
+    row
+}
+
+// Ids per SELECT of prefetch and forget_replaced, below the parameter limit
+// of every backend.
+const PREFETCH_CHUNK: usize = 900;
+
+pub type StateMap = KeyMap<(TypeId, ObjectId), Rc<Cell<ObjectState>>>;
+
+// The cache is looked up on every get, and its keys are a TypeId, which is a
+// hash already, and a rowid, neither of which needs SipHash. Mixes each word
+// in as FxHash does.
+#[derive(Default)]
+pub(crate) struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
 }
 
-pub type StateMap = HashMap<(TypeId, ObjectId), Rc<Cell<ObjectState>>>;
+pub(crate) type KeyMap<K, V> = HashMap<K, V, BuildHasherDefault<KeyHasher>>;
+pub(crate) type KeySet<K> = HashSet<K, BuildHasherDefault<KeyHasher>>;
@@ -0,0 +1,337 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{DataType, Value},
+    error::{Error, MissingColumnError, NotFoundError, Result, UnexpectedTypeError},
+    object::Schema,
+    storage::{OnConflict, Row, RowSlice, StorageConnection, StorageTransaction},
+    Connection, ObjectId,
+};
+use js_sys::{Array, Reflect, Uint8Array};
+use std::borrow::Cow;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[wasm_bindgen]
+extern "C" {
+    // A `Database` object of sql.js.
+    pub type SqlJsDatabase;
+
+    #[wasm_bindgen(method, catch)]
+    fn run(
+        this: &SqlJsDatabase,
+        sql: &str,
+        params: &Array,
+    ) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn exec(this: &SqlJsDatabase, sql: &str, params: &Array)
+        -> std::result::Result<Array, JsValue>;
+
+    #[wasm_bindgen(method, js_name = getRowsModified)]
+    fn get_rows_modified(this: &SqlJsDatabase) -> f64;
+
+    #[wasm_bindgen(method)]
+    fn export(this: &SqlJsDatabase) -> Uint8Array;
+}
+
+// sql.js keeps the whole database in wasm memory. Persisting it (e.g. to an
+// OPFS file) is up to the application, using the bytes returned by export().
+pub struct WasmStorage {
+    db: SqlJsDatabase,
+}
+
+impl WasmStorage {
+    pub fn from_sql_js(db: SqlJsDatabase) -> Self {
+        Self { db }
+    }
+
+    // `sql` is the module returned by sql.js's initSqlJs(); `data` is a
+    // previously exported database image.
+    pub fn open(sql: &JsValue, data: Option<&[u8]>) -> Result<Self> {
+        let constructor = Reflect::get(sql, &"Database".into())
+            .map_err(js_error)?
+            .dyn_into::<js_sys::Function>()
+            .map_err(js_error)?;
+        let args = Array::new();
+        if let Some(data) = data {
+            args.push(&Uint8Array::from(data));
+        }
+        let db = Reflect::construct(&constructor, &args).map_err(js_error)?;
+        Ok(Self::from_sql_js(db.unchecked_into()))
+    }
+
+    pub fn export(&self) -> Vec<u8> {
+        self.db.export().to_vec()
+    }
+}
+
+impl StorageConnection for WasmStorage {
+    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+        let tx = WasmTransaction { db: &self.db };
+        tx.execute_sql("BEGIN", &[])?;
+        Ok(Box::new(tx))
+    }
+
+    fn total_changes(&self) -> Result<usize> {
+        let tx = WasmTransaction { db: &self.db };
+        Ok(tx.query_i64("SELECT total_changes()", &[])? as usize)
+    }
+}
+
+impl Connection {
+    pub fn open_sql_js(sql: &JsValue, data: Option<&[u8]>) -> Result<Self> {
+        Ok(Self::from_storage(WasmStorage::open(sql, data)?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct WasmTransaction<'a> {
+    db: &'a SqlJsDatabase,
+}
+
+impl<'a> WasmTransaction<'a> {
+    fn execute_sql(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.db.run(sql, &to_params(params)).map_err(js_error)?;
+        Ok(self.db.get_rows_modified() as usize)
+    }
+
+    fn query_sql(&self, sql: &str, params: &RowSlice) -> Result<Vec<Array>> {
+        let results = self.db.exec(sql, &to_params(params)).map_err(js_error)?;
+        // exec() returns one {columns, values} entry per statement.
+        if results.length() == 0 {
+            return Ok(Vec::new());
+        }
+        let values = Reflect::get(&results.get(0), &"values".into()).map_err(js_error)?;
+        Ok(Array::from(&values)
+            .iter()
+            .map(|row| row.unchecked_into())
+            .collect())
+    }
+
+    fn query_i64(&self, sql: &str, params: &RowSlice) -> Result<i64> {
+        let rows = self.query_sql(sql, params)?;
+        Ok(rows
+            .first()
+            .and_then(|row| row.get(0).as_f64())
+            .unwrap_or(0.) as i64)
+    }
+}
+
+impl<'a> StorageTransaction for WasmTransaction<'a> {
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        let rows = self.query_sql(
+            "SELECT name FROM sqlite_master WHERE type IN ('table', 'view') AND name = ?",
+            &[table.into()],
+        )?;
+        Ok(!rows.is_empty())
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        self.execute_sql(&schema.create_text(), &[])?;
+        Ok(())
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.execute_sql(&schema.drop_text(), &[])?;
+        Ok(())
+    }
+
+    fn clear_table(&self, schema: &Schema) -> Result<usize> {
+        let changes = self.execute_sql(&schema.delete_where_text(""), &[])?;
+        if self.table_exists("sqlite_sequence")? {
+            self.execute_sql(
+                "DELETE FROM sqlite_sequence WHERE name = ?",
+                &[schema.table_name.into()],
+            )?;
+        }
+        Ok(changes)
+    }
+
+    fn insert_row_with(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        on_conflict: OnConflict,
+    ) -> Result<Option<ObjectId>> {
+        let changes = self
+            .execute_sql(schema.insert_text_with(on_conflict), row)
+            .map_err(|err| map_schema_error(err, schema))?;
+        if changes == 0 {
+            return Ok(None);
+        }
+        let id = if schema.without_rowid {
+            self.query_i64(&schema.last_id_text(), &[])?
+        } else {
+            self.query_i64("SELECT last_insert_rowid()", &[])?
+        };
+        Ok(Some(ObjectId::from(id)))
+    }
+
+    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<usize> {
+        if schema.columns.is_empty() {
+            return Ok(0);
+        }
+        let mut params = row.to_vec();
+        params.push(Value::Int64(id.into_i64()));
+        self.execute_sql(schema.update_text(), &params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn update_where(
+        &self,
+        schema: &Schema,
+        columns: &[&str],
+        values: &RowSlice,
+        where_sql: &str,
+        params: &RowSlice,
+    ) -> Result<usize> {
+        if columns.is_empty() {
+            return Ok(0);
+        }
+        let mut args = values.to_vec();
+        args.extend_from_slice(params);
+        self.execute_sql(&schema.update_where_text(columns, where_sql), &args)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        let rows = self
+            .query_sql(schema.select_text(), &[Value::Int64(id.into_i64())])
+            .map_err(|err| map_schema_error(err, schema))?;
+        match rows.first() {
+            Some(row) => read_row(row, schema, 0),
+            None => Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            )))),
+        }
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        where_sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let rows = self
+            .query_sql(&schema.select_where_text(where_sql), params)
+            .map_err(|err| map_schema_error(err, schema))?;
+        for row in rows {
+            let id = row.get(0).as_f64().unwrap_or_default() as i64;
+            f(ObjectId::from(id), read_row(&row, schema, 1)?)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        let count = self
+            .query_i64(&schema.count_text(where_sql), params)
+            .map_err(|err| map_schema_error(err, schema))?;
+        Ok(count as usize)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        if self.execute_sql(schema.delete_text(), &[Value::Int64(id.into_i64())])? == 0 {
+            return Err(Error::NotFound(Box::new(NotFoundError::new(
+                id,
+                schema.type_name,
+            ))));
+        }
+        Ok(())
+    }
+
+    fn delete_where(&self, schema: &Schema, where_sql: &str, params: &RowSlice) -> Result<usize> {
+        self.execute_sql(&schema.delete_where_text(where_sql), params)
+            .map_err(|err| map_schema_error(err, schema))
+    }
+
+    fn execute(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.execute_sql(sql, params)
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.execute_sql("COMMIT", &[])?;
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.execute_sql("ROLLBACK", &[])?;
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn js_error(err: JsValue) -> Error {
+    let message = err
+        .dyn_ref::<js_sys::Error>()
+        .map(|err| String::from(err.message()))
+        .or_else(|| err.as_string())
+        .unwrap_or_else(|| format!("{:?}", err));
+    Error::Storage(message.into())
+}
+
+fn map_schema_error(err: Error, schema: &Schema) -> Error {
+    let text = match &err {
+        Error::Storage(source) => source.to_string(),
+        _ => return err,
+    };
+    MissingColumnError::get_error_from_text(&text, schema).unwrap_or(err)
+}
+
+// Integers travel as JS numbers and are exact up to 2^53.
+fn to_params(values: &RowSlice) -> Array {
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(s) => JsValue::from_str(s),
+            Value::Bytes(b) => Uint8Array::from(b.as_ref()).into(),
+            Value::Int64(i) => JsValue::from_f64(*i as f64),
+            Value::Float64(f) => JsValue::from_f64(*f),
+            Value::Bool(b) => JsValue::from_f64(*b as u8 as f64),
+        })
+        .collect()
+}
+
+fn read_row(row: &Array, schema: &Schema, offset: usize) -> Result<Row<'static>> {
+    let mut line = Row::with_capacity(schema.columns.len());
+    for (i, column) in schema.columns.iter().enumerate() {
+        let value = row.get((i + offset) as u32);
+        let number = value.as_f64();
+        let value = match (column.typ, number) {
+            (DataType::String, _) if value.is_string() => {
+                Value::String(Cow::Owned(value.as_string().unwrap()))
+            }
+            (DataType::Bytes, _) if value.is_instance_of::<Uint8Array>() => {
+                Value::Bytes(Cow::Owned(Uint8Array::new(&value).to_vec()))
+            }
+            (DataType::Int64, Some(n)) if n.fract() == 0. => Value::Int64(n as i64),
+            (DataType::Float64, Some(n)) => Value::Float64(n),
+            (DataType::Bool, Some(n)) => Value::Bool(n != 0.),
+            _ => {
+                let got_type = if value.is_null() {
+                    "Null"
+                } else if number.is_some() {
+                    "Real"
+                } else if value.is_string() {
+                    "Text"
+                } else {
+                    "Blob"
+                };
+                return Err(Error::UnexpectedType(Box::new(UnexpectedTypeError::new(
+                    schema.type_name,
+                    column.attr_name,
+                    schema.table_name,
+                    column.column_name,
+                    column.typ,
+                    got_type.to_string(),
+                ))));
+            }
+        };
+        line.push(value);
+    }
+    Ok(line)
+}
@@ -0,0 +1,93 @@
+#![forbid(unsafe_code)]
+use crate::{
+    asynch::{Database, Transaction},
+    error::Error,
+};
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{ops::Deref, sync::Arc};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A unit of work per request: the middleware opens a transaction, handlers
+// take it with the RequestTransaction extractor, and once the handler has
+// responded it is committed if the status is a success or a redirect, and
+// rolled back otherwise.
+//
+//     Router::new()
+//         .route("/users", post(create_user))
+//         .layer(middleware::from_fn_with_state(db, orm::web::unit_of_work))
+//
+// A transaction that cannot be opened or committed turns the response into
+// a 500. Handlers reach the database itself with State<Database>.
+pub async fn unit_of_work(
+    State(db): State<Database>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let tx = match db.transaction().await {
+        Ok(tx) => Arc::new(tx),
+        Err(err) => return failed(err),
+    };
+    request
+        .extensions_mut()
+        .insert(RequestTransaction(tx.clone()));
+    let response = next.run(request).await;
+
+    // A handler that keeps the transaction past its response, say in a task
+    // it spawned, leaves it to roll back once the task lets go of it.
+    let Ok(tx) = Arc::try_unwrap(tx) else {
+        return failed(Error::Storage(
+            "the transaction of the request is still in use".into(),
+        ));
+    };
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        match tx.commit().await {
+            Ok(()) => response,
+            Err(err) => failed(err),
+        }
+    } else {
+        let _ = tx.rollback().await;
+        response
+    }
+}
+
+// The error is not shown to the client.
+fn failed(_err: Error) -> Response {
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The transaction of the request, for routes behind unit_of_work; others
+// are answered with a 500.
+#[derive(Clone)]
+pub struct RequestTransaction(Arc<Transaction>);
+
+impl Deref for RequestTransaction {
+    type Target = Transaction;
+
+    fn deref(&self) -> &Transaction {
+        &self.0
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestTransaction {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<RequestTransaction>()
+            .cloned()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
@@ -1,4 +1,4 @@
-use orm::{data::DataType, Connection, Object, ObjectId, ObjectState, Result, Tx};
+use orm::{column, data::DataType, Connection, Id, Object, ObjectId, ObjectState, Result, Tx};
 
 use rusqlite::params;
 use tempfile::NamedTempFile;
@@ -18,12 +18,12 @@ struct User {
 
 fn assert_not_found<'a>(
     res: Result<Tx<'a, User>>,
-    expected_object_id: ObjectId,
+    expected_object_id: Id<User>,
     expected_type_name: &str,
 ) {
     match res {
         Err(orm::Error::NotFound(err)) => {
-            assert_eq!(err.object_id, expected_object_id);
+            assert_eq!(err.object_id, expected_object_id.raw());
             assert_eq!(err.type_name, expected_type_name);
         }
         Ok(_) => panic!("expected NotFound error, got OK"),
@@ -38,6 +38,23 @@ fn fmt_res<T>(res: &Result<T>) -> String {
     }
 }
 
+fn user(visits: i64) -> User {
+    User {
+        name: format!("User {}", visits),
+        picture: vec![],
+        visits,
+        balance: 0.,
+        is_admin: false,
+    }
+}
+
+// A connection on a MockStorage, with the script that counts its calls.
+fn mock_connection() -> (Connection, orm::mock::MockScript) {
+    let storage = orm::mock::MockStorage::new();
+    let script = storage.script();
+    (Connection::from_storage(storage), script)
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[test]
@@ -245,6 +262,102 @@ fn delete_borrowed() {
     tx_user_2.delete();
 }
 
+#[test]
+fn try_borrow() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.create(Order { is_tall: false }).unwrap();
+    let other = tx.get::<Order>(order.id()).unwrap();
+
+    {
+        let _borrowed = order.try_borrow().unwrap();
+        assert!(other.try_borrow().is_ok());
+        assert!(matches!(
+            other.try_borrow_mut(),
+            Err(orm::Error::Borrowed(_))
+        ));
+        assert!(order.state() == ObjectState::Clean);
+        assert!(matches!(
+            other.clone().try_delete(),
+            Err(orm::Error::Borrowed(_))
+        ));
+    }
+
+    order.try_borrow_mut().unwrap().is_tall = true;
+    assert!(order.state() == ObjectState::Modified);
+    other.try_delete().unwrap();
+    assert!(matches!(order.try_borrow(), Err(orm::Error::NotFound(_))));
+    assert!(matches!(
+        order.clone().try_delete(),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+#[test]
+fn delete_by_id() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let loaded = tx.create(Order { is_tall: true }).unwrap().id();
+    let unloaded = tx.create(Order { is_tall: false }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let loaded = tx.get::<Order>(loaded).unwrap();
+    tx.delete::<Order>(loaded.id()).unwrap();
+    assert!(loaded.state() == ObjectState::Removed);
+    tx.delete::<Order>(unloaded).unwrap();
+    assert_eq!(tx.last_changes(), 1);
+    assert!(matches!(
+        tx.get::<Order>(unloaded),
+        Err(orm::Error::NotFound(_))
+    ));
+    assert!(matches!(
+        tx.delete::<Order>(unloaded),
+        Err(orm::Error::NotFound(_))
+    ));
+    assert!(matches!(
+        tx.delete::<Order>(ObjectId::from(100)),
+        Err(orm::Error::NotFound(_))
+    ));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.select_all::<Order>().unwrap().is_empty());
+}
+
+#[test]
+fn pending_changes() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let first = tx.create(Order { is_tall: false }).unwrap();
+    let second = tx.create(Order { is_tall: false }).unwrap();
+    tx.create(Order { is_tall: false }).unwrap();
+    assert!(tx.pending_changes().is_empty());
+
+    let _borrowed = second.borrow_mut();
+    tx.get::<Order>(first.id()).unwrap().delete();
+    let pending = tx.pending_changes();
+    assert_eq!(
+        (pending.len(), pending.modified(), pending.removed()),
+        (2, 1, 1)
+    );
+    assert_eq!(
+        pending.into_iter().collect::<Vec<_>>(),
+        vec![
+            orm::PendingChange {
+                type_name: "Order",
+                id: first.id().raw(),
+                state: ObjectState::Removed,
+            },
+            orm::PendingChange {
+                type_name: "Order",
+                id: second.id().raw(),
+                state: ObjectState::Modified,
+            },
+        ]
+    );
+}
+
 #[test]
 fn missing_column() {
     let path = NamedTempFile::new().unwrap().into_temp_path();
@@ -261,8 +374,8 @@ fn missing_column() {
             Err(orm::Error::MissingColumn(err)) => {
                 assert_eq!(err.type_name, "User");
                 assert_eq!(err.table_name, "User");
-                assert!(columns.contains(&err.attr_name));
-                assert!(columns.contains(&err.column_name));
+                assert!(columns.contains(&&*err.attr_name));
+                assert!(columns.contains(&&*err.column_name));
                 true
             }
             _ => false,
@@ -272,7 +385,7 @@ fn missing_column() {
     let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
     let tx = orm_conn.new_transaction().unwrap();
 
-    let res_get = tx.get::<User>(1.into());
+    let res_get = tx.get::<User>(ObjectId::from(1));
     assert!(
         check_missing_column(&res_get),
         "expected Error::MissingColumn at get(), got {}",
@@ -329,17 +442,22 @@ fn unexpected_type() {
     let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
     let tx = orm_conn.new_transaction().unwrap();
 
-    match tx.get::<User>(1i64.into()) {
-        Err(orm::Error::UnexpectedType(err)) => {
+    // The declared type gives it away before any row is read.
+    match tx.get::<User>(ObjectId::from(1)) {
+        Err(orm::Error::SchemaMismatch(err)) => {
             assert_eq!(err.type_name, "User");
             assert_eq!(err.table_name, "User");
-            assert_eq!(err.attr_name, "is_admin");
-            assert_eq!(err.column_name, "is_admin");
-            assert_eq!(err.expected_type, DataType::Bool);
-            assert_eq!(err.got_type, "Text");
+            assert_eq!(
+                err.mismatches,
+                [orm::Mismatch::Type {
+                    column: "is_admin".to_string(),
+                    expected: DataType::Bool,
+                    found: DataType::String,
+                }]
+            );
         }
         res => panic!(
-            "expecter Error::IncorrectType at get(), got {}",
+            "expected Error::SchemaMismatch at get(), got {}",
             fmt_res(&res),
         ),
     }
@@ -363,9 +481,8 @@ fn null_value() {
 
     tx.commit().unwrap();
 
-    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
-    sqlite_conn
-        .execute(
+    let insert_null = |conn: &rusqlite::Connection| {
+        conn.execute(
             "INSERT INTO User(id, name, picture, visits, balance, is_admin) \
             VALUES (?, ?, ?, ?, ?, ?)",
             params![
@@ -377,12 +494,27 @@ fn null_value() {
                 false
             ],
         )
+    };
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    assert!(insert_null(&sqlite_conn).is_err());
+
+    // A table created without NOT NULL columns, as older versions did.
+    sqlite_conn
+        .execute_batch(
+            "CREATE TABLE legacy (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, \
+            picture BLOB, visits INTEGER, balance REAL, is_admin INTEGER); \
+            INSERT INTO legacy SELECT * FROM User; \
+            DROP TABLE User; \
+            ALTER TABLE legacy RENAME TO User;",
+        )
         .unwrap();
+    insert_null(&sqlite_conn).unwrap();
     sqlite_conn.close().unwrap();
 
     let tx = orm_conn.new_transaction().unwrap();
 
-    match tx.get::<User>(100i64.into()) {
+    match tx.get::<User>(ObjectId::from(100)) {
         Err(orm::Error::UnexpectedType(err)) => {
             assert_eq!(err.type_name, "User");
             assert_eq!(err.attr_name, "picture");
@@ -455,12 +587,12 @@ fn empty_struct() {
     tx.get::<Void>(void_id).unwrap();
 
     assert!(matches!(
-        tx.get::<Empty>(125.into()),
+        tx.get::<Empty>(ObjectId::from(125)),
         Err(orm::Error::NotFound(_))
     ));
 
     assert!(matches!(
-        tx.get::<Void>(125.into()),
+        tx.get::<Void>(ObjectId::from(125)),
         Err(orm::Error::NotFound(_))
     ));
 }
@@ -531,7 +663,7 @@ fn not_found() {
     let mut conn = Connection::open_in_memory().unwrap();
 
     let tx = conn.new_transaction().unwrap();
-    match tx.get::<Order>(3523.into()) {
+    match tx.get::<Order>(ObjectId::from(3523)) {
         Err(orm::Error::NotFound(err)) => {
             assert_eq!(err.type_name, "Order");
             assert_eq!(err.object_id, 3523.into());
@@ -540,6 +672,24 @@ fn not_found() {
     }
 }
 
+#[test]
+fn try_get() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.create(Order { is_tall: true }).unwrap();
+    assert!(tx.try_get::<Order>(order.id()).unwrap().is_some());
+    assert!(tx.try_get::<Order>(ObjectId::from(100)).unwrap().is_none());
+
+    let id = order.id();
+    order.delete();
+    assert!(tx.try_get::<Order>(id).unwrap().is_none());
+
+    tx.execute("DROP TABLE order_table", &[]).unwrap();
+    tx.execute("CREATE TABLE order_table (id INTEGER PRIMARY KEY)", &[])
+        .unwrap();
+    assert!(tx.try_get::<Order>(ObjectId::from(5)).is_err());
+}
+
 #[test]
 fn unexpected_type_renamed() {
     let path = NamedTempFile::new().unwrap().into_temp_path();
@@ -562,17 +712,21 @@ fn unexpected_type_renamed() {
     let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
     let tx = orm_conn.new_transaction().unwrap();
 
-    match tx.get::<Order>(1.into()) {
-        Err(orm::Error::UnexpectedType(err)) => {
+    match tx.get::<Order>(ObjectId::from(1)) {
+        Err(orm::Error::SchemaMismatch(err)) => {
             assert_eq!(err.type_name, "Order");
             assert_eq!(err.table_name, "order_table");
-            assert_eq!(err.attr_name, "is_tall");
-            assert_eq!(err.column_name, "IsTall");
-            assert_eq!(err.expected_type, DataType::Bool);
-            assert_eq!(err.got_type, "Text");
+            assert_eq!(
+                err.mismatches,
+                [orm::Mismatch::Type {
+                    column: "IsTall".to_string(),
+                    expected: DataType::Bool,
+                    found: DataType::String,
+                }]
+            );
         }
         res => panic!(
-            "expecter Error::IncorrectType at get(), got {}",
+            "expected Error::SchemaMismatch at get(), got {}",
             fmt_res(&res),
         ),
     }
@@ -610,7 +764,7 @@ fn missing_column_renamed() {
     let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
     let tx = orm_conn.new_transaction().unwrap();
 
-    let res_get = tx.get::<Order>(1.into());
+    let res_get = tx.get::<Order>(ObjectId::from(1));
     assert!(
         check_missing_column(&res_get),
         "expected Error::MissingColumn at get(), got {}",
@@ -667,6 +821,5787 @@ fn rollback() {
     assert_eq!(tx_user.borrow().balance, 220.);
 }
 
+#[test]
+fn null_predicates() {
+    let (sql, params) = column("picture").is_null().to_sql();
+    assert_eq!(sql, "\"picture\" IS NULL");
+    assert!(params.is_empty());
+
+    let (sql, params) = column("picture")
+        .is_not_null()
+        .and(column("visits").gt(10i64))
+        .to_sql();
+    assert_eq!(sql, "(\"picture\" IS NOT NULL AND \"visits\" > ?)");
+    assert_eq!(params.len(), 1);
+
+    let sqlite_conn = rusqlite::Connection::open_in_memory().unwrap();
+    sqlite_conn
+        .execute("CREATE TABLE t (id INTEGER PRIMARY KEY, picture BLOB)", [])
+        .unwrap();
+    sqlite_conn
+        .execute("INSERT INTO t VALUES (1, NULL), (2, x'00'), (3, NULL)", [])
+        .unwrap();
+
+    let count = |predicate: orm::Predicate| -> i64 {
+        let (sql, _) = predicate.to_sql();
+        sqlite_conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM t WHERE {}", sql),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+    assert_eq!(count(column("picture").is_null()), 2);
+    assert_eq!(count(column("picture").is_not_null()), 1);
+    assert_eq!(count(!column("picture").is_null()), 1);
+}
+
+#[test]
+fn select() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let names = ["Alice", "Bob", "Carol", "Dave"];
+    for (i, name) in names.iter().enumerate() {
+        tx.create(User {
+            name: name.to_string(),
+            picture: b"fsdgaseg"[..].into(),
+            visits: i as i64 * 10,
+            balance: 10.,
+            is_admin: i % 2 == 0,
+        })
+        .unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let carol = tx
+        .select::<User>(&column("name").eq("Carol"))
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    let users = tx.select::<User>(&column("visits").ge(10i64)).unwrap();
+    let mut found: Vec<_> = users.iter().map(|u| u.borrow().name.clone()).collect();
+    found.sort();
+    assert_eq!(found, ["Bob", "Carol", "Dave"]);
+
+    users
+        .iter()
+        .find(|u| u.id() == carol.id())
+        .unwrap()
+        .borrow_mut()
+        .visits = 1000;
+    assert_eq!(carol.borrow().visits, 1000);
+
+    assert_eq!(
+        tx.count_where::<User>(&column("is_admin").eq(true))
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        tx.count_where::<User>(&column("visits").gt(100i64))
+            .unwrap(),
+        0
+    );
+
+    let by_id = tx.select_map::<User>(&column("is_admin").eq(true)).unwrap();
+    assert_eq!(by_id.len(), 2);
+    assert_eq!(by_id[&carol.id().raw()].borrow().name, "Carol");
+
+    let by_name = tx
+        .select_map_by::<User, _>(&column("is_admin").eq(false), |u| u.name.clone())
+        .unwrap();
+    assert_eq!(by_name.len(), 2);
+    assert!(by_name.contains_key("Bob") && by_name.contains_key("Dave"));
+
+    carol.delete();
+    assert_eq!(tx.select_all::<User>().unwrap().len(), 3);
+    assert!(tx
+        .select::<User>(&column("name").eq("Nobody"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn affected_rows() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    for visits in 0..5 {
+        tx.create(User {
+            name: "Eve".into(),
+            picture: b"asdgqwegzxcv"[..].into(),
+            visits,
+            balance: 0.,
+            is_admin: false,
+        })
+        .unwrap();
+    }
+    assert_eq!(tx.last_changes(), 1);
+    assert_eq!(tx.total_changes(), 5);
+
+    let changes = tx
+        .update_where::<User>(&column("visits").lt(2i64), &[("is_admin", true.into())])
+        .unwrap();
+    assert_eq!(changes, 2);
+    assert_eq!(tx.last_changes(), 2);
+
+    assert_eq!(
+        tx.delete_where::<User>(&column("is_admin").eq(true))
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        tx.execute(
+            "UPDATE User SET balance = ? WHERE visits > ?",
+            &[5.0.into(), 2i64.into()]
+        )
+        .unwrap(),
+        2
+    );
+    assert_eq!(tx.last_changes(), 2);
+    assert_eq!(tx.total_changes(), 11);
+
+    tx.select_all::<User>().unwrap()[0].borrow_mut().visits = 100;
+    tx.commit().unwrap();
+
+    // The connection also counts the fingerprint row written with the table.
+    assert_eq!(conn.total_changes().unwrap(), 13);
+}
+
+#[test]
+fn create_on_conflict() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    sqlite_conn
+        .execute(
+            "CREATE TABLE User (\
+                id INTEGER PRIMARY KEY AUTOINCREMENT,\
+                name TEXT UNIQUE,\
+                picture BLOB,\
+                visits BIGINT,\
+                balance REAL,\
+                is_admin BOOLEAN\
+            )",
+            [],
+        )
+        .unwrap();
+    sqlite_conn.close().unwrap();
+
+    let user = User {
+        name: "Zoe".into(),
+        picture: b"lkjhgfdsa"[..].into(),
+        visits: 1,
+        balance: 1.,
+        is_admin: false,
+    };
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let original = tx.create(user.clone()).unwrap();
+
+    assert!(tx
+        .create_with(user.clone(), orm::OnConflict::Ignore)
+        .unwrap()
+        .is_none());
+    assert!(tx
+        .create_with(user.clone(), orm::OnConflict::Abort)
+        .is_err());
+
+    tx.set_conflict_lookup(true);
+    match tx.create(user.clone()) {
+        Err(orm::Error::Constraint(err)) => {
+            assert_eq!(err.kind, orm::ConstraintKind::Unique);
+            assert_eq!(err.existing, Some(original.id().raw()));
+        }
+        res => panic!("expected Error::Constraint, got {}", fmt_res(&res)),
+    }
+    match tx.create_with_id(
+        original.id(),
+        User {
+            name: "Yan".into(),
+            ..user.clone()
+        },
+    ) {
+        Err(orm::Error::Constraint(err)) => assert_eq!(err.existing, Some(original.id().raw())),
+        res => panic!("expected Error::Constraint, got {}", fmt_res(&res)),
+    }
+    tx.set_conflict_lookup(false);
+    // More cached objects than one SELECT of their ids takes.
+    let others: Vec<_> = (0..1000)
+        .map(|i| {
+            tx.create(User {
+                name: format!("Other {}", i),
+                ..user.clone()
+            })
+            .unwrap()
+        })
+        .collect();
+
+    let replacement = tx
+        .create_with(
+            User {
+                visits: 2,
+                ..user.clone()
+            },
+            orm::OnConflict::Replace,
+        )
+        .unwrap()
+        .unwrap();
+    assert_ne!(replacement.id(), original.id());
+    assert!(matches!(original.state(), ObjectState::Removed));
+    assert_not_found(tx.get::<User>(original.id()), original.id(), "User");
+    assert!(others
+        .iter()
+        .all(|other| matches!(other.state(), ObjectState::Clean)));
+    drop(others);
+
+    let replacement_id = replacement.id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<User>(replacement_id).unwrap().borrow().visits, 2);
+    assert_eq!(tx.select_all::<User>().unwrap().len(), 1001);
+}
+
+#[test]
+fn custom_storage() {
+    use orm::storage::{StorageConnection, StorageTransaction};
+    use std::{cell::Cell, rc::Rc};
+
+    struct CountingStorage {
+        inner: rusqlite::Connection,
+        transactions: Rc<Cell<usize>>,
+    }
+
+    impl StorageConnection for CountingStorage {
+        fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
+            self.transactions.set(self.transactions.get() + 1);
+            self.inner.new_transaction()
+        }
+
+        fn total_changes(&self) -> Result<usize> {
+            self.inner.total_changes()
+        }
+    }
+
+    let transactions = Rc::new(Cell::new(0));
+    let mut conn = Connection::from_storage(CountingStorage {
+        inner: rusqlite::Connection::open_in_memory().unwrap(),
+        transactions: transactions.clone(),
+    });
+
+    let tx = conn.new_transaction().unwrap();
+    let order_id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.get::<Order>(order_id).unwrap().borrow().is_tall);
+    assert_eq!(transactions.get(), 2);
+}
+
+#[test]
+fn checked_tables_per_connection() {
+    #[derive(Object)]
+    struct Note {
+        text: String,
+    }
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SCHEMA_QUERIES: AtomicUsize = AtomicUsize::new(0);
+    fn trace(sql: &str) {
+        if ["sqlite_master", "table_info", "orm_fingerprints"]
+            .iter()
+            .any(|s| sql.contains(s))
+        {
+            SCHEMA_QUERIES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut inner = rusqlite::Connection::open_in_memory().unwrap();
+    inner.trace(Some(trace));
+    let mut conn = Connection::from_storage(inner);
+
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+
+    let checked = SCHEMA_QUERIES.load(Ordering::SeqCst);
+    for _ in 0..2 {
+        let tx = conn.new_transaction().unwrap();
+        assert!(tx.get::<Order>(id).unwrap().borrow().is_tall);
+        tx.create(Order { is_tall: false }).unwrap();
+        assert_eq!(tx.select_all::<Order>().unwrap().len(), 2);
+        tx.rollback().unwrap();
+    }
+    assert_eq!(SCHEMA_QUERIES.load(Ordering::SeqCst), checked);
+
+    // Tables created by a rolled back transaction are checked again.
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Note { text: "a".into() }).unwrap();
+    tx.rollback().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Note { text: "b".into() }).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.execute("DROP TABLE order_table", &[]).unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.commit().unwrap();
+    assert!(SCHEMA_QUERIES.load(Ordering::SeqCst) > checked);
+}
+
+#[test]
+fn clear_and_recreate_table() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    for _ in 0..3 {
+        tx.create(Order { is_tall: true }).unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let cached = tx.get::<Order>(ObjectId::from(2)).unwrap();
+    assert_eq!(tx.clear_table::<Order>().unwrap(), 3);
+    assert!(matches!(cached.state(), ObjectState::Removed));
+    assert!(matches!(
+        tx.get::<Order>(ObjectId::from(2)),
+        Err(orm::Error::NotFound(_))
+    ));
+    assert_eq!(
+        tx.create(Order { is_tall: false }).unwrap().id().raw(),
+        ObjectId::from(1)
+    );
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.truncate::<Order>().unwrap(), 1);
+    tx.execute("DROP TABLE order_table", &[]).unwrap();
+    tx.execute("CREATE TABLE order_table (id INTEGER PRIMARY KEY)", &[])
+        .unwrap();
+    tx.recreate_table::<Order>().unwrap();
+    let order = tx.create(Order { is_tall: true }).unwrap();
+    assert!(tx.get::<Order>(order.id()).unwrap().borrow().is_tall);
+}
+
+#[test]
+fn drop_table() {
+    #[derive(Object)]
+    #[table_name("order_table")]
+    struct RenamedOrder {
+        is_tall: bool,
+    }
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.transaction(|tx| {
+        let cached = tx.create(Order { is_tall: true })?;
+        tx.drop_table::<Order>()?;
+        assert!(cached.state() == ObjectState::Removed);
+        assert!(matches!(
+            tx.get::<Order>(cached.id()),
+            Err(orm::Error::NotFound(_))
+        ));
+        tx.drop_table::<Order>()
+    })
+    .unwrap();
+
+    // The fingerprint goes with the table.
+    db.transaction(|tx| tx.create(RenamedOrder { is_tall: true }).map(|_| ()))
+        .unwrap();
+    db.transaction(|tx| tx.create(RenamedOrder { is_tall: false }).map(|_| ()))
+        .unwrap();
+    assert_eq!(db.truncate::<RenamedOrder>().unwrap(), 2);
+    db.drop_table::<RenamedOrder>().unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+}
+
+#[test]
+fn schema_fingerprint() {
+    #[derive(Object)]
+    #[table_name("order_table")]
+    struct WideOrder {
+        is_tall: bool,
+        is_wide: bool,
+    }
+
+    assert_ne!(Order::TABLE.fingerprint, WideOrder::TABLE.fingerprint);
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    match tx.get::<WideOrder>(ObjectId::from(1)) {
+        Err(orm::Error::SchemaFingerprintMismatch(err)) => {
+            assert_eq!(err.table_name, "order_table");
+            assert_eq!(err.expected, WideOrder::TABLE.fingerprint);
+            assert_eq!(err.found, Order::TABLE.fingerprint);
+        }
+        res => panic!("expected SchemaFingerprintMismatch, got {}", fmt_res(&res)),
+    }
+
+    tx.recreate_table::<WideOrder>().unwrap();
+    tx.create(WideOrder {
+        is_tall: true,
+        is_wide: false,
+    })
+    .unwrap();
+    assert!(matches!(
+        tx.select_all::<Order>(),
+        Err(orm::Error::SchemaFingerprintMismatch(_))
+    ));
+}
+
+#[test]
+fn flush() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let first = tx.create(Order { is_tall: false }).unwrap();
+    let second = tx.create(Order { is_tall: false }).unwrap();
+    let second_id = second.id();
+
+    first.borrow_mut().is_tall = true;
+    second.delete();
+    let tall = column("IsTall").eq(true);
+    assert_eq!(tx.count_where::<Order>(&tall).unwrap(), 0);
+
+    tx.flush().unwrap();
+    assert!(first.state() == orm::ObjectState::Clean);
+    assert_eq!(tx.count_where::<Order>(&tall).unwrap(), 1);
+    assert_eq!(
+        tx.execute(
+            "UPDATE order_table SET IsTall = 0 WHERE id = ?",
+            &[second_id.into_i64().into()]
+        )
+        .unwrap(),
+        0
+    );
+    assert!(matches!(
+        tx.get::<Order>(second_id),
+        Err(orm::Error::NotFound(_))
+    ));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 1);
+}
+
+#[test]
+fn changed_columns_only() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let user = tx
+        .create(User {
+            name: "Eve".into(),
+            picture: vec![],
+            visits: 0,
+            balance: 0.,
+            is_admin: false,
+        })
+        .unwrap();
+    let id = user.id();
+    tx.execute("UPDATE User SET visits = 7", &[]).unwrap();
+
+    user.borrow_mut().name = "Mallory".into();
+    tx.flush().unwrap();
+    assert_eq!(tx.last_changes(), 1);
+    user.borrow_mut().name = "Mallory".into();
+    tx.flush().unwrap();
+    assert_eq!(tx.last_changes(), 0);
+    assert_eq!(tx.total_changes(), 3);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let user = tx.get::<User>(id).unwrap();
+    assert_eq!(user.borrow().name, "Mallory");
+    assert_eq!(user.borrow().visits, 7);
+}
+
+#[test]
+fn hooks() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COMMITTED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Object)]
+    #[hooks]
+    struct Post {
+        title: String,
+        slug: String,
+    }
+
+    #[derive(Object)]
+    struct AuditEntry {
+        message: String,
+    }
+
+    impl orm::object::Hooks for Post {
+        fn before_save(&mut self) -> Result<()> {
+            if self.title.is_empty() {
+                return Err(orm::Error::Storage("a post needs a title".into()));
+            }
+            self.slug = self.title.to_lowercase().replace(' ', "-");
+            Ok(())
+        }
+
+        fn after_create(&mut self, tx: &orm::Transaction, id: ObjectId) -> Result<()> {
+            tx.create(AuditEntry {
+                message: format!("created post {}", id),
+            })?;
+            Ok(())
+        }
+
+        fn after_commit(&mut self, _id: ObjectId) {
+            COMMITTED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let post = tx
+        .create(Post {
+            title: "Hello World".into(),
+            slug: String::new(),
+        })
+        .unwrap();
+    assert_eq!(post.borrow().slug, "hello-world");
+    assert!(tx
+        .create(Post {
+            title: String::new(),
+            slug: String::new(),
+        })
+        .is_err());
+    post.borrow_mut().title = "Hello Again".into();
+    let id = post.id();
+    tx.commit().unwrap();
+    assert_eq!(COMMITTED.load(Ordering::SeqCst), 1);
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Post>(id).unwrap().borrow().slug, "hello-again");
+    let entries = tx.select_all::<AuditEntry>().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].borrow().message, format!("created post {}", id));
+}
+
+#[test]
+fn reload() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.create(Order { is_tall: false }).unwrap();
+    let other = tx.create(Order { is_tall: false }).unwrap();
+
+    tx.execute("UPDATE order_table SET IsTall = 1", &[])
+        .unwrap();
+    assert!(!order.borrow().is_tall);
+    tx.reload(&order).unwrap();
+    assert!(order.borrow().is_tall);
+
+    order.borrow_mut().is_tall = false;
+    tx.reload(&order).unwrap();
+    assert!(order.state() == ObjectState::Clean);
+    assert!(order.borrow().is_tall);
+
+    tx.execute(
+        "DELETE FROM order_table WHERE id = ?",
+        &[other.id().into_i64().into()],
+    )
+    .unwrap();
+    assert!(matches!(tx.reload(&other), Err(orm::Error::NotFound(_))));
+    assert!(other.state() == ObjectState::Removed);
+    tx.commit().unwrap();
+}
+
+#[test]
+fn revert() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.create(Order { is_tall: false }).unwrap();
+    let id = order.id();
+
+    order.borrow_mut().is_tall = true;
+    order.revert();
+    assert!(!order.borrow().is_tall);
+    assert!(order.state() == ObjectState::Clean);
+
+    order.borrow_mut().is_tall = true;
+    let handle = order.clone();
+    order.delete();
+    assert!(tx.get::<Order>(id).is_err());
+    handle.undelete();
+    assert!(handle.state() == ObjectState::Modified);
+    assert!(tx.get::<Order>(id).unwrap().borrow().is_tall);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.get::<Order>(id).unwrap();
+    let handle = order.clone();
+    order.delete();
+    tx.flush().unwrap();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handle.undelete()));
+    assert!(res.is_err());
+}
+
+#[test]
+fn conflict_check() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let first = tx.create(Order { is_tall: false }).unwrap().id();
+    let second = tx.create(Order { is_tall: false }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.set_conflict_check(true);
+    tx.get::<Order>(first).unwrap().borrow_mut().is_tall = true;
+    tx.get::<Order>(second).unwrap();
+    // Stands in for a write by another connection.
+    tx.execute("UPDATE order_table SET IsTall = 1", &[])
+        .unwrap();
+    match tx.commit() {
+        Err(orm::Error::Conflict(err)) => {
+            assert_eq!(
+                err.objects,
+                vec![("Order", first.raw()), ("Order", second.raw())]
+            )
+        }
+        res => panic!("expected a conflict, got {}", fmt_res(&res)),
+    }
+
+    let tx = conn.new_transaction().unwrap();
+    tx.set_conflict_check(true);
+    tx.get::<Order>(first).unwrap().borrow_mut().is_tall = true;
+    tx.commit().unwrap();
+}
+
+#[test]
+fn memory_storage() {
+    use orm::memory::MemoryStorage;
+
+    let mut conn = Connection::from_storage(MemoryStorage::new());
+
+    let tx = conn.new_transaction().unwrap();
+    for (i, name) in ["Alice", "Bob", "Carol"].iter().enumerate() {
+        tx.create(User {
+            name: name.to_string(),
+            picture: b"zxcvbn"[..].into(),
+            visits: i as i64,
+            balance: 1.5,
+            is_admin: i == 1,
+        })
+        .unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let admins = tx.select::<User>(&column("is_admin").eq(true)).unwrap();
+    assert_eq!(admins.len(), 1);
+    assert_eq!(admins[0].borrow().name, "Bob");
+    assert_eq!(
+        tx.count_where::<User>(&column("visits").ge(1i64).and(!column("name").eq("Carol")))
+            .unwrap(),
+        1
+    );
+    assert!(tx.execute("DELETE FROM User", &[]).is_err());
+    tx.get::<User>(ObjectId::from(1))
+        .unwrap()
+        .borrow_mut()
+        .visits = 100;
+    tx.get::<User>(ObjectId::from(3)).unwrap().delete();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.get::<User>(ObjectId::from(1)).unwrap().borrow().visits,
+        100
+    );
+    assert_not_found(tx.get::<User>(ObjectId::from(3)), 3.into(), "User");
+    assert_eq!(
+        tx.delete_where::<User>(&column("visits").lt(50i64))
+            .unwrap(),
+        1
+    );
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<User>().unwrap().len(), 2);
+}
+
+#[test]
+fn connection_pool() {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = |timeout| orm::PoolOptions {
+        max_size: 2,
+        timeout,
+        ..Default::default()
+    };
+    let pool = Arc::new(orm::Pool::open_sqlite_file(
+        &path,
+        options(Duration::from_secs(30)),
+    ));
+
+    pool.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+
+    // The threads ask for connections while both are held here, and get
+    // them once they are released.
+    let first = pool.get().unwrap();
+    let second = pool.get().unwrap();
+    let (waiting, waiters) = mpsc::channel();
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            let waiting = waiting.clone();
+            thread::spawn(move || {
+                waiting.send(()).unwrap();
+                let mut conn = pool.get().unwrap();
+                let tx = conn.new_transaction().unwrap();
+                assert!(tx.get::<Order>(ObjectId::from(1)).unwrap().borrow().is_tall);
+            })
+        })
+        .collect();
+    for _ in 0..4 {
+        waiters.recv().unwrap();
+    }
+    drop(first);
+    drop(second);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(pool.open_connections() <= 2);
+    assert_eq!(pool.idle_connections(), 2);
+
+    let pool = orm::Pool::open_sqlite_file(&path, options(Duration::from_millis(10)));
+    let first = pool.get().unwrap();
+    let second = pool.get().unwrap();
+    assert!(pool.get().is_err());
+    drop(first);
+    drop(second);
+    assert_eq!(pool.idle_connections(), 2);
+}
+
+#[test]
+fn bulk_load_mode() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let indexes = || {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' ORDER BY name")
+            .unwrap();
+        let names: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|name| name.unwrap())
+            .collect();
+        names
+    };
+
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| {
+        tx.create(Order { is_tall: true })?;
+        tx.execute("CREATE INDEX order_tall ON order_table(IsTall)", &[])?;
+        tx.execute("CREATE UNIQUE INDEX order_id ON order_table(id)", &[])?;
+        Ok(())
+    })
+    .unwrap();
+
+    let loaded = db
+        .bulk_load_mode(|db| {
+            // Only the unique index is kept during the load.
+            assert_eq!(indexes(), ["order_id"]);
+            db.transaction(|tx| {
+                tx.create_many((0..100).map(|i| Order {
+                    is_tall: i % 2 == 0,
+                }))
+                .map(|orders| orders.len())
+            })
+        })
+        .unwrap();
+    assert_eq!(loaded, 100);
+    assert_eq!(indexes(), ["order_id", "order_tall"]);
+
+    // The settings are restored when the load fails as well.
+    let res = db.bulk_load_mode(|_| -> Result<()> { Err(orm::Error::ReadOnly) });
+    assert!(matches!(res, Err(orm::Error::ReadOnly)));
+    assert_eq!(indexes(), ["order_id", "order_tall"]);
+    assert_eq!(
+        db.read(|tx| tx.count_where::<Order>(&column("IsTall").eq(true)))
+            .unwrap(),
+        51
+    );
+
+    // Other backends just run the load.
+    let mut db = orm::Database::from_storage(orm::memory::MemoryStorage::new());
+    db.bulk_load_mode(|db| db.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ())))
+        .unwrap();
+}
+
+#[test]
+fn read_connections() {
+    use orm::{DatabaseOptions, JournalMode};
+    use std::thread;
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = DatabaseOptions::new().read_connections(2);
+    assert!(orm::Database::open_with(&path, &options).is_err());
+
+    let options = options.journal_mode(JournalMode::Wal);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+
+    let readers = db.readers().unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let readers = readers.clone();
+            thread::spawn(move || {
+                readers
+                    .read(|tx| Ok(tx.get::<Order>(id)?.borrow().is_tall))
+                    .unwrap()
+            })
+        })
+        .collect();
+    // The writer goes on while the readers read.
+    db.transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+    for handle in handles {
+        assert!(handle.join().unwrap());
+    }
+    assert!(readers.open_connections() <= 2);
+
+    assert_eq!(
+        db.read(|tx| Ok(tx.select_all::<Order>()?.len())).unwrap(),
+        2
+    );
+}
+
+#[test]
+fn database_transaction() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+    let res: Result<()> = db.transaction(|tx| {
+        tx.get::<Order>(id)?.delete();
+        tx.get::<Order>(ObjectId::from(42)).map(|_| ())
+    });
+    assert!(matches!(res, Err(orm::Error::NotFound(_))));
+
+    let is_tall = db
+        .transaction(|tx| Ok(tx.get::<Order>(id)?.borrow().is_tall))
+        .unwrap();
+    assert!(is_tall);
+}
+
+#[test]
+fn database_options() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new()
+        .journal_mode(orm::JournalMode::Wal)
+        .synchronous(orm::Synchronous::Normal)
+        .foreign_keys(true)
+        .cache_size(-4096)
+        .mmap_size(1 << 20);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+    drop(db);
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+}
+
+#[test]
+fn column_types() {
+    let declared = |conn: &rusqlite::Connection| -> Vec<(String, String, bool)> {
+        let mut stmt = conn.prepare("PRAGMA table_info(User)").unwrap();
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    };
+    let user = || User {
+        name: "Ann".into(),
+        picture: vec![1],
+        visits: 1,
+        balance: 2.0,
+        is_admin: false,
+    };
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| tx.create(user()).map(|_| ())).unwrap();
+    drop(db);
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    assert_eq!(
+        declared(&conn),
+        [
+            ("id".into(), "INTEGER".into(), false),
+            ("name".into(), "TEXT".into(), true),
+            ("picture".into(), "BLOB".into(), true),
+            ("visits".into(), "INTEGER".into(), true),
+            ("balance".into(), "REAL".into(), true),
+            ("is_admin".into(), "INTEGER".into(), true),
+        ]
+    );
+    // Without STRICT, SQLite still keeps a value of the wrong type.
+    conn.execute("UPDATE User SET visits = 'many'", []).unwrap();
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().strict_tables(true);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| tx.create(user()).map(|_| ())).unwrap();
+    drop(db);
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let sql: String = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE name = 'User'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(sql.ends_with("STRICT"));
+    assert!(conn.execute("UPDATE User SET visits = 'many'", []).is_err());
+}
+
+#[test]
+fn snapshot() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().journal_mode(orm::JournalMode::Wal);
+    let mut reader = orm::Database::open_with(&path, &options).unwrap();
+    let mut writer = orm::Database::open_with(&path, &options).unwrap();
+    writer
+        .transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+
+    let snapshot = reader.snapshot().unwrap();
+    writer
+        .transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    assert_eq!(snapshot.select_all::<Order>().unwrap().len(), 1);
+    writer
+        .transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    assert_eq!(snapshot.select_all::<Order>().unwrap().len(), 1);
+    snapshot.close().unwrap();
+
+    let tx = reader.read_transaction().unwrap();
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 3);
+}
+
+#[test]
+fn lock_retry() {
+    use std::time::Duration;
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().busy_timeout(Duration::ZERO);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+
+    let locker = rusqlite::Connection::open(&path).unwrap();
+    locker.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+    let mut attempts = 0;
+    let res = db.transaction(|tx| {
+        attempts += 1;
+        tx.get::<Order>(ObjectId::from(1)).map(|_| ())
+    });
+    assert!(matches!(res, Err(orm::Error::LockConflict)));
+    assert_eq!(attempts, 1);
+
+    let handle = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        locker.execute_batch("COMMIT").unwrap();
+    });
+    let mut db = db.with_retry_policy(orm::RetryPolicy {
+        max_attempts: 20,
+        ..Default::default()
+    });
+    let mut attempts = 0;
+    db.transaction(|tx| {
+        attempts += 1;
+        tx.get::<Order>(ObjectId::from(1)).map(|_| ())
+    })
+    .unwrap();
+    assert!(attempts > 1);
+    handle.join().unwrap();
+}
+
+#[test]
+fn commit_retry() {
+    use std::time::Duration;
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().busy_timeout(Duration::ZERO);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+
+    // An open read transaction keeps COMMIT from taking the exclusive lock.
+    let reader = rusqlite::Connection::open(&path).unwrap();
+    reader
+        .execute_batch("BEGIN; SELECT * FROM order_table")
+        .unwrap();
+
+    let tx = db.connection().new_transaction().unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    let res = tx.commit_with_retry(&orm::RetryPolicy::default());
+    assert!(matches!(res, Err(orm::Error::LockConflict)));
+
+    let handle = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        reader.execute_batch("COMMIT").unwrap();
+    });
+    let tx = db.connection().new_transaction().unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.commit_with_retry(&orm::RetryPolicy {
+        max_attempts: 20,
+        ..Default::default()
+    })
+    .unwrap();
+    handle.join().unwrap();
+
+    let policy = orm::RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::ZERO,
+        ..Default::default()
+    };
+    let mut attempts = 0;
+    let res = db.transaction_with_retry(&policy, |_| {
+        attempts += 1;
+        Err::<(), _>(orm::Error::LockConflict)
+    });
+    assert!(matches!(res, Err(orm::Error::LockConflict)));
+    assert_eq!(attempts, 3);
+    assert_eq!(
+        db.transaction(|tx| tx.count_where::<Order>(&column("IsTall").eq(true)))
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+fn transaction_behavior() {
+    use orm::TransactionBehavior;
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new()
+        .busy_timeout(std::time::Duration::ZERO)
+        .transaction_behavior(TransactionBehavior::Immediate);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    assert_eq!(db.transaction_behavior(), TransactionBehavior::Immediate);
+    let mut other = orm::Database::open_with(&path, &options).unwrap();
+
+    let tx = db
+        .connection()
+        .new_transaction_with(TransactionBehavior::Immediate)
+        .unwrap();
+    let res = other.transaction(|_| Ok(()));
+    assert!(matches!(res, Err(orm::Error::LockConflict)));
+    other
+        .connection()
+        .new_transaction_with(TransactionBehavior::Deferred)
+        .unwrap()
+        .rollback()
+        .unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.commit().unwrap();
+
+    let tx = db
+        .connection()
+        .new_transaction_with(TransactionBehavior::Exclusive)
+        .unwrap();
+    let res = other
+        .connection()
+        .new_transaction_with(TransactionBehavior::Deferred);
+    assert!(res
+        .and_then(|tx| tx.select_all::<Order>().map(|_| ()))
+        .is_err());
+    tx.rollback().unwrap();
+}
+
+#[test]
+fn read_only() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+    drop(db);
+
+    let mut db = orm::Database::open_read_only(&path).unwrap();
+    let res = db.transaction(|tx| {
+        assert!(tx.is_read_only());
+        assert!(tx.get::<Order>(id)?.borrow().is_tall);
+        tx.create(Order { is_tall: false }).map(|_| ())
+    });
+    assert!(matches!(res, Err(orm::Error::ReadOnly)));
+
+    let res = db.transaction(|tx| {
+        tx.get::<Order>(id)?.borrow_mut().is_tall = false;
+        Ok(())
+    });
+    assert!(matches!(res, Err(orm::Error::ReadOnly)));
+    assert!(matches!(
+        db.transaction(|tx| tx.execute("DELETE FROM order_table", &[])),
+        Err(orm::Error::ReadOnly)
+    ));
+    assert!(matches!(
+        db.transaction(|tx| tx.get::<User>(id.raw()).map(|_| ())),
+        Err(orm::Error::ReadOnly)
+    ));
+}
+
+#[test]
+fn attached_database() {
+    #[derive(Object)]
+    #[table_name("archive.orders")]
+    struct ArchivedOrder {
+        is_tall: bool,
+    }
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let archive_path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.attach(&archive_path, "archive").unwrap();
+
+    db.transaction(|tx| {
+        tx.create(Order { is_tall: true })?;
+        tx.create(ArchivedOrder { is_tall: false })?;
+        tx.create(ArchivedOrder { is_tall: true })?;
+        assert_eq!(tx.clear_table::<ArchivedOrder>()?, 2);
+        let id = tx.create(ArchivedOrder { is_tall: true })?.id();
+        assert_eq!(id.raw(), ObjectId::from(1));
+        Ok(())
+    })
+    .unwrap();
+    db.detach("archive").unwrap();
+    drop(db);
+
+    let archive = rusqlite::Connection::open(&archive_path).unwrap();
+    let count: i64 = archive
+        .query_row("SELECT COUNT(*) FROM orders", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+    let main = rusqlite::Connection::open(&path).unwrap();
+    assert!(main
+        .query_row("SELECT COUNT(*) FROM orders", [], |_| Ok(()))
+        .is_err());
+}
+
+#[test]
+fn backup() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let backup_path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| {
+        for _ in 0..100 {
+            tx.create(Order { is_tall: true })?;
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    let mut steps = Vec::new();
+    db.backup_to(&backup_path, |progress| steps.push(progress))
+        .unwrap();
+    assert_eq!(steps.last().unwrap().remaining, 0);
+
+    let mut copy = orm::Database::open(&backup_path).unwrap();
+    let count = copy
+        .transaction(|tx| tx.count_where::<Order>(&column("IsTall").eq(true)))
+        .unwrap();
+    assert_eq!(count, 100);
+}
+
+#[test]
+fn maintenance() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new()
+        .journal_mode(orm::JournalMode::Wal)
+        .auto_vacuum(orm::AutoVacuum::Incremental);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| {
+        for _ in 0..1000 {
+            tx.create(User {
+                name: "x".repeat(100),
+                picture: vec![0; 100],
+                visits: 0,
+                balance: 0.,
+                is_admin: false,
+            })?;
+        }
+        Ok(())
+    })
+    .unwrap();
+    db.transaction(|tx| tx.clear_table::<User>()).unwrap();
+
+    db.incremental_vacuum(Some(1)).unwrap();
+    db.incremental_vacuum(None).unwrap();
+    let checkpoint = db.checkpoint(orm::CheckpointMode::Truncate).unwrap();
+    assert!(!checkpoint.busy);
+    assert_eq!(
+        std::fs::metadata(format!("{}-wal", path.display()))
+            .unwrap()
+            .len(),
+        0
+    );
+    db.vacuum().unwrap();
+}
+
+#[test]
+fn planner_statistics() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().optimize_on_close(true);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| {
+        for i in 0..10 {
+            tx.create(Order {
+                is_tall: i % 2 == 0,
+            })?;
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    db.optimize().unwrap();
+    db.analyze().unwrap();
+    let analyzed = db
+        .transaction(|tx| tx.execute("UPDATE sqlite_stat1 SET stat = stat", &[]))
+        .unwrap();
+    assert!(analyzed > 0);
+    drop(db);
+
+    let mut db = orm::Database::open(&path).unwrap();
+    assert_eq!(
+        db.transaction(|tx| tx.count_where::<Order>(&column("IsTall").eq(true)))
+            .unwrap(),
+        5
+    );
+}
+
+#[test]
+fn mock_storage() {
+    use orm::mock::{MockOp, MockStorage};
+
+    let storage = MockStorage::new();
+    let script = storage.script();
+    script
+        .fail_nth(MockOp::Insert, 2, || orm::Error::LockConflict)
+        .return_row(1, orm::row![42i64.into()]);
+    let mut db = orm::Database::from_storage(storage).with_retry_policy(orm::RetryPolicy {
+        max_attempts: 2,
+        initial_backoff: std::time::Duration::ZERO,
+        ..Default::default()
+    });
+
+    let id = db
+        .transaction(|tx| {
+            tx.create(Order { is_tall: true })?;
+            Ok(tx.create(Order { is_tall: false })?.id())
+        })
+        .unwrap();
+    assert_eq!(script.calls(MockOp::Begin), 2);
+    assert_eq!(script.calls(MockOp::Insert), 4);
+    assert_eq!(script.calls(MockOp::Commit), 1);
+
+    let err = db
+        .transaction(|tx| tx.get::<Order>(id).map(|_| ()))
+        .unwrap_err();
+    assert!(matches!(err, orm::Error::UnexpectedType(_)), "{}", err);
+    assert!(!db
+        .transaction(|tx| Ok(tx.get::<Order>(id)?.borrow().is_tall))
+        .unwrap());
+}
+
+fn check_savepoints(mut conn: Connection) {
+    let tx = conn.new_transaction().unwrap();
+    let kept = tx.create(Order { is_tall: true }).unwrap();
+
+    let sp = tx.savepoint().unwrap();
+    sp.create(Order { is_tall: true }).unwrap();
+    kept.borrow_mut().is_tall = false;
+    sp.rollback().unwrap();
+    assert!(kept.borrow().is_tall);
+    assert!(kept.state() == orm::ObjectState::Clean);
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        1
+    );
+
+    let sp = tx.savepoint().unwrap();
+    sp.create(Order { is_tall: false }).unwrap();
+    {
+        let inner = sp.savepoint().unwrap();
+        inner.get::<Order>(kept.id()).unwrap().delete();
+    }
+    sp.release().unwrap();
+    assert!(kept.state() == orm::ObjectState::Clean);
+
+    // Releasing a savepoint releases those within it.
+    let outer = tx.savepoint().unwrap();
+    let inner = tx.savepoint().unwrap();
+    kept.borrow_mut().is_tall = false;
+    outer.release().unwrap();
+    assert!(inner.rollback().is_err());
+    assert!(!kept.borrow().is_tall);
+    let sp = tx.savepoint().unwrap();
+    kept.borrow_mut().is_tall = true;
+    sp.rollback().unwrap();
+    assert!(!kept.borrow().is_tall);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 2);
+
+    // An object still borrowed cannot be restored, and a savepoint dropped
+    // then leaves the transaction unable to commit.
+    let order = tx.select_all::<Order>().unwrap().remove(0);
+    let sp = tx.savepoint().unwrap();
+    order.borrow_mut().is_tall ^= true;
+    let guard = order.borrow();
+    assert!(matches!(sp.rollback(), Err(orm::Error::Borrowed(_))));
+    drop(guard);
+    assert!(tx.commit().is_err());
+}
+
+#[test]
+fn savepoints() {
+    check_savepoints(Connection::open_in_memory().unwrap());
+    check_savepoints(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+fn check_commit_and_continue(mut conn: Connection) {
+    let mut tx = conn.new_transaction().unwrap();
+    let mut ids = Vec::new();
+    for i in 0..4 {
+        ids.push(tx.create(Order { is_tall: false }).unwrap().id());
+        if i % 2 == 1 {
+            tx.get::<Order>(ids[i]).unwrap().borrow_mut().is_tall = true;
+            tx = tx.commit_and_continue().unwrap();
+        }
+    }
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 4);
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        2
+    );
+}
+
+#[test]
+fn commit_and_continue() {
+    check_commit_and_continue(Connection::open_in_memory().unwrap());
+    check_commit_and_continue(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+#[test]
+fn abandoned_transaction() {
+    let abandoned = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut conn = Connection::open_in_memory().unwrap();
+    let seen = abandoned.clone();
+    conn.on_abandoned_transaction(move |pending| {
+        seen.lock().unwrap().push(pending.len());
+    });
+
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: false }).unwrap().id();
+    tx.commit().unwrap();
+
+    {
+        let tx = conn.new_transaction().unwrap();
+        tx.get::<Order>(id).unwrap().borrow_mut().is_tall = true;
+        tx.create(Order { is_tall: true }).unwrap();
+    }
+    assert_eq!(*abandoned.lock().unwrap(), vec![1]);
+
+    conn.new_transaction().unwrap().rollback().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 1);
+    assert!(!tx.get::<Order>(id).unwrap().borrow().is_tall);
+    tx.commit().unwrap();
+    assert_eq!(abandoned.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn read_transaction() {
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+
+    let tx = db.read_transaction().unwrap();
+    assert!(tx.get::<Order>(id).unwrap().borrow().is_tall);
+    assert!(tx.try_get::<Order>(ObjectId::from(5)).unwrap().is_none());
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 1);
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        1
+    );
+    assert!(matches!(tx.select_all::<User>(), Err(orm::Error::ReadOnly)));
+    tx.close().unwrap();
+}
+
+#[test]
+fn into_owned() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.create(Order { is_tall: false }).unwrap();
+    order.borrow_mut().is_tall = true;
+    let owned = order.into_owned();
+    tx.commit().unwrap();
+
+    let handle = std::thread::spawn(move || owned.is_tall);
+    assert!(handle.join().unwrap());
+}
+
+#[test]
+fn threaded() {
+    let db = orm::threaded::Database::open_in_memory().unwrap();
+    let tx = db.transaction().unwrap();
+    let workers: Vec<_> = (0..4)
+        .map(|visits| {
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let user = User {
+                    name: "Threaded".into(),
+                    picture: Vec::new(),
+                    visits,
+                    balance: 0.,
+                    is_admin: false,
+                };
+                tx.create(user).unwrap()
+            })
+        })
+        .collect();
+    let ids: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+    let mut user = tx.get::<User>(ids[0]).unwrap();
+    user.is_admin = true;
+    tx.update(ids[0], user.clone()).unwrap();
+    tx.commit().unwrap();
+
+    let tx = db.transaction().unwrap();
+    assert_eq!(
+        tx.select::<User>(column("is_admin").eq(true)).unwrap(),
+        vec![(ids[0], user)]
+    );
+    tx.delete::<User>(ids[1]).unwrap();
+    drop(tx);
+
+    let tx = db.transaction().unwrap();
+    assert_eq!(
+        tx.select::<User>(column("visits").ge(0i64)).unwrap().len(),
+        4
+    );
+}
+
+#[test]
+fn cache_limit() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.set_cache_limit(Some(10));
+    let kept = tx.create(Order { is_tall: false }).unwrap();
+    let changed = tx.create(Order { is_tall: false }).unwrap().id();
+    tx.get::<Order>(changed).unwrap().borrow_mut().is_tall = true;
+    let deleted = tx.create(Order { is_tall: false }).unwrap().id();
+    tx.get::<Order>(deleted).unwrap().delete();
+    for _ in 0..100 {
+        tx.create(Order { is_tall: false }).unwrap();
+    }
+    assert!(tx.cached_objects() <= 10);
+
+    assert!(tx.get::<Order>(changed).unwrap().borrow().is_tall);
+    assert!(tx.try_get::<Order>(deleted).unwrap().is_none());
+    assert!(std::ptr::eq(
+        &*kept.borrow(),
+        &*tx.get::<Order>(kept.id()).unwrap().borrow()
+    ));
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 102);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        1
+    );
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 102);
+}
+
+#[test]
+fn cache_limit_lru() {
+    use orm::mock::MockOp;
+
+    let (mut conn, script) = mock_connection();
+    let tx = conn.new_transaction().unwrap();
+    tx.set_cache_limit(Some(4));
+    let ids: Vec<_> = (0..4)
+        .map(|_| tx.create(Order { is_tall: false }).unwrap().id())
+        .collect();
+    tx.get::<Order>(ids[0]).unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    assert_eq!(tx.cached_objects(), 3);
+
+    // ids[1] and ids[2] were used least recently.
+    let selects = script.calls(MockOp::Select);
+    tx.get::<Order>(ids[0]).unwrap();
+    tx.get::<Order>(ids[3]).unwrap();
+    assert_eq!(script.calls(MockOp::Select), selects);
+    tx.get::<Order>(ids[1]).unwrap();
+    assert_eq!(script.calls(MockOp::Select), selects + 1);
+}
+
+#[test]
+fn prefetch() {
+    use orm::mock::MockOp;
+
+    #[derive(Object)]
+    struct Post {
+        author: i64,
+        title: String,
+    }
+
+    let (mut conn, script) = mock_connection();
+    let tx = conn.new_transaction().unwrap();
+    let orders: Vec<_> = (0..3)
+        .map(|_| tx.create(Order { is_tall: true }).unwrap().id().raw())
+        .collect();
+    let posts: Vec<_> = [(orders[0], "a"), (orders[2], "b"), (orders[0], "c")]
+        .into_iter()
+        .map(|(author, title)| {
+            let post = Post {
+                author: author.into_i64(),
+                title: title.to_string(),
+            };
+            tx.create(post).unwrap().id().raw()
+        })
+        .collect();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(orders[1]).unwrap();
+    let selects = script.calls(MockOp::Select);
+    let gone = ObjectId::from(1000);
+    assert_eq!(
+        tx.prefetch::<Order>(&[orders[0], orders[1], orders[2], orders[0], gone])
+            .unwrap(),
+        2
+    );
+    assert_eq!(script.calls(MockOp::Select), selects + 1);
+    let children = tx.prefetch_children::<Post>("author", &orders).unwrap();
+    assert_eq!(script.calls(MockOp::Select), selects + 2);
+    assert_eq!(children.len(), 2);
+    assert_eq!(children[&orders[0]], vec![posts[0], posts[2]]);
+    assert_eq!(children[&orders[2]], vec![posts[1]]);
+
+    for id in &orders {
+        assert!(tx.get::<Order>(*id).unwrap().borrow().is_tall);
+    }
+    assert_eq!(tx.get::<Post>(posts[1]).unwrap().borrow().title, "b");
+    assert_eq!(script.calls(MockOp::Select), selects + 2);
+
+    match tx.prefetch_children::<Post>("title", &orders) {
+        Err(orm::Error::Storage(_)) => {}
+        res => panic!("expected Error::Storage, got {}", fmt_res(&res)),
+    }
+}
+
+#[test]
+fn batched_deletes() {
+    use orm::mock::MockOp;
+
+    let (mut conn, script) = mock_connection();
+    let tx = conn.new_transaction().unwrap();
+    let users = tx.create_many((0..5).map(user)).unwrap();
+    let orders: Vec<_> = (0..3)
+        .map(|_| tx.create(Order { is_tall: false }).unwrap())
+        .collect();
+    users.into_iter().for_each(|obj| obj.delete());
+    orders.into_iter().for_each(|obj| obj.delete());
+    tx.flush().unwrap();
+    assert_eq!(script.calls(MockOp::Delete), 2);
+    assert_eq!(tx.select_all::<User>().unwrap().len(), 0);
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 0);
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let users = tx.create_many((0..5).map(user)).unwrap();
+    users.into_iter().for_each(|obj| obj.delete());
+    tx.flush().unwrap();
+    assert_eq!(tx.last_changes(), 5);
+    assert_eq!(tx.count_where::<User>(&column("id").gt(0)).unwrap(), 0);
+
+    let users = tx.create_many((0..5).map(user)).unwrap();
+    let gone = users[3].id();
+    tx.execute(&format!("DELETE FROM User WHERE id = {}", gone), &[])
+        .unwrap();
+    users.into_iter().for_each(|obj| obj.delete());
+    match tx.flush() {
+        Err(orm::Error::NotFound(err)) => assert_eq!(err.object_id, gone.raw()),
+        res => panic!("expected Error::NotFound, got {}", fmt_res(&res)),
+    }
+}
+
+#[cfg(feature = "smallvec")]
+#[test]
+fn smallvec_rows() {
+    let user = User {
+        name: "Alice".into(),
+        picture: vec![1, 2],
+        visits: 3,
+        balance: 4.5,
+        is_admin: true,
+    };
+    assert!(!user.serialize().spilled());
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(user.clone()).unwrap().id();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<User>(id).unwrap().borrow(), user);
+}
+
+#[test]
+fn serialize_into() {
+    let user = User {
+        name: "Alice".into(),
+        picture: vec![1, 2],
+        visits: 3,
+        balance: 4.5,
+        is_admin: true,
+    };
+    let mut row = orm::row![orm::data::Value::from(7i64)];
+    user.serialize_into(&mut row);
+    assert_eq!(row[0], orm::data::Value::from(7i64));
+    assert_eq!(row[1..], user.serialize()[..]);
+
+    // Every modified object of a commit is written from one buffer.
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let ids: Vec<_> = (0..3)
+        .map(|i| {
+            tx.create(User {
+                visits: i,
+                ..user.clone()
+            })
+            .unwrap()
+            .id()
+        })
+        .collect();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for id in ids {
+        tx.get::<User>(id).unwrap().borrow_mut().visits += 10;
+    }
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let visits: Vec<_> = tx
+        .select_all::<User>()
+        .unwrap()
+        .iter()
+        .map(|user| user.borrow().visits)
+        .collect();
+    assert_eq!(visits, vec![10, 11, 12]);
+}
+
+#[test]
+fn batched_updates() {
+    use orm::mock::MockOp;
+
+    let (mut conn, script) = mock_connection();
+    let tx = conn.new_transaction().unwrap();
+    let users = tx.create_many((0..6).map(user)).unwrap();
+    let updates = script.calls(MockOp::Update);
+    for user in &users[..4] {
+        user.borrow_mut().visits += 10;
+    }
+    users[4].borrow_mut().name = "Renamed".into();
+    users[5].borrow_mut().visits += 10;
+    tx.flush().unwrap();
+    assert_eq!(script.calls(MockOp::Update), updates + 3);
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let users = tx.create_many((0..5).map(user)).unwrap();
+    for user in &users {
+        user.borrow_mut().visits += 1;
+    }
+    tx.flush().unwrap();
+    assert_eq!(tx.last_changes(), 5);
+    let ids: Vec<_> = users.iter().map(|user| user.id()).collect();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let visits: Vec<_> = ids
+        .iter()
+        .map(|&id| tx.get::<User>(id).unwrap().borrow().visits)
+        .collect();
+    assert_eq!(visits, [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn closure_update() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = tx.create(Order { is_tall: false }).unwrap();
+    let was_tall = order
+        .update(|order| std::mem::replace(&mut order.is_tall, true))
+        .unwrap();
+    assert!(!was_tall);
+    assert!(order.state() == ObjectState::Modified);
+
+    let guard = order.borrow();
+    assert!(matches!(
+        order.update(|order| order.is_tall = false),
+        Err(orm::Error::Borrowed(_))
+    ));
+    drop(guard);
+    order.clone().delete();
+    assert!(matches!(
+        order.update(|order| order.is_tall = false),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+fn check_create_with_id(mut conn: Connection) {
+    let tx = conn.new_transaction().unwrap();
+    let order = tx
+        .create_with_id(ObjectId::from(42), Order { is_tall: true })
+        .unwrap();
+    assert_eq!(order.id().raw(), ObjectId::from(42));
+    assert!(matches!(
+        tx.create_with_id(ObjectId::from(42), Order { is_tall: false }),
+        Err(orm::Error::Constraint(err)) if err.kind == orm::ConstraintKind::PrimaryKey
+            && err.table.as_deref() == Some("order_table")
+            && err.column() == Some("id")
+    ));
+    assert_eq!(
+        tx.create(Order { is_tall: false }).unwrap().id().raw(),
+        ObjectId::from(43)
+    );
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(
+        tx.get::<Order>(ObjectId::from(42))
+            .unwrap()
+            .borrow()
+            .is_tall
+    );
+}
+
+#[test]
+fn create_with_id() {
+    check_create_with_id(Connection::open_in_memory().unwrap());
+    check_create_with_id(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+fn check_audited(mut conn: Connection) {
+    use orm::ChangeOp;
+
+    #[derive(Object)]
+    #[audited]
+    struct Account {
+        owner: String,
+        balance: i64,
+    }
+
+    let tx = conn.new_transaction().unwrap();
+    let account = tx
+        .create(Account {
+            owner: "Ann".into(),
+            balance: 10,
+        })
+        .unwrap();
+    let id = account.id();
+    tx.set_actor(Some("teller"));
+    account.borrow_mut().balance = 20;
+    tx.flush().unwrap();
+    account.borrow_mut().balance = 30;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.delete::<Account>(id).unwrap();
+    let history = tx.history::<Account>(id).unwrap();
+    let summary: Vec<_> = history
+        .iter()
+        .map(|entry| (entry.operation, entry.actor.as_str(), entry.old.balance))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (ChangeOp::Insert, "", 10),
+            (ChangeOp::Update, "teller", 10),
+            (ChangeOp::Update, "teller", 20),
+            (ChangeOp::Delete, "", 30),
+        ]
+    );
+    assert_eq!(history[3].old.owner, "Ann");
+    assert!(history[0].changed_at <= history[3].changed_at);
+    assert!(tx.history::<Account>(ObjectId::from(7)).unwrap().is_empty());
+}
+
+#[test]
+fn audited() {
+    check_audited(Connection::open_in_memory().unwrap());
+    check_audited(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+#[test]
+fn get_as_of() {
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Object)]
+    #[audited]
+    struct Price {
+        cents: i64,
+    }
+
+    let tick = || {
+        std::thread::sleep(Duration::from_millis(5));
+        let now = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(5));
+        now
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    let before = tick();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Price { cents: 100 }).unwrap().id();
+    tx.commit().unwrap();
+    let created = tick();
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Price>(id).unwrap().borrow_mut().cents = 120;
+    tx.commit().unwrap();
+    let updated = tick();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get_as_of::<Price>(id, tick()).unwrap().cents, 120);
+    tx.delete::<Price>(id).unwrap();
+
+    let cents = |at| tx.get_as_of::<Price>(id, at).map(|price| price.cents);
+    assert!(matches!(cents(before), Err(orm::Error::NotFound(_))));
+    assert_eq!(cents(created).unwrap(), 100);
+    assert_eq!(cents(updated).unwrap(), 120);
+    assert!(matches!(cents(tick()), Err(orm::Error::NotFound(_))));
+}
+
+#[test]
+fn get_dynamic() {
+    use orm::data::Value;
+
+    orm::dynamic::register::<User>();
+    orm::dynamic::register::<Order>();
+    assert!(orm::dynamic::registered_tables().contains(&"order_table"));
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: true }).unwrap().id();
+    let row = tx.get_dynamic("order_table", id.raw()).unwrap();
+    assert_eq!(row.schema.type_name, "Order");
+    assert_eq!(row.get("IsTall"), Some(&Value::Bool(true)));
+    assert_eq!(row.get("is_tall"), None);
+    assert_eq!(
+        row.columns().collect::<Vec<_>>(),
+        vec![("IsTall", &Value::Bool(true))]
+    );
+
+    assert!(matches!(
+        tx.get_dynamic("order_table", ObjectId::from(9)),
+        Err(orm::Error::NotFound(_))
+    ));
+    assert!(matches!(
+        tx.get_dynamic("User", id.raw()),
+        Err(orm::Error::NotFound(_))
+    ));
+    assert!(matches!(
+        tx.get_dynamic("unknown", id.raw()),
+        Err(orm::Error::Storage(_))
+    ));
+}
+
+#[test]
+fn migrations() {
+    use orm::migrations::Migration;
+
+    struct Sql(i64, &'static str, &'static str);
+
+    impl Migration for Sql {
+        fn version(&self) -> i64 {
+            self.0
+        }
+
+        fn up(&self, tx: &orm::Transaction) -> Result<()> {
+            tx.execute(self.1, &[]).map(|_| ())
+        }
+
+        fn down(&self, tx: &orm::Transaction) -> Result<()> {
+            tx.execute(self.2, &[]).map(|_| ())
+        }
+    }
+
+    let create = Sql(
+        1,
+        "CREATE TABLE tag (id INTEGER PRIMARY KEY, name TEXT)",
+        "DROP TABLE tag",
+    );
+    let insert = Sql(
+        2,
+        "INSERT INTO tag (name) VALUES ('new')",
+        "DELETE FROM tag",
+    );
+    let broken = Sql(3, "INSERT INTO missing VALUES (1)", "");
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    assert_eq!(db.migrate(&[&insert, &create]).unwrap(), vec![1, 2]);
+    assert!(db.migrate(&[&create, &insert]).unwrap().is_empty());
+    assert!(db.migrate(&[&create, &broken, &insert]).is_err());
+    assert_eq!(db.applied_migrations().unwrap(), vec![1, 2]);
+    assert!(db.migrate(&[&create, &Sql(1, "", "")]).is_err());
+
+    assert_eq!(db.migrate_down(&[&create, &insert], 0).unwrap(), vec![2, 1]);
+    assert!(db.applied_migrations().unwrap().is_empty());
+    assert_eq!(db.migrate(&[&create]).unwrap(), vec![1]);
+    let count = db
+        .transaction(|tx| tx.execute("DELETE FROM tag", &[]))
+        .unwrap();
+    assert_eq!(count, 0);
+
+    // A failing down step leaves every migration applied.
+    let rename = Sql(
+        3,
+        "ALTER TABLE tag RENAME TO label",
+        "ALTER TABLE label RENAME TO tag",
+    );
+    let broken_down = Sql(3, "", "DROP TABLE missing");
+    assert_eq!(
+        db.migrate(&[&create, &insert, &rename]).unwrap(),
+        vec![2, 3]
+    );
+    assert!(db.migrate_down_to(&[&create, &insert], 0).is_err());
+    assert!(db
+        .migrate_down_to(&[&create, &insert, &broken_down], 0)
+        .is_err());
+    assert_eq!(db.applied_migrations().unwrap(), vec![1, 2, 3]);
+    assert_eq!(
+        db.migrate_down_to(&[&create, &insert, &rename], 1).unwrap(),
+        vec![3, 2]
+    );
+    assert_eq!(db.applied_migrations().unwrap(), vec![1]);
+    let count = db
+        .transaction(|tx| tx.execute("DELETE FROM tag", &[]))
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn rewrite_table() {
+    use orm::migrations::Migration;
+
+    #[derive(Object)]
+    #[table_name("person")]
+    struct FullName {
+        name: String,
+    }
+
+    #[derive(Object)]
+    #[table_name("person")]
+    struct SplitName {
+        first: String,
+        last: String,
+    }
+
+    struct Split;
+
+    impl Migration for Split {
+        fn version(&self) -> i64 {
+            1
+        }
+
+        fn up(&self, tx: &orm::Transaction) -> Result<()> {
+            tx.rewrite_table(|old: FullName| {
+                let (first, last) = old.name.split_once(' ').unwrap_or((&old.name, ""));
+                Ok(SplitName {
+                    first: first.into(),
+                    last: last.into(),
+                })
+            })?;
+            Ok(())
+        }
+    }
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let (ada, plato) = db
+        .transaction(|tx| {
+            let ada = tx.create(FullName {
+                name: "Ada Lovelace".into(),
+            })?;
+            let plato = tx.create(FullName {
+                name: "Plato".into(),
+            })?;
+            Ok((ada.id(), plato.id()))
+        })
+        .unwrap();
+
+    let res = db.transaction(|tx| {
+        tx.rewrite_table(|_: FullName| -> Result<SplitName> {
+            Err(orm::Error::Storage("bad row".into()))
+        })
+    });
+    assert!(res.is_err());
+    db.transaction(|tx| tx.get::<FullName>(ada).map(|_| ()))
+        .unwrap();
+
+    db.migrate(&[&Split]).unwrap();
+    db.transaction(|tx| {
+        let ada = tx.get::<SplitName>(ada.raw())?;
+        assert_eq!(ada.borrow().first, "Ada");
+        assert_eq!(ada.borrow().last, "Lovelace");
+        assert_eq!(tx.get::<SplitName>(plato.raw())?.borrow().last, "");
+        let next = tx.create(SplitName {
+            first: "Alan".into(),
+            last: "Turing".into(),
+        })?;
+        assert_eq!(next.id(), 3.into());
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn rewrite_table_lazy_fields() {
+    #[derive(Object)]
+    #[table_name("photo")]
+    struct OldPhoto {
+        #[lazy]
+        data: Vec<u8>,
+    }
+
+    #[derive(Object)]
+    #[table_name("photo")]
+    struct NewPhoto {
+        size: i64,
+        #[lazy]
+        data: Vec<u8>,
+    }
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let id = db
+        .transaction(|tx| Ok(tx.create(OldPhoto { data: vec![7; 8] })?.id()))
+        .unwrap();
+    db.transaction(|tx| {
+        tx.rewrite_table(|old: OldPhoto| {
+            Ok(NewPhoto {
+                size: old.data.len() as i64,
+                data: old.data,
+            })
+        })
+    })
+    .unwrap();
+    db.transaction(|tx| {
+        let photo = tx.get::<NewPhoto>(id.raw())?;
+        tx.load_field(&photo, "data")?;
+        assert_eq!(photo.borrow().size, 8);
+        assert_eq!(photo.borrow().data, vec![7; 8]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn migration_plan() {
+    use orm::migrations::Migration;
+
+    struct Sql(i64, &'static str);
+
+    impl Migration for Sql {
+        fn version(&self) -> i64 {
+            self.0
+        }
+
+        fn up(&self, tx: &orm::Transaction) -> Result<()> {
+            tx.execute(self.1, &[]).map(|_| ())
+        }
+    }
+
+    struct CreateOrders;
+
+    impl Migration for CreateOrders {
+        fn version(&self) -> i64 {
+            3
+        }
+
+        fn name(&self) -> &str {
+            "orders"
+        }
+
+        fn up(&self, tx: &orm::Transaction) -> Result<()> {
+            tx.recreate_table::<Order>()?;
+            tx.get::<Order>(tx.create(Order { is_tall: false })?.id())?
+                .borrow_mut()
+                .is_tall = true;
+            Ok(())
+        }
+    }
+
+    let create = Sql(1, "CREATE TABLE tag (id INTEGER PRIMARY KEY, name TEXT)");
+    let insert = Sql(2, "INSERT INTO tag (name) VALUES ('new')");
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.migrate(&[&create]).unwrap();
+
+    let plan = db
+        .migration_plan(&[&CreateOrders, &insert, &create])
+        .unwrap();
+    let statements: Vec<_> = plan
+        .iter()
+        .map(|step| (step.version, step.statements.clone()))
+        .collect();
+    assert_eq!(
+        statements,
+        [
+            (2, vec!["INSERT INTO tag (name) VALUES ('new')".to_string()]),
+            (
+                3,
+                vec![
+                    Order::TABLE.create_text(),
+                    "INSERT INTO order_table (IsTall) VALUES (0)".to_string(),
+                    "UPDATE order_table SET IsTall = 1 WHERE \"id\" = 1".to_string(),
+                ]
+            ),
+        ]
+    );
+    assert_eq!(plan[1].name, "orders");
+
+    // Nothing was applied.
+    assert_eq!(db.applied_migrations().unwrap(), vec![1]);
+    let count = db
+        .transaction(|tx| tx.execute("DELETE FROM tag", &[]))
+        .unwrap();
+    assert_eq!(count, 0);
+    assert!(db.migration_plan(&[&Sql(4, "DROP TABLE missing")]).is_err());
+
+    let mut db = orm::Database::from_storage(orm::memory::MemoryStorage::new());
+    assert!(db.migration_plan(&[&insert]).is_err());
+}
+
+fn check_sync_schema(mut conn: Connection) {
+    use orm::Mismatch;
+
+    #[derive(Object)]
+    #[table_name("note")]
+    struct Note {
+        text: String,
+    }
+
+    #[derive(Object)]
+    #[table_name("note")]
+    struct TaggedNote {
+        text: String,
+        tag: String,
+        priority: i64,
+    }
+
+    #[derive(Object)]
+    #[table_name("note")]
+    struct NumberedNote {
+        text: i64,
+    }
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.sync_schema::<Note>().unwrap().is_empty());
+    let id = tx.create(Note { text: "a".into() }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.sync_schema::<TaggedNote>().unwrap(), ["tag", "priority"]);
+    let note = tx.get::<TaggedNote>(id.raw()).unwrap();
+    assert_eq!(note.borrow().text, "a");
+    assert_eq!(note.borrow().tag, "");
+    assert_eq!(note.borrow().priority, 0);
+    assert!(tx.sync_schema::<TaggedNote>().unwrap().is_empty());
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    match tx.sync_schema::<Note>() {
+        Err(orm::Error::SchemaMismatch(err)) => {
+            assert_eq!(err.table_name, "note");
+            assert_eq!(
+                err.mismatches,
+                vec![
+                    Mismatch::ExtraColumn {
+                        column: "tag".into()
+                    },
+                    Mismatch::ExtraColumn {
+                        column: "priority".into()
+                    },
+                ]
+            );
+        }
+        res => panic!("expected SchemaMismatch, got {}", fmt_res(&res)),
+    }
+    assert!(matches!(
+        tx.get::<Note>(id),
+        Err(orm::Error::SchemaFingerprintMismatch(_))
+    ));
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.recreate_table::<Note>().unwrap();
+    match tx.sync_schema::<NumberedNote>() {
+        Err(orm::Error::SchemaMismatch(err)) => assert_eq!(
+            err.mismatches,
+            vec![Mismatch::Type {
+                column: "text".into(),
+                expected: orm::data::DataType::Int64,
+                found: orm::data::DataType::String,
+            }]
+        ),
+        res => panic!("expected SchemaMismatch, got {}", fmt_res(&res)),
+    }
+}
+
+#[test]
+fn sync_schema() {
+    check_sync_schema(Connection::open_in_memory().unwrap());
+    check_sync_schema(Connection::from_storage(orm::memory::MemoryStorage::new()));
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    assert!(db.sync_schema::<Order>().unwrap().is_empty());
+}
+
+fn check_validate_schema(mut conn: Connection) {
+    use orm::Mismatch;
+
+    #[derive(Object)]
+    #[table_name("order_table")]
+    struct OrderWithNote {
+        #[column_name("IsTall")]
+        is_tall: bool,
+        note: String,
+    }
+
+    let tx = conn.new_transaction().unwrap();
+    match tx.validate_schema::<Order>() {
+        Err(orm::Error::SchemaMismatch(err)) => {
+            assert_eq!(err.mismatches, vec![Mismatch::MissingTable])
+        }
+        res => panic!("expected SchemaMismatch, got {}", fmt_res(&res)),
+    }
+    assert!(tx.validate_schema::<Order>().is_err());
+
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.validate_schema::<Order>().unwrap();
+    match tx.validate_schema::<OrderWithNote>() {
+        Err(orm::Error::SchemaMismatch(err)) => assert_eq!(
+            err.mismatches,
+            vec![Mismatch::MissingColumn {
+                column: "note".into()
+            }]
+        ),
+        res => panic!("expected SchemaMismatch, got {}", fmt_res(&res)),
+    }
+
+    let errors = tx
+        .validate_schemas(&[Order::TABLE, User::TABLE, OrderWithNote::TABLE])
+        .unwrap();
+    let tables: Vec<_> = errors.iter().map(|err| err.type_name).collect();
+    assert_eq!(tables, ["User", "OrderWithNote"]);
+    assert_eq!(errors[0].mismatches, vec![Mismatch::MissingTable]);
+}
+
+#[test]
+fn validate_schema() {
+    check_validate_schema(Connection::open_in_memory().unwrap());
+    check_validate_schema(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+#[derive(Object)]
+#[table_name("pet")]
+struct Pet {
+    name: String,
+    age: i64,
+}
+
+#[derive(Object)]
+#[table_name("pet")]
+struct NamedPet {
+    #[column_name("nickname")]
+    name: String,
+    age: i64,
+}
+
+#[derive(Object)]
+#[table_name("animal")]
+struct Animal {
+    #[column_name("nickname")]
+    name: String,
+    age: i64,
+}
+
+#[derive(Object)]
+#[table_name("animal")]
+struct AgelessAnimal {
+    #[column_name("nickname")]
+    name: String,
+}
+
+fn check_rename_and_drop_columns(mut conn: Connection) {
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(Pet {
+            name: "Rex".into(),
+            age: 3,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.rename_column::<NamedPet>("name", "nickname").unwrap();
+    assert_eq!(tx.get::<NamedPet>(id.raw()).unwrap().borrow().name, "Rex");
+    assert!(matches!(
+        tx.get::<Pet>(id),
+        Err(orm::Error::SchemaFingerprintMismatch(_))
+    ));
+
+    tx.rename_table::<Animal>("pet").unwrap();
+    assert_eq!(tx.get::<Animal>(id.raw()).unwrap().borrow().age, 3);
+
+    tx.drop_column::<AgelessAnimal>("age").unwrap();
+    assert_eq!(
+        tx.get::<AgelessAnimal>(id.raw()).unwrap().borrow().name,
+        "Rex"
+    );
+    tx.commit().unwrap();
+
+    // The type has to describe the table after the change.
+    let tx = conn.new_transaction().unwrap();
+    assert!(matches!(
+        tx.rename_column::<Animal>("nickname", "name"),
+        Err(orm::Error::SchemaMismatch(_))
+    ));
+}
+
+#[test]
+fn rename_and_drop_columns() {
+    check_rename_and_drop_columns(Connection::open_in_memory().unwrap());
+    check_rename_and_drop_columns(Connection::from_storage(orm::memory::MemoryStorage::new()));
+
+    // An index on the column makes SQLite rebuild the table.
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for age in 1..=3 {
+        tx.create(Animal {
+            name: format!("pet {}", age),
+            age,
+        })
+        .unwrap();
+    }
+    tx.delete::<Animal>(ObjectId::from(3)).unwrap();
+    tx.execute("CREATE INDEX animal_age ON animal (age)", &[])
+        .unwrap();
+    tx.execute("CREATE INDEX animal_name ON animal (nickname)", &[])
+        .unwrap();
+    tx.drop_column::<AgelessAnimal>("age").unwrap();
+
+    assert_eq!(tx.select_all::<AgelessAnimal>().unwrap().len(), 2);
+    let id = tx
+        .create(AgelessAnimal {
+            name: "pet 4".into(),
+        })
+        .unwrap()
+        .id();
+    assert_eq!(id.raw(), ObjectId::from(4));
+    assert!(tx.execute("DROP INDEX animal_age", &[]).is_err());
+    tx.execute("DROP INDEX animal_name", &[]).unwrap();
+    tx.commit().unwrap();
+}
+
+fn check_introspect(mut conn: Connection) {
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.introspect().unwrap().is_empty());
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.create(User {
+        name: "Ann".into(),
+        picture: vec![],
+        visits: 0,
+        balance: 0.5,
+        is_admin: false,
+    })
+    .unwrap();
+
+    let tables = tx.introspect().unwrap();
+    let names: Vec<_> = tables.iter().map(|table| table.name.as_str()).collect();
+    assert_eq!(names, ["User", "order_table", "orm_fingerprints"]);
+
+    let columns: Vec<_> = tables[1]
+        .columns
+        .iter()
+        .map(|c| (c.name.as_str(), c.primary_key))
+        .collect();
+    assert_eq!(columns, [("id", true), ("IsTall", false)]);
+    let balance = tables[0].column("balance").unwrap();
+    assert_eq!(balance.typ, Some(DataType::Float64));
+    assert!(balance.not_null);
+}
+
+#[test]
+fn introspect() {
+    check_introspect(Connection::open_in_memory().unwrap());
+    check_introspect(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+#[test]
+fn codegen() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    db.transaction(|tx| {
+        tx.execute(
+            "CREATE TABLE legacy_items (id INTEGER PRIMARY KEY, \
+            type VARCHAR(20), PriceCents INT, ratio NUMERIC, payload, active BOOLEAN)",
+            &[],
+        )?;
+        tx.execute("CREATE TABLE tags (name TEXT PRIMARY KEY)", &[])
+    })
+    .unwrap();
+    drop(db);
+
+    let code = orm::codegen::generate_from_sqlite(&path).unwrap();
+    assert_eq!(
+        code,
+        "// Generated by orm::codegen.
+
+#[derive(::orm::Object, Clone, Debug)]
+#[table_name(\"legacy_items\")]
+pub struct LegacyItems {
+    #[column_name(\"type\")]
+    pub type_: String,
+    #[column_name(\"PriceCents\")]
+    pub price_cents: i64,
+    pub ratio: f64,
+    pub payload: Vec<u8>,
+    pub active: bool,
+}
+
+#[derive(::orm::Object, Clone, Debug)]
+#[table_name(\"order_table\")]
+pub struct OrderTable {
+    #[column_name(\"IsTall\")]
+    pub is_tall: i64,
+}
+
+// Skipped table tags: it has no integer id primary key.
+"
+    );
+}
+
+#[test]
+fn schema_dump() {
+    #[derive(Object)]
+    #[audited]
+    struct Invoice {
+        total: f64,
+    }
+
+    let script = orm::schema_dump(&[Order::TABLE, Invoice::TABLE]);
+    assert_eq!(
+        script.lines().collect::<Vec<_>>(),
+        [
+            "CREATE TABLE order_table (id INTEGER PRIMARY KEY AUTOINCREMENT, \
+            IsTall INTEGER NOT NULL);",
+            "CREATE TABLE Invoice (id INTEGER PRIMARY KEY AUTOINCREMENT, \
+            total REAL NOT NULL);",
+            "CREATE TABLE Invoice_history (id INTEGER PRIMARY KEY AUTOINCREMENT, \
+            audit_object_id INTEGER NOT NULL, audit_operation TEXT NOT NULL, \
+            audit_changed_at INTEGER NOT NULL, audit_actor TEXT NOT NULL, \
+            total REAL NOT NULL);",
+        ]
+    );
+
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute_batch(&script).unwrap();
+    let mut conn = Connection::from_storage(conn);
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Invoice { total: 1.5 }).unwrap();
+    tx.commit().unwrap();
+}
+
+#[cfg(feature = "fixtures")]
+#[test]
+fn fixtures() {
+    orm::dynamic::register::<User>();
+    orm::dynamic::register::<Order>();
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    std::fs::write(
+        &path,
+        r#"{
+            "order_table": [{"IsTall": true}, {}],
+            "User": {
+                "alice": {"name": "Alice", "picture": [1, 2], "visits": 3, "balance": 1.5},
+                "bob": {"name": "Bob", "is_admin": true}
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let ids = db.load_fixtures(&path).unwrap();
+    assert_eq!(ids.len(), 2);
+    db.transaction(|tx| {
+        let alice = tx.get::<User>(ids["alice"])?;
+        assert_eq!(alice.borrow().picture, [1, 2]);
+        assert_eq!(alice.borrow().visits, 3);
+        assert!(tx.get::<User>(ids["bob"])?.borrow().is_admin);
+        assert_eq!(tx.count_where::<Order>(&column("IsTall").eq(false))?, 1);
+        Ok(())
+    })
+    .unwrap();
+
+    let tx = db.connection().new_transaction().unwrap();
+    for invalid in [
+        r#"{"User": [{"name": 7}]}"#,
+        r#"{"User": [{"nickname": "Al"}]}"#,
+        r#"{"unknown": []}"#,
+        r#"{"User": {"a": {}}, "order_table": {"a": {}}}"#,
+    ] {
+        assert!(matches!(
+            tx.load_fixtures_str(invalid),
+            Err(orm::Error::Storage(_))
+        ));
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn json_export_import() {
+    let alice = User {
+        name: "Alice \"A\"".to_string(),
+        picture: vec![1, 2],
+        visits: 3,
+        balance: 1.5,
+        is_admin: true,
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(alice.clone()).unwrap().id();
+    tx.create(User {
+        name: "Bob".to_string(),
+        ..alice.clone()
+    })
+    .unwrap();
+
+    let mut json = Vec::new();
+    assert_eq!(tx.export_json::<User>(&mut json).unwrap(), 2);
+    tx.rollback().unwrap();
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.import_json::<User>(&json[..]).unwrap(), 2);
+    assert_eq!(*tx.get::<User>(id).unwrap().borrow(), alice);
+    let imported = br#"[{"name": "Carol", "picture": "xy"}]"#;
+    assert_eq!(tx.import_json::<User>(&imported[..]).unwrap(), 1);
+    assert_eq!(
+        tx.count_where::<User>(&column("name").eq("Carol")).unwrap(),
+        1
+    );
+
+    for invalid in [
+        &br#"[{"nickname": "Al"}]"#[..],
+        br#"[{"id": "one"}]"#,
+        b"{}",
+    ] {
+        let res = tx.import_json::<User>(invalid);
+        assert!(
+            matches!(res, Err(orm::Error::Storage(_))),
+            "{}",
+            fmt_res(&res)
+        );
+    }
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn csv_export_import() {
+    use orm::csv::{CsvError, CsvOptions};
+
+    let alice = User {
+        name: "Alice, \"A\"".to_string(),
+        picture: vec![1, 255],
+        visits: 3,
+        balance: 1.5,
+        is_admin: true,
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(alice.clone()).unwrap().id();
+    let mut csv = Vec::new();
+    assert_eq!(tx.export_csv::<User>(&mut csv).unwrap(), 1);
+    assert_eq!(
+        String::from_utf8(csv.clone()).unwrap(),
+        format!(
+            "id,name,picture,visits,balance,is_admin\n{},\"Alice, \"\"A\"\"\",01ff,3,1.5,true\n",
+            id.into_i64()
+        )
+    );
+    tx.rollback().unwrap();
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.import_csv::<User>(&csv[..]).unwrap(), 1);
+    assert_eq!(*tx.get::<User>(id).unwrap().borrow(), alice);
+
+    let options = CsvOptions::new()
+        .headers(false)
+        .with_id(false)
+        .delimiter(b';');
+    let sheet = "Bob;;7;;1\nCarol;;;2.5;FALSE\n";
+    assert_eq!(
+        tx.import_csv_with::<User>(sheet.as_bytes(), &options)
+            .unwrap(),
+        2
+    );
+    assert_eq!(tx.count_where::<User>(&column("visits").eq(7)).unwrap(), 1);
+
+    let sheet = "name,visits,is_admin\nDan,many,1\nEve,1,0\nFay,2,maybe\n";
+    let err = tx.import_csv::<User>(sheet.as_bytes()).unwrap_err();
+    let orm::Error::Storage(err) = err else {
+        panic!("{}", err)
+    };
+    let lines: Vec<_> = err
+        .downcast_ref::<CsvError>()
+        .unwrap()
+        .lines
+        .iter()
+        .map(|(line, _)| *line)
+        .collect();
+    assert_eq!(lines, vec![2, 4]);
+    assert_eq!(
+        tx.count_where::<User>(&column("name").eq("Eve")).unwrap(),
+        0
+    );
+
+    let res = tx.import_csv::<User>(&b"nickname\nAl\n"[..]);
+    assert!(
+        matches!(res, Err(orm::Error::Storage(_))),
+        "{}",
+        fmt_res(&res)
+    );
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn csv_lazy_fields() {
+    #[derive(Object)]
+    struct Photo {
+        title: String,
+        #[lazy]
+        data: Vec<u8>,
+    }
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(Photo {
+            title: "Sea".into(),
+            data: vec![1, 255],
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let mut csv = Vec::new();
+    assert_eq!(tx.export_csv::<Photo>(&mut csv).unwrap(), 1);
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        format!("id,title,data\n{},Sea,01ff\n", id.into_i64())
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn dump_restore() {
+    orm::dynamic::register::<User>();
+    orm::dynamic::register::<Order>();
+
+    let bob = User {
+        name: "Bob".to_string(),
+        picture: vec![7],
+        visits: 2,
+        balance: 0.5,
+        is_admin: false,
+    };
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let (bob_id, order_id) = db
+        .transaction(|tx| {
+            tx.create(bob.clone())?;
+            let bob_id = tx.create(bob.clone())?.id();
+            tx.delete::<User>(ObjectId::from(1))?;
+            Ok((bob_id, tx.create(Order { is_tall: true })?.id()))
+        })
+        .unwrap();
+
+    let mut dump = Vec::new();
+    assert_eq!(db.dump(&mut dump).unwrap(), 2);
+    let dump = String::from_utf8(dump).unwrap();
+    assert_eq!(dump.lines().count(), 2);
+    assert!(
+        dump.contains(r#"{"table":"User","id":2,"values":{"name":"Bob","#),
+        "{}",
+        dump
+    );
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    assert_eq!(db.restore(dump.as_bytes()).unwrap(), 2);
+    db.transaction(|tx| {
+        assert_eq!(*tx.get::<User>(bob_id)?.borrow(), bob);
+        assert!(tx.get::<Order>(order_id)?.borrow().is_tall);
+        assert_eq!(tx.count_where::<User>(&column("visits").eq(2))?, 1);
+        Ok(())
+    })
+    .unwrap();
+
+    for invalid in [
+        r#"{"table":"nope","id":1,"values":{}}"#,
+        r#"{"table":"User","values":{}}"#,
+    ] {
+        let res = db.restore(invalid.as_bytes());
+        assert!(
+            matches!(res, Err(orm::Error::Storage(_))),
+            "{}",
+            fmt_res(&res)
+        );
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn json_lazy_fields() {
+    #[derive(Object)]
+    struct DumpedPhoto {
+        title: String,
+        #[lazy]
+        data: Vec<u8>,
+    }
+    orm::dynamic::register::<DumpedPhoto>();
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let id = db
+        .transaction(|tx| {
+            Ok(tx
+                .create(DumpedPhoto {
+                    title: "Sea".into(),
+                    data: vec![7; 4],
+                })?
+                .id())
+        })
+        .unwrap();
+    let mut dump = Vec::new();
+    assert_eq!(db.dump(&mut dump).unwrap(), 1);
+    let mut json = Vec::new();
+    db.transaction(|tx| tx.export_json::<DumpedPhoto>(&mut json))
+        .unwrap();
+    assert!(String::from_utf8_lossy(&json).contains("[7,7,7,7]"));
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    assert_eq!(db.restore(&dump[..]).unwrap(), 1);
+    let mut imported = orm::Database::open_in_memory().unwrap();
+    imported
+        .transaction(|tx| tx.import_json::<DumpedPhoto>(&json[..]))
+        .unwrap();
+    for db in [&mut db, &mut imported] {
+        db.transaction(|tx| {
+            let photo = tx.get::<DumpedPhoto>(id)?;
+            tx.load_field(&photo, "data")?;
+            assert_eq!(photo.borrow().data, vec![7; 4]);
+            Ok(())
+        })
+        .unwrap();
+    }
+}
+
+#[test]
+fn prelude() {
+    mod app {
+        use orm::prelude::*;
+
+        #[derive(Object, Clone)]
+        #[hooks]
+        pub struct Note {
+            pub text: String,
+        }
+
+        impl Hooks for Note {}
+
+        pub fn run() -> Result<ObjectId> {
+            let mut conn = Connection::open_in_memory()?;
+            let tx: Transaction = conn.new_transaction()?;
+            let note: Tx<Note> = tx.create(Note {
+                text: "hello".to_string(),
+            })?;
+            let id = note.id();
+            assert_eq!(tx.count_where::<Note>(&column("text").eq("hello"))?, 1);
+            tx.commit()?;
+            Ok(id.raw())
+        }
+    }
+
+    app::run().unwrap();
+}
+
+#[test]
+fn typed_ids() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id: Id<Order> = tx.create(Order { is_tall: true }).unwrap().id();
+    assert!(tx.get(id).unwrap().borrow().is_tall);
+    assert_eq!(
+        tx.try_get::<Order>(id.raw()).unwrap().map(|o| o.id()),
+        Some(id)
+    );
+
+    let raw: ObjectId = id.into();
+    assert_eq!(raw, id.raw());
+    assert_eq!(Id::<Order>::from(raw), id);
+    assert_eq!(id.into_i64(), raw.into_i64());
+    assert_eq!(format!("{} {:?}", id, id), format!("{} Id({})", raw, raw));
+
+    tx.delete(id).unwrap();
+    assert!(tx.try_get(id).unwrap().is_none());
+}
+
+#[test]
+fn object_id_conversions() {
+    let id: ObjectId = "42".parse().unwrap();
+    assert_eq!(id, ObjectId::from(42));
+    assert_eq!(i64::from(id), 42);
+    assert_eq!(id.to_string().parse::<ObjectId>().unwrap(), id);
+    assert!("4x".parse::<ObjectId>().is_err());
+    assert_eq!("7".parse::<Id<User>>().unwrap(), Id::from(7));
+    assert_eq!(i64::from(Id::<User>::from(7)), 7);
+
+    let mut ids = vec![ObjectId::from(3), ObjectId::from(1), ObjectId::from(2)];
+    ids.sort();
+    assert_eq!(ids, [1, 2, 3].map(ObjectId::from));
+    assert!(Id::<User>::from(1) < Id::from(2));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn object_id_serde() {
+    let id = ObjectId::from(42);
+    assert_eq!(serde_json::to_string(&id).unwrap(), "42");
+    assert_eq!(serde_json::from_str::<ObjectId>("42").unwrap(), id);
+    let typed: Id<User> = serde_json::from_str("[7]")
+        .map(|ids: Vec<_>| ids[0])
+        .unwrap();
+    assert_eq!(serde_json::to_string(&typed).unwrap(), "7");
+    assert!(serde_json::from_str::<ObjectId>("\"42\"").is_err());
+}
+
+#[test]
+fn schema_inspection() {
+    let schema = User::TABLE;
+    assert_eq!(
+        schema.column_names().collect::<Vec<_>>(),
+        ["name", "picture", "visits", "balance", "is_admin"]
+    );
+    assert_eq!(schema.column("visits").unwrap().typ, DataType::Int64);
+    assert!(schema.column("IsTall").is_none());
+    assert_eq!(Order::TABLE.column("IsTall").unwrap().attr_name, "is_tall");
+
+    assert_eq!(Order::TABLE.to_string(), "order_table (IsTall Bool)");
+    assert_eq!(schema, User::TABLE);
+    assert_ne!(schema, Order::TABLE);
+    assert!(format!("{:?}", Order::TABLE).contains("table_name: \"order_table\""));
+}
+
+#[test]
+fn tracked_objects() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let ids: Vec<_> = (0..3)
+        .map(|_| tx.create(Order { is_tall: false }).unwrap().id())
+        .collect();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.tracked::<Order>().is_empty());
+    tx.get(ids[2]).unwrap().borrow_mut().is_tall = true;
+    tx.get(ids[0]).unwrap();
+    tx.delete(ids[1]).unwrap();
+    tx.create(User {
+        name: "Ann".into(),
+        picture: vec![],
+        visits: 0,
+        balance: 0.,
+        is_admin: false,
+    })
+    .unwrap();
+
+    let tracked = tx.tracked::<Order>();
+    let states: Vec<_> = tracked.iter().map(|obj| (obj.id(), obj.state())).collect();
+    assert_eq!(
+        states,
+        [
+            (ids[0], ObjectState::Clean),
+            (ids[2], ObjectState::Modified)
+        ]
+    );
+    for obj in tracked
+        .iter()
+        .filter(|obj| obj.state() == ObjectState::Modified)
+    {
+        obj.borrow_mut().is_tall = false;
+    }
+    assert_eq!(tx.tracked::<User>().len(), 1);
+    tx.commit().unwrap();
+}
+
+#[test]
+fn round_trip_testing() {
+    use orm::testing::{round_trip, Generator, RoundTripError};
+
+    #[derive(Object)]
+    #[hooks]
+    struct Trimmed {
+        text: String,
+    }
+
+    impl orm::object::Hooks for Trimmed {
+        fn before_save(&mut self) -> Result<()> {
+            self.text = self.text.trim().to_string();
+            Ok(())
+        }
+    }
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    round_trip::<User>(&tx, 7, 200).unwrap();
+    round_trip::<Order>(&tx, 7, 20).unwrap();
+
+    let err = round_trip::<Trimmed>(&tx, 7, 200).unwrap_err();
+    let orm::Error::Storage(err) = err else {
+        panic!("expected a storage error, got {}", err);
+    };
+    let err = err.downcast_ref::<RoundTripError>().unwrap();
+    assert_eq!(
+        (err.table, err.column, err.stage),
+        ("Trimmed", "text", "storage")
+    );
+    assert_ne!(err.expected, err.actual);
+    tx.rollback().unwrap();
+
+    let mut first = Generator::new(1);
+    let mut second = Generator::new(1);
+    for _ in 0..10 {
+        assert_eq!(first.row(User::TABLE), second.row(User::TABLE));
+    }
+    assert!(first.object::<User>().is_ok());
+}
+
+#[test]
+fn fixtures_macro() {
+    #[derive(Object)]
+    struct Post {
+        author: i64,
+        title: String,
+    }
+
+    let user = |name: &str| User {
+        name: name.to_string(),
+        picture: vec![],
+        visits: 0,
+        balance: 0.,
+        is_admin: false,
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let data = orm::fixtures!(&tx, {
+        users: [user("Ann"), user("Bob")],
+        posts: [
+            Post { author: users[1].id().into_i64(), title: "Hi".into() },
+            Post { author: users[0].id().into_i64(), title: "Yo".into() },
+        ],
+        orders: [],
+    })
+    .unwrap();
+    assert_eq!(data.users[1].borrow().name, "Bob");
+    assert_eq!(data.posts[0].borrow().author, data.users[1].id().into_i64());
+    assert_eq!(
+        tx.count_where::<Post>(&column("title").eq("Yo")).unwrap(),
+        1
+    );
+    let orders: &Vec<Tx<Order>> = &data.orders;
+    assert!(orders.is_empty());
+}
+
+#[test]
+fn sync_from() {
+    use orm::sync::{SyncReport, SyncStrategy};
+
+    #[derive(Object, Clone)]
+    struct Note {
+        text: String,
+        version: i64,
+    }
+
+    let note = |text: &str, version| Note {
+        text: text.to_string(),
+        version,
+    };
+    let mut master = orm::Database::open_in_memory().unwrap();
+    let mut device = orm::Database::open_in_memory().unwrap();
+    master
+        .transaction(|tx| {
+            tx.create(note("a", 1))?;
+            tx.create(note("b", 1))?;
+            tx.create(note("c", 1))?;
+            Ok(())
+        })
+        .unwrap();
+
+    let strategy = SyncStrategy::Version("version");
+    let report = device
+        .sync_from(&mut master, &[Note::TABLE], strategy)
+        .unwrap();
+    assert_eq!(report.inserted, 3);
+
+    device
+        .transaction(|tx| {
+            tx.get::<Note>(ObjectId::from(1))?.borrow_mut().version = 2;
+            *tx.get::<Note>(ObjectId::from(3))?.borrow_mut() = note("c on device", 1);
+            tx.create(note("d", 1))?;
+            Ok(())
+        })
+        .unwrap();
+    master
+        .transaction(|tx| {
+            *tx.get::<Note>(ObjectId::from(2))?.borrow_mut() = note("b on master", 2);
+            Ok(())
+        })
+        .unwrap();
+
+    let report = device
+        .sync_from(&mut master, &[Note::TABLE], strategy)
+        .unwrap();
+    assert_eq!(
+        report,
+        SyncReport {
+            inserted: 0,
+            updated: 1,
+            skipped: 1,
+            conflicts: vec![("Note", ObjectId::from(3))],
+        }
+    );
+    let report = master
+        .sync_from(
+            &mut device,
+            &[Note::TABLE],
+            SyncStrategy::LastModified("version"),
+        )
+        .unwrap();
+    assert_eq!((report.inserted, report.updated, report.skipped), (1, 1, 2));
+    assert!(report.conflicts.is_empty());
+    master
+        .read(|tx| {
+            assert_eq!(
+                tx.get::<Note>(ObjectId::from(2))?.borrow().text,
+                "b on master"
+            );
+            assert_eq!(tx.get::<Note>(ObjectId::from(3))?.borrow().text, "c");
+            assert_eq!(tx.get::<Note>(ObjectId::from(4))?.borrow().text, "d");
+            Ok(())
+        })
+        .unwrap();
+
+    let err = device.sync_from(
+        &mut master,
+        &[Note::TABLE],
+        SyncStrategy::Version("missing"),
+    );
+    assert!(err.is_err());
+}
+
+#[test]
+fn sync_lazy_fields() {
+    use orm::sync::SyncStrategy;
+
+    #[derive(Object)]
+    struct Photo {
+        version: i64,
+        #[lazy]
+        data: Vec<u8>,
+    }
+
+    let mut master = orm::Database::open_in_memory().unwrap();
+    let mut device = orm::Database::open_in_memory().unwrap();
+    for db in [&mut master, &mut device] {
+        db.transaction(|tx| {
+            tx.create(Photo {
+                version: 1,
+                data: vec![1; 16],
+            })
+            .map(|_| ())
+        })
+        .unwrap();
+    }
+    master
+        .transaction(|tx| {
+            tx.create(Photo {
+                version: 1,
+                data: vec![2; 16],
+            })?;
+            Ok(())
+        })
+        .unwrap();
+    device
+        .transaction(|tx| {
+            let photo = tx.get::<Photo>(ObjectId::from(1))?;
+            tx.load_field(&photo, "data")?;
+            photo.borrow_mut().data = vec![3; 16];
+            Ok(())
+        })
+        .unwrap();
+
+    let strategy = SyncStrategy::Version("version");
+    let report = device
+        .sync_from(&mut master, &[Photo::TABLE], strategy)
+        .unwrap();
+    assert_eq!(report.inserted, 1);
+    // The lazy columns differ, so the rows of the same version conflict.
+    assert_eq!(report.conflicts, vec![("Photo", ObjectId::from(1))]);
+    device
+        .transaction(|tx| {
+            for (id, data) in [(1, vec![3; 16]), (2, vec![2; 16])] {
+                let photo = tx.get::<Photo>(ObjectId::from(id))?;
+                tx.load_field(&photo, "data")?;
+                assert_eq!(photo.borrow().data, data);
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn outbox() {
+    use orm::ChangeOp;
+    use std::time::{Duration, SystemTime};
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+    assert_eq!(db.dispatch_outbox(10, |_| Ok(())).unwrap(), 0);
+
+    db.set_outbox(true);
+    let id = db
+        .transaction(|tx| {
+            let order = tx.create(Order { is_tall: false })?;
+            let sp = tx.savepoint()?;
+            sp.create(Order { is_tall: true })?;
+            sp.rollback()?;
+            Ok(order.id())
+        })
+        .unwrap();
+    db.transaction(|tx| {
+        tx.get(id)?.borrow_mut().is_tall = true;
+        tx.delete::<Order>(ObjectId::from(1))
+    })
+    .unwrap();
+    let result: orm::Result<()> = db.transaction(|tx| {
+        tx.create(Order { is_tall: true })?;
+        Err(orm::Error::ReadOnly)
+    });
+    assert!(result.is_err());
+
+    let pending = db.transaction(|tx| tx.pending_outbox(10)).unwrap();
+    let summary: Vec<_> = pending
+        .iter()
+        .map(|e| (e.table.as_str(), e.id, e.op))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("order_table", id.raw(), ChangeOp::Insert),
+            // Deletes are written right away, updates as the transaction
+            // commits.
+            ("order_table", ObjectId::from(1), ChangeOp::Delete),
+            ("order_table", id.raw(), ChangeOp::Update),
+        ]
+    );
+    let oldest = db.transaction(|tx| tx.pending_outbox(2)).unwrap();
+    assert_eq!(oldest, pending[..2]);
+
+    let mut published = Vec::new();
+    let err = db.dispatch_outbox(10, |event| {
+        if published.len() == 2 {
+            return Err(orm::Error::Storage("queue is down".into()));
+        }
+        published.push(event.seq);
+        Ok(())
+    });
+    assert!(err.is_err());
+    assert_eq!(published.len(), 2);
+    let mut rest = Vec::new();
+    let dispatched = db
+        .dispatch_outbox(10, |event| {
+            rest.push(event.op);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!((dispatched, rest), (1, vec![ChangeOp::Update]));
+
+    let later = SystemTime::now() + Duration::from_secs(1);
+    assert_eq!(db.transaction(|tx| tx.purge_outbox(later)).unwrap(), 3);
+    db.transaction(|tx| {
+        tx.set_outbox(false);
+        tx.create(Order { is_tall: false })?;
+        Ok(())
+    })
+    .unwrap();
+    assert!(db
+        .transaction(|tx| tx.pending_outbox(10))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn database_diff() {
+    use orm::diff::{RowChange, SchemaDifference};
+
+    let mut a = orm::Database::open_in_memory().unwrap();
+    let mut b = orm::Database::open_in_memory().unwrap();
+    for db in [&mut a, &mut b] {
+        db.transaction(|tx| {
+            for _ in 0..3 {
+                tx.create(Order { is_tall: false })?;
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+    let report = orm::diff(&mut a, &mut b, &[Order::TABLE, User::TABLE]).unwrap();
+    assert!(report.is_empty());
+
+    b.transaction(|tx| {
+        tx.get::<Order>(ObjectId::from(2))?.borrow_mut().is_tall = true;
+        tx.delete::<Order>(ObjectId::from(3))?;
+        tx.create(Order { is_tall: false })?;
+        tx.create(User {
+            name: "Ann".into(),
+            picture: vec![],
+            visits: 0,
+            balance: 0.,
+            is_admin: false,
+        })?;
+        tx.execute("ALTER TABLE order_table ADD COLUMN note TEXT", &[])?;
+        Ok(())
+    })
+    .unwrap();
+
+    let report = orm::diff(&mut a, &mut b, &[Order::TABLE, User::TABLE]).unwrap();
+    assert!(!report.is_empty());
+    let orders = &report.tables[0];
+    assert_eq!(orders.table, "order_table");
+    assert_eq!(
+        orders.schema,
+        [SchemaDifference::ColumnAdded("note".to_string())]
+    );
+    assert_eq!(orders.added, [ObjectId::from(4)]);
+    assert_eq!(orders.removed, [ObjectId::from(3)]);
+    assert_eq!(
+        orders.changed,
+        [RowChange {
+            id: ObjectId::from(2),
+            columns: vec![("IsTall", false.into(), true.into())],
+        }]
+    );
+    assert_eq!(report.tables[1].schema, [SchemaDifference::TableAdded]);
+    assert!(report.tables[1].added.is_empty());
+}
+
+#[test]
+fn database_diff_lazy_fields() {
+    use orm::diff::RowChange;
+
+    #[derive(Object)]
+    struct Photo {
+        #[lazy]
+        data: Vec<u8>,
+    }
+
+    let mut a = orm::Database::open_in_memory().unwrap();
+    let mut b = orm::Database::open_in_memory().unwrap();
+    for (db, data) in [(&mut a, vec![1]), (&mut b, vec![2])] {
+        db.transaction(|tx| tx.create(Photo { data: data.clone() }).map(drop))
+            .unwrap();
+    }
+    let report = orm::diff(&mut a, &mut b, &[Photo::TABLE]).unwrap();
+    assert_eq!(
+        report.tables[0].changed,
+        [RowChange {
+            id: ObjectId::from(1),
+            columns: vec![("data", vec![1u8].into(), vec![2u8].into())],
+        }]
+    );
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_fields() {
+    #[derive(Object)]
+    struct Patient {
+        name: String,
+        #[encrypted]
+        ssn: String,
+        #[encrypted]
+        weight: f64,
+    }
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.set_key_provider([7u8; 32]);
+    let id = db
+        .transaction(|tx| {
+            let patient = tx.create(Patient {
+                name: "Ann".into(),
+                ssn: "123-45-6789".into(),
+                weight: 61.5,
+            })?;
+            Ok(patient.id().raw())
+        })
+        .unwrap();
+    db.transaction(|tx| {
+        let patient = tx.get::<Patient>(id)?;
+        assert_eq!(patient.borrow().ssn, "123-45-6789");
+        assert_eq!(patient.borrow().weight, 61.5);
+        patient.borrow_mut().ssn = "987-65-4321".into();
+        Ok(())
+    })
+    .unwrap();
+    drop(db);
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    let (name, ssn): (String, Vec<u8>) = sqlite_conn
+        .query_row("SELECT name, ssn FROM Patient", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .unwrap();
+    assert_eq!(name, "Ann");
+    assert!(!String::from_utf8_lossy(&ssn).contains("987-65-4321"));
+    sqlite_conn.close().unwrap();
+
+    let mut db = orm::Database::open(&path).unwrap();
+    assert!(db
+        .transaction(|tx| tx.get::<Patient>(id).map(drop))
+        .is_err());
+    db.set_key_provider([8u8; 32]);
+    assert!(db
+        .transaction(|tx| tx.get::<Patient>(id).map(drop))
+        .is_err());
+    db.set_key_provider([7u8; 32]);
+    let ssn = db
+        .transaction(|tx| Ok(tx.get::<Patient>(id)?.borrow().ssn.clone()))
+        .unwrap();
+    assert_eq!(ssn, "987-65-4321");
+
+    // Values copied to another row, or emptied, do not open.
+    let other = db
+        .transaction(|tx| {
+            let patient = tx.create(Patient {
+                name: "Bob".into(),
+                ssn: "555-55-5555".into(),
+                weight: 80.,
+            })?;
+            tx.update_where::<Patient>(&column("name").eq("Bob"), &[("ssn", "000".into())])?;
+            assert_eq!(patient.borrow().ssn, "000");
+            Ok(patient.id().raw())
+        })
+        .unwrap();
+    db.transaction(|tx| {
+        tx.execute(
+            &format!(
+                "UPDATE Patient SET ssn = (SELECT ssn FROM Patient WHERE id = {}) WHERE id = {}",
+                id.into_i64(),
+                other.into_i64()
+            ),
+            &[],
+        )?;
+        tx.execute(
+            &format!(
+                "UPDATE Patient SET weight = X'' WHERE id = {}",
+                id.into_i64()
+            ),
+            &[],
+        )?;
+        Ok(())
+    })
+    .unwrap();
+    assert!(db
+        .transaction(|tx| tx.get::<Patient>(other).map(drop))
+        .is_err());
+    assert!(db
+        .transaction(|tx| tx.get::<Patient>(id).map(drop))
+        .is_err());
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_fields() {
+    #[derive(Object)]
+    struct Document {
+        title: String,
+        #[compress]
+        body: String,
+        #[compress]
+        attachment: Vec<u8>,
+    }
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let body = "All work and no play makes Jack a dull boy. ".repeat(1000);
+    let mut db = orm::Database::open(&path).unwrap();
+    let (large, small) = db
+        .transaction(|tx| {
+            let large = tx.create(Document {
+                title: "large".into(),
+                body: body.clone(),
+                attachment: vec![0; 4096],
+            })?;
+            let small = tx.create(Document {
+                title: "small".into(),
+                body: "short".into(),
+                attachment: vec![1, 2, 3],
+            })?;
+            Ok((large.id().raw(), small.id().raw()))
+        })
+        .unwrap();
+    drop(db);
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    let stored = |id: ObjectId| -> (Vec<u8>, Vec<u8>) {
+        sqlite_conn
+            .query_row(
+                "SELECT body, attachment FROM Document WHERE id = ?",
+                [id.into_i64()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+    };
+    let (stored_body, stored_attachment) = stored(large);
+    assert!(stored_body.starts_with(b"\0ORMZ"));
+    assert!(stored_body.len() < body.len() / 10);
+    assert!(stored_attachment.len() < 100);
+    assert_eq!(stored(small), (b"short".to_vec(), vec![1, 2, 3]));
+    // Written before the column was compressed.
+    sqlite_conn
+        .execute(
+            "INSERT INTO Document (title, body, attachment) VALUES ('old', X'6f6c64', X'ff')",
+            [],
+        )
+        .unwrap();
+    let old = ObjectId::from(sqlite_conn.last_insert_rowid());
+    sqlite_conn.close().unwrap();
+
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| {
+        let large = tx.get::<Document>(large)?;
+        assert_eq!(large.borrow().body, body);
+        assert_eq!(large.borrow().attachment, vec![0; 4096]);
+        assert_eq!(tx.get::<Document>(small)?.borrow().body, "short");
+        let old = tx.get::<Document>(old)?;
+        assert_eq!(old.borrow().body, "old");
+        assert_eq!(old.borrow().attachment, vec![0xff]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn computed_fields() {
+    #[derive(Object)]
+    struct Invoice {
+        customer: String,
+        quantity: i64,
+        unit_price: i64,
+        #[computed("total")]
+        total: i64,
+        #[computed("search_text")]
+        search: String,
+    }
+
+    impl Invoice {
+        fn total(&self) -> i64 {
+            self.quantity * self.unit_price
+        }
+
+        fn search_text(&self) -> String {
+            format!("{} {}", self.customer.to_lowercase(), self.total)
+        }
+    }
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let id = db
+        .transaction(|tx| {
+            let invoice = tx.create(Invoice {
+                customer: "ACME".into(),
+                quantity: 3,
+                unit_price: 20,
+                total: 0,
+                search: String::new(),
+            })?;
+            assert_eq!(invoice.borrow().total, 60);
+            assert_eq!(invoice.borrow().search, "acme 60");
+            Ok(invoice.id().raw())
+        })
+        .unwrap();
+    db.transaction(|tx| {
+        tx.get::<Invoice>(id)?.borrow_mut().quantity = 5;
+        Ok(())
+    })
+    .unwrap();
+    db.transaction(|tx| {
+        let invoice = tx.get::<Invoice>(id)?;
+        assert_eq!(invoice.borrow().total, 100);
+        assert_eq!(invoice.borrow().search, "acme 100");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(feature = "spatial")]
+#[test]
+fn spatial_index() {
+    use orm::spatial::{BBox, Point};
+
+    #[derive(Object)]
+    struct Place {
+        name: String,
+        location: Point,
+    }
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| {
+        for (name, x, y) in [("a", 1., 1.), ("b", 5., 5.), ("c", 9., 2.), ("d", 2., 4.)] {
+            tx.create(Place {
+                name: name.into(),
+                location: Point::new(x, y),
+            })?;
+        }
+        let names = |bbox: BBox| -> Result<Vec<String>> {
+            let mut names = tx
+                .select::<Place>(&column("location").within_bbox(bbox))?
+                .iter()
+                .map(|place| place.borrow().name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            Ok(names)
+        };
+        let bbox = BBox::new(0., 0., 5., 5.);
+        assert_eq!(names(bbox)?, ["a", "b", "d"]);
+        assert!(bbox.contains(Point::new(5., 5.)));
+
+        tx.create_spatial_index::<Place>("location")?;
+        tx.create_spatial_index::<Place>("location")?;
+        assert_eq!(names(bbox)?, ["a", "b", "d"]);
+        assert_eq!(names(BBox::new(8., 1., 10., 3.))?, ["c"]);
+
+        let c = tx.select::<Place>(&column("name").eq("c"))?.pop().unwrap();
+        c.borrow_mut().location = Point::new(3., 3.);
+        let d = tx.select::<Place>(&column("name").eq("d"))?.pop().unwrap();
+        d.delete();
+        tx.flush()?;
+        assert_eq!(names(bbox)?, ["a", "b", "c"]);
+        assert!(names(BBox::new(8., 1., 10., 3.))?.is_empty());
+        Ok(())
+    })
+    .unwrap();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    let indexed: i64 = sqlite_conn
+        .query_row("SELECT COUNT(*) FROM Place_location_rtree", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(indexed, 3);
+}
+
+#[test]
+fn repair_schema() {
+    use orm::repair::RepairPolicy;
+
+    #[derive(Object)]
+    struct Setting {
+        name: String,
+        value: i64,
+        enabled: bool,
+    }
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    sqlite_conn
+        .execute_batch(
+            "CREATE TABLE Setting (id INTEGER PRIMARY KEY, name TEXT NOT NULL, value TEXT NOT NULL, legacy INTEGER);
+             INSERT INTO Setting VALUES (3, 'width', '640', 1);
+             INSERT INTO Setting VALUES (5, 'height', '480', 0);",
+        )
+        .unwrap();
+    sqlite_conn.close().unwrap();
+
+    let mut db = orm::Database::open(&path).unwrap();
+    let report = db
+        .repair_schema::<Setting>(RepairPolicy::ReportOnly)
+        .unwrap();
+    assert_eq!(
+        report.mismatches,
+        [
+            orm::Mismatch::Type {
+                column: "value".into(),
+                expected: DataType::Int64,
+                found: DataType::String,
+            },
+            orm::Mismatch::MissingColumn {
+                column: "enabled".into(),
+            },
+            orm::Mismatch::ExtraColumn {
+                column: "legacy".into(),
+            },
+        ]
+    );
+    assert!(report.added.is_empty() && !report.recreated);
+    assert!(db.validate_schema::<Setting>().is_err());
+
+    let report = db
+        .repair_schema::<Setting>(RepairPolicy::AddColumns)
+        .unwrap();
+    assert_eq!(report.added, ["enabled"]);
+    assert!(!report.recreated);
+    let report = db
+        .repair_schema::<Setting>(RepairPolicy::AddColumns)
+        .unwrap();
+    assert_eq!(report.mismatches.len(), 2);
+    assert!(report.added.is_empty());
+
+    let report = db.repair_schema::<Setting>(RepairPolicy::Recreate).unwrap();
+    assert!(report.recreated);
+    db.validate_schema::<Setting>().unwrap();
+    db.transaction(|tx| {
+        let width = tx.get::<Setting>(ObjectId::from(3))?;
+        assert_eq!(width.borrow().name, "width");
+        assert_eq!(width.borrow().value, 640);
+        assert!(!width.borrow().enabled);
+        assert_eq!(tx.get::<Setting>(ObjectId::from(5))?.borrow().value, 480);
+        tx.create(Setting {
+            name: "depth".into(),
+            value: 24,
+            enabled: true,
+        })?;
+        Ok(())
+    })
+    .unwrap();
+    let report = db.repair_schema::<Setting>(RepairPolicy::Recreate).unwrap();
+    assert!(report.mismatches.is_empty() && !report.recreated);
+
+    drop(db);
+
+    // Nothing changes if a value does not convert.
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    sqlite_conn
+        .execute_batch(
+            "DROP TABLE Setting;
+             CREATE TABLE Setting (id INTEGER PRIMARY KEY, name TEXT, value TEXT, enabled INTEGER);
+             INSERT INTO Setting VALUES (1, 'width', 'wide', 0);",
+        )
+        .unwrap();
+    sqlite_conn.close().unwrap();
+    let mut db = orm::Database::open(&path).unwrap();
+    let err = db
+        .repair_schema::<Setting>(RepairPolicy::Recreate)
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("Setting.value of row 1"),
+        "{}",
+        err
+    );
+    let report = db
+        .repair_schema::<Setting>(RepairPolicy::ReportOnly)
+        .unwrap();
+    assert_eq!(report.mismatches.len(), 1);
+}
+
+#[test]
+fn scoped_transaction() {
+    use orm::scope::Scope;
+
+    #[derive(Object)]
+    struct Invoice {
+        tenant_id: i64,
+        number: i64,
+        deleted: bool,
+    }
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    let ids = db
+        .transaction(|tx| {
+            let mut ids = Vec::new();
+            for (tenant_id, number, deleted) in [(1, 10, false), (1, 11, true), (2, 20, false)] {
+                let invoice = tx.create(Invoice {
+                    tenant_id,
+                    number,
+                    deleted,
+                })?;
+                ids.push(invoice.id());
+            }
+            Ok(ids)
+        })
+        .unwrap();
+
+    let scope = Scope::new()
+        .tables_with("tenant_id", column("tenant_id").eq(1))
+        .table::<Invoice>(column("deleted").eq(false));
+    db.scoped_transaction(&scope, |tx| {
+        let numbers: Vec<_> = tx
+            .select::<Invoice>(&column("number").gt(0))?
+            .iter()
+            .map(|invoice| invoice.borrow().number)
+            .collect();
+        assert_eq!(numbers, [10]);
+        assert_eq!(tx.count_where::<Invoice>(&column("tenant_id").eq(2))?, 0);
+        assert!(matches!(tx.get(ids[1]), Err(orm::Error::NotFound(_))));
+        assert!(matches!(tx.get(ids[2]), Err(orm::Error::NotFound(_))));
+        assert_eq!(
+            tx.update_where::<Invoice>(&column("number").gt(0), &[("number", 99.into())])?,
+            1
+        );
+        assert_eq!(tx.delete_where::<Invoice>(&column("number").eq(20))?, 0);
+        Ok(())
+    })
+    .unwrap();
+
+    // Rows written outside the scope fail.
+    let err = db
+        .scoped_transaction(&scope, |tx| {
+            tx.create(Invoice {
+                tenant_id: 2,
+                number: 21,
+                deleted: false,
+            })?;
+            Ok(())
+        })
+        .unwrap_err();
+    assert!(err.to_string().contains("outside the scope"), "{}", err);
+    let err = db
+        .scoped_transaction(&scope, |tx| {
+            tx.get(ids[0])?.borrow_mut().tenant_id = 2;
+            tx.flush()
+        })
+        .unwrap_err();
+    assert!(err.to_string().contains("outside the scope"), "{}", err);
+
+    db.transaction(|tx| {
+        let numbers: Vec<_> = tx
+            .select::<Invoice>(&column("number").gt(0))?
+            .iter()
+            .map(|invoice| (invoice.borrow().tenant_id, invoice.borrow().number))
+            .collect();
+        assert_eq!(numbers, [(1, 99), (1, 11), (2, 20)]);
+        Ok(())
+    })
+    .unwrap();
+
+    // Nor are they left behind when the error is handled.
+    db.scoped_transaction(&scope, |tx| {
+        let invoice = Invoice {
+            tenant_id: 2,
+            number: 22,
+            deleted: false,
+        };
+        assert!(tx.create(invoice).is_err());
+        assert!(tx
+            .update_where::<Invoice>(&column("number").eq(99), &[("tenant_id", 2.into())])
+            .is_err());
+        Ok(())
+    })
+    .unwrap();
+    db.transaction(|tx| {
+        assert_eq!(tx.count_where::<Invoice>(&column("number").eq(22))?, 0);
+        assert_eq!(tx.get::<Invoice>(ids[0])?.borrow().tenant_id, 1);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn scoped_history() {
+    use orm::scope::Scope;
+
+    #[derive(Object)]
+    #[audited]
+    struct Ledger {
+        tenant_id: i64,
+        amount: i64,
+    }
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let ids = db
+        .transaction(|tx| {
+            let mine = tx.create(Ledger {
+                tenant_id: 1,
+                amount: 5,
+            })?;
+            let theirs = tx.create(Ledger {
+                tenant_id: 2,
+                amount: 7,
+            })?;
+            Ok([mine.id(), theirs.id()])
+        })
+        .unwrap();
+
+    let scope = Scope::new().table::<Ledger>(column("tenant_id").eq(1));
+    db.scoped_transaction(&scope, |tx| {
+        assert_eq!(tx.history::<Ledger>(ids[0])?.len(), 1);
+        assert!(tx.history::<Ledger>(ids[1])?.is_empty());
+        let now = std::time::SystemTime::now();
+        assert!(matches!(
+            tx.get_as_of::<Ledger>(ids[1], now),
+            Err(orm::Error::NotFound(_))
+        ));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(feature = "archive")]
+#[test]
+fn archive_snapshot_restore() {
+    orm::dynamic::register::<User>();
+    orm::dynamic::register::<Order>();
+
+    let users: Vec<_> = (0..100)
+        .map(|i| User {
+            name: format!("user {}", i),
+            picture: vec![i as u8; 16],
+            visits: i,
+            balance: i as f64 / 4.,
+            is_admin: i % 10 == 0,
+        })
+        .collect();
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let ids = db
+        .transaction(|tx| {
+            tx.create(Order { is_tall: true })?;
+            users
+                .iter()
+                .map(|user| Ok(tx.create(user.clone())?.id()))
+                .collect::<Result<Vec<_>>>()
+        })
+        .unwrap();
+
+    let archive = NamedTempFile::new().unwrap().into_temp_path();
+    assert_eq!(db.snapshot_to_archive(&archive).unwrap(), 101);
+    let bytes = std::fs::read(&archive).unwrap();
+    assert!(bytes.starts_with(b"ORMARCHIVE1\0"));
+    assert!(bytes.len() < 100 * 16);
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    assert_eq!(db.restore_from_archive(&archive).unwrap(), 101);
+    db.transaction(|tx| {
+        for (id, user) in ids.iter().zip(&users) {
+            assert_eq!(*tx.get::<User>(*id)?.borrow(), *user);
+        }
+        assert_eq!(tx.count_where::<Order>(&column("IsTall").eq(true))?, 1);
+        Ok(())
+    })
+    .unwrap();
+
+    std::fs::write(&archive, &bytes[..bytes.len() / 2]).unwrap();
+    let mut db = orm::Database::open_in_memory().unwrap();
+    assert!(db.restore_from_archive(&archive).is_err());
+    std::fs::write(&archive, b"not an archive").unwrap();
+    let err = db.restore_from_archive(&archive).unwrap_err();
+    assert!(err.to_string().contains("invalid archive"), "{}", err);
+}
+
+#[cfg(feature = "archive")]
+#[test]
+fn archive_lazy_fields() {
+    #[derive(Object)]
+    struct ArchivedPhoto {
+        title: String,
+        #[lazy]
+        data: Vec<u8>,
+    }
+    orm::dynamic::register::<ArchivedPhoto>();
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let id = db
+        .transaction(|tx| {
+            Ok(tx
+                .create(ArchivedPhoto {
+                    title: "Sea".into(),
+                    data: vec![7; 64],
+                })?
+                .id())
+        })
+        .unwrap();
+    let archive = NamedTempFile::new().unwrap().into_temp_path();
+    assert_eq!(db.snapshot_to_archive(&archive).unwrap(), 1);
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    assert_eq!(db.restore_from_archive(&archive).unwrap(), 1);
+    db.transaction(|tx| {
+        let photo = tx.get::<ArchivedPhoto>(id)?;
+        tx.load_field(&photo, "data")?;
+        assert_eq!(photo.borrow().title, "Sea");
+        assert_eq!(photo.borrow().data, vec![7; 64]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn unknown_predicate_column() {
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.transaction(|tx| {
+        tx.create(Order { is_tall: true })?;
+        tx.create(Order { is_tall: false })?;
+        let typo = column("IsTal").is_not_null();
+        for result in [
+            tx.delete_where::<Order>(&typo),
+            tx.count_where::<Order>(&typo),
+            tx.update_where::<Order>(&typo, &[("IsTall", true.into())]),
+            tx.select::<Order>(&typo).map(|orders| orders.len()),
+        ] {
+            let err = result.unwrap_err();
+            assert!(err.to_string().contains("has no column IsTal"), "{}", err);
+        }
+        assert_eq!(tx.count_where::<Order>(&column("id").gt(0))?, 2);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn where_statements_update_cache() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let kept = tx.create(user(5)).unwrap().id();
+    let gone = tx.create(user(1)).unwrap().id();
+
+    let cached = tx.get(kept).unwrap();
+    cached.borrow_mut().balance = 2.5;
+    tx.get(gone).unwrap();
+    assert_eq!(
+        tx.update_where::<User>(&column("visits").gt(2i64), &[("is_admin", true.into())])
+            .unwrap(),
+        1
+    );
+    assert!(cached.borrow().is_admin);
+    assert_eq!(cached.borrow().balance, 2.5);
+
+    assert_eq!(
+        tx.delete_where::<User>(&column("visits").lt(2i64)).unwrap(),
+        1
+    );
+    assert!(matches!(tx.get(gone), Err(orm::Error::NotFound(_))));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let kept = tx.get(kept).unwrap();
+    assert!(kept.borrow().is_admin);
+    assert_eq!(kept.borrow().balance, 2.5);
+}
+
+#[test]
+fn flush_while_borrowed() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let user = tx
+        .create(User {
+            name: "Eve".into(),
+            picture: vec![],
+            visits: 1,
+            balance: 0.,
+            is_admin: false,
+        })
+        .unwrap();
+
+    let mut guard = user.borrow_mut();
+    guard.visits = 2;
+    assert!(matches!(tx.flush(), Err(orm::Error::Borrowed(_))));
+    drop(guard);
+    let guard = user.borrow();
+    assert!(matches!(tx.flush(), Err(orm::Error::Borrowed(_))));
+    drop(guard);
+    tx.flush().unwrap();
+    assert_eq!(
+        tx.count_where::<User>(&column("visits").eq(2i64)).unwrap(),
+        1
+    );
+}
+
+// For backends without constraints, which cannot tell the rows to ignore or
+// replace.
+fn check_conflict_strategies_unsupported(tx: &orm::Transaction) {
+    let order = tx
+        .create_with(Order { is_tall: true }, orm::OnConflict::Abort)
+        .unwrap();
+    assert!(order.is_some());
+    let orders = tx.select_all::<Order>().unwrap().len();
+    for on_conflict in [orm::OnConflict::Ignore, orm::OnConflict::Replace] {
+        match tx.create_with(Order { is_tall: true }, on_conflict) {
+            Err(err) => assert!(err.to_string().contains("does not support"), "{}", err),
+            Ok(_) => panic!("{:?} is not supported", on_conflict),
+        }
+    }
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), orders);
+}
+
+#[test]
+fn memory_conflict_strategies() {
+    let mut conn = Connection::from_storage(orm::memory::MemoryStorage::new());
+    let tx = conn.new_transaction().unwrap();
+    check_conflict_strategies_unsupported(&tx);
+}
+
+#[test]
+fn table_options() {
+    #[derive(Object, Clone)]
+    #[strict]
+    #[without_rowid]
+    struct Currency {
+        code: String,
+    }
+
+    assert_eq!(
+        Currency::TABLE.create_text(),
+        "CREATE TABLE Currency (id INTEGER PRIMARY KEY, code TEXT NOT NULL) STRICT, WITHOUT ROWID"
+    );
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    let ids = db
+        .transaction(|tx| {
+            let mut ids = Vec::new();
+            for code in ["EUR", "USD", "JPY"] {
+                ids.push(tx.create(Currency { code: code.into() })?.id());
+            }
+            Ok(ids)
+        })
+        .unwrap();
+    assert_eq!(ids, [1.into(), 2.into(), 3.into()]);
+    db.transaction(|tx| {
+        assert_eq!(tx.get::<Currency>(ids[1])?.borrow().code, "USD");
+        Ok(())
+    })
+    .unwrap();
+    drop(db);
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    assert!(conn
+        .execute("UPDATE Currency SET code = x'00' WHERE id = 1", [])
+        .is_err());
+}
+
+#[test]
+fn table_prefix() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let open = |prefix: &str| {
+        let options = orm::DatabaseOptions::new().table_prefix(prefix);
+        orm::Database::open_with(&path, &options).unwrap()
+    };
+
+    let mut app1 = open("app1_");
+    let mut app2 = open("app2_");
+    app1.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    app2.transaction(|tx| {
+        tx.create(Order { is_tall: false })?;
+        tx.create(Order { is_tall: false })?;
+        Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(
+        app1.transaction(|tx| tx.select_all::<Order>().map(|all| all.len()))
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        app2.transaction(|tx| tx.select_all::<Order>().map(|all| all.len()))
+            .unwrap(),
+        2
+    );
+    let tables = app1.introspect().unwrap();
+    let names: Vec<_> = tables.iter().map(|t| t.name.as_str()).collect();
+    assert!(names.contains(&"order_table"));
+    assert!(!names.iter().any(|name| name.starts_with("app2_")));
+    drop((app1, app2));
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM app2_order_table", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(count, 2);
+    assert!(conn.prepare("SELECT * FROM order_table").is_err());
+}
+
+#[test]
+fn triggers() {
+    #[derive(Object, Clone)]
+    struct Author {
+        name: String,
+        posts: i64,
+    }
+
+    #[derive(Object, Clone)]
+    #[trigger(
+        "CREATE TRIGGER count_posts AFTER INSERT ON Post
+        BEGIN UPDATE Author SET posts = posts + 1 WHERE id = NEW.author; END"
+    )]
+    #[trigger(
+        "CREATE TRIGGER uncount_posts AFTER DELETE ON Post
+        BEGIN UPDATE Author SET posts = posts - 1 WHERE id = OLD.author; END"
+    )]
+    struct Post {
+        author: i64,
+    }
+
+    assert_eq!(Post::TABLE.triggers.len(), 2);
+    assert!(orm::schema_dump(&[Post::TABLE]).contains("CREATE TRIGGER uncount_posts"));
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let author = db
+        .transaction(|tx| {
+            let author = tx.create(Author {
+                name: "Ann".into(),
+                posts: 0,
+            })?;
+            Ok(author.id())
+        })
+        .unwrap();
+    let post = db
+        .transaction(|tx| {
+            tx.create(Post {
+                author: author.into_i64(),
+            })?;
+            Ok(tx
+                .create(Post {
+                    author: author.into_i64(),
+                })?
+                .id())
+        })
+        .unwrap();
+    db.transaction(|tx| tx.delete::<Post>(post)).unwrap();
+
+    let posts = db
+        .transaction(|tx| Ok(tx.get::<Author>(author)?.borrow().posts))
+        .unwrap();
+    assert_eq!(posts, 1);
+}
+
+#[test]
+fn views() {
+    #[derive(Object, Clone)]
+    #[view("SELECT id, name, visits + 1 AS next_visit FROM User WHERE NOT is_admin")]
+    struct Visitor {
+        name: String,
+        next_visit: i64,
+    }
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let ann = db
+        .transaction(|tx| {
+            for (name, is_admin) in [("Bob", true), ("Ann", false)] {
+                tx.create(User {
+                    name: name.into(),
+                    picture: vec![],
+                    visits: 4,
+                    balance: 0.0,
+                    is_admin,
+                })?;
+            }
+            Ok(tx.select::<User>(&column("name").eq("Ann"))?[0].id())
+        })
+        .unwrap();
+
+    db.transaction(|tx| {
+        let visitors = tx.select_all::<Visitor>()?;
+        assert_eq!(visitors.len(), 1);
+        assert_eq!(visitors[0].id().raw(), ann.raw());
+        assert_eq!(tx.get::<Visitor>(ann.raw())?.borrow().next_visit, 5);
+        Ok(())
+    })
+    .unwrap();
+
+    let res = db.transaction(|tx| {
+        tx.create(Visitor {
+            name: "Eve".into(),
+            next_visit: 1,
+        })
+        .map(|_| ())
+    });
+    assert!(
+        matches!(res, Err(orm::Error::Storage(_))),
+        "{}",
+        fmt_res(&res)
+    );
+    let res = db.transaction(|tx| {
+        tx.get::<Visitor>(ann.raw())?.borrow_mut().next_visit = 9;
+        Ok(())
+    });
+    assert!(
+        matches!(res, Err(orm::Error::Storage(_))),
+        "{}",
+        fmt_res(&res)
+    );
+
+    db.drop_table::<Visitor>().unwrap();
+    db.transaction(|tx| {
+        assert_eq!(tx.get::<User>(ann)?.borrow().visits, 4);
+        Ok(())
+    })
+    .unwrap();
+}
+
+fn check_dynamic_tables(mut conn: Connection) {
+    use orm::dynamic::DynamicSchema;
+
+    let fields = DynamicSchema::new("custom_fields").column("color", DataType::String);
+    let tx = conn.new_transaction().unwrap();
+    let table = tx.dynamic_table(&fields).unwrap();
+    let red = table.create(orm::row!["red".into()]).unwrap();
+    let res = table.create(orm::row![7.into()]);
+    assert!(
+        matches!(res, Err(orm::Error::UnexpectedType(_))),
+        "{}",
+        fmt_res(&res)
+    );
+    tx.commit().unwrap();
+
+    // A field added at runtime gets a column; existing rows get zero.
+    let fields = fields.column("size", DataType::Int64);
+    let tx = conn.new_transaction().unwrap();
+    let table = tx.dynamic_table(&fields).unwrap();
+    let blue = table.create(orm::row!["blue".into(), 3.into()]).unwrap();
+    assert_eq!(table.get(red).unwrap().get("size"), Some(&0.into()));
+    table
+        .update(red, orm::row!["red".into(), 5.into()])
+        .unwrap();
+
+    let large = table.select(&column("size").gt(1)).unwrap();
+    let mut ids: Vec<_> = large.iter().map(|row| row.id).collect();
+    ids.sort_by_key(|id| id.into_i64());
+    assert_eq!(ids, [red, blue]);
+
+    table.delete(blue).unwrap();
+    let res = table.get(blue);
+    assert!(
+        matches!(res, Err(orm::Error::NotFound(_))),
+        "{}",
+        fmt_res(&res)
+    );
+    tx.commit().unwrap();
+}
+
+#[test]
+fn dynamic_tables() {
+    check_dynamic_tables(Connection::open_in_memory().unwrap());
+    check_dynamic_tables(Connection::from_storage(orm::memory::MemoryStorage::new()));
+}
+
+#[test]
+fn tenants() {
+    let mut db = orm::Database::open_in_memory().unwrap();
+    for (tenant, count) in [("acme", 2), ("globex", 1)] {
+        db.tenant_transaction(tenant, |tx| {
+            for _ in 0..count {
+                tx.create(Order { is_tall: true })?;
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    let count = |db: &mut orm::Database, tenant| {
+        db.tenant_transaction(tenant, |tx| Ok(tx.select_all::<Order>()?.len()))
+            .unwrap()
+    };
+    assert_eq!(count(&mut db, "acme"), 2);
+    assert_eq!(count(&mut db, "globex"), 1);
+    assert_eq!(count(&mut db, "initech"), 0);
+    assert_eq!(
+        db.transaction(|tx| Ok(tx.select_all::<Order>()?.len()))
+            .unwrap(),
+        0
+    );
+
+    let tx = db.connection().tenant_transaction("acme").unwrap();
+    let tables: Vec<_> = tx
+        .introspect()
+        .unwrap()
+        .into_iter()
+        .map(|t| t.name)
+        .collect();
+    assert!(tables.contains(&"order_table".to_string()));
+    assert!(!tables.iter().any(|name| name.starts_with("globex_")));
+    tx.rollback().unwrap();
+
+    let res = db.tenant_transaction("a; DROP TABLE x", |_| Ok(()));
+    assert!(matches!(res, Err(orm::Error::Storage(_))));
+}
+
+#[test]
+fn error_is_send_and_sync() {
+    let err = std::thread::spawn(|| {
+        let mut db = orm::Database::open_in_memory().unwrap();
+        db.transaction(|tx| tx.get::<Order>(ObjectId::from(1)).map(|_| ()))
+            .unwrap_err()
+    })
+    .join()
+    .unwrap();
+    let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(err);
+    assert!(boxed.to_string().contains("Order"), "{}", boxed);
+}
+
+#[test]
+fn statement_errors() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let err = tx
+        .execute("INSERT INTO missing VALUES (?)", &["secret".into()])
+        .unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("no such table: missing"), "{}", message);
+    assert!(
+        message.contains("INSERT INTO missing VALUES (?)"),
+        "{}",
+        message
+    );
+    assert!(message.contains("[String]"), "{}", message);
+    assert!(!message.contains("secret"), "{}", message);
+    drop(tx);
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().log_parameter_values(true);
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    let err = db
+        .transaction(|tx| tx.execute("INSERT INTO missing VALUES (?)", &["secret".into()]))
+        .unwrap_err();
+    assert!(err.to_string().contains("secret"), "{}", err);
+}
+
+#[test]
+fn error_kinds() {
+    use orm::ErrorKind;
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let err = tx.get::<User>(ObjectId::from(1)).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(err.kind().code(), "not_found");
+    let err = tx.execute("SELECT * FROM missing", &[]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Storage);
+    drop(tx);
+
+    assert_eq!(orm::Error::LockConflict.kind(), ErrorKind::Locked);
+    let io = std::io::Error::other("disk");
+    assert_eq!(orm::Error::Storage(Box::new(io)).kind(), ErrorKind::Io);
+    let err = Connection::open_sqlite_file("/nonexistent/dir/db.sqlite")
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::Io);
+}
+
+#[test]
+fn fallible_deserialize() {
+    let err = Order::deserialize(orm::row![1i64.into()]).err().unwrap();
+    assert_eq!(err.attr_name, "is_tall");
+    assert_eq!(err.expected, DataType::Bool);
+    assert_eq!(err.got, Some(DataType::Int64));
+    assert_eq!(
+        err.to_string(),
+        "cannot deserialize Order::is_tall: expected Bool, got Int64"
+    );
+
+    let err = User::deserialize(orm::row!["Bob".into()]).err().unwrap();
+    assert_eq!(err.attr_name, "picture");
+    assert_eq!(err.got, None);
+    assert!(matches!(orm::Error::from(err), orm::Error::Deserialize(_)));
+
+    let order = Order::deserialize(orm::row![true.into()]).unwrap();
+    assert!(order.is_tall);
+}
+
+#[test]
+fn owned_error_names() {
+    use orm::error::{MissingColumnError, NotFoundError};
+
+    let table = String::from("runtime_table");
+    let err = NotFoundError::new(ObjectId::from(7), table.clone());
+    assert_eq!(err.type_name, "runtime_table");
+    assert_eq!(
+        err.to_string(),
+        "object is not found: type 'runtime_table', id 7"
+    );
+    let err = MissingColumnError::new(table.clone(), "x", table, "x");
+    assert_eq!(err.column_name, "x");
+}
+
+#[test]
+fn retryable_errors() {
+    assert!(orm::Error::LockConflict.is_retryable());
+    assert!(!orm::Error::ReadOnly.is_retryable());
+    let locked = orm::error::LockedError::new(6, "database table is locked".to_string());
+    let err = orm::Error::Locked(Box::new(locked));
+    assert!(!err.is_retryable());
+    assert_eq!(err.kind(), orm::ErrorKind::Locked);
+    let io = std::io::Error::from(std::io::ErrorKind::Interrupted);
+    assert!(orm::Error::Storage(Box::new(io)).is_retryable());
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let err = tx.get::<User>(ObjectId::from(1)).err().unwrap();
+    assert!(!err.is_retryable());
+    let err = tx.execute("SELECT * FROM missing", &[]).unwrap_err();
+    assert!(!err.is_retryable());
+}
+
+#[test]
+#[cfg(feature = "diagnostics")]
+fn diagnostics() {
+    use miette::Diagnostic;
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute("CREATE TABLE account(id INTEGER PRIMARY KEY)", &[])
+        .unwrap();
+    let err = tx
+        .execute("UPDATE account SET nickname = 'x'", &[])
+        .unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "orm::storage");
+    let label = err.labels().unwrap().next().unwrap();
+    assert_eq!(label.offset(), "UPDATE account SET ".len());
+    assert_eq!(label.len(), "nickname".len());
+
+    let err = tx.get::<User>(ObjectId::from(3)).err().unwrap();
+    assert_eq!(err.code().unwrap().to_string(), "orm::not_found");
+    let label = err.labels().unwrap().next().unwrap();
+    assert_eq!(label.label(), Some("no object with id 3"));
+    drop(tx);
+
+    let err = orm::Error::LockConflict;
+    assert_eq!(err.code().unwrap().to_string(), "orm::locked");
+    assert!(err.help().unwrap().to_string().contains("busy_timeout"));
+}
+
+#[test]
+fn error_hook() {
+    use std::sync::{Arc, Mutex};
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let seen = errors.clone();
+    db.set_error_hook(move |err| seen.lock().unwrap().push(err.kind()));
+
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+    assert!(errors.lock().unwrap().is_empty());
+
+    let res = db.transaction(|tx| {
+        tx.get::<Order>(ObjectId::from(id.into_i64() + 1))
+            .map(|_| ())
+    });
+    assert!(res.is_err());
+    assert!(db.detach("missing").is_err());
+    assert_eq!(
+        *errors.lock().unwrap(),
+        [orm::ErrorKind::NotFound, orm::ErrorKind::Storage]
+    );
+
+    db.clear_error_hook();
+    assert!(db.detach("missing").is_err());
+    assert_eq!(errors.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn missing_column_up_front() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.execute("ALTER TABLE order_table ADD COLUMN extra INTEGER", &[])
+        .unwrap();
+    tx.execute("ALTER TABLE order_table DROP COLUMN IsTall", &[])
+        .unwrap();
+    tx.commit().unwrap();
+
+    // Counting does not name the column, so only the table check notices.
+    let tx = conn.new_transaction().unwrap();
+    match tx.count_where::<Order>(&column("id").gt(0)) {
+        Err(orm::Error::MissingColumn(err)) => {
+            assert_eq!(err.attr_name, "is_tall");
+            assert_eq!(err.column_name, "IsTall");
+        }
+        res => panic!("unexpected {}", fmt_res(&res)),
+    }
+}
+
+#[cfg(feature = "backtrace")]
+#[test]
+fn error_backtraces() {
+    #[derive(Object)]
+    struct User {}
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let err = tx.get::<User>(ObjectId::from(1)).err().unwrap();
+    assert!(err.backtrace().is_some());
+
+    let err = tx.execute("SELECT * FROM no_such_table", &[]).unwrap_err();
+    assert!(matches!(err, orm::Error::Storage(_)));
+    assert!(err.backtrace().is_some());
+    assert!(orm::Error::LockConflict.backtrace().is_none());
+}
+
+#[test]
+fn transaction_deadline() {
+    use std::time::{Duration, Instant};
+
+    let runaway = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) \
+        SELECT count(*) FROM n";
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.set_timeout(Duration::from_millis(50)).unwrap();
+    let started = Instant::now();
+    let err = tx.execute(runaway, &[]).unwrap_err();
+    assert!(matches!(err, orm::Error::Timeout), "{}", err);
+    assert_eq!(err.kind(), orm::ErrorKind::Timeout);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    tx.set_deadline(None).unwrap();
+    tx.execute("CREATE TABLE t(x)", &[]).unwrap();
+    tx.set_timeout(Duration::from_secs(60)).unwrap();
+    tx.rollback().unwrap();
+
+    // The deadline ends with the transaction.
+    let tx = conn.new_transaction().unwrap();
+    std::thread::sleep(Duration::from_millis(60));
+    tx.execute("CREATE TABLE t(x)", &[]).unwrap();
+    tx.rollback().unwrap();
+}
+
+#[test]
+fn cached_sql() {
+    use orm::Object;
+
+    let sql = User::TABLE.update_text();
+    assert_eq!(
+        sql,
+        "UPDATE User SET name = ?, picture = ?, visits = ?, balance = ?, is_admin = ? \
+        WHERE id = ?"
+    );
+    assert!(std::ptr::eq(sql, User::TABLE.update_text()));
+    assert!(!std::ptr::eq(
+        User::TABLE.insert_text(),
+        User::TABLE.insert_text_with(orm::OnConflict::Replace)
+    ));
+    assert!(!std::ptr::eq(
+        User::TABLE.select_text(),
+        Order::TABLE.select_text()
+    ));
+}
+
+#[test]
+fn create_many() {
+    // More rows than fit in one statement.
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(user(-1)).unwrap();
+    let users = tx.create_many((0..8000).map(user)).unwrap();
+    assert_eq!(tx.last_changes(), 8000);
+    assert_eq!(users.len(), 8000);
+    let ids: Vec<_> = users.iter().map(|obj| obj.id()).collect();
+    drop(users);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<User>().unwrap().len(), 8001);
+    for (visits, id) in ids.into_iter().enumerate().step_by(997) {
+        assert_eq!(tx.get::<User>(id).unwrap().borrow().visits, visits as i64);
+    }
+    assert!(tx.create_many(Vec::<User>::new()).unwrap().is_empty());
+
+    let mut conn = Connection::from_storage(orm::memory::MemoryStorage::new());
+    let tx = conn.new_transaction().unwrap();
+    let users = tx.create_many((0..3).map(user)).unwrap();
+    assert_eq!(users[2].borrow().name, "User 2");
+    assert_eq!(tx.get::<User>(users[2].id()).unwrap().borrow().visits, 2);
+}
+
+#[test]
+fn lazy_fields() {
+    #[derive(Object, Debug)]
+    struct Photo {
+        title: String,
+        #[lazy]
+        data: Vec<u8>,
+        #[lazy]
+        #[column_name("Size")]
+        size: i64,
+    }
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(Photo {
+            title: "Sea".into(),
+            data: vec![7; 4096],
+            size: 4096,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let photo = tx.get::<Photo>(id).unwrap();
+    assert!(photo.borrow().data.is_empty());
+    assert_eq!(photo.borrow().size, 0);
+    assert!(tx.select_all::<Photo>().unwrap()[0]
+        .borrow()
+        .data
+        .is_empty());
+
+    // Fields that were not loaded are not written back.
+    photo.borrow_mut().title = "Lake".into();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let photo = tx.get::<Photo>(id).unwrap();
+    assert_eq!(photo.borrow().title, "Lake");
+    tx.load_field(&photo, "data").unwrap();
+    tx.load_field(&photo, "size").unwrap();
+    assert_eq!(photo.borrow().data, vec![7; 4096]);
+    assert_eq!(photo.borrow().size, 4096);
+    assert!(matches!(
+        tx.load_field(&photo, "nothing"),
+        Err(orm::Error::Storage(_))
+    ));
+
+    tx.set_conflict_check(true);
+    photo.borrow_mut().size = 1;
+    tx.load_field(&photo, "size").unwrap();
+    assert_eq!(photo.borrow().size, 1);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let photo = tx.get::<Photo>(id).unwrap();
+    tx.load_field(&photo, "size").unwrap();
+    assert_eq!(photo.borrow().size, 1);
+    tx.load_field(&photo, "data").unwrap();
+    assert_eq!(photo.borrow().data.len(), 4096);
+}
+
+#[test]
+fn scan_rows() {
+    use std::borrow::Cow;
+
+    let check = |mut conn: Connection| {
+        let tx = conn.new_transaction().unwrap();
+        for visits in 0..10 {
+            tx.create(User {
+                name: format!("User {}", visits),
+                picture: vec![visits as u8; 3],
+                visits,
+                balance: 0.,
+                is_admin: visits % 2 == 0,
+            })
+            .unwrap();
+        }
+
+        let mut names = Vec::new();
+        let mut bytes = 0;
+        tx.scan::<User>(&column("visits").ge(7), |_, row| {
+            match (&row[0], &row[1]) {
+                (orm::data::Value::String(name), orm::data::Value::Bytes(picture)) => {
+                    names.push(name.to_string());
+                    bytes += picture.len();
+                }
+                _ => panic!("unexpected row {:?}", row),
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(names, ["User 7", "User 8", "User 9"]);
+        assert_eq!(bytes, 9);
+
+        let res = tx.scan::<User>(&column("visits").eq(3), |_, _| Err(orm::Error::ReadOnly));
+        assert!(matches!(res, Err(orm::Error::ReadOnly)));
+    };
+    check(Connection::open_in_memory().unwrap());
+    check(Connection::from_storage(orm::memory::MemoryStorage::new()));
+
+    // The SQLite backend lends its buffers.
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(User {
+            name: "Zoe".into(),
+            picture: vec![1],
+            visits: 1,
+            balance: 1.,
+            is_admin: false,
+        })
+        .unwrap()
+        .id();
+    tx.scan::<User>(&column("id").eq(id.into_i64()), |scanned, row| {
+        assert_eq!(scanned, id.raw());
+        assert!(matches!(
+            &row[0],
+            orm::data::Value::String(Cow::Borrowed("Zoe"))
+        ));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn shared_memory() {
+    let mut first = orm::Database::open_shared_memory("shared_memory_test").unwrap();
+    let mut second = orm::Database::open_shared_memory("shared_memory_test").unwrap();
+    let mut other = orm::Database::open_shared_memory("other_memory_test").unwrap();
+
+    let id = first
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+    let is_tall = second
+        .transaction(|tx| Ok(tx.get::<Order>(id)?.borrow().is_tall))
+        .unwrap();
+    assert!(is_tall);
+    assert!(matches!(
+        other.transaction(|tx| tx.get::<Order>(id).map(|_| ())),
+        Err(orm::Error::NotFound(_))
+    ));
+
+    let tx = first.connection().new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap().borrow_mut().is_tall = false;
+    tx.create(Order { is_tall: false }).unwrap();
+    assert!(matches!(
+        second.transaction(|tx| tx.get::<Order>(id).map(|_| ())),
+        Err(orm::Error::Locked(err)) if err.is_shared_cache()
+    ));
+    tx.commit().unwrap();
+
+    drop(first);
+    drop(second);
+    let mut reopened = orm::Database::open_shared_memory("shared_memory_test").unwrap();
+    assert!(matches!(
+        reopened.transaction(|tx| tx.get::<Order>(id).map(|_| ())),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+#[test]
+fn statement_cache() {
+    let options = orm::DatabaseOptions::new().statement_cache_capacity(64);
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+    db.transaction(|tx| tx.get::<Order>(id).map(|_| ()))
+        .unwrap();
+    let before = db.statement_cache_stats().unwrap();
+    assert_eq!(before.capacity, 64);
+
+    for _ in 0..10 {
+        db.transaction(|tx| tx.get::<Order>(id).map(|_| ()))
+            .unwrap();
+    }
+    let after = db.statement_cache_stats().unwrap();
+    assert!(after.hits >= before.hits + 10);
+    assert_eq!(after.misses, before.misses);
+
+    let mut db = orm::Database::open_with(
+        &path,
+        &orm::DatabaseOptions::new().statement_cache_capacity(0),
+    )
+    .unwrap();
+    for _ in 0..3 {
+        db.transaction(|tx| tx.get::<Order>(id).map(|_| ()))
+            .unwrap();
+    }
+    assert_eq!(db.statement_cache_stats().unwrap().hits, 0);
+}
+
+#[test]
+fn pinned_statements() {
+    let options = orm::DatabaseOptions::new().statement_cache_capacity(2);
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    let id = db
+        .transaction(|tx| Ok(tx.create(Order { is_tall: true })?.id()))
+        .unwrap();
+    db.transaction(|tx| tx.get::<Order>(id).map(|_| ()))
+        .unwrap();
+    let before = db.statement_cache_stats().unwrap();
+    assert!(before.pinned >= 2);
+
+    // Other statements come and go without pushing out the pinned ones.
+    let mut predicate = column("IsTall").eq(true);
+    for _ in 0..10 {
+        predicate = predicate.or(column("IsTall").eq(true));
+        db.transaction(|tx| {
+            tx.count_where::<Order>(&predicate)?;
+            tx.get::<Order>(id).map(|_| ())
+        })
+        .unwrap();
+    }
+    let after = db.statement_cache_stats().unwrap();
+    assert_eq!(after.pinned, before.pinned);
+    assert!(after.hits >= before.hits + 10);
+    assert_eq!(after.misses, before.misses + 10);
+}
+
+#[test]
+fn instrument() {
+    use orm::instrument::{Instrument, Operation};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<(Operation, String)>>>);
+
+    impl Instrument for Recorder {
+        fn record(&self, operation: Operation, target: &str, _elapsed: Duration) {
+            self.0.lock().unwrap().push((operation, target.to_string()));
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<(Operation, String)> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    let recorder = Recorder::default();
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.set_instrument(recorder.clone()).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap();
+    drop(tx);
+
+    let ops = recorder.take();
+    assert!(ops.contains(&(Operation::Deserialize, "Order".to_string())));
+    for op in [Operation::Prepare, Operation::Execute] {
+        assert!(ops
+            .iter()
+            .any(|(o, sql)| *o == op && sql.starts_with("INSERT INTO order_table")));
+        assert!(ops
+            .iter()
+            .any(|(o, sql)| *o == op && sql.starts_with("SELECT")));
+    }
+
+    conn.clear_instrument().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap();
+    drop(tx);
+    assert!(recorder.take().is_empty());
+
+    // Storages that do not time their statements report deserializing.
+    let mut conn = Connection::from_storage(orm::memory::MemoryStorage::new());
+    conn.set_instrument(recorder.clone()).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap();
+    assert_eq!(
+        recorder.take(),
+        vec![(Operation::Deserialize, "Order".to_string())]
+    );
+}
+
+#[test]
+fn slow_query_log() {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let mut conn = Connection::open_in_memory().unwrap();
+    let sink = logged.clone();
+    conn.set_slow_query_log(Duration::ZERO, move |query| {
+        sink.lock()
+            .unwrap()
+            .push((query.sql.to_string(), query.params.clone()));
+    })
+    .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+    logged.lock().unwrap().clear();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap();
+    drop(tx);
+    let queries = std::mem::take(&mut *logged.lock().unwrap());
+    assert_eq!(queries.len(), 1);
+    assert!(queries[0].0.starts_with("SELECT"));
+    assert_eq!(queries[0].1, vec!["Integer".to_string()]);
+
+    // Statements faster than the threshold are left out.
+    let sink = logged.clone();
+    conn.set_slow_query_log(Duration::from_secs(60), move |query| {
+        sink.lock()
+            .unwrap()
+            .push((query.sql.to_string(), Vec::new()));
+    })
+    .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap();
+    drop(tx);
+    conn.clear_slow_query_log().unwrap();
+    assert!(logged.lock().unwrap().is_empty());
+
+    let mut conn = Connection::from_storage(orm::memory::MemoryStorage::new());
+    let res = conn.set_slow_query_log(Duration::ZERO, |_| {});
+    assert!(
+        matches!(res, Err(orm::Error::Storage(_))),
+        "{}",
+        fmt_res(&res)
+    );
+}
+
+#[test]
+fn sql_trace() {
+    use orm::data::Value;
+    use std::sync::{Arc, Mutex};
+
+    let traced = Arc::new(Mutex::new(Vec::new()));
+    let mut conn = Connection::open_in_memory().unwrap();
+    let sink = traced.clone();
+    conn.set_sql_trace(move |trace| {
+        let params: Vec<_> = trace
+            .params
+            .iter()
+            .cloned()
+            .map(Value::into_owned)
+            .collect();
+        sink.lock().unwrap().push((trace.sql.to_string(), params));
+    })
+    .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.execute(
+        "UPDATE order_table SET IsTall = ? WHERE id = ?",
+        &[Value::Bool(false), Value::Int64(id.into_i64())],
+    )
+    .unwrap();
+    tx.commit().unwrap();
+
+    let traces = std::mem::take(&mut *traced.lock().unwrap());
+    let (sql, params) = traces
+        .iter()
+        .find(|(sql, _)| sql.starts_with("INSERT INTO order_table"))
+        .unwrap();
+    assert!(sql.contains("IsTall"), "{}", sql);
+    assert_eq!(params, &vec![Value::Int64(1)]);
+    assert_eq!(
+        traces.last().unwrap(),
+        &(
+            "UPDATE order_table SET IsTall = ? WHERE id = ?".to_string(),
+            vec![Value::Int64(0), Value::Int64(id.into_i64())]
+        )
+    );
+
+    conn.clear_sql_trace().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Order>(id).unwrap();
+    drop(tx);
+    assert!(traced.lock().unwrap().is_empty());
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_events() {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    // Every event as its message followed by its fields, in order.
+    #[derive(Clone, Default)]
+    struct Collector {
+        events: Arc<Mutex<Vec<String>>>,
+        spans: Arc<AtomicU64>,
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            match field.name() {
+                "message" => self.0.insert_str(0, &format!("{:?}", value)),
+                "elapsed_us" => {}
+                name => self.0.push_str(&format!(" {}={:?}", name, value)),
+            }
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "orm"
+        }
+
+        fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(self.spans.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _span: &span::Id) {}
+
+        fn exit(&self, _span: &span::Id) {}
+    }
+
+    let collector = Collector::default();
+    tracing::subscriber::with_default(collector.clone(), || {
+        let mut conn = Connection::from_storage(orm::memory::MemoryStorage::new());
+        let tx = conn.new_transaction().unwrap();
+        let id = tx.create(Order { is_tall: true }).unwrap().id();
+        tx.execute("DELETE FROM order_table", &[]).ok();
+        tx.commit().unwrap();
+        let tx = conn.new_transaction().unwrap();
+        assert_eq!(tx.select_all::<Order>().unwrap().len(), 1);
+        tx.get::<Order>(id).unwrap();
+        tx.rollback().unwrap();
+    });
+
+    let events = collector.events.lock().unwrap().clone();
+    let orders: Vec<_> = events
+        .iter()
+        .filter(|event| !event.contains("orm_fingerprints"))
+        .map(String::as_str)
+        .collect();
+    assert_eq!(orders[0], "begin");
+    assert!(orders.contains(&"statement operation=\"insert\" table=\"order_table\" rows=1"));
+    assert!(orders.iter().any(|event| event.starts_with(
+        "statement operation=\"execute\" table=\"\" sql=\"DELETE FROM order_table\" error="
+    )));
+    assert!(orders.contains(&"commit"));
+    assert!(orders.contains(&"statement operation=\"select\" table=\"order_table\" rows=1"));
+    assert_eq!(orders.last(), Some(&"rollback"));
+    assert_eq!(orders.iter().filter(|event| **event == "begin").count(), 2);
+}
+
+#[test]
+fn metrics() {
+    use orm::metrics::{Metrics, Operation};
+    use orm::mock::{MockOp, MockStorage};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct Counters(Arc<Mutex<HashMap<String, usize>>>);
+
+    impl Counters {
+        fn add(&self, key: String, n: usize) {
+            *self.0.lock().unwrap().entry(key).or_default() += n;
+        }
+
+        fn get(&self, key: &str) -> usize {
+            self.0.lock().unwrap().get(key).copied().unwrap_or(0)
+        }
+    }
+
+    impl Metrics for Counters {
+        fn operation(&self, operation: Operation, table: &str, _elapsed: Duration) {
+            self.add(format!("{} {}", operation.as_str(), table), 1);
+        }
+
+        fn rows_read(&self, table: &str, rows: usize) {
+            self.add(format!("read {}", table), rows);
+        }
+
+        fn rows_written(&self, table: &str, rows: usize) {
+            self.add(format!("written {}", table), rows);
+        }
+
+        fn lock_conflict(&self, operation: Operation, _table: &str) {
+            self.add(format!("conflict {}", operation.as_str()), 1);
+        }
+    }
+
+    let counters = Counters::default();
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.set_metrics(counters.clone());
+    db.transaction(|tx| {
+        tx.create_many((0..3).map(|_| Order { is_tall: true }))?;
+        Ok(())
+    })
+    .unwrap();
+    let tall = db
+        .transaction(|tx| Ok(tx.select::<Order>(&column("IsTall").eq(true))?.len()))
+        .unwrap();
+    assert_eq!(tall, 3);
+    assert_eq!(counters.get("insert order_table"), 1);
+    assert_eq!(counters.get("written order_table"), 3);
+    assert_eq!(counters.get("select order_table"), 1);
+    assert_eq!(counters.get("read order_table"), 3);
+    assert_eq!(counters.get("commit "), 2);
+
+    db.clear_metrics();
+    db.transaction(|tx| tx.select_all::<Order>().map(|_| ()))
+        .unwrap();
+    assert_eq!(counters.get("select order_table"), 1);
+
+    // A transaction that runs into another connection's lock.
+    let storage = MockStorage::new();
+    storage
+        .script()
+        .fail_nth(MockOp::Insert, 1, || orm::Error::LockConflict);
+    let mut db = orm::Database::from_storage(storage).with_retry_policy(orm::RetryPolicy {
+        max_attempts: 2,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    });
+    db.set_metrics(counters.clone());
+    db.transaction(|tx| tx.create(Order { is_tall: false }).map(|_| ()))
+        .unwrap();
+    assert_eq!(counters.get("conflict insert"), 1);
+    assert_eq!(counters.get("written order_table"), 4);
+}
+
+#[test]
+fn scalar_function() {
+    use orm::data::Value;
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.create_scalar_function("normalize", |args| match args {
+        [Value::String(s)] => Ok(Value::from(s.trim().to_lowercase())),
+        _ => Err(orm::Error::Storage("normalize expects one string".into())),
+    })
+    .unwrap();
+
+    let res = db.transaction(|tx| {
+        for name in [" Alice ", "BOB"] {
+            tx.create(User {
+                name: name.into(),
+                picture: Vec::new(),
+                visits: 0,
+                balance: 0.,
+                is_admin: false,
+            })?;
+        }
+        tx.execute("UPDATE User SET name = normalize(name)", &[])
+    });
+    assert_eq!(res.unwrap(), 2);
+
+    let res = db.transaction(|tx| {
+        Ok(tx
+            .select_all::<User>()?
+            .iter()
+            .map(|user| user.borrow().name.clone())
+            .collect::<Vec<_>>())
+    });
+    assert_eq!(res.unwrap(), ["alice", "bob"]);
+
+    let res = db.transaction(|tx| tx.execute("UPDATE User SET name = normalize(visits)", &[]));
+    assert!(matches!(res, Err(orm::Error::Storage(_))));
+}
+
+#[test]
+fn change_hook() {
+    use orm::ChangeOp;
+    use std::sync::{Arc, Mutex};
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let sink = changes.clone();
+    db.on_change(move |table, op, id| {
+        if table == "order_table" {
+            sink.lock().unwrap().push((op, id));
+        }
+    })
+    .unwrap();
+
+    let id = db
+        .transaction(|tx| {
+            let order = tx.create(Order { is_tall: false })?;
+            order.borrow_mut().is_tall = true;
+            Ok(order.id().raw())
+        })
+        .unwrap();
+    db.transaction(|tx| {
+        tx.get::<Order>(id)?.delete();
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+        *changes.lock().unwrap(),
+        [
+            (ChangeOp::Insert, id),
+            (ChangeOp::Update, id),
+            (ChangeOp::Delete, id)
+        ]
+    );
+
+    db.clear_change_hook().unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    assert_eq!(changes.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn apply_order() {
+    use orm::ChangeOp;
+    use std::sync::{Arc, Mutex};
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let ids = db
+        .transaction(|tx| {
+            (0..6)
+                .map(|_| Ok(tx.create(Order { is_tall: false })?.id().raw()))
+                .collect::<orm::Result<Vec<_>>>()
+        })
+        .unwrap();
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let sink = changes.clone();
+    db.on_change(move |_, op, id| sink.lock().unwrap().push((op, id)))
+        .unwrap();
+    db.transaction(|tx| {
+        for &id in [ids[3], ids[0], ids[4], ids[1], ids[5], ids[2]].iter() {
+            let order = tx.get::<Order>(id)?;
+            if ids[..3].contains(&id) {
+                order.borrow_mut().is_tall = true;
+            } else {
+                order.delete();
+            }
+        }
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![
+            (ChangeOp::Update, ids[0]),
+            (ChangeOp::Update, ids[1]),
+            (ChangeOp::Update, ids[2]),
+            (ChangeOp::Delete, ids[5]),
+            (ChangeOp::Delete, ids[4]),
+            (ChangeOp::Delete, ids[3]),
+        ]
+    );
+}
+
+#[test]
+fn observers() {
+    use orm::ChangeOp;
+    use std::sync::{Arc, Mutex};
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    db.observe(move |event| {
+        assert_eq!(event.table(), "order_table");
+        sink.lock()
+            .unwrap()
+            .push((event.op, event.id, event.old.clone(), event.new.clone()));
+    });
+
+    let (first, second) = db
+        .transaction(|tx| {
+            let first = tx.create(Order { is_tall: false })?;
+            first.borrow_mut().is_tall = true;
+            Ok((
+                first.id().raw(),
+                tx.create(Order { is_tall: false })?.id().raw(),
+            ))
+        })
+        .unwrap();
+    let row = |is_tall: bool| Some(orm::row![orm::data::Value::from(is_tall)]);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (ChangeOp::Insert, first, None, row(false)),
+            (ChangeOp::Insert, second, None, row(false)),
+            (ChangeOp::Update, first, row(false), row(true)),
+        ]
+    );
+
+    events.lock().unwrap().clear();
+    let result: orm::Result<()> = db.transaction(|tx| {
+        tx.delete::<Order>(first)?;
+        Err(orm::Error::ReadOnly)
+    });
+    assert!(result.is_err());
+    assert!(events.lock().unwrap().is_empty());
+
+    db.transaction(|tx| {
+        let sp = tx.savepoint()?;
+        sp.create(Order { is_tall: true })?;
+        sp.rollback()?;
+        tx.get::<Order>(second)?.delete();
+        tx.delete::<Order>(first)
+    })
+    .unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (ChangeOp::Delete, first, row(true), None),
+            (ChangeOp::Delete, second, row(false), None),
+        ]
+    );
+}
+
+#[test]
+fn foreign_keys() {
+    use orm::ConstraintKind;
+
+    let mut db = orm::Database::open_in_memory().unwrap();
+    db.set_foreign_keys(true).unwrap();
+    db.transaction(|tx| {
+        tx.execute("CREATE TABLE parent(id INTEGER PRIMARY KEY)", &[])?;
+        tx.execute(
+            "CREATE TABLE child(id INTEGER PRIMARY KEY, parent INTEGER REFERENCES parent(id))",
+            &[],
+        )
+    })
+    .unwrap();
+    let insert_child = "INSERT INTO child(parent) VALUES (1)";
+    let constraint = |res: orm::Result<usize>| match res {
+        Err(orm::Error::Constraint(err)) => Some(err.kind),
+        _ => None,
+    };
+
+    let res = db.transaction(|tx| tx.execute(insert_child, &[]));
+    assert_eq!(constraint(res), Some(ConstraintKind::ForeignKey));
+
+    let res = db.transaction(|tx| {
+        tx.defer_foreign_keys(true)?;
+        tx.execute(insert_child, &[])
+    });
+    assert_eq!(constraint(res), Some(ConstraintKind::ForeignKey));
+
+    db.transaction(|tx| {
+        tx.defer_foreign_keys(true)?;
+        tx.execute(insert_child, &[])?;
+        tx.execute("INSERT INTO parent(id) VALUES (1)", &[])
+    })
+    .unwrap();
+    let res = db.transaction(|tx| tx.execute("INSERT INTO parent(id) VALUES (1)", &[]));
+    assert_eq!(constraint(res), Some(ConstraintKind::PrimaryKey));
+
+    db.transaction(|tx| {
+        tx.execute(
+            "CREATE TABLE account(id INTEGER PRIMARY KEY, email TEXT UNIQUE)",
+            &[],
+        )?;
+        tx.execute("INSERT INTO account(email) VALUES ('a@b.c')", &[])
+    })
+    .unwrap();
+    match db.transaction(|tx| tx.execute("INSERT INTO account(email) VALUES ('a@b.c')", &[])) {
+        Err(orm::Error::Constraint(err)) => {
+            assert_eq!(err.kind, ConstraintKind::Unique);
+            assert_eq!(err.table.as_deref(), Some("account"));
+            assert_eq!(err.column(), Some("email"));
+        }
+        res => panic!("unexpected {}", fmt_res(&res)),
+    }
+
+    db.set_foreign_keys(false).unwrap();
+    db.transaction(|tx| tx.execute("INSERT INTO child(parent) VALUES (2)", &[]))
+        .unwrap();
+}
+
+#[test]
+fn integrity_check() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut db = orm::Database::open(&path).unwrap();
+    db.transaction(|tx| {
+        for visits in 0..500 {
+            tx.create(User {
+                name: format!("User {}", visits),
+                picture: vec![7; 64],
+                visits,
+                balance: 0.,
+                is_admin: false,
+            })?;
+        }
+        Ok(())
+    })
+    .unwrap();
+    assert!(db.integrity_check().unwrap().is_ok());
+    assert!(db.quick_check().unwrap().is_ok());
+    drop(db);
+
+    let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(4096 * 3)).unwrap();
+    file.write_all(&[0xff; 16]).unwrap();
+    drop(file);
+
+    let mut db = orm::Database::open(&path).unwrap();
+    assert!(!db.integrity_check().unwrap().is_ok());
+}
+
+#[cfg(feature = "postgres")]
+#[test]
+fn postgres_storage() {
+    let params = match std::env::var("ORM_POSTGRES_URL") {
+        Ok(params) => params,
+        Err(_) => return,
+    };
+    let mut conn = Connection::open_postgres(&params).unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.execute("DROP TABLE IF EXISTS \"User\"", &[]).unwrap();
+    let names = ["Alice", "Bob", "Carol"];
+    for (i, name) in names.iter().enumerate() {
+        tx.create(User {
+            name: name.to_string(),
+            picture: b"qwerty"[..].into(),
+            visits: i as i64,
+            balance: 2.5,
+            is_admin: i == 0,
+        })
+        .unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let alice = tx.get::<User>(ObjectId::from(1)).unwrap();
+    assert_eq!(alice.borrow().name, "Alice");
+    assert_eq!(alice.borrow().picture, b"qwerty");
+    alice.borrow_mut().visits = 10;
+    tx.select::<User>(&column("name").eq("Carol"))
+        .unwrap()
+        .pop()
+        .unwrap()
+        .delete();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.get::<User>(ObjectId::from(1)).unwrap().borrow().visits,
+        10
+    );
+    assert_not_found(tx.get::<User>(ObjectId::from(3)), 3.into(), "User");
+    assert_eq!(
+        tx.count_where::<User>(&column("visits").ge(1i64).and(column("is_admin").eq(false)))
+            .unwrap(),
+        1
+    );
+    assert_eq!(tx.clear_table::<User>().unwrap(), 2);
+    let dave = tx
+        .create(User {
+            name: "Dave".into(),
+            picture: Vec::new(),
+            visits: 0,
+            balance: 0.,
+            is_admin: false,
+        })
+        .unwrap();
+    assert_eq!(dave.id().raw(), ObjectId::from(1));
+    tx.rollback().unwrap();
+
+    // Replace takes over a row at the next id of the sequence.
+    let tx = conn.new_transaction().unwrap();
+    tx.execute("DROP TABLE IF EXISTS order_table", &[]).unwrap();
+    tx.create(Order { is_tall: false }).unwrap();
+    tx.execute(
+        "INSERT INTO order_table (id, \"IsTall\") VALUES (2, FALSE)",
+        &[],
+    )
+    .unwrap();
+    let replaced = tx
+        .create_with(Order { is_tall: true }, orm::OnConflict::Replace)
+        .unwrap()
+        .unwrap();
+    assert_eq!(replaced.id().raw(), ObjectId::from(2));
+    assert!(tx
+        .create_with(Order { is_tall: true }, orm::OnConflict::Ignore)
+        .unwrap()
+        .is_some());
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        2
+    );
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.drop_table::<Pet>().unwrap();
+    tx.drop_table::<Animal>().unwrap();
+    tx.commit().unwrap();
+    check_rename_and_drop_columns(conn);
+}
+
+#[cfg(feature = "duckdb")]
+#[test]
+fn duckdb_storage() {
+    let mut conn = Connection::open_duckdb_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    for is_tall in [true, false, true] {
+        tx.create(Order { is_tall }).unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        2
+    );
+    tx.get::<Order>(ObjectId::from(2))
+        .unwrap()
+        .borrow_mut()
+        .is_tall = true;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<Order>().unwrap().len(), 3);
+    assert_eq!(tx.clear_table::<Order>().unwrap(), 3);
+    assert_eq!(
+        tx.create(Order { is_tall: false }).unwrap().id().raw(),
+        ObjectId::from(1)
+    );
+}
+
+#[cfg(feature = "redb")]
+#[test]
+fn redb_storage() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut conn = Connection::open_redb_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for visits in 0..3 {
+        tx.create(User {
+            name: format!("User {}", visits),
+            picture: vec![visits as u8; 4],
+            visits,
+            balance: 0.5,
+            is_admin: visits == 2,
+        })
+        .unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<User>(ObjectId::from(1)).unwrap().delete();
+    tx.rollback().unwrap();
+    drop(conn);
+
+    let mut conn = Connection::open_redb_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let admin = tx.select::<User>(&column("is_admin").eq(true)).unwrap();
+    assert_eq!(admin.len(), 1);
+    assert_eq!(admin[0].borrow().picture, [2; 4]);
+    assert_eq!(
+        tx.delete_where::<User>(&column("visits").le(1i64)).unwrap(),
+        2
+    );
+    let id = tx
+        .create(User {
+            name: "Late".into(),
+            picture: Vec::new(),
+            visits: 10,
+            balance: 0.,
+            is_admin: false,
+        })
+        .unwrap()
+        .id();
+    assert_eq!(id.raw(), ObjectId::from(4));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.select_all::<User>().unwrap().len(), 2);
+    check_conflict_strategies_unsupported(&tx);
+}
+
+#[cfg(feature = "sqlcipher")]
+#[test]
+fn encrypted_database() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let options = orm::DatabaseOptions::new().key("correct horse");
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    db.transaction(|tx| tx.create(Order { is_tall: true }).map(|_| ()))
+        .unwrap();
+    db.rekey("battery staple").unwrap();
+    drop(db);
+
+    assert!(orm::Database::open_with(&path, &options).is_err());
+    let options = orm::DatabaseOptions::new().key("battery staple");
+    let mut db = orm::Database::open_with(&path, &options).unwrap();
+    let is_tall = db
+        .transaction(|tx| Ok(tx.get::<Order>(ObjectId::from(1))?.borrow().is_tall))
+        .unwrap();
+    assert!(is_tall);
+}
+
+#[cfg(feature = "libsql")]
+#[test]
+fn libsql_storage() {
+    let (url, token) = match (
+        std::env::var("ORM_LIBSQL_URL"),
+        std::env::var("ORM_LIBSQL_TOKEN"),
+    ) {
+        (Ok(url), Ok(token)) => (url, token),
+        _ => return,
+    };
+    let mut conn = Connection::open_libsql(&url, &token).unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    tx.execute("DROP TABLE IF EXISTS order_table", &[]).unwrap();
+    let order_id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.get::<Order>(order_id).unwrap().borrow().is_tall);
+    assert_eq!(
+        tx.count_where::<Order>(&column("IsTall").eq(true)).unwrap(),
+        1
+    );
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn web_unit_of_work() {
+    use axum::{
+        body::Body, extract::Path, http::Request, http::StatusCode, middleware, routing::post,
+        Router,
+    };
+    use orm::web::{unit_of_work, RequestTransaction};
+    use tower::ServiceExt;
+
+    async fn create(tx: RequestTransaction, Path(status): Path<u16>) -> StatusCode {
+        tx.create(Order { is_tall: true }).await.unwrap();
+        StatusCode::from_u16(status).unwrap()
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let db = orm::asynch::Database::open_in_memory().await.unwrap();
+        let app = Router::new()
+            .route("/orders/{status}", post(create))
+            .layer(middleware::from_fn_with_state(db.clone(), unit_of_work));
+        let app_without = Router::new().route("/orders/{status}", post(create));
+
+        for status in [201, 303, 422, 500] {
+            let request = Request::post(format!("/orders/{}", status))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status().as_u16(), status);
+        }
+        let request = Request::post("/orders/201").body(Body::empty()).unwrap();
+        let response = app_without.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let tx = db.transaction().await.unwrap();
+        let orders = tx.run(|tx| tx.count_where::<Order>(&column("IsTall").eq(true)));
+        assert_eq!(orders.await.unwrap(), 2);
+    });
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_database() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        let db = orm::asynch::Database::open(&path).await.unwrap();
+
+        let tx = db.transaction().await.unwrap();
+        // The worker is held by the transaction until it ends.
+        assert!(db.total_changes().await.is_err());
+        assert!(db.transaction().await.is_err());
+        let mut user = User {
+            name: "Async".into(),
+            picture: Vec::new(),
+            visits: 1,
+            balance: 0.,
+            is_admin: false,
+        };
+        let id = tx.create(user.clone()).await.unwrap();
+        user.visits = 2;
+        tx.update(id, user.clone()).await.unwrap();
+        tx.commit().await.unwrap();
+        assert!(db.total_changes().await.unwrap() >= 2);
+
+        let tx = db.transaction().await.unwrap();
+        assert_eq!(tx.get::<User>(id).await.unwrap(), user);
+        tx.delete::<User>(id).await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let tx = db.transaction().await.unwrap();
+        let users = tx.select::<User>(column("visits").eq(2i64)).await.unwrap();
+        assert_eq!(users, vec![(id, user)]);
+        assert!(matches!(
+            tx.get::<User>(ObjectId::from(2)).await,
+            Err(orm::Error::NotFound(_))
+        ));
+    });
+}
+
 #[cfg(feature = "test-lifetimes-create")]
 #[test]
 fn lifetimes_create() {